@@ -0,0 +1,305 @@
+//! Packet capture layer, records every frame an interface sends and receives
+//! in pcap or pcapng format.
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use smoltcp::phy::{Medium, PcapLinkType};
+
+use super::tapped::Direction;
+
+/// Frames longer than this are truncated in the capture.
+const SNAPLEN: u32 = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureFormat {
+    /// libpcap format, microsecond timestamps
+    Pcap,
+    /// pcapng format, records the direction of each frame as well
+    Pcapng,
+}
+
+#[derive(Debug)]
+enum CaptureSink {
+    /// Buffered, records are written on the poll thread; the rest is flushed
+    /// by [`Capture::flush`] or on drop.
+    File(BufWriter<File>),
+    /// Encoded records, the oldest are dropped once `capacity` bytes are exceeded.
+    Ring {
+        records: VecDeque<Vec<u8>>,
+        size: usize,
+        capacity: usize,
+    },
+}
+
+/// A packet capture of one interface, see [`Iface::start_capture`](crate::interface::Iface::start_capture).
+#[derive(Debug)]
+pub struct Capture {
+    format: CaptureFormat,
+    sink: CaptureSink,
+    /// Known once the capture sees the device, the file header is written then.
+    link_type: Option<PcapLinkType>,
+}
+
+impl Capture {
+    /// Captures into the file at `path`, truncating it.
+    pub fn to_file<P: AsRef<Path>>(path: P, format: CaptureFormat) -> io::Result<Capture> {
+        Ok(Capture {
+            format,
+            sink: CaptureSink::File(BufWriter::new(File::create(path)?)),
+            link_type: None,
+        })
+    }
+
+    /// Captures into an in-memory ring holding at most `capacity` bytes of
+    /// records, read it with [`Capture::dump`].
+    pub fn ring(capacity: usize, format: CaptureFormat) -> Capture {
+        Capture {
+            format,
+            sink: CaptureSink::Ring {
+                records: VecDeque::new(),
+                size: 0,
+                capacity,
+            },
+            link_type: None,
+        }
+    }
+
+    pub fn format(&self) -> CaptureFormat {
+        self.format
+    }
+
+    /// Writes the buffered records of a file capture out to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            CaptureSink::File(file) => file.flush(),
+            CaptureSink::Ring { .. } => Ok(()),
+        }
+    }
+
+    /// The content of a ring capture as a complete pcap/pcapng file,
+    /// `None` for a file capture.
+    pub fn dump(&self) -> Option<Vec<u8>> {
+        let CaptureSink::Ring { records, size, .. } = &self.sink else {
+            return None;
+        };
+        let mut data = self.header(self.link_type.unwrap_or(PcapLinkType::Ethernet));
+        data.reserve(*size);
+        for record in records {
+            data.extend_from_slice(record);
+        }
+        Some(data)
+    }
+
+    /// Learns the link type from the device of the interface, a file capture
+    /// writes its header then.
+    pub fn attach(&mut self, medium: Medium) {
+        if self.link_type.is_some() {
+            return;
+        }
+        let link_type = match medium {
+            Medium::Ethernet => PcapLinkType::Ethernet,
+            Medium::Ip => PcapLinkType::Ip,
+        };
+        self.link_type = Some(link_type);
+        let header = self.header(link_type);
+        if let CaptureSink::File(file) = &mut self.sink {
+            if let Err(err) = file.write_all(&header) {
+                log::warn!("capture: failed to write header: {}", err);
+            }
+        }
+    }
+
+    /// Records a frame seen on the device, fed by the tap layer of the interface.
+    pub fn record(&mut self, direction: Direction, frame: &[u8]) {
+        let record = self.encode(direction, frame);
+        match &mut self.sink {
+            CaptureSink::File(file) => {
+                if let Err(err) = file.write_all(&record) {
+                    log::warn!("capture: failed to write record: {}", err);
+                }
+            }
+            CaptureSink::Ring {
+                records,
+                size,
+                capacity,
+            } => {
+                *size += record.len();
+                records.push_back(record);
+                while *size > *capacity {
+                    let Some(oldest) = records.pop_front() else {
+                        break;
+                    };
+                    *size -= oldest.len();
+                }
+            }
+        }
+    }
+
+    fn header(&self, link_type: PcapLinkType) -> Vec<u8> {
+        let mut data = Vec::new();
+        match self.format {
+            CaptureFormat::Pcap => {
+                data.extend_from_slice(&0xa1b2c3d4u32.to_ne_bytes()); // magic number
+                data.extend_from_slice(&2u16.to_ne_bytes()); // major version
+                data.extend_from_slice(&4u16.to_ne_bytes()); // minor version
+                data.extend_from_slice(&0u32.to_ne_bytes()); // timezone (= UTC)
+                data.extend_from_slice(&0u32.to_ne_bytes()); // accuracy (not used)
+                data.extend_from_slice(&SNAPLEN.to_ne_bytes());
+                data.extend_from_slice(&u32::from(link_type).to_ne_bytes());
+            }
+            CaptureFormat::Pcapng => {
+                // Section Header Block
+                let mut body = Vec::new();
+                body.extend_from_slice(&0x1a2b3c4du32.to_ne_bytes()); // byte-order magic
+                body.extend_from_slice(&1u16.to_ne_bytes()); // major version
+                body.extend_from_slice(&0u16.to_ne_bytes()); // minor version
+                body.extend_from_slice(&(-1i64).to_ne_bytes()); // section length unknown
+                push_block(&mut data, 0x0a0d0d0a, &body);
+                // Interface Description Block, default resolution is microseconds
+                let mut body = Vec::new();
+                body.extend_from_slice(&(u32::from(link_type) as u16).to_ne_bytes());
+                body.extend_from_slice(&0u16.to_ne_bytes()); // reserved
+                body.extend_from_slice(&SNAPLEN.to_ne_bytes());
+                push_block(&mut data, 0x00000001, &body);
+            }
+        }
+        data
+    }
+
+    fn encode(&self, direction: Direction, frame: &[u8]) -> Vec<u8> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let captured = &frame[..frame.len().min(SNAPLEN as usize)];
+        let mut data = Vec::with_capacity(captured.len() + 48);
+        match self.format {
+            CaptureFormat::Pcap => {
+                data.extend_from_slice(&(now.as_secs() as u32).to_ne_bytes());
+                data.extend_from_slice(&now.subsec_micros().to_ne_bytes());
+                data.extend_from_slice(&(captured.len() as u32).to_ne_bytes());
+                data.extend_from_slice(&(frame.len() as u32).to_ne_bytes());
+                data.extend_from_slice(captured);
+            }
+            CaptureFormat::Pcapng => {
+                // Enhanced Packet Block
+                let micros = now.as_micros() as u64;
+                let mut body = Vec::with_capacity(captured.len() + 32);
+                body.extend_from_slice(&0u32.to_ne_bytes()); // interface id
+                body.extend_from_slice(&((micros >> 32) as u32).to_ne_bytes());
+                body.extend_from_slice(&(micros as u32).to_ne_bytes());
+                body.extend_from_slice(&(captured.len() as u32).to_ne_bytes());
+                body.extend_from_slice(&(frame.len() as u32).to_ne_bytes());
+                body.extend_from_slice(captured);
+                body.resize(body.len().next_multiple_of(4), 0);
+                // epb_flags option, the lowest two bits hold the direction
+                let flags: u32 = match direction {
+                    Direction::Inbound => 0b01,
+                    Direction::Outbound => 0b10,
+                };
+                body.extend_from_slice(&2u16.to_ne_bytes());
+                body.extend_from_slice(&4u16.to_ne_bytes());
+                body.extend_from_slice(&flags.to_ne_bytes());
+                body.extend_from_slice(&[0; 4]); // opt_endofopt
+                push_block(&mut data, 0x00000006, &body);
+            }
+        }
+        data
+    }
+}
+
+/// Appends a pcapng block, `body` must be padded to 32 bits already.
+fn push_block(data: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+    let total = (body.len() + 12) as u32;
+    data.extend_from_slice(&block_type.to_ne_bytes());
+    data.extend_from_slice(&total.to_ne_bytes());
+    data.extend_from_slice(body);
+    data.extend_from_slice(&total.to_ne_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_ne_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_ne_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn pcap_header_and_records() {
+        let mut capture = Capture::ring(1 << 20, CaptureFormat::Pcap);
+        capture.attach(Medium::Ethernet);
+        capture.record(Direction::Inbound, &[0xaa; 60]);
+        capture.record(Direction::Outbound, &vec![0xbb; SNAPLEN as usize + 10]);
+        let data = capture.dump().unwrap();
+
+        assert_eq!(u32_at(&data, 0), 0xa1b2c3d4);
+        assert_eq!((u16_at(&data, 4), u16_at(&data, 6)), (2, 4));
+        assert_eq!(u32_at(&data, 16), SNAPLEN);
+        assert_eq!(u32_at(&data, 20), u32::from(PcapLinkType::Ethernet));
+
+        let first = &data[24..];
+        assert_eq!((u32_at(first, 8), u32_at(first, 12)), (60, 60));
+        assert_eq!(&first[16..76], &[0xaa; 60]);
+        // frames beyond the snap length are truncated, their length is kept
+        let second = &first[76..];
+        assert_eq!(u32_at(second, 8), SNAPLEN);
+        assert_eq!(u32_at(second, 12), SNAPLEN + 10);
+        assert_eq!(second.len(), 16 + SNAPLEN as usize);
+    }
+
+    #[test]
+    fn pcapng_blocks() {
+        let mut capture = Capture::ring(1 << 20, CaptureFormat::Pcapng);
+        capture.attach(Medium::Ip);
+        capture.record(Direction::Inbound, &[0x45; 21]);
+        capture.record(Direction::Outbound, &[0x45; 20]);
+        let data = capture.dump().unwrap();
+
+        // section header block
+        assert_eq!(u32_at(&data, 0), 0x0a0d0d0a);
+        assert_eq!(u32_at(&data, 4), 28);
+        assert_eq!(u32_at(&data, 8), 0x1a2b3c4d);
+        assert_eq!(u32_at(&data, 24), 28);
+        // interface description block
+        let idb = &data[28..];
+        assert_eq!((u32_at(idb, 0), u32_at(idb, 4)), (1, 20));
+        assert_eq!(u16_at(idb, 8) as u32, u32::from(PcapLinkType::Ip));
+        assert_eq!(u32_at(idb, 12), SNAPLEN);
+
+        // enhanced packet blocks, padded to 32 bits, with the direction in epb_flags
+        let mut block = &idb[20..];
+        for (len, flags) in [(21, 0b01), (20, 0b10)] {
+            let total = u32_at(block, 4) as usize;
+            assert_eq!(u32_at(block, 0), 6);
+            assert_eq!(total % 4, 0);
+            assert_eq!(u32_at(block, total - 4) as usize, total);
+            assert_eq!((u32_at(block, 20), u32_at(block, 24)), (len, len));
+            let options = 28 + (len as usize).next_multiple_of(4);
+            assert_eq!((u16_at(block, options), u16_at(block, options + 2)), (2, 4));
+            assert_eq!(u32_at(block, options + 4), flags);
+            block = &block[total..];
+        }
+        assert!(block.is_empty());
+    }
+
+    #[test]
+    fn ring_drops_the_oldest_records() {
+        let mut capture = Capture::ring(100, CaptureFormat::Pcap);
+        capture.attach(Medium::Ethernet);
+        for byte in 0..4 {
+            capture.record(Direction::Inbound, &[byte; 34]);
+        }
+        // 50 bytes a record, the last two fit
+        let data = capture.dump().unwrap();
+        assert_eq!(data.len(), 24 + 2 * 50);
+        assert_eq!(data[24 + 16], 2);
+        assert_eq!(data[24 + 50 + 16], 3);
+    }
+}
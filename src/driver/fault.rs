@@ -0,0 +1,501 @@
+//! Fault injection layer, emulates a lossy link in the spirit of `tc netem`
+//! without root or a qdisc.
+//!
+//! Every random decision is drawn from a generator seeded by
+//! [`FaultConfig::seed`], the same seed and the same traffic give the same
+//! faults in every run. Delays are measured on the wall clock though, with
+//! `jitter` the order in which frames arrive also depends on when they were sent.
+use std::collections::BTreeMap;
+
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::{Duration, Instant};
+use spin::Mutex;
+
+use super::tapped::Direction;
+
+/// Frames held back in one direction beyond this are dropped.
+const FAULT_QUEUE_LEN: usize = 1024;
+
+/// What goes wrong on the link, probabilities are in `0.0..=1.0`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultConfig {
+    /// Seed of the generator behind every random decision
+    pub seed: u64,
+    /// Probability of a frame being dropped
+    pub loss: f64,
+    /// Probability of a frame being sent twice
+    pub duplicate: f64,
+    /// Probability of a single bit of a frame being flipped
+    pub corrupt: f64,
+    /// Probability of a frame skipping `delay`, overtaking the frames
+    /// delayed before it. Needs a non-zero `delay` to have an effect.
+    pub reorder: f64,
+    /// Latency added to every frame
+    pub delay: Duration,
+    /// Random variation of `delay`, uniformly in `-jitter..=jitter`
+    pub jitter: Duration,
+    /// Apply the faults to received frames
+    pub ingress: bool,
+    /// Apply the faults to transmitted frames
+    pub egress: bool,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            loss: 0.0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            reorder: 0.0,
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            ingress: true,
+            egress: true,
+        }
+    }
+}
+
+/// Counters of the faults injected so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub corrupted: u64,
+    pub reordered: u64,
+    pub delayed: u64,
+    /// Frames dropped because too many were held back already
+    pub overflowed: u64,
+}
+
+/// Frames held back in one direction, ordered by release time and then by
+/// arrival so that frames due at the same time keep their order.
+#[derive(Debug, Default)]
+struct Pending {
+    frames: BTreeMap<(Instant, u64), Vec<u8>>,
+    seq: u64,
+}
+
+impl Pending {
+    fn push(&mut self, at: Instant, frame: Vec<u8>) -> bool {
+        if self.frames.len() >= FAULT_QUEUE_LEN {
+            return false;
+        }
+        self.seq += 1;
+        self.frames.insert((at, self.seq), frame);
+        true
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.next().is_some_and(|at| at <= now)
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if !self.is_due(now) {
+            return None;
+        }
+        self.frames.pop_first().map(|(_, frame)| frame)
+    }
+
+    fn next(&self) -> Option<Instant> {
+        self.frames.first_key_value().map(|(&(at, _), _)| at)
+    }
+}
+
+/// The faults of one interface, see [`Iface::inject_faults`](crate::interface::Iface::inject_faults).
+#[derive(Debug)]
+pub struct FaultInjector {
+    config: FaultConfig,
+    /// xorshift64* state, never zero
+    rng: u64,
+    rx: Pending,
+    tx: Pending,
+    stats: FaultStats,
+}
+
+impl FaultInjector {
+    pub fn new(config: FaultConfig) -> Self {
+        // splitmix64 finalizer, spreads small seeds and keeps the state non-zero
+        let mut rng = config.seed.wrapping_add(0x9e3779b97f4a7c15);
+        rng = (rng ^ (rng >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        rng = (rng ^ (rng >> 27)).wrapping_mul(0x94d049bb133111eb);
+        rng ^= rng >> 31;
+        Self {
+            config,
+            rng: rng.max(1),
+            rx: Pending::default(),
+            tx: Pending::default(),
+            stats: FaultStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &FaultConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> FaultStats {
+        self.stats
+    }
+
+    /// The earliest time a held back frame is due.
+    pub fn poll_at(&self) -> Option<Instant> {
+        match (self.rx.next(), self.tx.next()) {
+            (Some(rx), Some(tx)) => Some(rx.min(tx)),
+            (rx, tx) => rx.or(tx),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        // 53 random bits, uniform in [0, 1)
+        let sample = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        sample < probability
+    }
+
+    fn pending(&mut self, direction: Direction) -> &mut Pending {
+        match direction {
+            Direction::Inbound => &mut self.rx,
+            Direction::Outbound => &mut self.tx,
+        }
+    }
+
+    fn inject(&mut self, direction: Direction, now: Instant, mut frame: Vec<u8>) {
+        let applies = match direction {
+            Direction::Inbound => self.config.ingress,
+            Direction::Outbound => self.config.egress,
+        };
+        if !applies {
+            self.hold(direction, now, frame);
+            return;
+        }
+
+        if self.chance(self.config.loss) {
+            self.stats.dropped += 1;
+            return;
+        }
+        if !frame.is_empty() && self.chance(self.config.corrupt) {
+            let bit = self.next_u64() % (frame.len() as u64 * 8);
+            frame[(bit / 8) as usize] ^= 1 << (bit % 8);
+            self.stats.corrupted += 1;
+        }
+
+        let at = if self.config.delay > Duration::ZERO && self.chance(self.config.reorder) {
+            self.stats.reordered += 1;
+            now
+        } else {
+            self.delayed(now)
+        };
+
+        if self.chance(self.config.duplicate) {
+            self.stats.duplicated += 1;
+            self.hold(direction, at, frame.clone());
+        }
+        self.hold(direction, at, frame);
+    }
+
+    fn delayed(&mut self, now: Instant) -> Instant {
+        let delay = self.config.delay.total_micros() as i64;
+        let jitter = self.config.jitter.total_micros() as i64;
+        let delay = if jitter > 0 {
+            let offset = (self.next_u64() % (2 * jitter as u64 + 1)) as i64 - jitter;
+            (delay + offset).max(0)
+        } else {
+            delay
+        };
+        if delay > 0 {
+            self.stats.delayed += 1;
+        }
+        now + Duration::from_micros(delay as u64)
+    }
+
+    fn hold(&mut self, direction: Direction, at: Instant, frame: Vec<u8>) {
+        if !self.pending(direction).push(at, frame) {
+            log::debug!("fault: queue full, frame dropped");
+            self.stats.overflowed += 1;
+        }
+    }
+}
+
+/// Wraps the device of an interface during a poll, running every frame through
+/// the fault injector of the interface, if one is set.
+///
+/// Frames the faults hold back are kept in the injector and handed over in a
+/// later poll, once they are due.
+pub struct FaultDevice<'a, D: Device + ?Sized> {
+    inner: &'a mut D,
+    faults: &'a Mutex<Option<FaultInjector>>,
+    timestamp: Instant,
+}
+
+impl<'a, D: Device + ?Sized> FaultDevice<'a, D> {
+    pub fn new(
+        inner: &'a mut D,
+        faults: &'a Mutex<Option<FaultInjector>>,
+        timestamp: Instant,
+    ) -> Self {
+        let mut device = Self {
+            inner,
+            faults,
+            timestamp,
+        };
+        device.flush();
+        device
+    }
+
+    /// Transmits the held back frames that are due.
+    fn flush(&mut self) {
+        let faults = self.faults;
+        loop {
+            let mut guard = faults.lock();
+            let Some(injector) = guard.as_mut() else {
+                return;
+            };
+            if !injector.tx.is_due(self.timestamp) {
+                return;
+            }
+            let Some(token) = self.inner.transmit(self.timestamp) else {
+                return;
+            };
+            let Some(frame) = injector.tx.pop_due(self.timestamp) else {
+                return;
+            };
+            drop(guard);
+            phy::TxToken::consume(token, frame.len(), |buffer| buffer.copy_from_slice(&frame));
+        }
+    }
+}
+
+impl<D: Device + ?Sized> Drop for FaultDevice<'_, D> {
+    fn drop(&mut self) {
+        // frames sent without delay during this poll go out right away
+        self.flush();
+    }
+}
+
+impl<D: Device + ?Sized> Device for FaultDevice<'_, D> {
+    type RxToken<'b>
+        = RxToken<D::RxToken<'b>>
+    where
+        Self: 'b;
+    type TxToken<'b>
+        = TxToken<'b, D::TxToken<'b>>
+    where
+        Self: 'b;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let faults = self.faults;
+        let mut guard = faults.lock();
+        let Some(injector) = guard.as_mut() else {
+            drop(guard);
+            return self
+                .inner
+                .receive(timestamp)
+                .map(|(rx, tx)| (RxToken::Direct(rx), TxToken::Direct(tx)));
+        };
+
+        // take in everything the device has, the faults decide when it is due
+        while let Some((rx, _)) = self.inner.receive(timestamp) {
+            let frame = phy::RxToken::consume(rx, |buffer| buffer.to_vec());
+            injector.inject(Direction::Inbound, timestamp, frame);
+        }
+        let frame = injector.rx.pop_due(timestamp)?;
+        drop(guard);
+        Some((
+            RxToken::Faulty(frame),
+            TxToken::Faulty { faults, timestamp },
+        ))
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        let faults = self.faults;
+        if faults.lock().is_some() {
+            return Some(TxToken::Faulty { faults, timestamp });
+        }
+        self.inner.transmit(timestamp).map(TxToken::Direct)
+    }
+}
+
+#[doc(hidden)]
+pub enum RxToken<T: phy::RxToken> {
+    Direct(T),
+    Faulty(Vec<u8>),
+}
+
+impl<T: phy::RxToken> phy::RxToken for RxToken<T> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        match self {
+            RxToken::Direct(inner) => inner.consume(f),
+            RxToken::Faulty(buffer) => f(&buffer),
+        }
+    }
+
+    fn meta(&self) -> phy::PacketMeta {
+        match self {
+            RxToken::Direct(inner) => inner.meta(),
+            RxToken::Faulty(_) => phy::PacketMeta::default(),
+        }
+    }
+}
+
+#[doc(hidden)]
+pub enum TxToken<'a, T: phy::TxToken> {
+    Direct(T),
+    Faulty {
+        faults: &'a Mutex<Option<FaultInjector>>,
+        timestamp: Instant,
+    },
+}
+
+impl<T: phy::TxToken> phy::TxToken for TxToken<'_, T> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        match self {
+            TxToken::Direct(inner) => inner.consume(len, f),
+            TxToken::Faulty { faults, timestamp } => {
+                let mut buffer = vec![0; len];
+                let result = f(&mut buffer);
+                if let Some(injector) = faults.lock().as_mut() {
+                    injector.inject(Direction::Outbound, timestamp, buffer);
+                }
+                result
+            }
+        }
+    }
+
+    fn set_meta(&mut self, meta: phy::PacketMeta) {
+        if let TxToken::Direct(inner) = self {
+            inner.set_meta(meta)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `count` numbered frames through the egress faults, one per
+    /// millisecond, and returns the frames in the order they come out
+    fn run(config: FaultConfig, count: u32) -> (Vec<Vec<u8>>, FaultStats) {
+        let mut injector = FaultInjector::new(config);
+        let mut out = Vec::new();
+        for n in 0..count {
+            let now = Instant::from_millis(n as i64);
+            let mut frame = n.to_be_bytes().to_vec();
+            frame.resize(64, 0xa5);
+            injector.inject(Direction::Outbound, now, frame);
+            while let Some(frame) = injector.tx.pop_due(now) {
+                out.push(frame);
+            }
+        }
+        while let Some(frame) = injector.tx.pop_due(Instant::from_secs(3600)) {
+            out.push(frame);
+        }
+        (out, injector.stats())
+    }
+
+    fn lossy(seed: u64) -> FaultConfig {
+        FaultConfig {
+            seed,
+            loss: 0.1,
+            duplicate: 0.05,
+            corrupt: 0.05,
+            reorder: 0.1,
+            delay: Duration::from_millis(5),
+            jitter: Duration::from_millis(3),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_faults() {
+        let (frames, stats) = run(lossy(42), 2000);
+        assert_eq!(run(lossy(42), 2000), (frames.clone(), stats));
+        assert_ne!(run(lossy(43), 2000).0, frames);
+
+        assert!(stats.dropped > 0);
+        assert!(stats.duplicated > 0);
+        assert!(stats.corrupted > 0);
+        assert!(stats.reordered > 0);
+        assert_eq!(stats.overflowed, 0);
+        let sent = 2000 - stats.dropped + stats.duplicated;
+        assert_eq!(frames.len() as u64, sent);
+        // the jitter and reordering let frames overtake each other
+        assert!(frames.windows(2).any(|pair| pair[0][..4] > pair[1][..4]));
+    }
+
+    #[test]
+    fn probabilities_are_respected() {
+        let config = FaultConfig {
+            seed: 7,
+            loss: 0.25,
+            ..Default::default()
+        };
+        let (frames, stats) = run(config, 10000);
+        assert!((2200..2800).contains(&stats.dropped), "{:?}", stats);
+        assert_eq!(frames.len() as u64, 10000 - stats.dropped);
+
+        // without faults every frame comes out unchanged and in order
+        let (frames, stats) = run(FaultConfig::default(), 100);
+        assert_eq!(stats, FaultStats::default());
+        for (n, frame) in frames.iter().enumerate() {
+            assert_eq!(frame[..4], (n as u32).to_be_bytes());
+            assert!(frame[4..].iter().all(|&byte| byte == 0xa5));
+        }
+    }
+
+    #[test]
+    fn delay_holds_frames_back() {
+        let mut injector = FaultInjector::new(FaultConfig {
+            delay: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let start = Instant::from_millis(100);
+        injector.inject(Direction::Outbound, start, vec![1]);
+        injector.inject(Direction::Inbound, start, vec![2]);
+        assert_eq!(injector.poll_at(), Some(Instant::from_millis(110)));
+        assert_eq!(injector.tx.pop_due(Instant::from_millis(109)), None);
+        assert_eq!(
+            injector.tx.pop_due(Instant::from_millis(110)),
+            Some(vec![1])
+        );
+        assert_eq!(
+            injector.rx.pop_due(Instant::from_millis(110)),
+            Some(vec![2])
+        );
+        assert_eq!(injector.poll_at(), None);
+        assert_eq!(injector.stats().delayed, 2);
+    }
+
+    #[test]
+    fn faults_apply_to_the_chosen_directions() {
+        let mut injector = FaultInjector::new(FaultConfig {
+            loss: 1.0,
+            ingress: false,
+            ..Default::default()
+        });
+        let now = Instant::from_millis(0);
+        injector.inject(Direction::Inbound, now, vec![1]);
+        injector.inject(Direction::Outbound, now, vec![2]);
+        assert_eq!(injector.rx.pop_due(now), Some(vec![1]));
+        assert_eq!(injector.tx.pop_due(now), None);
+        assert_eq!(injector.stats().dropped, 1);
+    }
+}
@@ -1,38 +1,83 @@
-use std::os::unix::io::RawFd;
-use std::time::Duration;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
 use std::{io, thread};
 
 use hashbrown::HashMap;
+use smoltcp::time::Instant;
 
-use crate::socket::inet::common::NET_DEVICES;
+use crate::interface::registry::{IfaceEvent, IfaceSubscriber, NET_DEVICES};
+use crate::interface::Iface;
 
 const EPOLL_TIMEOUT_MS: i32 = 100;
+const MIN_POLL_TIMEOUT_MS: i32 = 1;
+
+/// Marks the registry subscription among the epoll events, never a file descriptor
+const REGISTRY_EVENT: u64 = u64::MAX;
 
 /// Start a thread that polls network devices when their tap interfaces are readable
 pub fn start_network_polling_thread() -> io::Result<thread::JoinHandle<()>> {
+    let mut workers = start_network_polling_workers(1)?;
+    Ok(workers.remove(0))
+}
+
+/// Start `workers` threads polling the queues of the network devices, queue `n`
+/// of every device is polled by worker `n % workers`. Worker 0 also runs the
+/// timers and polls the devices without a file descriptor.
+///
+/// The workers read the frames of their queues in parallel, but the queues of
+/// one interface share its protocol stack and are processed one at a time: a
+/// worker finding the stack busy sleeps until the other one is done.
+pub fn start_network_polling_workers(workers: usize) -> io::Result<Vec<thread::JoinHandle<()>>> {
+    if workers == 0 {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    (0..workers)
+        .map(|worker| start_worker(worker, workers))
+        .collect()
+}
+
+fn start_worker(worker: usize, workers: usize) -> io::Result<thread::JoinHandle<()>> {
     // Create an epoll instance
     let epoll_fd = unsafe { libc::epoll_create1(0) };
     if epoll_fd < 0 {
         return Err(io::Error::last_os_error());
     }
 
+    // Subscribe before listing the devices, so that none registered in between is missed
+    let subscriber = NET_DEVICES.subscribe()?;
+    let mut event = libc::epoll_event {
+        events: libc::EPOLLIN as u32,
+        u64: REGISTRY_EVENT,
+    };
+    if unsafe {
+        libc::epoll_ctl(
+            epoll_fd,
+            libc::EPOLL_CTL_ADD,
+            subscriber.as_raw_fd(),
+            &mut event,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
     let handle = thread::spawn(move || {
         let mut events = Vec::with_capacity(32);
         events.resize(32, libc::epoll_event { events: 0, u64: 0 });
-        let mut fd_to_device_id = HashMap::new();
+        let mut fd_to_queue = HashMap::new();
+        for device in NET_DEVICES.ifaces() {
+            watch_device(epoll_fd, worker, workers, &device, &mut fd_to_queue);
+        }
 
         loop {
-            // Update the list of devices to watch
-            update_watched_devices(epoll_fd, &mut fd_to_device_id);
-
-            // Wait for events
+            // Wait for events, or until the earliest device asks to be polled
+            let timeout = if worker == 0 {
+                next_poll_timeout()
+            } else {
+                EPOLL_TIMEOUT_MS
+            };
             let num_events = unsafe {
-                libc::epoll_wait(
-                    epoll_fd,
-                    events.as_mut_ptr(),
-                    events.len() as i32,
-                    EPOLL_TIMEOUT_MS,
-                )
+                libc::epoll_wait(epoll_fd, events.as_mut_ptr(), events.len() as i32, timeout)
             };
 
             if num_events < 0 {
@@ -47,85 +92,146 @@ pub fn start_network_polling_thread() -> io::Result<thread::JoinHandle<()>> {
             // Process events
             for event in events.iter().take(num_events as usize) {
                 log::trace!("epoll_wait returned {} events", num_events);
+                if event.u64 == REGISTRY_EVENT {
+                    // Update the list of queues to watch
+                    update_watched_devices(
+                        epoll_fd,
+                        worker,
+                        workers,
+                        &subscriber,
+                        &mut fd_to_queue,
+                    );
+                    continue;
+                }
                 let fd = event.u64 as RawFd;
 
-                if let Some(&device_id) = fd_to_device_id.get(&fd) {
-                    if let Some(device) = NET_DEVICES.read().get(&device_id) {
-                        // Poll the device that has data available
-                        device.poll();
+                if let Some(&(device_id, queue)) = fd_to_queue.get(&fd) {
+                    if let Some(device) = NET_DEVICES.get(device_id) {
+                        // Poll the queue that has data available
+                        device.poll_queue(queue);
                     }
                 }
             }
 
-            // // Also poll all devices periodically regardless of events
-            // // This ensures timers and other internal state are updated
-            // for device in NET_DEVICES.read().values() {
-            //     device.poll();
-            // }
+            // the timers are left to worker 0, the others only wait for frames
+            if worker != 0 {
+                continue;
+            }
 
-            // Small sleep to prevent CPU hogging
-            thread::sleep(Duration::from_millis(1));
+            // Devices without a file descriptor (e.g. loopback) never show up in
+            // epoll, poll them each round so their timers keep running. The others
+            // are polled once their timers (retransmission, delayed frames) are due.
+            let now = Instant::now();
+            for device in NET_DEVICES.ifaces() {
+                if device.raw_fd().is_none() {
+                    device.poll();
+                } else if device.poll_at().is_some_and(|at| at <= now) {
+                    device.poll_queue(0);
+                }
+            }
         }
     });
 
     Ok(handle)
 }
 
-/// Update the list of devices being watched by epoll
-fn update_watched_devices(epoll_fd: RawFd, fd_to_device_id: &mut HashMap<RawFd, usize>) {
-    let devices = NET_DEVICES.read();
-
-    // Find new devices to add
-    for (&id, device) in devices.iter() {
-        // Try to downcast to get TAP device
-        if let Some(tap_fd) = device.raw_fd() {
-            if !fd_to_device_id.contains_key(&tap_fd) {
-                // Add new device to epoll
-                let mut event = libc::epoll_event {
-                    events: (libc::EPOLLIN | libc::EPOLLET) as u32,
-                    u64: tap_fd as u64,
-                };
-
-                let result =
-                    unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, tap_fd, &mut event) };
-
-                if result == 0 {
-                    fd_to_device_id.insert(tap_fd, id);
-                    log::trace!("Added device fd {} to epoll", tap_fd);
-                } else {
-                    log::error!(
-                        "Failed to add device fd {} to epoll: {:?}",
-                        tap_fd,
-                        io::Error::last_os_error()
-                    );
+/// Milliseconds until the earliest device asks to be polled, capped at `EPOLL_TIMEOUT_MS`.
+/// A poll already due still waits 1 ms for frames, so that a device asking to be
+/// polled again right away (e.g. a full transmit queue) does not hog the CPU.
+fn next_poll_timeout() -> i32 {
+    let now = Instant::now();
+    NET_DEVICES
+        .ifaces()
+        .iter()
+        .filter_map(|device| device.poll_at())
+        .map(|at| {
+            (at - now.min(at))
+                .total_millis()
+                .min(EPOLL_TIMEOUT_MS as u64) as i32
+        })
+        .fold(EPOLL_TIMEOUT_MS, i32::min)
+        .max(MIN_POLL_TIMEOUT_MS)
+}
+
+/// Update the device queues watched by the epoll of `worker` with the registry
+/// events since the last call
+fn update_watched_devices(
+    epoll_fd: RawFd,
+    worker: usize,
+    workers: usize,
+    subscriber: &IfaceSubscriber,
+    fd_to_queue: &mut HashMap<RawFd, (usize, usize)>,
+) {
+    for event in subscriber.take_events() {
+        match event {
+            IfaceEvent::Added(id) => {
+                if let Some(device) = NET_DEVICES.get(id) {
+                    watch_device(epoll_fd, worker, workers, &device, fd_to_queue);
+                }
+            }
+            IfaceEvent::Removed(id) => unwatch_device(epoll_fd, id, fd_to_queue),
+            // a device brought up restarts its timers with a poll
+            IfaceEvent::StateChanged(id) if worker == 0 => {
+                if let Some(device) = NET_DEVICES.get(id) {
+                    if device.common().is_running() {
+                        device.poll_queue(0);
+                    }
                 }
             }
+            IfaceEvent::StateChanged(_) => {}
         }
     }
+}
 
-    // Find devices to remove
-    let mut to_remove = Vec::new();
-    for &fd in fd_to_device_id.keys() {
-        let id = fd_to_device_id[&fd];
-        if !devices.contains_key(&id) {
-            to_remove.push(fd);
-
-            // Remove from epoll
-            let result =
-                unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) };
-
-            if result != 0 {
-                log::error!(
-                    "Failed to remove device fd {} from epoll: {:?}",
-                    fd,
-                    io::Error::last_os_error()
-                );
-            }
+/// Add the queues of `device` polled by `worker` to its epoll
+fn watch_device(
+    epoll_fd: RawFd,
+    worker: usize,
+    workers: usize,
+    device: &Arc<dyn Iface>,
+    fd_to_queue: &mut HashMap<RawFd, (usize, usize)>,
+) {
+    for (queue, tap_fd) in device.queue_fds().into_iter().enumerate() {
+        if queue % workers != worker || fd_to_queue.contains_key(&tap_fd) {
+            continue;
+        }
+        // Add new device to epoll
+        let mut event = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLET) as u32,
+            u64: tap_fd as u64,
+        };
+
+        let result = unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, tap_fd, &mut event) };
+
+        if result == 0 {
+            fd_to_queue.insert(tap_fd, (device.nic_id(), queue));
+            log::trace!("Added device fd {} to epoll", tap_fd);
+        } else {
+            log::error!(
+                "Failed to add device fd {} to epoll: {:?}",
+                tap_fd,
+                io::Error::last_os_error()
+            );
         }
     }
+}
 
-    // Actually remove the entries
-    for fd in to_remove {
-        fd_to_device_id.remove(&fd);
-    }
+/// Remove the queues of the device `id` from the epoll
+fn unwatch_device(epoll_fd: RawFd, id: usize, fd_to_queue: &mut HashMap<RawFd, (usize, usize)>) {
+    fd_to_queue.retain(|&fd, &mut (device_id, _)| {
+        if device_id != id {
+            return true;
+        }
+        let result =
+            unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) };
+        // a closed fd has already left the epoll
+        if result != 0 {
+            log::debug!(
+                "Failed to remove device fd {} from epoll: {:?}",
+                fd,
+                io::Error::last_os_error()
+            );
+        }
+        false
+    });
 }
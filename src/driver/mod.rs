@@ -1,10 +1,65 @@
 use std::{io, mem};
 
+use scopeguard::defer;
+
+pub mod capture;
+pub mod fault;
 pub mod tap;
 pub use tap::TapDesc;
 pub mod irq;
+pub mod loopback;
+pub mod neighbor;
+pub mod stats;
+pub mod tapped;
+pub mod veth;
+pub mod vnet;
 
 use libc::ifreq;
+use smoltcp::wire::EthernetAddress;
+
+/// A random unicast, locally administered MAC address.
+pub fn random_mac() -> EthernetAddress {
+    let random: u64 = std::random::random(..);
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&random.to_ne_bytes()[..6]);
+    // clear the multicast bit, set the locally administered bit
+    mac[0] = (mac[0] & !0x01) | 0x02;
+    EthernetAddress(mac)
+}
+
+/// What happened on a device since it was last asked, see
+/// [`TapDevice::take_io_status`](tap::TapDevice::take_io_status).
+#[derive(Debug, Default)]
+pub struct IoStatus {
+    /// Failed reads
+    pub rx_errors: u64,
+    /// Failed writes
+    pub tx_errors: u64,
+    /// Frames read but unusable
+    pub rx_dropped: u64,
+    /// Frames lost on a full queue
+    pub tx_dropped: u64,
+    /// The latest failure, cleared by a later successful read or write.
+    /// Transient failures, like a full queue, are only counted.
+    pub error: Option<io::Error>,
+    /// Some frame was read or written
+    pub ok: bool,
+}
+
+impl IoStatus {
+    fn succeeded(&mut self) {
+        self.ok = true;
+        self.error = None;
+    }
+
+    fn failed(&mut self, err: io::Error) {
+        let transient =
+            err.kind() == io::ErrorKind::Interrupted || err.raw_os_error() == Some(libc::ENOBUFS);
+        if !transient {
+            self.error = Some(err);
+        }
+    }
+}
 
 fn ifreq_for(name: &str) -> ifreq {
     let mut ifreq = ifreq {
@@ -26,3 +81,72 @@ fn ifreq_ioctl(lower: libc::c_int, cmd: libc::c_ulong, ifreq: &mut ifreq) -> io:
         Ok(res)
     }
 }
+
+/// Issues an interface ioctl (`SIOCGIFMTU`, `SIOCSIFFLAGS`, ...) through a
+/// throwaway socket, the TUN/TAP descriptor does not answer them.
+fn ifreq_socket_ioctl(cmd: libc::c_ulong, ifreq: &mut ifreq) -> io::Result<i32> {
+    let lower = unsafe {
+        let lower = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_IP);
+        if lower == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        lower
+    };
+    defer!(unsafe {
+        libc::close(lower);
+    });
+    ifreq_ioctl(lower, cmd, ifreq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_mac_is_unicast_and_locally_administered() {
+        let macs: Vec<EthernetAddress> = (0..64).map(|_| random_mac()).collect();
+        for mac in &macs {
+            assert!(mac.is_unicast(), "{}", mac);
+            assert!(mac.is_local(), "{}", mac);
+        }
+        // the other bits are random
+        assert!(macs.iter().any(|mac| *mac != macs[0]));
+    }
+
+    #[test]
+    fn io_status_keeps_the_latest_lasting_failure() {
+        let mut status = IoStatus::default();
+        status.failed(io::Error::from_raw_os_error(libc::EIO));
+        assert_eq!(
+            status.error.as_ref().unwrap().raw_os_error(),
+            Some(libc::EIO)
+        );
+
+        // a full queue or an interrupted call says nothing about the link
+        status.failed(io::Error::from_raw_os_error(libc::ENOBUFS));
+        status.failed(io::Error::from(io::ErrorKind::Interrupted));
+        assert_eq!(
+            status.error.as_ref().unwrap().raw_os_error(),
+            Some(libc::EIO)
+        );
+
+        status.failed(io::Error::from_raw_os_error(libc::ENETDOWN));
+        assert_eq!(
+            status.error.as_ref().unwrap().raw_os_error(),
+            Some(libc::ENETDOWN)
+        );
+
+        status.succeeded();
+        assert!(status.ok);
+        assert!(status.error.is_none());
+    }
+
+    #[test]
+    fn transient_failures_leave_no_error() {
+        let mut status = IoStatus::default();
+        status.failed(io::Error::from_raw_os_error(libc::ENOBUFS));
+        status.failed(io::Error::from(io::ErrorKind::Interrupted));
+        assert!(status.error.is_none());
+        assert!(!status.ok);
+    }
+}
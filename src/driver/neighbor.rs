@@ -0,0 +1,1100 @@
+//! Neighbor table of an interface, like `ip neigh`.
+//!
+//! The ARP/NDP cache of smoltcp is private, so this layer keeps a mirror of it:
+//! ARP and NDP frames reaching smoltcp are snooped the way smoltcp fills its
+//! cache. Static entries are enforced from the outside, a solicitation smoltcp
+//! sends for a static address is answered with a reply made up here, and a
+//! frame claiming a static address for another MAC is dropped before smoltcp
+//! sees it. Addresses smoltcp keeps soliciting without an answer are listed as
+//! incomplete.
+//!
+//! smoltcp resolves only the addresses on its subnets, an on-link route to
+//! another subnet is resolved here. smoltcp routes the destinations through an
+//! address of the interface, which this layer resolves to [`ON_LINK_MAC`], and
+//! the frames sent there are addressed to the destination on the way out.
+use std::collections::VecDeque;
+
+use smoltcp::phy::{self, ChecksumCapabilities, Device, DeviceCapabilities, Medium};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+    EthernetRepr, HardwareAddress, Icmpv6Packet, Icmpv6Repr, IpAddress, IpCidr, IpProtocol,
+    Ipv4Packet, Ipv6Address, Ipv6Packet, Ipv6Repr, NdiscNeighborFlags, NdiscRepr,
+};
+use spin::Mutex;
+
+/// Lifetime of a learned entry, as in smoltcp.
+const ENTRY_LIFETIME: Duration = Duration::from_millis(60_000);
+/// Learned entries kept at most, as in smoltcp (`IFACE_NEIGHBOR_CACHE_COUNT`).
+const LEARNED_COUNT: usize = 8;
+/// `ff02::1`
+const ALL_NODES: Ipv6Address = Ipv6Address::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+/// Frames held for an on-link destination while it is resolved, later ones are dropped.
+const HELD_COUNT: usize = 3;
+
+/// The MAC smoltcp is told for the address it routes on-link destinations
+/// through, no frame leaves with it.
+pub const ON_LINK_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0]);
+
+/// State of a neighbor entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NeighborState {
+    /// Learned from the link, forgotten at `expires_at` unless heard from again
+    Reachable { expires_at: Instant },
+    /// Added by hand, kept until removed
+    Permanent,
+    /// Solicited `probes` times since `since` without an answer, forgotten a
+    /// lifetime after the last solicitation
+    Incomplete { since: Instant, probes: u32 },
+}
+
+/// An entry of the neighbor table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Neighbor {
+    pub ip: IpAddress,
+    /// `None` while incomplete
+    pub mac: Option<EthernetAddress>,
+    pub state: NeighborState,
+}
+
+/// The neighbor table of one interface, see [`Iface::neighbors`](crate::interface::Iface::neighbors).
+#[derive(Debug, Default)]
+pub struct NeighborTable {
+    learned: Vec<(IpAddress, EthernetAddress, Instant)>,
+    permanent: Vec<(IpAddress, EthernetAddress)>,
+    /// Unanswered solicitations: first one, last one, count
+    incomplete: Vec<(IpAddress, Instant, Instant, u32)>,
+    /// Replies made up for static entries, not yet handed to smoltcp
+    pending: VecDeque<Vec<u8>>,
+    /// Destinations of the on-link routes, with the address of ours smoltcp
+    /// routes them through
+    on_link: Vec<(IpCidr, IpAddress)>,
+    /// Frames to on-link destinations waiting for their MAC
+    held: Vec<(IpAddress, Vec<u8>)>,
+    /// Frames made up or released here, not yet transmitted
+    outgoing: VecDeque<Vec<u8>>,
+}
+
+impl NeighborTable {
+    /// Static entries first, then the learned ones that did not expire, then
+    /// the incomplete ones.
+    pub fn neighbors(&self, now: Instant) -> Vec<Neighbor> {
+        let permanent = self.permanent.iter().map(|&(ip, mac)| Neighbor {
+            ip,
+            mac: Some(mac),
+            state: NeighborState::Permanent,
+        });
+        let learned = self
+            .learned
+            .iter()
+            .filter(|&&(ip, _, expires_at)| expires_at > now && self.permanent_mac(ip).is_none())
+            .map(|&(ip, mac, expires_at)| Neighbor {
+                ip,
+                mac: Some(mac),
+                state: NeighborState::Reachable { expires_at },
+            });
+        let incomplete = self
+            .incomplete
+            .iter()
+            .filter(|&&(_, _, last, _)| last + ENTRY_LIFETIME > now)
+            .map(|&(ip, since, _, probes)| Neighbor {
+                ip,
+                mac: None,
+                state: NeighborState::Incomplete { since, probes },
+            });
+        permanent.chain(learned).chain(incomplete).collect()
+    }
+
+    /// Forgets the learned and incomplete entries, static entries stay.
+    pub fn flush(&mut self) {
+        self.learned.clear();
+        self.incomplete.clear();
+    }
+
+    /// Adds a static entry, returns `false` if `ip` already has one.
+    pub fn add_permanent(&mut self, ip: IpAddress, mac: EthernetAddress) -> bool {
+        if self.permanent_mac(ip).is_some() {
+            return false;
+        }
+        self.permanent.push((ip, mac));
+        true
+    }
+
+    /// Removes a static entry, returns `false` if `ip` has none.
+    pub fn remove_permanent(&mut self, ip: IpAddress) -> bool {
+        let len = self.permanent.len();
+        self.permanent.retain(|&(other, _)| other != ip);
+        self.permanent.len() != len
+    }
+
+    /// Some static entry exists, only an Ethernet interface has them.
+    pub fn has_permanent(&self) -> bool {
+        !self.permanent.is_empty()
+    }
+
+    /// Some made up reply waits to be received, or some frame to be transmitted.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty() || !self.outgoing.is_empty()
+    }
+
+    /// Replaces the on-link routes, each a destination and the address of the
+    /// interface smoltcp routes it through.
+    pub fn set_on_link(&mut self, routes: Vec<(IpCidr, IpAddress)>) {
+        self.held
+            .retain(|(ip, _)| routes.iter().any(|(dst, _)| dst.contains_addr(ip)));
+        self.on_link = routes;
+    }
+
+    fn is_on_link(&self, ip: &IpAddress) -> bool {
+        self.on_link.iter().any(|(dst, _)| dst.contains_addr(ip))
+    }
+
+    /// The MAC of `ip`, static or learned.
+    fn resolved(&self, ip: IpAddress, now: Instant) -> Option<EthernetAddress> {
+        self.permanent_mac(ip).or_else(|| {
+            self.learned
+                .iter()
+                .find(|&&(other, _, expires_at)| other == ip && expires_at > now)
+                .map(|&(_, mac, _)| mac)
+        })
+    }
+
+    fn permanent_mac(&self, ip: IpAddress) -> Option<EthernetAddress> {
+        self.permanent
+            .iter()
+            .find(|&&(other, _)| other == ip)
+            .map(|&(_, mac)| mac)
+    }
+
+    fn learn(&mut self, ip: IpAddress, mac: EthernetAddress, now: Instant) {
+        self.incomplete.retain(|&(other, ..)| other != ip);
+        let expires_at = now + ENTRY_LIFETIME;
+        if let Some(entry) = self.learned.iter_mut().find(|(other, ..)| *other == ip) {
+            *entry = (ip, mac, expires_at);
+            self.release(ip, mac);
+            return;
+        }
+        if self.learned.len() >= LEARNED_COUNT {
+            // like smoltcp, evict the entry closest to expiring
+            if let Some(index) = (0..self.learned.len()).min_by_key(|&i| self.learned[i].2) {
+                self.learned.remove(index);
+            }
+        }
+        self.learned.push((ip, mac, expires_at));
+        self.release(ip, mac);
+    }
+
+    /// Sends the frames held for `ip`, which is at `mac`.
+    fn release(&mut self, ip: IpAddress, mac: EthernetAddress) {
+        let (released, held) = core::mem::take(&mut self.held)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(other, _)| *other == ip);
+        self.held = held;
+        for (_, mut frame) in released {
+            EthernetFrame::new_unchecked(&mut frame[..]).set_dst_addr(mac);
+            self.outgoing.push_back(frame);
+        }
+    }
+
+    /// Traffic from a known neighbor keeps its entry alive.
+    fn refresh(&mut self, ip: IpAddress, mac: EthernetAddress, now: Instant) {
+        if let Some(entry) = self
+            .learned
+            .iter_mut()
+            .find(|(other, other_mac, _)| *other == ip && *other_mac == mac)
+        {
+            entry.2 = now + ENTRY_LIFETIME;
+        }
+    }
+
+    /// Snoops a received frame, returns `false` if it contradicts a static entry.
+    /// `mac` and `ip_addrs` are those of the interface.
+    fn receive(
+        &mut self,
+        frame: &[u8],
+        mac: EthernetAddress,
+        ip_addrs: &[IpCidr],
+        now: Instant,
+    ) -> bool {
+        let Ok(frame) = EthernetFrame::new_checked(frame) else {
+            return true;
+        };
+        let our_mac = mac;
+        let Some((ip, mac, kind)) = neighbor_of(&frame) else {
+            return true;
+        };
+        if let Some(permanent) = self.permanent_mac(ip) {
+            return permanent == mac || kind == Learned::Refresh;
+        }
+        match kind {
+            Learned::Arp { target, request } => {
+                let ours = ip_addrs.iter().any(|cidr| cidr.address() == target);
+                let on_subnet = ip_addrs.iter().any(|cidr| cidr.contains_addr(&ip));
+                let on_link = !on_subnet && self.is_on_link(&ip);
+                if ours && (on_subnet || on_link) {
+                    self.learn(ip, mac, now);
+                }
+                // smoltcp only answers the hosts on its subnets
+                if ours && on_link && request {
+                    if let Some(reply) = advert(target, our_mac, ip, mac) {
+                        self.outgoing.push_back(reply);
+                    }
+                }
+            }
+            Learned::Ndisc => self.learn(ip, mac, now),
+            Learned::Refresh => self.refresh(ip, mac, now),
+        }
+        true
+    }
+
+    /// Tells smoltcp about every static entry, for when its cache was flushed.
+    /// `mac` and `ip_addrs` are those of the interface, an entry outside the
+    /// subnets of the interface is left to be solicited.
+    pub fn announce(&mut self, mac: EthernetAddress, ip_addrs: &[IpCidr]) {
+        for &(ip, permanent) in &self.permanent {
+            let Some(to) = ip_addrs.iter().find(|cidr| cidr.contains_addr(&ip)) else {
+                continue;
+            };
+            if let Some(frame) = advert(ip, permanent, to.address(), mac) {
+                self.pending.push_back(frame);
+            }
+        }
+    }
+
+    /// Answers a solicitation smoltcp sent for a static entry or for the
+    /// address of an on-link route, other solicitations are incomplete until
+    /// answered. A frame sent to [`ON_LINK_MAC`] is forwarded.
+    fn transmit(&mut self, buffer: &mut [u8], now: Instant) {
+        let Ok(frame) = EthernetFrame::new_checked(&buffer[..]) else {
+            return;
+        };
+        if frame.dst_addr() == ON_LINK_MAC {
+            self.forward(buffer, now);
+            return;
+        }
+        let Some((target, to, to_mac)) = solicited(&frame) else {
+            return;
+        };
+        let mac = if self.on_link.iter().any(|&(_, via)| via == target) {
+            ON_LINK_MAC
+        } else if let Some(mac) = self.permanent_mac(target) {
+            mac
+        } else {
+            self.solicit(target, now);
+            return;
+        };
+        if let Some(frame) = advert(target, mac, to, to_mac) {
+            self.pending.push_back(frame);
+        }
+    }
+
+    /// Addresses a frame for an on-link destination to the destination. Until
+    /// it is resolved the frame is held and a solicitation goes out instead.
+    fn forward(&mut self, buffer: &mut [u8], now: Instant) {
+        let frame = EthernetFrame::new_unchecked(&buffer[..]);
+        let Some((src, dst)) = ip_addrs_of(&frame) else {
+            return;
+        };
+        if let Some(mac) = self.resolved(dst, now) {
+            EthernetFrame::new_unchecked(&mut buffer[..]).set_dst_addr(mac);
+            return;
+        }
+        // not routed on the link, nobody takes the frame
+        if !self.is_on_link(&dst) {
+            return;
+        }
+        let our_mac = frame.src_addr();
+        if self.held.iter().filter(|(ip, _)| *ip == dst).count() < HELD_COUNT {
+            self.held.push((dst, buffer.to_vec()));
+        }
+        self.solicit(dst, now);
+        let Some(solicitation) = solicitation(src, dst, our_mac) else {
+            return;
+        };
+        // the padding of a short frame is ignored
+        if solicitation.len() <= buffer.len() {
+            buffer[..solicitation.len()].copy_from_slice(&solicitation);
+            buffer[solicitation.len()..].fill(0);
+        } else {
+            self.outgoing.push_back(solicitation);
+        }
+    }
+
+    fn solicit(&mut self, ip: IpAddress, now: Instant) {
+        // smoltcp solicits an expired entry again
+        self.learned.retain(|&(other, ..)| other != ip);
+        if let Some(entry) = self.incomplete.iter_mut().find(|(other, ..)| *other == ip) {
+            if entry.2 + ENTRY_LIFETIME <= now {
+                entry.1 = now;
+                entry.3 = 0;
+            }
+            entry.2 = now;
+            entry.3 += 1;
+            return;
+        }
+        if self.incomplete.len() >= LEARNED_COUNT {
+            if let Some(index) = (0..self.incomplete.len()).min_by_key(|&i| self.incomplete[i].2) {
+                self.incomplete.remove(index);
+            }
+        }
+        self.incomplete.push((ip, now, now, 1));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Learned {
+    /// An ARP request or reply aimed at `target`
+    Arp { target: IpAddress, request: bool },
+    /// A neighbor solicitation or advertisement
+    Ndisc,
+    /// Any other IP packet
+    Refresh,
+}
+
+/// The neighbor a received frame tells about.
+fn neighbor_of(frame: &EthernetFrame<&[u8]>) -> Option<(IpAddress, EthernetAddress, Learned)> {
+    match frame.ethertype() {
+        EthernetProtocol::Arp => {
+            let packet = ArpPacket::new_checked(frame.payload()).ok()?;
+            match ArpRepr::parse(&packet).ok()? {
+                ArpRepr::EthernetIpv4 {
+                    operation: operation @ (ArpOperation::Request | ArpOperation::Reply),
+                    source_hardware_addr,
+                    source_protocol_addr,
+                    target_protocol_addr,
+                    ..
+                } if source_hardware_addr.is_unicast()
+                    && IpAddress::Ipv4(source_protocol_addr).is_unicast() =>
+                {
+                    Some((
+                        source_protocol_addr.into(),
+                        source_hardware_addr,
+                        Learned::Arp {
+                            target: target_protocol_addr.into(),
+                            request: operation == ArpOperation::Request,
+                        },
+                    ))
+                }
+                _ => None,
+            }
+        }
+        EthernetProtocol::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+            let src = IpAddress::Ipv4(packet.src_addr());
+            (src.is_unicast() && IpAddress::Ipv4(packet.dst_addr()).is_unicast()).then_some((
+                src,
+                frame.src_addr(),
+                Learned::Refresh,
+            ))
+        }
+        EthernetProtocol::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(frame.payload()).ok()?;
+            let src = IpAddress::Ipv6(packet.src_addr());
+            if !src.is_unicast() {
+                return None;
+            }
+            let lladdr = match ndisc(&packet) {
+                Some(NdiscRepr::NeighborSolicit { lladdr, .. })
+                | Some(NdiscRepr::NeighborAdvert { lladdr, .. }) => lladdr,
+                _ => {
+                    return IpAddress::Ipv6(packet.dst_addr()).is_unicast().then_some((
+                        src,
+                        frame.src_addr(),
+                        Learned::Refresh,
+                    ));
+                }
+            };
+            match lladdr?.parse(Medium::Ethernet).ok()? {
+                HardwareAddress::Ethernet(mac) if mac.is_unicast() => {
+                    Some((src, mac, Learned::Ndisc))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The source and destination of the IP packet in a frame.
+fn ip_addrs_of(frame: &EthernetFrame<&[u8]>) -> Option<(IpAddress, IpAddress)> {
+    match frame.ethertype() {
+        EthernetProtocol::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+            Some((packet.src_addr().into(), packet.dst_addr().into()))
+        }
+        EthernetProtocol::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(frame.payload()).ok()?;
+            Some((packet.src_addr().into(), packet.dst_addr().into()))
+        }
+        _ => None,
+    }
+}
+
+/// The NDP message carried by an IPv6 packet, extension headers are not followed.
+fn ndisc<'a>(packet: &Ipv6Packet<&'a [u8]>) -> Option<NdiscRepr<'a>> {
+    if packet.next_header() != IpProtocol::Icmpv6 {
+        return None;
+    }
+    let icmp = Icmpv6Packet::new_checked(packet.payload()).ok()?;
+    match Icmpv6Repr::parse(
+        &packet.src_addr(),
+        &packet.dst_addr(),
+        &icmp,
+        &ChecksumCapabilities::default(),
+    )
+    .ok()?
+    {
+        Icmpv6Repr::Ndisc(repr) => Some(repr),
+        _ => None,
+    }
+}
+
+/// The address a transmitted ARP request or neighbor solicitation asks for,
+/// and where the answer goes.
+fn solicited(frame: &EthernetFrame<&[u8]>) -> Option<(IpAddress, IpAddress, EthernetAddress)> {
+    match frame.ethertype() {
+        EthernetProtocol::Arp => {
+            let packet = ArpPacket::new_checked(frame.payload()).ok()?;
+            match ArpRepr::parse(&packet).ok()? {
+                ArpRepr::EthernetIpv4 {
+                    operation: ArpOperation::Request,
+                    source_hardware_addr,
+                    source_protocol_addr,
+                    target_protocol_addr,
+                    ..
+                } => Some((
+                    target_protocol_addr.into(),
+                    source_protocol_addr.into(),
+                    source_hardware_addr,
+                )),
+                _ => None,
+            }
+        }
+        EthernetProtocol::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(frame.payload()).ok()?;
+            let NdiscRepr::NeighborSolicit { target_addr, .. } = ndisc(&packet)? else {
+                return None;
+            };
+            // a solicitation from the unspecified address is answered to all nodes
+            let dst_addr = if packet.src_addr().is_unspecified() {
+                ALL_NODES
+            } else {
+                packet.src_addr()
+            };
+            Some((target_addr.into(), dst_addr.into(), frame.src_addr()))
+        }
+        _ => None,
+    }
+}
+
+/// An ARP reply or a neighbor advertisement telling `to` that `ip` is at `mac`.
+fn advert(
+    ip: IpAddress,
+    mac: EthernetAddress,
+    to: IpAddress,
+    to_mac: EthernetAddress,
+) -> Option<Vec<u8>> {
+    let mut ethernet = EthernetRepr {
+        src_addr: mac,
+        dst_addr: to_mac,
+        ethertype: EthernetProtocol::Arp,
+    };
+    match (ip, to) {
+        (IpAddress::Ipv4(ip), IpAddress::Ipv4(to)) => {
+            let arp = ArpRepr::EthernetIpv4 {
+                operation: ArpOperation::Reply,
+                source_hardware_addr: mac,
+                source_protocol_addr: ip,
+                target_hardware_addr: to_mac,
+                target_protocol_addr: to,
+            };
+            let mut buffer = vec![0; ethernet.buffer_len() + arp.buffer_len()];
+            let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+            ethernet.emit(&mut frame);
+            arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+            Some(buffer)
+        }
+        (IpAddress::Ipv6(ip), IpAddress::Ipv6(to)) => {
+            ethernet.ethertype = EthernetProtocol::Ipv6;
+            let icmp = Icmpv6Repr::Ndisc(NdiscRepr::NeighborAdvert {
+                flags: NdiscNeighborFlags::SOLICITED | NdiscNeighborFlags::OVERRIDE,
+                target_addr: ip,
+                lladdr: Some(mac.into()),
+            });
+            let ipv6 = Ipv6Repr {
+                src_addr: ip,
+                dst_addr: to,
+                next_header: IpProtocol::Icmpv6,
+                payload_len: icmp.buffer_len(),
+                hop_limit: 255,
+            };
+            let mut buffer = vec![0; ethernet.buffer_len() + ipv6.buffer_len() + icmp.buffer_len()];
+            let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+            ethernet.emit(&mut frame);
+            let mut packet = Ipv6Packet::new_unchecked(frame.payload_mut());
+            ipv6.emit(&mut packet);
+            icmp.emit(
+                &ip,
+                &to,
+                &mut Icmpv6Packet::new_unchecked(packet.payload_mut()),
+                &ChecksumCapabilities::default(),
+            );
+            Some(buffer)
+        }
+        _ => None,
+    }
+}
+
+/// An ARP request or a neighbor solicitation from `src` at `mac` asking for `target`.
+fn solicitation(src: IpAddress, target: IpAddress, mac: EthernetAddress) -> Option<Vec<u8>> {
+    let mut ethernet = EthernetRepr {
+        src_addr: mac,
+        dst_addr: EthernetAddress::BROADCAST,
+        ethertype: EthernetProtocol::Arp,
+    };
+    match (src, target) {
+        (IpAddress::Ipv4(src), IpAddress::Ipv4(target)) => {
+            let arp = ArpRepr::EthernetIpv4 {
+                operation: ArpOperation::Request,
+                source_hardware_addr: mac,
+                source_protocol_addr: src,
+                target_hardware_addr: EthernetAddress([0; 6]),
+                target_protocol_addr: target,
+            };
+            let mut buffer = vec![0; ethernet.buffer_len() + arp.buffer_len()];
+            let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+            ethernet.emit(&mut frame);
+            arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+            Some(buffer)
+        }
+        (IpAddress::Ipv6(src), IpAddress::Ipv6(target)) => {
+            // the solicited-node multicast address of `target`
+            let [.., a, b, c] = target.octets();
+            let dst = Ipv6Address::new(
+                0xff02,
+                0,
+                0,
+                0,
+                0,
+                1,
+                0xff00 | a as u16,
+                u16::from_be_bytes([b, c]),
+            );
+            ethernet.dst_addr = EthernetAddress([0x33, 0x33, 0xff, a, b, c]);
+            ethernet.ethertype = EthernetProtocol::Ipv6;
+            let icmp = Icmpv6Repr::Ndisc(NdiscRepr::NeighborSolicit {
+                target_addr: target,
+                lladdr: Some(mac.into()),
+            });
+            let ipv6 = Ipv6Repr {
+                src_addr: src,
+                dst_addr: dst,
+                next_header: IpProtocol::Icmpv6,
+                payload_len: icmp.buffer_len(),
+                hop_limit: 255,
+            };
+            let mut buffer = vec![0; ethernet.buffer_len() + ipv6.buffer_len() + icmp.buffer_len()];
+            let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+            ethernet.emit(&mut frame);
+            let mut packet = Ipv6Packet::new_unchecked(frame.payload_mut());
+            ipv6.emit(&mut packet);
+            icmp.emit(
+                &src,
+                &dst,
+                &mut Icmpv6Packet::new_unchecked(packet.payload_mut()),
+                &ChecksumCapabilities::default(),
+            );
+            Some(buffer)
+        }
+        _ => None,
+    }
+}
+
+/// What the tokens of a [`NeighborDevice`] need to snoop a frame.
+#[derive(Clone, Copy)]
+#[doc(hidden)]
+pub struct Snoop<'a> {
+    table: &'a Mutex<NeighborTable>,
+    mac: EthernetAddress,
+    ip_addrs: &'a [IpCidr],
+    timestamp: Instant,
+}
+
+/// Wraps the device of an Ethernet interface during a poll, keeping the
+/// neighbor table of the interface, handing the replies made up for static
+/// entries to smoltcp and sending the frames of on-link routes. Other media
+/// pass through.
+pub struct NeighborDevice<'a, D: Device + ?Sized> {
+    inner: &'a mut D,
+    snoop: Option<Snoop<'a>>,
+}
+
+impl<'a, D: Device + ?Sized> NeighborDevice<'a, D> {
+    /// `mac` and `ip_addrs` are the addresses of the interface, smoltcp only
+    /// learns from ARP aimed at them.
+    pub fn new(
+        inner: &'a mut D,
+        table: &'a Mutex<NeighborTable>,
+        mac: EthernetAddress,
+        ip_addrs: &'a [IpCidr],
+        timestamp: Instant,
+    ) -> Self {
+        let snoop = (inner.capabilities().medium == Medium::Ethernet).then_some(Snoop {
+            table,
+            mac,
+            ip_addrs,
+            timestamp,
+        });
+        Self { inner, snoop }
+    }
+
+    /// Transmits the frames made up or released by the table, as long as the
+    /// device takes them.
+    fn flush(&mut self, timestamp: Instant) {
+        let Some(snoop) = self.snoop else {
+            return;
+        };
+        while let Some(frame) = snoop.table.lock().outgoing.pop_front() {
+            let Some(tx) = self.inner.transmit(timestamp) else {
+                snoop.table.lock().outgoing.push_front(frame);
+                return;
+            };
+            phy::TxToken::consume(tx, frame.len(), |buffer| buffer.copy_from_slice(&frame));
+        }
+    }
+}
+
+impl<D: Device + ?Sized> Device for NeighborDevice<'_, D> {
+    type RxToken<'b>
+        = RxToken<'b, D::RxToken<'b>>
+    where
+        Self: 'b;
+    type TxToken<'b>
+        = TxToken<'b, D::TxToken<'b>>
+    where
+        Self: 'b;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        self.flush(timestamp);
+        let snoop = self.snoop;
+        let pending = snoop.and_then(|snoop| snoop.table.lock().pending.pop_front());
+        if let (Some(snoop), Some(frame)) = (snoop, pending) {
+            return match self.inner.transmit(timestamp) {
+                Some(tx) => Some((
+                    RxToken::MadeUp(frame),
+                    TxToken {
+                        inner: tx,
+                        snoop: Some(snoop),
+                    },
+                )),
+                None => {
+                    snoop.table.lock().pending.push_front(frame);
+                    None
+                }
+            };
+        }
+        self.inner.receive(timestamp).map(|(rx, tx)| {
+            (
+                RxToken::Device { inner: rx, snoop },
+                TxToken { inner: tx, snoop },
+            )
+        })
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        self.flush(timestamp);
+        let snoop = self.snoop;
+        self.inner
+            .transmit(timestamp)
+            .map(|tx| TxToken { inner: tx, snoop })
+    }
+}
+
+#[doc(hidden)]
+pub enum RxToken<'a, T: phy::RxToken> {
+    Device { inner: T, snoop: Option<Snoop<'a>> },
+    MadeUp(Vec<u8>),
+}
+
+impl<T: phy::RxToken> phy::RxToken for RxToken<'_, T> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        match self {
+            RxToken::Device { inner, snoop } => inner.consume(|buffer| {
+                let accepted = snoop.is_none_or(|snoop| {
+                    snoop
+                        .table
+                        .lock()
+                        .receive(buffer, snoop.mac, snoop.ip_addrs, snoop.timestamp)
+                });
+                if accepted {
+                    f(buffer)
+                } else {
+                    log::debug!("neighbor: frame contradicting a static entry dropped");
+                    f(&[])
+                }
+            }),
+            RxToken::MadeUp(buffer) => f(&buffer),
+        }
+    }
+
+    fn meta(&self) -> phy::PacketMeta {
+        match self {
+            RxToken::Device { inner, .. } => inner.meta(),
+            RxToken::MadeUp(_) => phy::PacketMeta::default(),
+        }
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken<'a, T: phy::TxToken> {
+    inner: T,
+    snoop: Option<Snoop<'a>>,
+}
+
+impl<T: phy::TxToken> phy::TxToken for TxToken<'_, T> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let snoop = self.snoop;
+        self.inner.consume(len, |buffer| {
+            let result = f(buffer);
+            if let Some(snoop) = snoop {
+                snoop.table.lock().transmit(buffer, snoop.timestamp);
+            }
+            result
+        })
+    }
+
+    fn set_meta(&mut self, meta: phy::PacketMeta) {
+        self.inner.set_meta(meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::Ipv4Address;
+
+    const OURS: EthernetAddress = EthernetAddress([2, 0, 0, 0, 0, 1]);
+    const PEER: EthernetAddress = EthernetAddress([2, 0, 0, 0, 0, 2]);
+    const STATIC: EthernetAddress = EthernetAddress([2, 0, 0, 0, 0, 5]);
+
+    fn v4(host: u8) -> Ipv4Address {
+        Ipv4Address::new(10, 0, 0, host)
+    }
+
+    fn v6(host: u16) -> Ipv6Address {
+        Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, host)
+    }
+
+    fn ip_addrs() -> [IpCidr; 2] {
+        [IpCidr::new(v4(1).into(), 24), IpCidr::new(v6(1).into(), 64)]
+    }
+
+    fn arp_request(mac: EthernetAddress, ip: Ipv4Address, target: Ipv4Address) -> Vec<u8> {
+        let ethernet = EthernetRepr {
+            src_addr: mac,
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Arp,
+        };
+        let arp = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: mac,
+            source_protocol_addr: ip,
+            target_hardware_addr: EthernetAddress([0; 6]),
+            target_protocol_addr: target,
+        };
+        let mut buffer = vec![0; ethernet.buffer_len() + arp.buffer_len()];
+        let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+        ethernet.emit(&mut frame);
+        arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+        buffer
+    }
+
+    fn neighbor_solicit(target: Ipv6Address) -> Vec<u8> {
+        let ethernet = EthernetRepr {
+            src_addr: OURS,
+            dst_addr: EthernetAddress([0x33, 0x33, 0xff, 0, 0, target.octets()[15]]),
+            ethertype: EthernetProtocol::Ipv6,
+        };
+        let icmp = Icmpv6Repr::Ndisc(NdiscRepr::NeighborSolicit {
+            target_addr: target,
+            lladdr: Some(OURS.into()),
+        });
+        let ipv6 = Ipv6Repr {
+            src_addr: v6(1),
+            dst_addr: Ipv6Address::new(0xff02, 0, 0, 0, 0, 1, 0xff00, target.segments()[7]),
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp.buffer_len(),
+            hop_limit: 255,
+        };
+        let mut buffer = vec![0; ethernet.buffer_len() + ipv6.buffer_len() + icmp.buffer_len()];
+        let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+        ethernet.emit(&mut frame);
+        let mut packet = Ipv6Packet::new_unchecked(frame.payload_mut());
+        ipv6.emit(&mut packet);
+        icmp.emit(
+            &ipv6.src_addr,
+            &ipv6.dst_addr,
+            &mut Icmpv6Packet::new_unchecked(packet.payload_mut()),
+            &ChecksumCapabilities::default(),
+        );
+        buffer
+    }
+
+    #[test]
+    fn arp_aimed_at_us_from_the_link_is_learned() {
+        let mut table = NeighborTable::default();
+        let now = Instant::from_secs(10);
+        assert!(table.receive(&arp_request(PEER, v4(2), v4(1)), OURS, &ip_addrs(), now));
+        // aimed at another host, or from off the link
+        assert!(table.receive(&arp_request(PEER, v4(3), v4(9)), OURS, &ip_addrs(), now));
+        let off_link = Ipv4Address::new(192, 0, 2, 1);
+        assert!(table.receive(&arp_request(PEER, off_link, v4(1)), OURS, &ip_addrs(), now));
+        assert_eq!(
+            table.neighbors(now),
+            [Neighbor {
+                ip: v4(2).into(),
+                mac: Some(PEER),
+                state: NeighborState::Reachable {
+                    expires_at: now + ENTRY_LIFETIME
+                },
+            }]
+        );
+        assert!(table.neighbors(now + ENTRY_LIFETIME).is_empty());
+    }
+
+    #[test]
+    fn neighbor_advert_is_learned_and_traffic_refreshes_it() {
+        let mut table = NeighborTable::default();
+        let now = Instant::from_secs(10);
+        let frame = advert(v6(2).into(), PEER, v6(1).into(), OURS).unwrap();
+        assert!(table.receive(&frame, OURS, &ip_addrs(), now));
+        assert_eq!(table.neighbors(now)[0].ip, IpAddress::Ipv6(v6(2)));
+
+        // any unicast packet from the same MAC keeps the entry
+        let later = now + Duration::from_secs(30);
+        let mut frame = neighbor_solicit(v6(1));
+        frame[6..12].copy_from_slice(&PEER.0);
+        let mut packet = Ipv6Packet::new_unchecked(&mut frame[14..]);
+        packet.set_src_addr(v6(2));
+        packet.set_dst_addr(v6(1));
+        packet.set_next_header(IpProtocol::Udp);
+        assert!(table.receive(&frame, OURS, &ip_addrs(), later));
+        assert_eq!(
+            table.neighbors(later)[0].state,
+            NeighborState::Reachable {
+                expires_at: later + ENTRY_LIFETIME
+            }
+        );
+    }
+
+    #[test]
+    fn learned_entries_are_capped_like_smoltcp() {
+        let mut table = NeighborTable::default();
+        for host in 2..(2 + LEARNED_COUNT as u8 + 1) {
+            let now = Instant::from_secs(host as i64);
+            let mac = EthernetAddress([2, 0, 0, 0, 1, host]);
+            table.receive(&arp_request(mac, v4(host), v4(1)), OURS, &ip_addrs(), now);
+        }
+        let neighbors = table.neighbors(Instant::from_secs(20));
+        assert_eq!(neighbors.len(), LEARNED_COUNT);
+        // the one closest to expiring made room
+        assert!(neighbors.iter().all(|neighbor| neighbor.ip != v4(2).into()));
+    }
+
+    #[test]
+    fn unanswered_solicitations_are_incomplete() {
+        let mut table = NeighborTable::default();
+        let since = Instant::from_secs(10);
+        let later = since + Duration::from_secs(1);
+        table.transmit(&mut arp_request(OURS, v4(1), v4(7)), since);
+        table.transmit(&mut arp_request(OURS, v4(1), v4(7)), later);
+        table.transmit(&mut neighbor_solicit(v6(7)), later);
+        assert!(!table.has_pending());
+        assert_eq!(
+            table.neighbors(later),
+            [
+                Neighbor {
+                    ip: v4(7).into(),
+                    mac: None,
+                    state: NeighborState::Incomplete { since, probes: 2 },
+                },
+                Neighbor {
+                    ip: v6(7).into(),
+                    mac: None,
+                    state: NeighborState::Incomplete {
+                        since: later,
+                        probes: 1
+                    },
+                },
+            ]
+        );
+
+        // the answer completes the entry
+        table.receive(&arp_request(PEER, v4(7), v4(1)), OURS, &ip_addrs(), later);
+        let neighbors = table.neighbors(later);
+        assert_eq!(neighbors.len(), 2);
+        assert_eq!(neighbors[0].mac, Some(PEER));
+        assert_eq!(neighbors[1].mac, None);
+        assert!(table.neighbors(later + ENTRY_LIFETIME).is_empty());
+    }
+
+    #[test]
+    fn solicitations_for_static_entries_are_answered_here() {
+        let mut table = NeighborTable::default();
+        let now = Instant::from_secs(10);
+        assert!(table.add_permanent(v4(5).into(), STATIC));
+        assert!(table.add_permanent(v6(5).into(), STATIC));
+        assert!(!table.add_permanent(v4(5).into(), PEER));
+
+        table.transmit(&mut arp_request(OURS, v4(1), v4(5)), now);
+        table.transmit(&mut neighbor_solicit(v6(5)), now);
+        let reply = table.pending.pop_front().unwrap();
+        let frame = EthernetFrame::new_checked(&reply[..]).unwrap();
+        assert_eq!(frame.dst_addr(), OURS);
+        let arp = ArpRepr::parse(&ArpPacket::new_checked(frame.payload()).unwrap()).unwrap();
+        assert_eq!(
+            arp,
+            ArpRepr::EthernetIpv4 {
+                operation: ArpOperation::Reply,
+                source_hardware_addr: STATIC,
+                source_protocol_addr: v4(5),
+                target_hardware_addr: OURS,
+                target_protocol_addr: v4(1),
+            }
+        );
+        let reply = table.pending.pop_front().unwrap();
+        let frame = EthernetFrame::new_checked(&reply[..]).unwrap();
+        let packet = Ipv6Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(packet.dst_addr(), v6(1));
+        assert!(matches!(
+            ndisc(&packet),
+            Some(NdiscRepr::NeighborAdvert { target_addr, lladdr: Some(lladdr), .. })
+                if target_addr == v6(5) && lladdr == STATIC.into()
+        ));
+        assert!(!table.has_pending());
+
+        // only the static MAC may claim the address
+        let now = now + Duration::from_secs(1);
+        assert!(!table.receive(&arp_request(PEER, v4(5), v4(1)), OURS, &ip_addrs(), now));
+        assert!(table.receive(&arp_request(STATIC, v4(5), v4(1)), OURS, &ip_addrs(), now));
+        let neighbors = table.neighbors(now);
+        assert_eq!(neighbors.len(), 2);
+        assert!(neighbors
+            .iter()
+            .all(|neighbor| neighbor.state == NeighborState::Permanent));
+
+        table.flush();
+        assert_eq!(table.neighbors(now).len(), 2);
+        assert!(table.remove_permanent(v4(5).into()));
+        assert!(!table.remove_permanent(v4(5).into()));
+        assert_eq!(table.neighbors(now).len(), 1);
+    }
+
+    fn ipv4_frame(dst_mac: EthernetAddress, dst: Ipv4Address) -> Vec<u8> {
+        let ethernet = EthernetRepr {
+            src_addr: OURS,
+            dst_addr: dst_mac,
+            ethertype: EthernetProtocol::Ipv4,
+        };
+        let ipv4 = smoltcp::wire::Ipv4Repr {
+            src_addr: v4(1),
+            dst_addr: dst,
+            next_header: IpProtocol::Udp,
+            payload_len: 32,
+            hop_limit: 64,
+        };
+        let mut buffer = vec![0; ethernet.buffer_len() + ipv4.buffer_len() + 32];
+        let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+        ethernet.emit(&mut frame);
+        ipv4.emit(
+            &mut Ipv4Packet::new_unchecked(frame.payload_mut()),
+            &ChecksumCapabilities::default(),
+        );
+        buffer
+    }
+
+    #[test]
+    fn on_link_destinations_are_resolved_here() {
+        let mut table = NeighborTable::default();
+        let now = Instant::from_secs(10);
+        let far = Ipv4Address::new(192, 0, 2, 7);
+        table.set_on_link(vec![(IpCidr::new(far.into(), 24), v4(1).into())]);
+
+        // smoltcp routes the destinations through our own address
+        table.transmit(&mut arp_request(OURS, v4(1), v4(1)), now);
+        let reply = table.pending.pop_front().unwrap();
+        let frame = EthernetFrame::new_checked(&reply[..]).unwrap();
+        let arp = ArpRepr::parse(&ArpPacket::new_checked(frame.payload()).unwrap()).unwrap();
+        assert!(matches!(
+            arp,
+            ArpRepr::EthernetIpv4 { source_hardware_addr, source_protocol_addr, .. }
+                if source_hardware_addr == ON_LINK_MAC && source_protocol_addr == v4(1)
+        ));
+
+        // the first frame is held and an ARP request goes out in its place
+        let mut frame = ipv4_frame(ON_LINK_MAC, far);
+        table.transmit(&mut frame, now);
+        let sent = EthernetFrame::new_checked(&frame[..]).unwrap();
+        assert_eq!(sent.ethertype(), EthernetProtocol::Arp);
+        let arp = ArpRepr::parse(&ArpPacket::new_checked(sent.payload()).unwrap()).unwrap();
+        assert!(matches!(
+            arp,
+            ArpRepr::EthernetIpv4 { operation: ArpOperation::Request, target_protocol_addr, .. }
+                if target_protocol_addr == far
+        ));
+        assert_eq!(table.neighbors(now)[0].mac, None);
+        assert!(!table.has_pending());
+
+        // smoltcp drops the reply from off its subnets, the table takes it
+        let mut reply = arp_request(PEER, far, v4(1));
+        ArpPacket::new_unchecked(&mut reply[14..]).set_operation(ArpOperation::Reply);
+        assert!(table.receive(&reply, OURS, &ip_addrs(), now));
+        assert_eq!(table.outgoing.pop_front().unwrap(), ipv4_frame(PEER, far));
+        let mut frame = ipv4_frame(ON_LINK_MAC, far);
+        table.transmit(&mut frame, now);
+        assert_eq!(frame, ipv4_frame(PEER, far));
+
+        // and answers the requests smoltcp leaves alone
+        let other = Ipv4Address::new(192, 0, 2, 8);
+        assert!(table.receive(&arp_request(STATIC, other, v4(1)), OURS, &ip_addrs(), now));
+        let reply = table.outgoing.pop_front().unwrap();
+        let frame = EthernetFrame::new_checked(&reply[..]).unwrap();
+        assert_eq!(frame.dst_addr(), STATIC);
+        assert!(table.outgoing.is_empty());
+
+        // a frame off the on-link routes is left alone
+        let mut frame = ipv4_frame(ON_LINK_MAC, Ipv4Address::new(198, 51, 100, 1));
+        table.transmit(&mut frame, now);
+        assert_eq!(
+            frame,
+            ipv4_frame(ON_LINK_MAC, Ipv4Address::new(198, 51, 100, 1))
+        );
+    }
+
+    #[test]
+    fn announce_covers_the_static_entries_on_our_subnets() {
+        let mut table = NeighborTable::default();
+        table.add_permanent(v4(5).into(), STATIC);
+        table.add_permanent(Ipv4Address::new(192, 0, 2, 5).into(), STATIC);
+        table.announce(OURS, &ip_addrs());
+        let reply = table.pending.pop_front().unwrap();
+        let frame = EthernetFrame::new_checked(&reply[..]).unwrap();
+        let arp = ArpRepr::parse(&ArpPacket::new_checked(frame.payload()).unwrap()).unwrap();
+        assert!(matches!(
+            arp,
+            ArpRepr::EthernetIpv4 { source_protocol_addr, target_protocol_addr, .. }
+                if source_protocol_addr == v4(5) && target_protocol_addr == v4(1)
+        ));
+        assert!(!table.has_pending());
+    }
+}
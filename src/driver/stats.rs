@@ -0,0 +1,180 @@
+//! Traffic counters of an interface, frames are counted as they pass between
+//! the device and smoltcp and sorted by protocol like /proc/net/snmp.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, Ipv6Packet};
+
+/// Counters of the frames going one way.
+#[derive(Debug, Default)]
+pub struct DirectionCounters {
+    pub packets: AtomicU64,
+    pub bytes: AtomicU64,
+    /// IPv4 and IPv6 packets
+    pub ip: AtomicU64,
+    pub tcp: AtomicU64,
+    pub udp: AtomicU64,
+    /// ICMP and ICMPv6 messages
+    pub icmp: AtomicU64,
+}
+
+impl DirectionCounters {
+    fn count(&self, medium: Medium, frame: &[u8]) {
+        // an empty frame stands for a read the device dropped
+        if frame.is_empty() {
+            return;
+        }
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
+        let Some(protocol) = ip_protocol(medium, frame) else {
+            return;
+        };
+        self.ip.fetch_add(1, Ordering::Relaxed);
+        let counter = match protocol {
+            IpProtocol::Tcp => &self.tcp,
+            IpProtocol::Udp => &self.udp,
+            IpProtocol::Icmp | IpProtocol::Icmpv6 => &self.icmp,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Traffic counters of an interface, see [`Iface::stats`](crate::interface::Iface::stats).
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    pub rx: DirectionCounters,
+    pub tx: DirectionCounters,
+}
+
+/// The protocol carried by an IP packet, `None` for a frame without one (e.g. ARP).
+/// IPv6 extension headers are not followed.
+fn ip_protocol(medium: Medium, frame: &[u8]) -> Option<IpProtocol> {
+    let packet = match medium {
+        Medium::Ethernet => {
+            let frame = EthernetFrame::new_checked(frame).ok()?;
+            match frame.ethertype() {
+                EthernetProtocol::Ipv4 | EthernetProtocol::Ipv6 => {}
+                _ => return None,
+            }
+            &frame.into_inner()[EthernetFrame::<&[u8]>::header_len()..]
+        }
+        Medium::Ip => frame,
+    };
+    match packet.first()? >> 4 {
+        4 => Some(Ipv4Packet::new_checked(packet).ok()?.next_header()),
+        6 => Some(Ipv6Packet::new_checked(packet).ok()?.next_header()),
+        _ => None,
+    }
+}
+
+/// Wraps the device of an interface during a poll, counting every frame in
+/// the counters of the interface.
+pub struct StatsDevice<'a, D: Device + ?Sized> {
+    inner: &'a mut D,
+    counters: &'a TrafficCounters,
+    medium: Medium,
+}
+
+impl<'a, D: Device + ?Sized> StatsDevice<'a, D> {
+    pub fn new(inner: &'a mut D, counters: &'a TrafficCounters) -> Self {
+        let medium = inner.capabilities().medium;
+        Self {
+            inner,
+            counters,
+            medium,
+        }
+    }
+}
+
+impl<D: Device + ?Sized> Device for StatsDevice<'_, D> {
+    type RxToken<'b>
+        = RxToken<'b, D::RxToken<'b>>
+    where
+        Self: 'b;
+    type TxToken<'b>
+        = TxToken<'b, D::TxToken<'b>>
+    where
+        Self: 'b;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let (counters, medium) = (self.counters, self.medium);
+        self.inner.receive(timestamp).map(|(rx, tx)| {
+            (
+                RxToken {
+                    inner: rx,
+                    counters: &counters.rx,
+                    medium,
+                },
+                TxToken {
+                    inner: tx,
+                    counters: &counters.tx,
+                    medium,
+                },
+            )
+        })
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        let (counters, medium) = (self.counters, self.medium);
+        self.inner.transmit(timestamp).map(|tx| TxToken {
+            inner: tx,
+            counters: &counters.tx,
+            medium,
+        })
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken<'a, T: phy::RxToken> {
+    inner: T,
+    counters: &'a DirectionCounters,
+    medium: Medium,
+}
+
+impl<T: phy::RxToken> phy::RxToken for RxToken<'_, T> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let (counters, medium) = (self.counters, self.medium);
+        self.inner.consume(|buffer| {
+            counters.count(medium, buffer);
+            f(buffer)
+        })
+    }
+
+    fn meta(&self) -> phy::PacketMeta {
+        self.inner.meta()
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken<'a, T: phy::TxToken> {
+    inner: T,
+    counters: &'a DirectionCounters,
+    medium: Medium,
+}
+
+impl<T: phy::TxToken> phy::TxToken for TxToken<'_, T> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let (counters, medium) = (self.counters, self.medium);
+        self.inner.consume(len, |buffer| {
+            let result = f(buffer);
+            counters.count(medium, buffer);
+            result
+        })
+    }
+
+    fn set_meta(&mut self, meta: phy::PacketMeta) {
+        self.inner.set_meta(meta)
+    }
+}
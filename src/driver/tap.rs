@@ -1,14 +1,22 @@
+use super::vnet::{self, TapOffload, TcpCoalescer, VNET_GSO_MAX_FRAME, VNET_HDR_LEN};
 use super::*;
-use scopeguard::defer;
 use smoltcp::{phy::Medium, wire::EthernetFrame};
 use std::io;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, IntoRawFd, OwnedFd, RawFd};
 
 #[derive(Debug)]
 pub struct TapDesc {
     lower: libc::c_int,
+    /// `None` when attached through [`TapDesc::from_fd`], the link is then
+    /// configured by whoever opened it and is never touched by us
+    name: Option<String>,
     mtu: usize,
-    mac: smoltcp::wire::EthernetAddress,
+    /// `None` for a TUN device, which has no link layer
+    mac: Option<smoltcp::wire::EthernetAddress>,
+    /// `None` when opened without `IFF_VNET_HDR`, otherwise every frame is
+    /// prefixed with a virtio-net header and these offloads are negotiated
+    offload: Option<TapOffload>,
+    status: IoStatus,
 }
 
 impl AsRawFd for TapDesc {
@@ -18,7 +26,53 @@ impl AsRawFd for TapDesc {
 }
 
 impl TapDesc {
+    /// Attaches to `name`, a TAP device gets a random locally administered MAC address.
     pub fn new(name: &str, medium: Medium) -> io::Result<TapDesc> {
+        Self::open(name, medium, None, None, false)
+    }
+
+    /// Attaches to the TAP device `name` and sets its MAC address to `mac`.
+    pub fn with_mac(name: &str, mac: smoltcp::wire::EthernetAddress) -> io::Result<TapDesc> {
+        Self::open(name, Medium::Ethernet, Some(mac), None, false)
+    }
+
+    /// Attaches to `name` with `IFF_VNET_HDR` and negotiates `offload` with
+    /// `TUNSETOFFLOAD`, see [`TapOffload`].
+    pub fn with_offload(name: &str, medium: Medium, offload: TapOffload) -> io::Result<TapDesc> {
+        Self::open(name, medium, None, Some(offload), false)
+    }
+
+    /// Attaches `queues` file descriptors to `name` with `IFF_MULTI_QUEUE`, the
+    /// kernel spreads the flows of the device over them. The queues share the
+    /// link, a TAP device gets a single random MAC address.
+    pub fn multi_queue(name: &str, medium: Medium, queues: usize) -> io::Result<Vec<TapDesc>> {
+        if queues == 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let first = Self::open(name, medium, None, None, true)?;
+        let mac = first.mac;
+        let mut descs = vec![first];
+        for _ in 1..queues {
+            descs.push(Self::open(name, medium, mac, None, true)?);
+        }
+        Ok(descs)
+    }
+
+    fn open(
+        name: &str,
+        medium: Medium,
+        mac: Option<smoltcp::wire::EthernetAddress>,
+        offload: Option<TapOffload>,
+        multi_queue: bool,
+    ) -> io::Result<TapDesc> {
+        if offload.is_some_and(|offload| !offload.is_empty() && !offload.contains(TapOffload::CSUM))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "offloads need TapOffload::CSUM",
+            ));
+        }
+
         let lower = unsafe {
             let lower = libc::open(
                 c"/dev/net/tun".as_ptr() as *const libc::c_char,
@@ -31,81 +85,238 @@ impl TapDesc {
         };
 
         let mut ifreq = ifreq_for(name);
-        Self::attach_interface_ifreq(lower, &mut ifreq)?;
+        let mut flags = libc::IFF_NO_PI;
+        if offload.is_some() {
+            flags |= libc::IFF_VNET_HDR;
+        }
+        if multi_queue {
+            flags |= libc::IFF_MULTI_QUEUE;
+        }
+        Self::attach_interface_ifreq(lower, medium, flags, &mut ifreq)?;
         log::trace!("Successfully attach interface: {}", name);
+        Self::set_offload(lower, offload)?;
+        log::trace!("Successfully set offload: {:?}", offload);
         let mtu = Self::mtu_ifreq(medium, &mut ifreq)?;
         log::trace!("Successfully get MTU: {}", mtu);
-        let mac = Self::mac_ifreq(&mut ifreq)?;
-        log::trace!("Successfully get MAC: {}", mac);
+        let mac = match medium {
+            Medium::Ethernet => {
+                let mac = mac.unwrap_or_else(random_mac);
+                Self::mac_ifreq(&mut ifreq, mac)?;
+                log::trace!("Successfully set MAC: {}", mac);
+                Some(mac)
+            }
+            Medium::Ip => None,
+        };
 
-        Ok(TapDesc { lower, mtu, mac })
+        Ok(TapDesc {
+            lower,
+            name: Some(name.to_string()),
+            mtu,
+            mac,
+            offload,
+            status: IoStatus::default(),
+        })
     }
 
-    // pub fn from_fd(fd: RawFd, mtu: usize) -> io::Result<TapDesc> {
-    //     Ok(TapDesc { lower: fd, mtu })
-    // }
+    /// Wraps an already opened TUN/TAP file descriptor, e.g. one received over
+    /// `SCM_RIGHTS` from a privileged helper. The descriptor is owned by the
+    /// returned `TapDesc` and closed on drop, or right away on failure.
+    ///
+    /// No privileged ioctl is issued: `mtu` is the IP MTU of the link and `mac`
+    /// is the address used on a TAP link, a random one if `None`. A descriptor
+    /// opened with `IFF_VNET_HDR` is used with no offloads.
+    pub fn from_fd(
+        fd: OwnedFd,
+        medium: Medium,
+        mtu: usize,
+        mac: Option<smoltcp::wire::EthernetAddress>,
+    ) -> io::Result<TapDesc> {
+        let lower = fd.as_raw_fd();
+        // the polling thread expects reads to never block
+        unsafe {
+            let flags = libc::fcntl(lower, libc::F_GETFL);
+            if flags == -1 || libc::fcntl(lower, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
 
-    fn attach_interface_ifreq(lower: libc::c_int, ifr: &mut ifreq) -> io::Result<()> {
-        ifr.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short;
+        let mut ifreq = ifreq_for("");
+        let mut offload = None;
+        // not every platform exposes TUNGETIFF (e.g. Android), only check when it is there
+        if ifreq_ioctl(lower, libc::TUNGETIFF, &mut ifreq).is_ok() {
+            let flags = unsafe { ifreq.ifr_ifru.ifru_flags } as libc::c_int;
+            let expected = match medium {
+                Medium::Ip => libc::IFF_TUN,
+                Medium::Ethernet => libc::IFF_TAP,
+            };
+            if flags & (libc::IFF_TUN | libc::IFF_TAP) != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "fd medium does not match",
+                ));
+            }
+            if flags & libc::IFF_VNET_HDR != 0 {
+                offload = Some(TapOffload::empty());
+            }
+            // whatever the helper negotiated, frames must come with full checksums
+            Self::set_offload(lower, offload)?;
+        }
+
+        let mac = match medium {
+            Medium::Ethernet => Some(mac.unwrap_or_else(random_mac)),
+            Medium::Ip => None,
+        };
+
+        Ok(TapDesc {
+            lower: fd.into_raw_fd(),
+            name: None,
+            mtu: Self::frame_mtu(medium, mtu),
+            mac,
+            offload,
+            status: IoStatus::default(),
+        })
+    }
+
+    fn attach_interface_ifreq(
+        lower: libc::c_int,
+        medium: Medium,
+        flags: libc::c_int,
+        ifr: &mut ifreq,
+    ) -> io::Result<()> {
+        let mode = match medium {
+            Medium::Ip => libc::IFF_TUN,
+            Medium::Ethernet => libc::IFF_TAP,
+        };
+        ifr.ifr_ifru.ifru_flags = (mode | flags) as libc::c_short;
         ifreq_ioctl(lower, libc::TUNSETIFF, ifr).map(|_| ())
     }
 
-    fn mtu_ifreq(medium: Medium, ifr: &mut ifreq) -> io::Result<usize> {
-        let lower = unsafe {
-            let lower = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_IP);
-            if lower == -1 {
+    /// Negotiates `offload`, or none without a virtio-net header. The offloads
+    /// of a persistent device outlive the file, frames would otherwise come
+    /// with the partial checksums an earlier user asked for.
+    fn set_offload(lower: libc::c_int, offload: Option<TapOffload>) -> io::Result<()> {
+        let hdr_len = VNET_HDR_LEN as libc::c_int;
+        unsafe {
+            if offload.is_some() && libc::ioctl(lower, libc::TUNSETVNETHDRSZ as _, &hdr_len) == -1 {
                 return Err(io::Error::last_os_error());
             }
-            lower
-        };
-        defer!(unsafe {
-            libc::close(lower);
-        });
+            let offload = offload.unwrap_or(TapOffload::empty()).bits() as libc::c_ulong;
+            if libc::ioctl(lower, libc::TUNSETOFFLOAD as _, offload) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
 
-        let _ = ifreq_ioctl(lower, libc::SIOCGIFMTU, ifr)?;
+    fn mtu_ifreq(medium: Medium, ifr: &mut ifreq) -> io::Result<usize> {
+        let _ = ifreq_socket_ioctl(libc::SIOCGIFMTU, ifr)?;
 
         let ip_mtu = unsafe { ifr.ifr_ifru.ifru_mtu } as usize;
 
+        Ok(Self::frame_mtu(medium, ip_mtu))
+    }
+
+    fn frame_mtu(medium: Medium, ip_mtu: usize) -> usize {
         // SIOCGIFMTU returns the IP MTU (typically 1500 bytes.)
         // smoltcp counts the entire Ethernet packet in the MTU, so add the Ethernet header size to it.
-        let mtu = match medium {
+        match medium {
             Medium::Ip => ip_mtu,
             Medium::Ethernet => ip_mtu + EthernetFrame::<&[u8]>::header_len(),
             // Medium::Ieee802154 => todo!(),
-        };
-
-        Ok(mtu)
+        }
     }
 
-    fn mac_ifreq(ifr: &mut ifreq) -> io::Result<smoltcp::wire::EthernetAddress> {
-        let lower = unsafe {
-            let lower = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_IP);
-            if lower == -1 {
-                return Err(io::Error::last_os_error());
-            }
-            lower
-        };
-        defer!(unsafe {
-            libc::close(lower);
-        });
-
-        let mac = smoltcp::wire::EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
-
+    fn mac_ifreq(ifr: &mut ifreq, mac: smoltcp::wire::EthernetAddress) -> io::Result<()> {
         unsafe {
             ifr.ifr_ifru.ifru_hwaddr.sa_family = libc::ARPHRD_ETHER;
-            ifr.ifr_ifru.ifru_hwaddr.sa_data[..6]
-                .copy_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+            for (dst, src) in ifr.ifr_ifru.ifru_hwaddr.sa_data[..6]
+                .iter_mut()
+                .zip(mac.as_bytes())
+            {
+                *dst = *src as libc::c_char;
+            }
         }
 
-        let _ = ifreq_ioctl(lower, libc::SIOCSIFHWADDR, ifr)?;
+        let _ = ifreq_socket_ioctl(libc::SIOCSIFHWADDR, ifr)?;
 
-        Ok(mac)
+        Ok(())
+    }
+
+    /// Changes the MAC address of a TAP device at runtime.
+    pub fn set_mac(&mut self, mac: smoltcp::wire::EthernetAddress) -> io::Result<()> {
+        if self.mac.is_none() {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+        if let Some(name) = &self.name {
+            Self::mac_ifreq(&mut ifreq_for(name), mac)?;
+        }
+        self.mac = Some(mac);
+        Ok(())
     }
 
     pub fn interface_mtu(&self) -> io::Result<usize> {
         Ok(self.mtu)
     }
 
+    fn medium(&self) -> Medium {
+        match self.mac {
+            Some(_) => Medium::Ethernet,
+            None => Medium::Ip,
+        }
+    }
+
+    /// Changes the IP MTU of the device at runtime, with `SIOCSIFMTU` unless
+    /// attached through [`TapDesc::from_fd`].
+    pub fn set_mtu(&mut self, ip_mtu: usize) -> io::Result<()> {
+        if let Some(name) = &self.name {
+            let mut ifr = ifreq_for(name);
+            ifr.ifr_ifru.ifru_mtu = ip_mtu as libc::c_int;
+            let _ = ifreq_socket_ioctl(libc::SIOCSIFMTU, &mut ifr)?;
+        }
+        self.mtu = Self::frame_mtu(self.medium(), ip_mtu);
+        Ok(())
+    }
+
+    /// The `IFF_*` flags of the link with `SIOCGIFFLAGS`, `None` when attached
+    /// through [`TapDesc::from_fd`].
+    pub fn link_flags(&self) -> io::Result<Option<libc::c_int>> {
+        let Some(name) = &self.name else {
+            return Ok(None);
+        };
+        let mut ifr = ifreq_for(name);
+        let _ = ifreq_socket_ioctl(libc::SIOCGIFFLAGS, &mut ifr)?;
+        Ok(Some(unsafe { ifr.ifr_ifru.ifru_flags } as libc::c_int))
+    }
+
+    /// Brings the link up or down with `SIOCSIFFLAGS`, a no-op when attached
+    /// through [`TapDesc::from_fd`].
+    pub fn set_up(&mut self, up: bool) -> io::Result<()> {
+        let Some(name) = &self.name else {
+            return Ok(());
+        };
+        let mut ifr = ifreq_for(name);
+        let _ = ifreq_socket_ioctl(libc::SIOCGIFFLAGS, &mut ifr)?;
+        unsafe {
+            if up {
+                ifr.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+            } else {
+                ifr.ifr_ifru.ifru_flags &= !(libc::IFF_UP as libc::c_short);
+            }
+        }
+        let _ = ifreq_socket_ioctl(libc::SIOCSIFFLAGS, &mut ifr)?;
+        Ok(())
+    }
+
+    /// The negotiated offloads, `None` without a virtio-net header.
+    pub fn offload(&self) -> Option<TapOffload> {
+        self.offload
+    }
+
+    /// The name of the link, `None` when attached through [`TapDesc::from_fd`].
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
         unsafe {
             let len = libc::read(
@@ -143,24 +354,123 @@ impl Drop for TapDesc {
     }
 }
 
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::mem;
 use std::vec::Vec;
 
-use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::phy::{self, Checksum, Device, DeviceCapabilities};
 use smoltcp::time::Instant;
 
+/// Frames read from the device in one burst.
+const TAP_RX_BATCH: usize = 32;
+
+/// Frames read ahead of smoltcp into buffers allocated once with the device.
+///
+/// Each buffer is large enough for a frame behind the virtio-net header, or
+/// for a super-packet once segmentation is offloaded. Partial checksums are
+/// finished as frames are handed to smoltcp, which verifies all of them.
+///
+/// A TUN/TAP fd hands out a single frame per `read`/`readv`, so a batch is a
+/// burst of reads into the free buffers until the device would block. It is
+/// only refilled once drained, smoltcp keeps receiving until then, so no frame
+/// is left behind when the edge triggered epoll goes quiet. The queues of a
+/// multi-queue device are batched together by `TapIface`, one poll of the
+/// interface receives the bursts of all of them.
+#[derive(Debug)]
+struct RxBatch {
+    /// the buffers and the length of the frame each one holds
+    slots: Vec<(Box<[u8]>, usize)>,
+    /// the next frame handed to smoltcp
+    next: usize,
+    /// frames read in the last burst
+    filled: usize,
+    /// length of the virtio-net header in front of each frame
+    hdr_len: usize,
+}
+
+impl RxBatch {
+    fn new(frame_len: usize, hdr_len: usize) -> Self {
+        RxBatch {
+            slots: (0..TAP_RX_BATCH)
+                .map(|_| (vec![0; hdr_len + frame_len].into_boxed_slice(), 0))
+                .collect(),
+            next: 0,
+            filled: 0,
+            hdr_len,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.next == self.filled
+    }
+
+    fn refill(&mut self, lower: &mut TapDesc) {
+        self.next = 0;
+        self.filled = 0;
+        for (buffer, len) in self.slots.iter_mut() {
+            match lower.recv(buffer) {
+                // a TUN/TAP fd never reads 0 bytes, the peer of a descriptor
+                // handed to `from_fd` hung up and every read returns at once
+                Ok(0) => {
+                    lower.status.rx_errors += 1;
+                    lower.status.failed(io::ErrorKind::UnexpectedEof.into());
+                    break;
+                }
+                Ok(size) => {
+                    lower.status.succeeded();
+                    // a read shorter than the header leaves an empty frame,
+                    // which smoltcp skips
+                    if size <= self.hdr_len {
+                        lower.status.rx_dropped += 1;
+                    }
+                    *len = size.max(self.hdr_len);
+                    self.filled += 1;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::debug!("phy: rx failed: {}", err);
+                    lower.status.rx_errors += 1;
+                    lower.status.failed(err);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<&[u8]> {
+        if self.is_empty() {
+            return None;
+        }
+        let (buffer, len) = &mut self.slots[self.next];
+        self.next += 1;
+        let (header, frame) = buffer[..*len].split_at_mut(self.hdr_len);
+        if !header.is_empty() {
+            vnet::parse(header, frame);
+        }
+        Some(frame)
+    }
+}
+
 /// A virtual TUN (IP) or TAP (Ethernet) interface.
+///
+/// Frames are received into and transmitted from buffers allocated along with
+/// the device, the tokens borrow them instead of allocating per frame.
+///
+/// With [`TapOffload::TSO4`] or [`TapOffload::TSO6`] the TCP segments of a
+/// burst are held back and written as one super-packet, [`TapDevice::flush`]
+/// writes what is held once the interface is polled.
 #[derive(Debug)]
 pub struct TapDevice {
-    lower: Rc<RefCell<crate::driver::TapDesc>>,
+    lower: TapDesc,
     mtu: usize,
     medium: Medium,
+    rx: RxBatch,
+    tx: Vec<u8>,
+    gso: Option<TcpCoalescer>,
 }
 
 impl AsRawFd for TapDevice {
     fn as_raw_fd(&self) -> RawFd {
-        self.lower.borrow().as_raw_fd()
+        self.lower.as_raw_fd()
     }
 }
 
@@ -171,102 +481,457 @@ impl TapDevice {
     /// no special privileges are needed. Otherwise, this requires superuser privileges
     /// or a corresponding capability set on the executable.
     pub fn new(name: &str, medium: Medium) -> io::Result<TapDevice> {
-        let lower = crate::driver::TapDesc::new(name, medium)?;
+        let lower = TapDesc::new(name, medium)?;
+        Self::from_desc(lower, medium)
+    }
+
+    /// Attaches to the TAP interface called `name` like [`TapDevice::new`], with `mac`
+    /// as its MAC address instead of a random one.
+    pub fn with_mac(name: &str, mac: smoltcp::wire::EthernetAddress) -> io::Result<TapDevice> {
+        let lower = TapDesc::with_mac(name, mac)?;
+        Self::from_desc(lower, Medium::Ethernet)
+    }
+
+    /// Attaches to the TUN/TAP interface called `name` like [`TapDevice::new`],
+    /// exchanging frames behind a virtio-net header with `offload` negotiated.
+    /// With [`TapOffload::CSUM`] smoltcp neither computes nor verifies TCP and
+    /// UDP checksums, the kernel finishes them.
+    pub fn with_offload(name: &str, medium: Medium, offload: TapOffload) -> io::Result<TapDevice> {
+        let lower = TapDesc::with_offload(name, medium, offload)?;
+        Self::from_desc(lower, medium)
+    }
+
+    /// Attaches to the TUN/TAP interface called `name` with `queues` queues,
+    /// see [`TapDesc::multi_queue`]. Each queue is a device of its own.
+    pub fn multi_queue(name: &str, medium: Medium, queues: usize) -> io::Result<Vec<TapDevice>> {
+        TapDesc::multi_queue(name, medium, queues)?
+            .into_iter()
+            .map(|lower| Self::from_desc(lower, medium))
+            .collect()
+    }
+
+    fn from_desc(lower: crate::driver::TapDesc, medium: Medium) -> io::Result<TapDevice> {
         let mtu = lower.interface_mtu()?;
+        let (rx, tx) = Self::buffers(&lower, mtu);
+        let gso = lower
+            .offload()
+            .filter(|offload| offload.intersects(TapOffload::TSO4 | TapOffload::TSO6))
+            .map(|offload| TcpCoalescer::new(medium, offload));
         Ok(TapDevice {
-            lower: Rc::new(RefCell::new(lower)),
+            lower,
             mtu,
             medium,
+            rx,
+            tx,
+            gso,
         })
     }
 
-    // /// Attaches to a TUN/TAP interface specified by file descriptor `fd`.
-    // ///
-    // /// On platforms like Android, a file descriptor to a tun interface is exposed.
-    // /// On these platforms, a TunTapInterface cannot be instantiated with a name.
-    // pub fn from_fd(fd: RawFd, medium: Medium, mtu: usize) -> io::Result<TapDevice> {
-    //     let lower = crate::driver::TapDesc::from_fd(fd, mtu)?;
-    //     Ok(TapDevice {
-    //         lower: Rc::new(RefCell::new(lower)),
-    //         mtu,
-    //         medium,
-    //     })
-    // }
+    fn buffers(lower: &TapDesc, mtu: usize) -> (RxBatch, Vec<u8>) {
+        let (rx_len, hdr_len) = match lower.offload() {
+            Some(offload) if offload.segmentation() => (mtu.max(VNET_GSO_MAX_FRAME), VNET_HDR_LEN),
+            Some(_) => (mtu, VNET_HDR_LEN),
+            None => (mtu, 0),
+        };
+        (RxBatch::new(rx_len, hdr_len), vec![0; hdr_len + mtu])
+    }
+
+    /// Changes the IP MTU of the device, see [`TapDesc::set_mtu`]. The frames
+    /// read but not yet received are dropped along with the old buffers.
+    pub fn set_mtu(&mut self, ip_mtu: usize) -> io::Result<()> {
+        self.lower.set_mtu(ip_mtu)?;
+        self.mtu = self.lower.interface_mtu()?;
+        (self.rx, self.tx) = Self::buffers(&self.lower, self.mtu);
+        Ok(())
+    }
 
-    pub fn mac(&self) -> smoltcp::wire::EthernetAddress {
-        self.lower.borrow().mac
+    /// See [`TapDesc::link_flags`].
+    pub fn link_flags(&self) -> io::Result<Option<libc::c_int>> {
+        self.lower.link_flags()
+    }
+
+    /// See [`TapDesc::set_up`].
+    pub fn set_up(&mut self, up: bool) -> io::Result<()> {
+        self.lower.set_up(up)
+    }
+
+    /// Attaches to a TUN/TAP interface specified by file descriptor `fd`.
+    ///
+    /// On platforms like Android, or in a sandbox receiving the descriptor from a
+    /// privileged helper, a TunTapInterface cannot be instantiated with a name.
+    /// See [`TapDesc::from_fd`](TapDesc::from_fd) for the arguments.
+    pub fn from_fd(
+        fd: OwnedFd,
+        medium: Medium,
+        mtu: usize,
+        mac: Option<smoltcp::wire::EthernetAddress>,
+    ) -> io::Result<TapDevice> {
+        let lower = TapDesc::from_fd(fd, medium, mtu, mac)?;
+        Self::from_desc(lower, medium)
+    }
+
+    /// See [`TapDesc::name`].
+    pub fn name(&self) -> Option<&str> {
+        self.lower.name()
+    }
+
+    /// The MAC address of a TAP device, `None` for a TUN device.
+    pub fn mac(&self) -> Option<smoltcp::wire::EthernetAddress> {
+        self.lower.mac
+    }
+
+    pub fn set_mac(&mut self, mac: smoltcp::wire::EthernetAddress) -> io::Result<()> {
+        self.lower.set_mac(mac)
+    }
+
+    pub fn medium(&self) -> Medium {
+        self.medium
+    }
+
+    /// Takes what happened on the device since the last call, a failed read
+    /// or write drops the frame instead of failing the poll.
+    pub fn take_io_status(&mut self) -> IoStatus {
+        mem::take(&mut self.lower.status)
+    }
+
+    /// Reads the pending frames before polling, so that the reads happen
+    /// outside the locks of the interface shared by every queue.
+    pub fn prefetch(&mut self) {
+        if self.rx.is_empty() {
+            self.rx.refill(&mut self.lower);
+        }
+    }
+
+    /// Writes the TCP super-packet held back, if any.
+    pub fn flush(&mut self) {
+        if let Some(frame) = self.gso.as_mut().and_then(TcpCoalescer::finish) {
+            send_frame(&mut self.lower, frame);
+        }
+    }
+
+    /// Reads ahead like [`TapDevice::prefetch`], and tells whether frames are
+    /// waiting to be received.
+    pub fn has_rx(&mut self) -> bool {
+        self.prefetch();
+        !self.rx.is_empty()
     }
 }
 
 impl Device for TapDevice {
-    type RxToken<'a> = RxToken;
-    type TxToken<'a> = TxToken;
+    type RxToken<'a> = RxToken<'a>;
+    type TxToken<'a> = TxToken<'a>;
 
     fn capabilities(&self) -> DeviceCapabilities {
         let mut caps = DeviceCapabilities::default();
         caps.max_transmission_unit = self.mtu;
         caps.medium = self.medium;
+        if self
+            .lower
+            .offload()
+            .is_some_and(|offload| offload.contains(TapOffload::CSUM))
+        {
+            // the kernel computes the checksums of transmitted frames
+            caps.checksum.tcp = Checksum::Rx;
+            caps.checksum.udp = Checksum::Rx;
+        }
         caps
     }
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        let mut lower = self.lower.borrow_mut();
-        let mut buffer = vec![0; self.mtu];
-        match lower.recv(&mut buffer[..]) {
-            Ok(size) => {
-                buffer.resize(size, 0);
-                let rx = RxToken { buffer };
-                let tx = TxToken {
-                    lower: self.lower.clone(),
-                };
-                Some((rx, tx))
-            }
-            Err(err) if err.kind() == io::ErrorKind::WouldBlock => None,
-            Err(err) => panic!("{}", err),
+        let TapDevice {
+            lower,
+            medium,
+            rx,
+            tx,
+            gso,
+            ..
+        } = self;
+        if rx.is_empty() {
+            rx.refill(lower);
         }
+        let frame = rx.pop()?;
+        let tx = TxToken {
+            lower,
+            medium: *medium,
+            buffer: tx,
+            gso: gso.as_mut(),
+        };
+        Some((RxToken { frame }, tx))
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
         Some(TxToken {
-            lower: self.lower.clone(),
+            lower: &mut self.lower,
+            medium: self.medium,
+            buffer: &mut self.tx,
+            gso: self.gso.as_mut(),
         })
     }
 }
 
 #[doc(hidden)]
-pub struct RxToken {
-    buffer: Vec<u8>,
+pub struct RxToken<'a> {
+    frame: &'a [u8],
 }
 
-impl phy::RxToken for RxToken {
+impl phy::RxToken for RxToken<'_> {
     fn consume<R, F>(self, f: F) -> R
     where
         F: FnOnce(&[u8]) -> R,
     {
-        f(&self.buffer[..])
+        f(self.frame)
     }
 }
 
 #[doc(hidden)]
-pub struct TxToken {
-    lower: Rc<RefCell<crate::driver::TapDesc>>,
+pub struct TxToken<'a> {
+    lower: &'a mut TapDesc,
+    medium: Medium,
+    buffer: &'a mut Vec<u8>,
+    gso: Option<&'a mut TcpCoalescer>,
 }
 
-impl phy::TxToken for TxToken {
+impl phy::TxToken for TxToken<'_> {
     fn consume<R, F>(self, len: usize, f: F) -> R
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        let mut lower = self.lower.borrow_mut();
-        let mut buffer = vec![0; len];
-        let result = f(&mut buffer);
-        match lower.send(&buffer[..]) {
-            Ok(_) => {}
-            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
-                log::debug!("phy: tx failed due to WouldBlock")
+        let offload = self.lower.offload();
+        let hdr_len = if offload.is_some() { VNET_HDR_LEN } else { 0 };
+        // smoltcp never asks for more than the MTU, grow once if it does
+        if self.buffer.len() < hdr_len + len {
+            self.buffer.resize(hdr_len + len, 0);
+        }
+        // smoltcp writes all of the frame and the header is emitted below
+        let buffer = &mut self.buffer[..hdr_len + len];
+        let (header, frame) = buffer.split_at_mut(hdr_len);
+        let result = f(frame);
+        if let Some(gso) = self.gso {
+            if gso.push(frame) {
+                return result;
+            }
+            // the frame ends the burst held back, and may start the next one
+            if let Some(held) = gso.finish() {
+                send_frame(self.lower, held);
+                if gso.push(frame) {
+                    return result;
+                }
             }
-            Err(err) => panic!("{}", err),
         }
+        if let Some(offload) = offload {
+            vnet::emit(
+                header,
+                frame,
+                self.medium,
+                offload.contains(TapOffload::CSUM),
+            );
+        }
+        send_frame(self.lower, buffer);
         result
     }
 }
+
+/// Writes a frame, a failure drops it and is recorded in the status of `lower`
+fn send_frame(lower: &mut TapDesc, frame: &[u8]) {
+    match lower.send(frame) {
+        Ok(_) => lower.status.succeeded(),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+            log::debug!("phy: tx failed due to WouldBlock");
+            lower.status.tx_dropped += 1;
+        }
+        Err(err) => {
+            log::debug!("phy: tx failed: {}", err);
+            lower.status.tx_errors += 1;
+            lower.status.failed(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::phy::{RxToken, TxToken};
+    use std::os::fd::FromRawFd;
+
+    /// Two connected sockets keeping the frame boundaries, like a TUN/TAP fd
+    fn socketpair() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        let res = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(res, 0, "socketpair: {}", io::Error::last_os_error());
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    fn read_peer(peer: &OwnedFd, buffer: &mut [u8]) -> isize {
+        unsafe {
+            libc::read(
+                peer.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        }
+    }
+
+    #[test]
+    fn from_fd_wraps_a_socketpair() {
+        let (fd, peer) = socketpair();
+        let mut device = TapDevice::from_fd(fd, Medium::Ip, 1500, None).unwrap();
+        assert_eq!(device.name(), None);
+        assert_eq!(device.mac(), None);
+        assert_eq!(device.capabilities().max_transmission_unit, 1500);
+        // reads of the polling thread must not block
+        let flags = unsafe { libc::fcntl(device.as_raw_fd(), libc::F_GETFL) };
+        assert_ne!(flags & libc::O_NONBLOCK, 0);
+        assert!(device.receive(Instant::ZERO).is_none());
+
+        let sent = unsafe { libc::write(peer.as_raw_fd(), b"ping".as_ptr() as _, 4) };
+        assert_eq!(sent, 4);
+        let (rx, _) = device.receive(Instant::ZERO).unwrap();
+        assert_eq!(rx.consume(|frame| frame.to_vec()), b"ping");
+
+        let tx = device.transmit(Instant::ZERO).unwrap();
+        tx.consume(4, |frame| frame.copy_from_slice(b"pong"));
+        let mut buffer = [0; 16];
+        assert_eq!(read_peer(&peer, &mut buffer), 4);
+        assert_eq!(&buffer[..4], b"pong");
+
+        // the device owns the fd, the peer sees it closed
+        drop(device);
+        assert_eq!(read_peer(&peer, &mut buffer), 0);
+    }
+
+    #[test]
+    fn from_fd_stops_reading_once_the_peer_hangs_up() {
+        let (fd, peer) = socketpair();
+        let mut device = TapDevice::from_fd(fd, Medium::Ip, 1500, None).unwrap();
+        let sent = unsafe { libc::write(peer.as_raw_fd(), b"last".as_ptr() as _, 4) };
+        assert_eq!(sent, 4);
+        drop(peer);
+
+        // the frame sent before hanging up is still received
+        let (rx, _) = device.receive(Instant::ZERO).unwrap();
+        assert_eq!(rx.consume(|frame| frame.to_vec()), b"last");
+        let status = device.take_io_status();
+        assert_eq!(status.rx_errors, 1);
+        assert_eq!(
+            status.error.map(|err| err.kind()),
+            Some(io::ErrorKind::UnexpectedEof)
+        );
+        assert!(device.receive(Instant::ZERO).is_none());
+    }
+
+    #[test]
+    fn frames_are_read_in_bursts_of_a_batch() {
+        let (fd, peer) = socketpair();
+        let mut device = TapDevice::from_fd(fd, Medium::Ip, 1500, None).unwrap();
+        let count = TAP_RX_BATCH + 8;
+        for i in 0..count {
+            let frame = (i as u32).to_be_bytes();
+            let sent = unsafe { libc::write(peer.as_raw_fd(), frame.as_ptr() as _, 4) };
+            assert_eq!(sent, 4);
+        }
+
+        device.prefetch();
+        assert_eq!(device.rx.filled, TAP_RX_BATCH);
+        // a batch not drained yet is not refilled
+        device.prefetch();
+        assert_eq!(device.rx.filled, TAP_RX_BATCH);
+
+        for i in 0..count {
+            assert!(device.has_rx());
+            let (rx, _) = device.receive(Instant::ZERO).unwrap();
+            let frame = rx.consume(|frame| frame.to_vec());
+            assert_eq!(frame, (i as u32).to_be_bytes());
+        }
+        assert_eq!(device.rx.filled, 8);
+        assert!(!device.has_rx());
+        assert!(device.receive(Instant::ZERO).is_none());
+        assert!(device.take_io_status().ok);
+    }
+
+    #[test]
+    fn partial_checksums_are_finished_on_receive() {
+        use smoltcp::phy::ChecksumCapabilities;
+        use smoltcp::wire::{
+            EthernetAddress, EthernetProtocol, EthernetRepr, IpAddress, IpProtocol, Ipv4Address,
+            Ipv4Packet, Ipv4Repr, UdpPacket, UdpRepr,
+        };
+
+        let (fd, peer) = socketpair();
+        let mut device = TapDevice::from_fd(fd, Medium::Ethernet, 1500, None).unwrap();
+        // as if opened with `IFF_VNET_HDR` and `TapOffload::CSUM`
+        device.lower.offload = Some(TapOffload::CSUM);
+        device.rx = RxBatch::new(1514, VNET_HDR_LEN);
+        let caps = device.capabilities();
+        assert!(caps.checksum.udp.rx() && !caps.checksum.udp.tx());
+        assert!(caps.checksum.tcp.rx() && !caps.checksum.tcp.tx());
+
+        let (src, dst) = (Ipv4Address::new(10, 0, 0, 2), Ipv4Address::new(10, 0, 0, 1));
+        let udp = UdpRepr {
+            src_port: 4000,
+            dst_port: 53,
+        };
+        let payload = b"partial";
+        let ip = Ipv4Repr {
+            src_addr: src,
+            dst_addr: dst,
+            next_header: IpProtocol::Udp,
+            payload_len: udp.header_len() + payload.len(),
+            hop_limit: 64,
+        };
+        let mut frame = vec![0; VNET_HDR_LEN + 14 + ip.buffer_len() + ip.payload_len];
+        let (header, ethernet) = frame.split_at_mut(VNET_HDR_LEN);
+        let mut ethernet = EthernetFrame::new_unchecked(ethernet);
+        EthernetRepr {
+            src_addr: EthernetAddress([2, 0, 0, 0, 0, 2]),
+            dst_addr: EthernetAddress([2, 0, 0, 0, 0, 1]),
+            ethertype: EthernetProtocol::Ipv4,
+        }
+        .emit(&mut ethernet);
+        let mut ipv4 = Ipv4Packet::new_unchecked(ethernet.payload_mut());
+        ip.emit(&mut ipv4, &ChecksumCapabilities::default());
+        udp.emit(
+            &mut UdpPacket::new_unchecked(ipv4.payload_mut()),
+            &IpAddress::Ipv4(src),
+            &IpAddress::Ipv4(dst),
+            payload.len(),
+            |buffer| buffer.copy_from_slice(payload),
+            &ChecksumCapabilities::ignored(),
+        );
+        // the kernel hands the frame over like we hand ours to it
+        vnet::emit(header, ethernet.into_inner(), Medium::Ethernet, true);
+        assert_ne!(frame[0], 0);
+        let sent = unsafe { libc::write(peer.as_raw_fd(), frame.as_ptr() as _, frame.len()) };
+        assert_eq!(sent as usize, frame.len());
+
+        let (rx, _) = device.receive(Instant::ZERO).unwrap();
+        rx.consume(|frame| {
+            let ethernet = EthernetFrame::new_checked(frame).unwrap();
+            let ipv4 = Ipv4Packet::new_checked(ethernet.payload()).unwrap();
+            let udp = UdpPacket::new_checked(ipv4.payload()).unwrap();
+            assert!(udp.verify_checksum(&IpAddress::Ipv4(src), &IpAddress::Ipv4(dst)));
+            assert_eq!(udp.payload(), payload);
+        });
+    }
+
+    #[test]
+    fn from_fd_gives_a_tap_device_a_mac() {
+        let (fd, _peer) = socketpair();
+        let device = TapDevice::from_fd(fd, Medium::Ethernet, 1500, None).unwrap();
+        let mac = device.mac().unwrap();
+        assert!(mac.is_unicast() && mac.is_local());
+        // the Ethernet header is not part of the IP MTU
+        assert_eq!(device.capabilities().max_transmission_unit, 1514);
+
+        let mac = smoltcp::wire::EthernetAddress([0x02, 0, 0, 0, 0, 0x42]);
+        let (fd, _peer) = socketpair();
+        let device = TapDevice::from_fd(fd, Medium::Ethernet, 1500, Some(mac)).unwrap();
+        assert_eq!(device.mac(), Some(mac));
+    }
+}
@@ -0,0 +1,102 @@
+//! Tap layer, hands every frame an interface sends and receives to a closure.
+//! Packet capture and packet sockets both see the frames through it.
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+
+/// The way a frame crosses the device, shared by every layer that looks at frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Called with every frame seen on the device.
+pub type Tap<'a> = &'a dyn Fn(Direction, &[u8]);
+
+/// Wraps the device of an interface during a poll, handing every frame to `tap`.
+pub struct TappedDevice<'a, D: Device + ?Sized> {
+    inner: &'a mut D,
+    tap: Tap<'a>,
+}
+
+impl<'a, D: Device + ?Sized> TappedDevice<'a, D> {
+    pub fn new(inner: &'a mut D, tap: Tap<'a>) -> Self {
+        Self { inner, tap }
+    }
+}
+
+impl<D: Device + ?Sized> Device for TappedDevice<'_, D> {
+    type RxToken<'b>
+        = RxToken<'b, D::RxToken<'b>>
+    where
+        Self: 'b;
+    type TxToken<'b>
+        = TxToken<'b, D::TxToken<'b>>
+    where
+        Self: 'b;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let tap = self.tap;
+        self.inner
+            .receive(timestamp)
+            .map(|(rx, tx)| (RxToken { inner: rx, tap }, TxToken { inner: tx, tap }))
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        let tap = self.tap;
+        self.inner
+            .transmit(timestamp)
+            .map(|tx| TxToken { inner: tx, tap })
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken<'a, T: phy::RxToken> {
+    inner: T,
+    tap: Tap<'a>,
+}
+
+impl<T: phy::RxToken> phy::RxToken for RxToken<'_, T> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let tap = self.tap;
+        self.inner.consume(|buffer| {
+            tap(Direction::Inbound, buffer);
+            f(buffer)
+        })
+    }
+
+    fn meta(&self) -> phy::PacketMeta {
+        self.inner.meta()
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken<'a, T: phy::TxToken> {
+    inner: T,
+    tap: Tap<'a>,
+}
+
+impl<T: phy::TxToken> phy::TxToken for TxToken<'_, T> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let tap = self.tap;
+        self.inner.consume(len, |buffer| {
+            let result = f(buffer);
+            tap(Direction::Outbound, buffer);
+            result
+        })
+    }
+
+    fn set_meta(&mut self, meta: phy::PacketMeta) {
+        self.inner.set_meta(meta)
+    }
+}
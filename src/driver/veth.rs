@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::vec::Vec;
+
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, EthernetFrame};
+use spin::Mutex;
+
+use super::random_mac;
+
+/// Default IP MTU of a veth endpoint, the frames carry an Ethernet header on top.
+pub const VETH_DEFAULT_MTU: usize = 1500;
+
+/// Bounds of the IP MTU of an Ethernet link, `ETH_MIN_MTU` and `ETH_MAX_MTU` of Linux.
+const ETH_MIN_MTU: usize = 68;
+const ETH_MAX_MTU: usize = 0xffff;
+
+/// Frames queued on an endpoint beyond this are dropped, like a full NIC ring.
+const VETH_QUEUE_LEN: usize = 1024;
+
+/// The receive queue of one veth endpoint, written by its peer.
+#[derive(Debug)]
+struct VethQueue {
+    frames: Mutex<VecDeque<Vec<u8>>>,
+    /// eventfd signalled whenever a frame is queued, so that the endpoint
+    /// can be watched by epoll like a tap device
+    event_fd: RawFd,
+}
+
+impl VethQueue {
+    fn new() -> io::Result<Self> {
+        let event_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if event_fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            frames: Mutex::new(VecDeque::new()),
+            event_fd,
+        })
+    }
+
+    fn push(&self, frame: Vec<u8>) {
+        let mut frames = self.frames.lock();
+        if frames.len() >= VETH_QUEUE_LEN {
+            log::debug!("veth: queue full, frame dropped");
+            return;
+        }
+        frames.push_back(frame);
+        drop(frames);
+
+        let one: u64 = 1;
+        unsafe {
+            libc::write(
+                self.event_fd,
+                &one as *const u64 as *const libc::c_void,
+                core::mem::size_of::<u64>(),
+            );
+        }
+    }
+
+    fn pop(&self) -> Option<Vec<u8>> {
+        if let Some(frame) = self.frames.lock().pop_front() {
+            return Some(frame);
+        }
+        // drained, reset the eventfd counter until the next push
+        let mut counter: u64 = 0;
+        unsafe {
+            libc::read(
+                self.event_fd,
+                &mut counter as *mut u64 as *mut libc::c_void,
+                core::mem::size_of::<u64>(),
+            );
+        }
+        // a frame pushed before the reset would otherwise go unnoticed
+        self.frames.lock().pop_front()
+    }
+}
+
+impl Drop for VethQueue {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.event_fd);
+        }
+    }
+}
+
+/// One end of an in-memory virtual Ethernet cable.
+///
+/// Frames transmitted on an endpoint appear on the receive queue of its peer,
+/// see [`veth_pair`].
+#[derive(Debug)]
+pub struct VethDevice {
+    rx: Arc<VethQueue>,
+    peer: Arc<VethQueue>,
+    /// the IP MTU plus the Ethernet header
+    frame_mtu: usize,
+    mac: EthernetAddress,
+}
+
+impl AsRawFd for VethDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.rx.event_fd
+    }
+}
+
+/// Creates two connected veth endpoints with random MAC addresses and an IP
+/// MTU of `ip_mtu`, fails with `EINVAL` outside of the Ethernet bounds.
+pub fn veth_pair(ip_mtu: usize) -> io::Result<(VethDevice, VethDevice)> {
+    let frame_mtu = frame_mtu(ip_mtu)?;
+    let a = Arc::new(VethQueue::new()?);
+    let b = Arc::new(VethQueue::new()?);
+    Ok((
+        VethDevice {
+            rx: a.clone(),
+            peer: b.clone(),
+            frame_mtu,
+            mac: random_mac(),
+        },
+        VethDevice {
+            rx: b,
+            peer: a,
+            frame_mtu,
+            mac: random_mac(),
+        },
+    ))
+}
+
+fn frame_mtu(ip_mtu: usize) -> io::Result<usize> {
+    if !(ETH_MIN_MTU..=ETH_MAX_MTU).contains(&ip_mtu) {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    Ok(ip_mtu + EthernetFrame::<&[u8]>::header_len())
+}
+
+impl VethDevice {
+    pub fn mac(&self) -> EthernetAddress {
+        self.mac
+    }
+
+    pub fn set_mac(&mut self, mac: EthernetAddress) {
+        self.mac = mac;
+    }
+
+    /// The IP MTU, the frames carry an Ethernet header on top of it.
+    pub fn ip_mtu(&self) -> usize {
+        self.frame_mtu - EthernetFrame::<&[u8]>::header_len()
+    }
+
+    /// Changes the IP MTU of this end, the peer keeps its own. Fails with
+    /// `EINVAL` outside of the Ethernet bounds.
+    pub fn set_mtu(&mut self, ip_mtu: usize) -> io::Result<()> {
+        self.frame_mtu = frame_mtu(ip_mtu)?;
+        Ok(())
+    }
+}
+
+impl Device for VethDevice {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = self.frame_mtu;
+        caps.medium = Medium::Ethernet;
+        caps
+    }
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        self.rx.pop().map(|buffer| {
+            let rx = RxToken { buffer };
+            let tx = TxToken { peer: &self.peer };
+            (rx, tx)
+        })
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken { peer: &self.peer })
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken {
+    buffer: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.buffer[..])
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken<'a> {
+    peer: &'a VethQueue,
+}
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer);
+        self.peer.push(buffer);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mtus_are_ip_mtus_within_the_ethernet_bounds() {
+        let (mut left, right) = veth_pair(VETH_DEFAULT_MTU).unwrap();
+        assert_eq!(left.ip_mtu(), 1500);
+        assert_eq!(left.capabilities().max_transmission_unit, 1514);
+        assert_eq!(left.capabilities().ip_mtu(), 1500);
+
+        left.set_mtu(ETH_MIN_MTU).unwrap();
+        assert_eq!(left.ip_mtu(), 68);
+        assert_eq!(right.ip_mtu(), 1500, "the peer keeps its own");
+
+        for ip_mtu in [0, 13, ETH_MIN_MTU - 1, ETH_MAX_MTU + 1] {
+            assert_eq!(
+                veth_pair(ip_mtu).unwrap_err().raw_os_error(),
+                Some(libc::EINVAL)
+            );
+            assert_eq!(
+                left.set_mtu(ip_mtu).unwrap_err().raw_os_error(),
+                Some(libc::EINVAL)
+            );
+        }
+        assert_eq!(left.ip_mtu(), 68, "a rejected MTU changes nothing");
+    }
+}
@@ -0,0 +1,724 @@
+//! The virtio-net header in front of every frame of a TUN/TAP device opened
+//! with `IFF_VNET_HDR`, see `include/uapi/linux/virtio_net.h`.
+//!
+//! The header lets the kernel hand over frames it did not finish: TCP and UDP
+//! checksums left partial, and super-packets it did not segment. What it may
+//! hand over is negotiated with `TUNSETOFFLOAD`. In the other direction we
+//! leave the TCP and UDP checksums of transmitted frames to the kernel, and
+//! with `TSO4`/`TSO6` the TCP segments smoltcp sends back to back on one
+//! connection are merged by [`TcpCoalescer`] into a super-packet, which the
+//! kernel segments again.
+use smoltcp::phy::Medium;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, Ipv6Packet, IPV4_HEADER_LEN,
+    IPV6_HEADER_LEN, TCP_HEADER_LEN,
+};
+
+/// Length of `struct virtio_net_hdr`.
+pub const VNET_HDR_LEN: usize = 10;
+
+/// Largest frame the kernel hands over once segmentation is offloaded, an IP
+/// packet of 64 KiB behind the link header.
+pub const VNET_GSO_MAX_FRAME: usize = u16::MAX as usize + EthernetFrame::<&[u8]>::header_len();
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+
+bitflags::bitflags! {
+    /// Offloads of a device opened with `IFF_VNET_HDR`, the `TUN_F_*` flags of `TUNSETOFFLOAD`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct TapOffload: u32 {
+        /// Frames may carry partial TCP/UDP checksums, needed by all the others
+        const CSUM = libc::TUN_F_CSUM;
+        /// IPv4 TCP super-packets
+        const TSO4 = libc::TUN_F_TSO4;
+        /// IPv6 TCP super-packets
+        const TSO6 = libc::TUN_F_TSO6;
+        /// TCP super-packets with ECN
+        const TSO_ECN = libc::TUN_F_TSO_ECN;
+        /// UDP super-packets, ignored by kernels since 4.14
+        const UFO = libc::TUN_F_UFO;
+    }
+}
+
+impl TapOffload {
+    /// The kernel may hand over frames larger than the MTU
+    pub fn segmentation(self) -> bool {
+        self.intersects(TapOffload::TSO4 | TapOffload::TSO6 | TapOffload::UFO)
+    }
+}
+
+/// Fills the header of a transmitted frame. With `csum` the TCP or UDP checksum
+/// smoltcp left zeroed is replaced by the pseudo-header sum and left to the kernel.
+pub fn emit(header: &mut [u8], frame: &mut [u8], medium: Medium, csum: bool) {
+    header.fill(0);
+    if !csum {
+        return;
+    }
+    if let Some((start, offset)) = partial_checksum(frame, medium) {
+        header[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+        header[6..8].copy_from_slice(&start.to_ne_bytes());
+        header[8..10].copy_from_slice(&offset.to_ne_bytes());
+    }
+}
+
+/// Reads the header of a received frame. A TCP or UDP checksum the kernel left
+/// partial with `NEEDS_CSUM` is finished here, so that smoltcp verifies every
+/// frame the same way, `DATA_VALID` or not.
+pub fn parse(header: &[u8], frame: &mut [u8]) {
+    if header[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM == 0 {
+        return;
+    }
+    let start = u16::from_ne_bytes([header[6], header[7]]) as usize;
+    let offset = u16::from_ne_bytes([header[8], header[9]]) as usize;
+    // a header pointing out of the frame leaves it to fail verification
+    let Some(packet) = frame.get_mut(start..) else {
+        return;
+    };
+    if packet.len() < offset + 2 {
+        return;
+    }
+    // the checksum field holds the pseudo-header sum, summed along
+    let checksum = match !fold(sum_words(packet)) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    packet[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Merges the TCP segments smoltcp transmits back to back on one connection
+/// into a super-packet, handed to the kernel with a GSO header so that a burst
+/// costs one write instead of one per segment. The segments must follow each
+/// other in sequence and carry nothing but ACK, all of them but the last with
+/// the payload size of the first, which the kernel segments with again.
+#[derive(Debug)]
+pub struct TcpCoalescer {
+    medium: Medium,
+    offload: TapOffload,
+    /// room for the virtio-net header followed by the super-packet
+    buffer: Vec<u8>,
+    /// length of the super-packet
+    len: usize,
+    /// the headers of the first segment, `None` while no segment is held
+    first: Option<TcpSegment>,
+    /// payload size of the first segment
+    gso_size: usize,
+    segments: usize,
+    next_seq: u32,
+    /// the last segment carries PSH
+    push: bool,
+    /// the last segment ends the burst with PSH or a short payload
+    closed: bool,
+}
+
+impl TcpCoalescer {
+    /// Merges IPv4 segments with [`TapOffload::TSO4`] in `offload`, and IPv6
+    /// segments with [`TapOffload::TSO6`].
+    pub fn new(medium: Medium, offload: TapOffload) -> Self {
+        TcpCoalescer {
+            medium,
+            offload,
+            buffer: vec![0; VNET_HDR_LEN + VNET_GSO_MAX_FRAME],
+            len: 0,
+            first: None,
+            gso_size: 0,
+            segments: 0,
+            next_seq: 0,
+            push: false,
+            closed: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.first.is_none()
+    }
+
+    /// Takes `frame` into the super-packet, `false` when it does not continue
+    /// the one held, or cannot start one.
+    pub fn push(&mut self, frame: &[u8]) -> bool {
+        let Some(segment) = tcp_segment(frame, self.medium) else {
+            return false;
+        };
+        let offloaded = match segment.version {
+            4 => TapOffload::TSO4,
+            _ => TapOffload::TSO6,
+        };
+        if !self.offload.contains(offloaded) {
+            return false;
+        }
+        let payload = &frame[segment.hdr_len..];
+        let start = VNET_HDR_LEN + self.len;
+
+        match self.first {
+            None => {
+                self.buffer[VNET_HDR_LEN..][..frame.len()].copy_from_slice(frame);
+                self.first = Some(segment);
+                self.gso_size = payload.len();
+                self.segments = 0;
+            }
+            Some(first) => {
+                // the IP length field counts the headers of IPv4 only
+                let ip_len = match first.version {
+                    4 => self.len - first.l3,
+                    _ => self.len - first.l3 - IPV6_HEADER_LEN,
+                };
+                if self.closed
+                    || segment.hdr_len != first.hdr_len
+                    || segment.seq != self.next_seq
+                    || payload.len() > self.gso_size
+                    || ip_len + payload.len() > u16::MAX as usize
+                    || start + payload.len() > self.buffer.len()
+                    || !first.same_flow(&self.buffer[VNET_HDR_LEN..], frame)
+                {
+                    return false;
+                }
+                self.buffer[start..][..payload.len()].copy_from_slice(payload);
+            }
+        }
+        self.len += if self.segments == 0 {
+            frame.len()
+        } else {
+            payload.len()
+        };
+        self.segments += 1;
+        self.next_seq = segment.seq.wrapping_add(payload.len() as u32);
+        self.push = segment.flags & TCP_FLAG_PSH != 0;
+        self.closed = self.push || payload.len() < self.gso_size;
+        true
+    }
+
+    /// Ends the super-packet and returns it behind its virtio-net header. A
+    /// single segment goes out as it came, with its checksum left to the kernel.
+    pub fn finish(&mut self) -> Option<&[u8]> {
+        let first = self.first.take()?;
+        let len = VNET_HDR_LEN + self.len;
+        let (header, frame) = self.buffer[..len].split_at_mut(VNET_HDR_LEN);
+        self.len = 0;
+        if self.segments == 1 {
+            emit(header, frame, self.medium, true);
+            return Some(&self.buffer[..len]);
+        }
+
+        let ip = &mut frame[first.l3..];
+        let ip_len = ip.len();
+        let (gso_type, l4) = match first.version {
+            4 => {
+                let mut packet = Ipv4Packet::new_unchecked(&mut *ip);
+                packet.set_total_len(ip_len as u16);
+                packet.fill_checksum();
+                (VIRTIO_NET_HDR_GSO_TCPV4, IPV4_HEADER_LEN)
+            }
+            _ => {
+                Ipv6Packet::new_unchecked(&mut *ip)
+                    .set_payload_len((ip_len - IPV6_HEADER_LEN) as u16);
+                (VIRTIO_NET_HDR_GSO_TCPV6, IPV6_HEADER_LEN)
+            }
+        };
+        // the kernel copies the flags of the super-packet into every segment
+        // but the last, which alone keeps PSH
+        if self.push {
+            ip[l4 + 13] |= TCP_FLAG_PSH;
+        }
+        emit(header, frame, self.medium, true);
+        header[1] = gso_type;
+        header[2..4].copy_from_slice(&(first.hdr_len as u16).to_ne_bytes());
+        header[4..6].copy_from_slice(&(self.gso_size as u16).to_ne_bytes());
+        Some(&self.buffer[..len])
+    }
+}
+
+/// The headers of a transmitted TCP segment a super-packet can be made of
+#[derive(Debug, Clone, Copy)]
+struct TcpSegment {
+    /// where the IP header starts
+    l3: usize,
+    /// where the payload starts
+    hdr_len: usize,
+    version: u8,
+    seq: u32,
+    flags: u8,
+}
+
+impl TcpSegment {
+    /// Whether the headers of `frame` are those of `first`, the frame that
+    /// `self` was parsed from, but for the lengths, the IPv4 identification,
+    /// the checksums and the TCP sequence number and flags
+    fn same_flow(&self, first: &[u8], frame: &[u8]) -> bool {
+        let l4 = match self.version {
+            4 => self.l3 + IPV4_HEADER_LEN,
+            _ => self.l3 + IPV6_HEADER_LEN,
+        };
+        let ignored = |at: usize| match self.version {
+            4 => matches!(at - self.l3, 2..=5 | 10..=11),
+            _ => matches!(at - self.l3, 4..=5),
+        };
+        (0..self.hdr_len).all(|at| {
+            let ignored = if at < self.l3 {
+                false
+            } else if at < l4 {
+                ignored(at)
+            } else {
+                matches!(at - l4, 4..=7 | 13 | 16..=17)
+            };
+            ignored || first[at] == frame[at]
+        })
+    }
+}
+
+/// Parses a TCP segment with a payload, only ACK or ACK|PSH set and its checksum
+/// left zeroed, in an IP packet without options, extension headers or fragmentation
+fn tcp_segment(frame: &[u8], medium: Medium) -> Option<TcpSegment> {
+    let l3 = match medium {
+        Medium::Ethernet => {
+            let ethernet = EthernetFrame::new_checked(frame).ok()?;
+            match ethernet.ethertype() {
+                EthernetProtocol::Ipv4 | EthernetProtocol::Ipv6 => {
+                    EthernetFrame::<&[u8]>::header_len()
+                }
+                _ => return None,
+            }
+        }
+        Medium::Ip => 0,
+    };
+    let packet = &frame[l3..];
+    let (version, l4, end) = match packet.first()? >> 4 {
+        4 => {
+            let ip = Ipv4Packet::new_checked(packet).ok()?;
+            if ip.header_len() as usize != IPV4_HEADER_LEN
+                || ip.more_frags()
+                || ip.frag_offset() != 0
+                || ip.next_header() != IpProtocol::Tcp
+            {
+                return None;
+            }
+            (4, IPV4_HEADER_LEN, ip.total_len() as usize)
+        }
+        6 => {
+            let ip = Ipv6Packet::new_checked(packet).ok()?;
+            if ip.next_header() != IpProtocol::Tcp {
+                return None;
+            }
+            let end = IPV6_HEADER_LEN + ip.payload_len() as usize;
+            (6, IPV6_HEADER_LEN, end)
+        }
+        _ => return None,
+    };
+    let tcp = packet.get(l4..l4 + TCP_HEADER_LEN)?;
+    let hdr_len = l4 + (tcp[12] >> 4) as usize * 4;
+    let flags = tcp[13];
+    // padding behind the packet would end up inside the super-packet, and a
+    // checksum filled by someone else, e.g. a raw socket, cannot be left to the kernel
+    if end != packet.len()
+        || hdr_len < l4 + TCP_HEADER_LEN
+        || hdr_len >= end
+        || flags & !TCP_FLAG_PSH != TCP_FLAG_ACK
+        || tcp[16..18] != [0, 0]
+    {
+        return None;
+    }
+    Some(TcpSegment {
+        l3,
+        hdr_len: l3 + hdr_len,
+        version,
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        flags,
+    })
+}
+
+/// Writes the pseudo-header sum into the checksum of a TCP or UDP packet,
+/// returns where the kernel starts summing and where it stores the result.
+fn partial_checksum(frame: &mut [u8], medium: Medium) -> Option<(u16, u16)> {
+    let l3 = match medium {
+        Medium::Ethernet => {
+            let ethernet = EthernetFrame::new_checked(&*frame).ok()?;
+            match ethernet.ethertype() {
+                EthernetProtocol::Ipv4 | EthernetProtocol::Ipv6 => {
+                    EthernetFrame::<&[u8]>::header_len()
+                }
+                _ => return None,
+            }
+        }
+        Medium::Ip => 0,
+    };
+    let packet = &mut frame[l3..];
+
+    let (protocol, l4, sum) = match packet.first()? >> 4 {
+        4 => {
+            let ip = Ipv4Packet::new_checked(&*packet).ok()?;
+            // the transport header is in the first fragment only
+            if ip.more_frags() || ip.frag_offset() != 0 {
+                return None;
+            }
+            let l4 = ip.header_len() as usize;
+            let len = ip.total_len() as usize - l4;
+            let sum = pseudo_header(
+                &ip.src_addr().octets(),
+                &ip.dst_addr().octets(),
+                ip.next_header(),
+                len,
+            );
+            (ip.next_header(), l4, sum)
+        }
+        6 => {
+            let ip = Ipv6Packet::new_checked(&*packet).ok()?;
+            let (protocol, l4) = ipv6_upper_layer(packet)?;
+            let len = (ip.header_len() + ip.payload_len() as usize).checked_sub(l4)?;
+            let sum = pseudo_header(
+                &ip.src_addr().octets(),
+                &ip.dst_addr().octets(),
+                protocol,
+                len,
+            );
+            // the extension headers are rare enough to be summed here
+            if l4 != ip.header_len() {
+                software_checksum(&mut packet[l4..], protocol, sum);
+                return None;
+            }
+            (protocol, l4, sum)
+        }
+        _ => return None,
+    };
+    let offset = match protocol {
+        IpProtocol::Tcp => 16,
+        IpProtocol::Udp => 6,
+        _ => return None,
+    };
+
+    let field = packet.get_mut(l4 + offset..l4 + offset + 2)?;
+    // a checksum filled by someone else, e.g. a raw socket, is left alone
+    if field != [0, 0] {
+        return None;
+    }
+    field.copy_from_slice(&sum.to_be_bytes());
+    Some(((l3 + l4) as u16, offset as u16))
+}
+
+/// Finds the TCP or UDP header behind the extension headers of an IPv6 packet,
+/// returns its protocol and offset. A fragment has no checksum to fill.
+fn ipv6_upper_layer(packet: &[u8]) -> Option<(IpProtocol, usize)> {
+    let mut protocol = IpProtocol::from(*packet.get(6)?);
+    let mut offset = IPV6_HEADER_LEN;
+    loop {
+        match protocol {
+            IpProtocol::Tcp | IpProtocol::Udp => return Some((protocol, offset)),
+            IpProtocol::HopByHop | IpProtocol::Ipv6Route | IpProtocol::Ipv6Opts => {
+                let header = packet.get(offset..offset + 2)?;
+                protocol = IpProtocol::from(header[0]);
+                offset += (header[1] as usize + 1) * 8;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Fills the zeroed checksum of a TCP or UDP packet, `sum` being the sum of its
+/// pseudo header
+fn software_checksum(packet: &mut [u8], protocol: IpProtocol, sum: u16) {
+    let offset = match protocol {
+        IpProtocol::Tcp => 16,
+        IpProtocol::Udp => 6,
+        _ => return,
+    };
+    match packet.get(offset..offset + 2) {
+        Some([0, 0]) => {}
+        _ => return,
+    }
+    let mut checksum = !fold(sum as u32 + sum_words(packet));
+    // a zero UDP checksum means none was computed
+    if protocol == IpProtocol::Udp && checksum == 0 {
+        checksum = 0xffff;
+    }
+    packet[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// RFC 1071 sum of the pseudo header, without the final complement
+fn pseudo_header(src: &[u8], dst: &[u8], protocol: IpProtocol, len: usize) -> u16 {
+    let mut sum = sum_words(src) + sum_words(dst);
+    sum += u8::from(protocol) as u32;
+    sum += (len >> 16) as u32 + (len & 0xffff) as u32;
+    fold(sum)
+}
+
+/// Sum of the big endian 16 bit words of `data`, an odd byte is padded with zero
+fn sum_words(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32)
+        .fold(0, |sum, word| fold(sum + word) as u32)
+}
+
+/// Folds the carries of a one's complement sum back into 16 bits
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::phy::ChecksumCapabilities;
+    use smoltcp::wire::{
+        EthernetAddress, EthernetRepr, IpAddress, Ipv4Address, Ipv4Repr, Ipv6Address, Ipv6Repr,
+        TcpControl, TcpPacket, TcpRepr, TcpSeqNumber, UdpPacket,
+    };
+
+    const SRC4: Ipv4Address = Ipv4Address::new(192, 168, 77, 2);
+    const DST4: Ipv4Address = Ipv4Address::new(192, 168, 77, 1);
+    const SRC6: Ipv6Address = Ipv6Address::new(0xfd77, 0, 0, 0, 0, 0, 0, 2);
+    const DST6: Ipv6Address = Ipv6Address::new(0xfd77, 0, 0, 0, 0, 0, 0, 1);
+    const ETHERNET_LEN: usize = 14;
+
+    /// A TCP segment over Ethernet with its checksum left zeroed like smoltcp
+    /// does when the device computes it
+    fn tcp_frame(v6: bool, seq: u32, payload: &[u8], push: bool) -> Vec<u8> {
+        let tcp = TcpRepr {
+            src_port: 7,
+            dst_port: 40000,
+            control: if push {
+                TcpControl::Psh
+            } else {
+                TcpControl::None
+            },
+            seq_number: TcpSeqNumber(seq as i32),
+            ack_number: Some(TcpSeqNumber(1)),
+            window_len: 1024,
+            window_scale: None,
+            max_seg_size: None,
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            timestamp: None,
+            payload,
+        };
+        let ip_len = if v6 { IPV6_HEADER_LEN } else { IPV4_HEADER_LEN };
+        let mut frame = vec![0; ETHERNET_LEN + ip_len + tcp.buffer_len()];
+        EthernetRepr {
+            src_addr: EthernetAddress([2, 0, 0, 0, 0, 2]),
+            dst_addr: EthernetAddress([2, 0, 0, 0, 0, 1]),
+            ethertype: if v6 {
+                EthernetProtocol::Ipv6
+            } else {
+                EthernetProtocol::Ipv4
+            },
+        }
+        .emit(&mut EthernetFrame::new_unchecked(&mut frame[..]));
+        let (src, dst) = if v6 {
+            Ipv6Repr {
+                src_addr: SRC6,
+                dst_addr: DST6,
+                next_header: IpProtocol::Tcp,
+                payload_len: tcp.buffer_len(),
+                hop_limit: 64,
+            }
+            .emit(&mut Ipv6Packet::new_unchecked(&mut frame[ETHERNET_LEN..]));
+            (IpAddress::Ipv6(SRC6), IpAddress::Ipv6(DST6))
+        } else {
+            Ipv4Repr {
+                src_addr: SRC4,
+                dst_addr: DST4,
+                next_header: IpProtocol::Tcp,
+                payload_len: tcp.buffer_len(),
+                hop_limit: 64,
+            }
+            .emit(
+                &mut Ipv4Packet::new_unchecked(&mut frame[ETHERNET_LEN..]),
+                &ChecksumCapabilities::default(),
+            );
+            (IpAddress::Ipv4(SRC4), IpAddress::Ipv4(DST4))
+        };
+        tcp.emit(
+            &mut TcpPacket::new_unchecked(&mut frame[ETHERNET_LEN + ip_len..]),
+            &src,
+            &dst,
+            &ChecksumCapabilities::ignored(),
+        );
+        frame
+    }
+
+    /// Finishes a partial checksum the way the kernel does with `NEEDS_CSUM`
+    fn complete_checksum(header: &[u8], frame: &mut [u8]) {
+        assert_eq!(header[0], VIRTIO_NET_HDR_F_NEEDS_CSUM);
+        let start = u16::from_ne_bytes([header[6], header[7]]) as usize;
+        let offset = u16::from_ne_bytes([header[8], header[9]]) as usize;
+        let checksum = !fold(sum_words(&frame[start..]));
+        frame[start + offset..start + offset + 2].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    fn tcp_checksum_ok(frame: &[u8], v6: bool) -> bool {
+        if v6 {
+            let tcp = TcpPacket::new_checked(&frame[ETHERNET_LEN + IPV6_HEADER_LEN..]).unwrap();
+            tcp.verify_checksum(&IpAddress::Ipv6(SRC6), &IpAddress::Ipv6(DST6))
+        } else {
+            let tcp = TcpPacket::new_checked(&frame[ETHERNET_LEN + IPV4_HEADER_LEN..]).unwrap();
+            tcp.verify_checksum(&IpAddress::Ipv4(SRC4), &IpAddress::Ipv4(DST4))
+        }
+    }
+
+    #[test]
+    fn partial_checksum_is_finished_by_the_kernel() {
+        for v6 in [false, true] {
+            let mut frame = tcp_frame(v6, 1000, b"hello world", true);
+            let mut header = [0xff; VNET_HDR_LEN];
+            emit(&mut header, &mut frame, Medium::Ethernet, true);
+
+            let l4 = ETHERNET_LEN + if v6 { IPV6_HEADER_LEN } else { IPV4_HEADER_LEN };
+            assert_eq!(u16::from_ne_bytes([header[6], header[7]]) as usize, l4);
+            assert_eq!(u16::from_ne_bytes([header[8], header[9]]), 16);
+            assert!(!tcp_checksum_ok(&frame, v6));
+            complete_checksum(&header, &mut frame);
+            assert!(tcp_checksum_ok(&frame, v6));
+        }
+    }
+
+    #[test]
+    fn received_partial_checksum_is_finished() {
+        for v6 in [false, true] {
+            // the kernel hands over what `emit` leaves for it
+            let mut frame = tcp_frame(v6, 1000, b"odd payload", true);
+            let mut header = [0; VNET_HDR_LEN];
+            emit(&mut header, &mut frame, Medium::Ethernet, true);
+            assert!(!tcp_checksum_ok(&frame, v6));
+            parse(&header, &mut frame);
+            assert!(tcp_checksum_ok(&frame, v6));
+        }
+    }
+
+    #[test]
+    fn received_frame_without_needs_csum_is_left_alone() {
+        let mut frame = tcp_frame(false, 1000, b"hello", false);
+        let copy = frame.clone();
+        parse(&[0; VNET_HDR_LEN], &mut frame);
+        assert_eq!(frame, copy);
+
+        // a header pointing past the frame changes nothing either
+        let mut header = [0; VNET_HDR_LEN];
+        header[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+        header[6..8].copy_from_slice(&(frame.len() as u16 - 1).to_ne_bytes());
+        header[8..10].copy_from_slice(&16u16.to_ne_bytes());
+        parse(&header, &mut frame);
+        assert_eq!(frame, copy);
+    }
+
+    #[test]
+    fn emit_without_csum_clears_the_header() {
+        let mut frame = tcp_frame(false, 1000, b"hello", false);
+        let copy = frame.clone();
+        let mut header = [0xff; VNET_HDR_LEN];
+        emit(&mut header, &mut frame, Medium::Ethernet, false);
+        assert_eq!(header, [0; VNET_HDR_LEN]);
+        assert_eq!(frame, copy);
+    }
+
+    #[test]
+    fn filled_checksum_is_left_alone() {
+        let mut frame = tcp_frame(false, 1000, b"hello", false);
+        frame[ETHERNET_LEN + IPV4_HEADER_LEN + 16] = 0x12;
+        let copy = frame.clone();
+        let mut header = [0; VNET_HDR_LEN];
+        emit(&mut header, &mut frame, Medium::Ethernet, true);
+        assert_eq!(header[0], 0);
+        assert_eq!(frame, copy);
+    }
+
+    #[test]
+    fn ipv6_extension_headers_are_summed_in_software() {
+        // hop-by-hop options padded with PadN, then UDP with a zeroed checksum
+        let payload = b"odd payload";
+        let udp_len = 8 + payload.len();
+        let mut packet = vec![0; IPV6_HEADER_LEN + 8 + udp_len];
+        Ipv6Repr {
+            src_addr: SRC6,
+            dst_addr: DST6,
+            next_header: IpProtocol::HopByHop,
+            payload_len: 8 + udp_len,
+            hop_limit: 64,
+        }
+        .emit(&mut Ipv6Packet::new_unchecked(&mut packet[..]));
+        packet[IPV6_HEADER_LEN..IPV6_HEADER_LEN + 8].copy_from_slice(&[17, 0, 1, 4, 0, 0, 0, 0]);
+        let mut udp = UdpPacket::new_unchecked(&mut packet[IPV6_HEADER_LEN + 8..]);
+        udp.set_src_port(53);
+        udp.set_dst_port(40000);
+        udp.set_len(udp_len as u16);
+        udp.set_checksum(0);
+        udp.payload_mut().copy_from_slice(payload);
+
+        let mut header = [0; VNET_HDR_LEN];
+        emit(&mut header, &mut packet, Medium::Ip, true);
+        assert_eq!(header, [0; VNET_HDR_LEN]);
+        let udp = UdpPacket::new_checked(&packet[IPV6_HEADER_LEN + 8..]).unwrap();
+        assert_ne!(udp.checksum(), 0);
+        assert!(udp.verify_checksum(&IpAddress::Ipv6(SRC6), &IpAddress::Ipv6(DST6)));
+    }
+
+    #[test]
+    fn coalesces_a_burst_into_a_gso_packet() {
+        for v6 in [false, true] {
+            let mut gso = TcpCoalescer::new(Medium::Ethernet, TapOffload::all());
+            let mss = 100;
+            let payloads: Vec<Vec<u8>> = (0..4u8)
+                .map(|n| vec![n; if n == 3 { 40 } else { mss }])
+                .collect();
+            let mut seq = 5000;
+            for (n, payload) in payloads.iter().enumerate() {
+                assert!(gso.push(&tcp_frame(v6, seq, payload, n == 3)));
+                seq += payload.len() as u32;
+            }
+            // the short segment with PSH ended the burst
+            assert!(!gso.push(&tcp_frame(v6, seq, &[9; 100], false)));
+
+            let packet = gso.finish().unwrap().to_vec();
+            assert!(gso.is_empty());
+            let (header, frame) = packet.split_at(VNET_HDR_LEN);
+            let mut frame = frame.to_vec();
+            let (gso_type, l4) = if v6 {
+                (VIRTIO_NET_HDR_GSO_TCPV6, ETHERNET_LEN + IPV6_HEADER_LEN)
+            } else {
+                (VIRTIO_NET_HDR_GSO_TCPV4, ETHERNET_LEN + IPV4_HEADER_LEN)
+            };
+            assert_eq!(header[1], gso_type);
+            assert_eq!(u16::from_ne_bytes([header[2], header[3]]) as usize, l4 + 20);
+            assert_eq!(u16::from_ne_bytes([header[4], header[5]]) as usize, mss);
+            assert_eq!(frame.len(), l4 + 20 + 3 * mss + 40);
+            if v6 {
+                let ip = Ipv6Packet::new_checked(&frame[ETHERNET_LEN..]).unwrap();
+                assert_eq!(ip.payload_len() as usize, 20 + 3 * mss + 40);
+            } else {
+                let ip = Ipv4Packet::new_checked(&frame[ETHERNET_LEN..]).unwrap();
+                assert_eq!(ip.total_len() as usize, frame.len() - ETHERNET_LEN);
+                assert!(ip.verify_checksum());
+            }
+            let tcp = TcpPacket::new_checked(&frame[l4..]).unwrap();
+            assert_eq!(tcp.seq_number(), TcpSeqNumber(5000));
+            assert!(tcp.psh());
+            assert_eq!(tcp.payload(), payloads.concat());
+            complete_checksum(header, &mut frame);
+            assert!(tcp_checksum_ok(&frame, v6));
+        }
+    }
+
+    #[test]
+    fn breaks_the_burst_on_a_gap_or_another_flow() {
+        let mut gso = TcpCoalescer::new(Medium::Ethernet, TapOffload::CSUM | TapOffload::TSO4);
+        assert!(gso.push(&tcp_frame(false, 0, &[1; 100], false)));
+        assert!(!gso.push(&tcp_frame(false, 200, &[1; 100], false)));
+        let mut other = tcp_frame(false, 100, &[1; 100], false);
+        other[ETHERNET_LEN + IPV4_HEADER_LEN + 1] = 8;
+        assert!(!gso.push(&other));
+        assert!(!gso.push(&tcp_frame(false, 100, &[1; 101], false)));
+        // without TSO6 IPv6 segments are written as they are
+        assert!(!gso.push(&tcp_frame(true, 100, &[1; 100], false)));
+
+        // a single segment goes out without GSO, its checksum left to the kernel
+        let packet = gso.finish().unwrap().to_vec();
+        let (header, frame) = packet.split_at(VNET_HDR_LEN);
+        assert_eq!(header[1], 0);
+        let mut frame = frame.to_vec();
+        complete_checksum(header, &mut frame);
+        assert!(tcp_checksum_ok(&frame, false));
+        assert!(gso.finish().is_none());
+    }
+}
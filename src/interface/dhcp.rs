@@ -0,0 +1,483 @@
+//! DHCPv4 客户端，每个以太网卡至多一个，基于 smoltcp 的 dhcpv4 socket。
+//! 租到的地址通过 `update_ip_addrs` 加到网卡上，路由器成为默认网关，DNS 服务器记录在租约中。
+//! 续租 (T1) 和重新绑定 (T2) 由 smoltcp 发出请求，每次收到 ACK 都会刷新租约的时间，
+//! 租约到期后地址和默认路由被撤销，客户端重新开始寻找服务器
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::dhcpv4;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{DhcpRepr, IpAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+
+use super::route::{Route, ROUTES};
+use super::IfaceCommon;
+
+/// smoltcp 在服务器没有给出租期时使用的租期
+const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(120);
+/// 保存最近一个 DHCP 报文的缓冲区大小，要装得下以太网上的整个报文
+const PACKET_BUFFER_LEN: usize = 1500;
+
+/// DHCP 客户端的配置
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DhcpConfig {
+    /// 租期上限，服务器给出的租期更长时按上限续租，`None` 表示不限
+    pub max_lease_duration: Option<Duration>,
+    /// 默认路由的度量值
+    pub route_metric: u32,
+}
+
+/// 租约所处的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhcpState {
+    /// 租约有效，还未到续租时间
+    Bound,
+    /// 过了 T1，向分配租约的服务器续租
+    Renewing,
+    /// 过了 T2，向所有服务器广播请求
+    Rebinding,
+    /// 租约已到期，等待 smoltcp 撤销
+    Expired,
+}
+
+/// 从 DHCP 服务器租到的配置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpLease {
+    /// 地址和子网掩码
+    pub address: Ipv4Cidr,
+    /// 默认网关
+    pub router: Option<Ipv4Address>,
+    pub dns_servers: Vec<Ipv4Address>,
+    /// 分配租约的服务器
+    pub server: Ipv4Address,
+    /// 最近一次收到 ACK 的时间
+    pub acquired_at: Instant,
+    /// T1
+    pub renew_at: Instant,
+    /// T2
+    pub rebind_at: Instant,
+    pub expires_at: Instant,
+}
+
+impl DhcpLease {
+    /// # `state`
+    /// 租约在 `now` 时所处的阶段
+    pub fn state(&self, now: Instant) -> DhcpState {
+        if now >= self.expires_at {
+            DhcpState::Expired
+        } else if now >= self.rebind_at {
+            DhcpState::Rebinding
+        } else if now >= self.renew_at {
+            DhcpState::Renewing
+        } else {
+            DhcpState::Bound
+        }
+    }
+
+    /// 按 smoltcp 的方式 (RFC 2131) 由 ACK 算出租约的时间，
+    /// smoltcp 自己的计时器不对外公开
+    fn new(config: &dhcpv4::Config, max_lease_duration: Option<Duration>, now: Instant) -> Self {
+        let repr = config
+            .packet
+            .as_ref()
+            .and_then(|packet| DhcpRepr::parse(packet).ok());
+        let seconds = |seconds: u32| Duration::from_secs(seconds as u64);
+        let mut lease_duration = repr
+            .as_ref()
+            .and_then(|repr| repr.lease_duration)
+            .map_or(DEFAULT_LEASE_DURATION, seconds);
+        if let Some(max_lease_duration) = max_lease_duration {
+            lease_duration = lease_duration.min(max_lease_duration);
+        }
+        let renew = repr.as_ref().and_then(|repr| repr.renew_duration);
+        let rebind = repr.as_ref().and_then(|repr| repr.rebind_duration);
+        let (renew_duration, rebind_duration) = match (renew.map(seconds), rebind.map(seconds)) {
+            (Some(renew), Some(rebind)) => (renew, rebind),
+            (None, None) => (lease_duration / 2, lease_duration * 7 / 8),
+            (Some(renew), None) => (renew, renew + (lease_duration - renew) * 3 / 4),
+            (None, Some(rebind)) => ((lease_duration / 2).min(rebind), rebind),
+        };
+        DhcpLease {
+            address: config.address,
+            router: config.router,
+            dns_servers: config.dns_servers.iter().copied().collect(),
+            server: config.server.identifier,
+            acquired_at: now,
+            renew_at: now + renew_duration,
+            rebind_at: now + rebind_duration,
+            expires_at: now + lease_duration,
+        }
+    }
+}
+
+/// 网卡上运行的 DHCP 客户端
+#[derive(Debug)]
+pub(super) struct DhcpClient {
+    /// smoltcp 的 dhcpv4 socket，在网卡的套接字集中
+    pub(super) handle: SocketHandle,
+    config: DhcpConfig,
+    lease: Option<DhcpLease>,
+    /// 客户端安装的默认路由
+    route: Option<Route>,
+}
+
+impl DhcpClient {
+    /// 创建 smoltcp 的 dhcpv4 socket，`spare` 是之前停止的客户端留下的 socket。
+    /// smoltcp 要求 `'static` 的报文缓冲区且不再交还，所以只在网卡第一次启动客户端时
+    /// 泄漏一块 `PACKET_BUFFER_LEN` 字节的内存，此后重用带着它的 socket
+    pub(super) fn new(
+        config: DhcpConfig,
+        sockets: &mut smoltcp::iface::SocketSet<'static>,
+        spare: Option<dhcpv4::Socket<'static>>,
+    ) -> Self {
+        let mut socket = match spare {
+            Some(mut socket) => {
+                socket.reset();
+                socket
+            }
+            None => {
+                let mut socket = dhcpv4::Socket::new();
+                // with a buffer every ACK is reported, which keeps the lease times up to date
+                socket.set_receive_packet_buffer(Box::leak(
+                    vec![0; PACKET_BUFFER_LEN].into_boxed_slice(),
+                ));
+                socket
+            }
+        };
+        socket.set_max_lease_duration(config.max_lease_duration);
+        DhcpClient {
+            handle: sockets.add(socket),
+            config,
+            lease: None,
+            route: None,
+        }
+    }
+
+    pub(super) fn lease(&self) -> Option<&DhcpLease> {
+        self.lease.as_ref()
+    }
+
+    /// 取出 smoltcp 的 dhcpv4 socket 上的事件并应用到网卡上，在每次轮询之后调用
+    pub(super) fn poll(&mut self, common: &IfaceCommon) {
+        // the event borrows the socket, turn it into a lease before configuring the iface
+        let lease = match common
+            .sockets
+            .lock()
+            .get_mut::<dhcpv4::Socket>(self.handle)
+            .poll()
+        {
+            None => return,
+            Some(dhcpv4::Event::Configured(config)) => Some(DhcpLease::new(
+                &config,
+                self.config.max_lease_duration,
+                Instant::now(),
+            )),
+            Some(dhcpv4::Event::Deconfigured) => None,
+        };
+        match lease {
+            Some(lease) => self.configure(common, lease),
+            None => {
+                if let Some(lease) = &self.lease {
+                    log::info!(
+                        "iface {}: DHCP lease of {} lost",
+                        common.name(),
+                        lease.address
+                    );
+                }
+                self.deconfigure(common);
+            }
+        }
+    }
+
+    fn configure(&mut self, common: &IfaceCommon, lease: DhcpLease) {
+        let old = self.lease.replace(lease.clone());
+        if old.as_ref().is_some_and(|old| old.address == lease.address) {
+            log::debug!(
+                "iface {}: DHCP lease of {} renewed until {}",
+                common.name(),
+                lease.address,
+                lease.expires_at
+            );
+        } else {
+            log::info!(
+                "iface {}: DHCP lease of {} from {}",
+                common.name(),
+                lease.address,
+                lease.server
+            );
+            let old_address = old.as_ref().map(|old| IpCidr::Ipv4(old.address));
+            update_address(common, old_address, Some(IpCidr::Ipv4(lease.address)));
+        }
+
+        let route = lease.router.and_then(|router| {
+            let ifindex = common.iface_id();
+            // an unregistered iface has no routes
+            (ifindex != 0).then(|| {
+                Route::default_via(IpAddress::Ipv4(router), ifindex, self.config.route_metric)
+            })
+        });
+        if route != self.route {
+            self.remove_route();
+            if let Some(route) = route {
+                match ROUTES.add(route) {
+                    Ok(()) => self.route = Some(route),
+                    Err(err) => log::warn!(
+                        "iface {}: DHCP default route via {:?} not installed: {:?}",
+                        common.name(),
+                        route.gateway,
+                        err
+                    ),
+                }
+            }
+        }
+    }
+
+    /// 撤销租约的地址和默认路由
+    pub(super) fn deconfigure(&mut self, common: &IfaceCommon) -> Option<DhcpLease> {
+        self.remove_route();
+        let lease = self.lease.take()?;
+        update_address(common, Some(IpCidr::Ipv4(lease.address)), None);
+        Some(lease)
+    }
+
+    fn remove_route(&mut self) {
+        if let Some(route) = self.route.take() {
+            // the route is gone already if the iface was unregistered
+            let _ = ROUTES.delete(&route);
+        }
+    }
+}
+
+/// 用 `new` 替换网卡上的 `old` 地址，保留其它地址
+fn update_address(common: &IfaceCommon, old: Option<IpCidr>, new: Option<IpCidr>) {
+    let mut ip_addrs = common.ip_addrs();
+    ip_addrs.retain(|ip_addr| Some(*ip_addr) != old && Some(*ip_addr) != new);
+    ip_addrs.extend(new);
+    if let Err(err) = common.update_ip_addrs(&ip_addrs) {
+        log::warn!(
+            "iface {}: DHCP address {:?} not applied: {:?}",
+            common.name(),
+            new,
+            err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::veth::{veth_pair, VETH_DEFAULT_MTU};
+    use crate::interface::registry::NET_DEVICES;
+    use crate::interface::veth::VethIface;
+    use crate::interface::Iface;
+    use alloc::sync::Arc;
+    use smoltcp::wire::{DhcpMessageType, DhcpOption, DhcpPacket, EthernetAddress};
+
+    const SERVER: Ipv4Address = Ipv4Address::new(10, 62, 0, 1);
+
+    fn cidr(host: u8) -> Ipv4Cidr {
+        Ipv4Cidr::new(Ipv4Address::new(10, 62, 0, host), 24)
+    }
+
+    /// An ACK with the given lease, renew and rebind times in seconds
+    fn ack(lease: Option<u32>, renew: Option<u32>, rebind: Option<u32>) -> Vec<u8> {
+        // smoltcp does not emit the renewal and rebinding times itself
+        let (renew, rebind) = (renew.map(u32::to_be_bytes), rebind.map(u32::to_be_bytes));
+        let times: Vec<DhcpOption> = [(58, &renew), (59, &rebind)]
+            .into_iter()
+            .filter_map(|(kind, data)| {
+                Some(DhcpOption {
+                    kind,
+                    data: data.as_ref()?,
+                })
+            })
+            .collect();
+        let repr = DhcpRepr {
+            message_type: DhcpMessageType::Ack,
+            transaction_id: 1,
+            secs: 0,
+            client_hardware_address: EthernetAddress([2, 0, 0, 0, 0, 2]),
+            client_ip: Ipv4Address::UNSPECIFIED,
+            your_ip: cidr(2).address(),
+            server_ip: SERVER,
+            router: Some(SERVER),
+            subnet_mask: Some(Ipv4Address::new(255, 255, 255, 0)),
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+            broadcast: false,
+            requested_ip: None,
+            client_identifier: None,
+            server_identifier: Some(SERVER),
+            parameter_request_list: None,
+            dns_servers: None,
+            max_size: None,
+            lease_duration: lease,
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &times,
+        };
+        let mut buffer = vec![0; repr.buffer_len()];
+        repr.emit(&mut DhcpPacket::new_unchecked(&mut buffer[..]))
+            .unwrap();
+        buffer
+    }
+
+    fn config(packet: Option<&[u8]>) -> dhcpv4::Config<'_> {
+        dhcpv4::Config {
+            server: dhcpv4::ServerInfo {
+                address: SERVER,
+                identifier: SERVER,
+            },
+            address: cidr(2),
+            router: Some(SERVER),
+            dns_servers: Default::default(),
+            packet: packet.map(DhcpPacket::new_unchecked),
+        }
+    }
+
+    /// T1 and T2 after `now` for an ACK
+    fn times(packet: Option<&[u8]>, max: Option<Duration>) -> (u64, u64, u64) {
+        let now = Instant::from_secs(100);
+        let lease = DhcpLease::new(&config(packet), max, now);
+        assert_eq!(lease.acquired_at, now);
+        let secs = |at: Instant| (at - now).secs();
+        (
+            secs(lease.renew_at),
+            secs(lease.rebind_at),
+            secs(lease.expires_at),
+        )
+    }
+
+    fn lease(host: u8, router: Option<Ipv4Address>, now: Instant) -> DhcpLease {
+        DhcpLease {
+            address: cidr(host),
+            router,
+            dns_servers: vec![SERVER],
+            server: SERVER,
+            acquired_at: now,
+            renew_at: now + Duration::from_secs(50),
+            rebind_at: now + Duration::from_secs(80),
+            expires_at: now + Duration::from_secs(100),
+        }
+    }
+
+    fn iface() -> Arc<dyn Iface> {
+        let (device, _) = veth_pair(VETH_DEFAULT_MTU).unwrap();
+        Arc::new(VethIface::new(device))
+    }
+
+    #[test]
+    fn lease_times_follow_rfc_2131() {
+        // T1 at half the lease, T2 at seven eighths
+        assert_eq!(
+            times(Some(&ack(Some(1000), None, None)), None),
+            (500, 875, 1000)
+        );
+        assert_eq!(
+            times(Some(&ack(Some(1000), Some(100), Some(200))), None),
+            (100, 200, 1000)
+        );
+        // T2 three quarters of the way from T1 to the expiry
+        assert_eq!(
+            times(Some(&ack(Some(1000), Some(200), None)), None),
+            (200, 800, 1000)
+        );
+        // T1 never after T2
+        assert_eq!(
+            times(Some(&ack(Some(1000), None, Some(400))), None),
+            (400, 400, 1000)
+        );
+        // without a lease time smoltcp assumes two minutes
+        assert_eq!(times(Some(&ack(None, None, None)), None), (60, 105, 120));
+        assert_eq!(times(None, None), (60, 105, 120));
+        // the cap shortens the lease before T1 and T2 are derived
+        assert_eq!(
+            times(
+                Some(&ack(Some(1000), None, None)),
+                Some(Duration::from_secs(600))
+            ),
+            (300, 525, 600)
+        );
+    }
+
+    #[test]
+    fn lease_state_follows_the_clock() {
+        let now = Instant::from_secs(10);
+        let lease = lease(2, None, now);
+        let at = |secs| lease.state(now + Duration::from_secs(secs));
+        assert_eq!(at(0), DhcpState::Bound);
+        assert_eq!(at(50), DhcpState::Renewing);
+        assert_eq!(at(80), DhcpState::Rebinding);
+        assert_eq!(at(100), DhcpState::Expired);
+    }
+
+    #[test]
+    fn update_address_keeps_the_other_addresses() {
+        let iface = iface();
+        let common = iface.common();
+        let others = [
+            IpCidr::new(IpAddress::v4(10, 63, 0, 1), 24),
+            IpCidr::new(IpAddress::v6(0xfd62, 0, 0, 0, 0, 0, 0, 1), 64),
+        ];
+        common.update_ip_addrs(&others).unwrap();
+        let (first, second) = (IpCidr::Ipv4(cidr(2)), IpCidr::Ipv4(cidr(3)));
+
+        update_address(common, None, Some(first));
+        assert_eq!(common.ip_addrs(), [others[0], others[1], first]);
+        update_address(common, Some(first), Some(second));
+        assert_eq!(common.ip_addrs(), [others[0], others[1], second]);
+        // the same address twice is not added again
+        update_address(common, None, Some(second));
+        assert_eq!(common.ip_addrs(), [others[0], others[1], second]);
+        update_address(common, Some(second), None);
+        assert_eq!(common.ip_addrs(), others);
+    }
+
+    #[test]
+    fn leases_configure_and_deconfigure_the_iface() {
+        let iface = iface();
+        let common = iface.common();
+        let other = IpCidr::new(IpAddress::v4(10, 62, 9, 1), 24);
+        common.update_ip_addrs(&[other]).unwrap();
+        let ifindex = NET_DEVICES.register(iface.clone()).unwrap();
+        let routes = || {
+            ROUTES
+                .routes()
+                .into_iter()
+                .filter(|route| route.ifindex == ifindex)
+                .collect::<Vec<_>>()
+        };
+        let config = DhcpConfig {
+            max_lease_duration: None,
+            route_metric: 7,
+        };
+        let mut client = DhcpClient::new(config, &mut common.sockets.lock(), None);
+        let now = Instant::from_secs(10);
+        let address = IpCidr::Ipv4(cidr(2));
+        let via = |router: Ipv4Address| Route::default_via(IpAddress::Ipv4(router), ifindex, 7);
+
+        client.configure(common, lease(2, Some(SERVER), now));
+        assert_eq!(common.ip_addrs(), [other, address]);
+        assert_eq!(routes(), [via(SERVER)]);
+
+        // a renewal only moves the times
+        let later = now + Duration::from_secs(50);
+        client.configure(common, lease(2, Some(SERVER), later));
+        assert_eq!(client.lease().unwrap().acquired_at, later);
+        assert_eq!(common.ip_addrs(), [other, address]);
+        assert_eq!(routes(), [via(SERVER)]);
+
+        // another address and router replace the old ones
+        let router = Ipv4Address::new(10, 62, 0, 254);
+        client.configure(common, lease(3, Some(router), later));
+        assert_eq!(common.ip_addrs(), [other, IpCidr::Ipv4(cidr(3))]);
+        assert_eq!(routes(), [via(router)]);
+        client.configure(common, lease(3, None, later));
+        assert_eq!(routes(), []);
+        client.configure(common, lease(3, Some(router), later));
+
+        let lost = client.deconfigure(common).unwrap();
+        assert_eq!(lost.address, cidr(3));
+        assert_eq!(common.ip_addrs(), [other]);
+        assert_eq!(routes(), []);
+        assert!(client.lease().is_none());
+        assert!(client.deconfigure(common).is_none());
+        NET_DEVICES.unregister(ifindex).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use std::ops::DerefMut;
+
+use smoltcp::{
+    iface::{Config, Interface},
+    phy::Medium,
+    wire::{HardwareAddress, IpAddress, IpCidr},
+};
+use spin::Mutex;
+
+use crate::driver::loopback::LoopbackDevice;
+
+use super::{Iface, IfaceCommon};
+
+/// Interface index of the loopback interface, the first one registered.
+pub const LOOPBACK_IFACE_ID: usize = 1;
+
+/// Upper bound of poll rounds in one `poll` call, so that two sockets
+/// ping-ponging through the loopback cannot starve the caller.
+const MAX_POLL_ROUNDS: usize = 64;
+
+#[derive(Debug)]
+pub struct LoopbackIface {
+    pub inner: Mutex<LoopbackDevice>,
+    pub common: IfaceCommon,
+}
+
+impl LoopbackIface {
+    pub fn new() -> Self {
+        let mut device = LoopbackDevice::new();
+        let mut iface_config = Config::new(HardwareAddress::Ip);
+        iface_config.random_seed = std::random::random(..);
+
+        let mut iface = Interface::new(iface_config, &mut device, smoltcp::time::Instant::now());
+        iface.update_ip_addrs(|addrs| {
+            addrs
+                .push(IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8))
+                .expect("Push ipCidr failed: full");
+            addrs
+                .push(IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1), 128))
+                .expect("Push ipCidr failed: full");
+        });
+
+        let common = IfaceCommon::new("lo", Medium::Ip, iface);
+        LoopbackIface {
+            inner: Mutex::new(device),
+            common,
+        }
+    }
+}
+
+impl Default for LoopbackIface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iface for LoopbackIface {
+    fn common(&self) -> &IfaceCommon {
+        &self.common
+    }
+
+    fn mac(&self) -> smoltcp::wire::EthernetAddress {
+        smoltcp::wire::EthernetAddress([0, 0, 0, 0, 0, 0])
+    }
+
+    fn mtu(&self) -> usize {
+        use smoltcp::phy::Device;
+        self.inner.lock().capabilities().ip_mtu()
+    }
+
+    fn poll(&self) {
+        let mut guard = self.inner.lock();
+        // Frames sent during a poll are only received in the next one, keep
+        // polling until the queue drains so that a request and its reply are
+        // handled within a single call.
+        for _ in 0..MAX_POLL_ROUNDS {
+            self.common.poll(guard.deref_mut());
+            if guard.is_empty() {
+                break;
+            }
+        }
+    }
+}
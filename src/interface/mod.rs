@@ -2,14 +2,29 @@ use linux_errnos::Errno;
 use spin::Mutex;
 use spin::RwLock;
 use std::any::Any;
+use std::collections::VecDeque;
 use std::fmt;
 use std::fmt::Debug;
 use std::sync::Arc;
 
-use crate::socket::inet::common::PortManager;
+use crate::driver::capture::Capture;
+use crate::driver::fault::{FaultDevice, FaultInjector, FaultStats};
+use crate::driver::neighbor::{Neighbor, NeighborDevice, NeighborTable};
+use crate::driver::stats::{StatsDevice, TrafficCounters};
+use crate::driver::tapped::{Direction, TappedDevice};
+use crate::driver::IoStatus;
+use crate::socket::inet::common::{PortFamily, PortManager, Types};
 use crate::socket::inet::InetSocket;
+use crate::socket::packet::PacketTap;
+use dhcp::{DhcpClient, DhcpConfig, DhcpLease};
 
+pub mod dhcp;
+pub mod loopback;
+pub mod registry;
+pub mod route;
 pub mod tap;
+pub mod tun;
+pub mod veth;
 
 pub trait Iface: Sync + Send + Debug + Any {
     /// # `common`
@@ -20,15 +35,26 @@ pub trait Iface: Sync + Send + Debug + Any {
     /// 获取网卡的MAC地址
     fn mac(&self) -> smoltcp::wire::EthernetAddress;
 
-    // /// # `name`
-    // /// 获取网卡名
-    // fn iface_name(&self) -> String;
+    /// # `set_mac`
+    /// 运行时修改网卡的MAC地址，并同步到 smoltcp 的 `Interface`
+    /// ## 返回值
+    /// - 没有链路层的网卡返回 `Err(Errno::EOPNOTSUPP)`
+    fn set_mac(&self, mac: smoltcp::wire::EthernetAddress) -> Result<(), Errno> {
+        let _ = mac;
+        Err(Errno::EOPNOTSUPP)
+    }
 
-    // /// # `nic_id`
-    // /// 获取网卡id
-    // fn nic_id(&self) -> usize {
-    //     self.common().iface_id
-    // }
+    /// # `iface_name`
+    /// 获取网卡名，注册前可能是含 `%d` 的模板
+    fn iface_name(&self) -> String {
+        self.common().name()
+    }
+
+    /// # `nic_id`
+    /// 获取网卡的 ifindex，未注册的网卡为 0
+    fn nic_id(&self) -> usize {
+        self.common().iface_id()
+    }
 
     /// # `poll`
     /// 用于轮询接口的状态。
@@ -40,15 +66,40 @@ pub trait Iface: Sync + Send + Debug + Any {
     fn poll(&self);
 
     /// # `update_ip_addrs`
-    /// 用于更新接口的 IP 地址
+    /// 用于更新接口的 IP 地址，替换网卡原有的全部地址
     /// ## 参数
     /// - `ip_addrs` ：一个包含 `smoltcp::wire::IpCidr` 的切片，表示要设置的 IP 地址和子网掩码
     /// ## 返回值
-    /// - 如果 `ip_addrs` 的长度不为 1，返回 `Err(Errno::EINVAL)`，表示输入参数无效
+    /// - 如果地址不是单播地址或有重复，返回 `Err(Errno::EINVAL)`，表示输入参数无效
+    /// - 如果地址数超过 `IFACE_MAX_ADDR_COUNT`，返回 `Err(Errno::ENOSPC)`
     fn update_ip_addrs(&self, ip_addrs: &[smoltcp::wire::IpCidr]) -> Result<(), Errno> {
         self.common().update_ip_addrs(ip_addrs)
     }
 
+    /// # `ip_addrs`
+    /// 获取网卡的全部 IP 地址，IPv4 和 IPv6 地址按添加顺序排列
+    fn ip_addrs(&self) -> Vec<smoltcp::wire::IpCidr> {
+        self.common().ip_addrs()
+    }
+
+    /// # `add_ip_addr`
+    /// 为网卡添加一个 IP 地址
+    /// ## 返回值
+    /// - 如果地址不是单播地址，返回 `Err(Errno::EINVAL)`
+    /// - 如果网卡已有该地址，返回 `Err(Errno::EEXIST)`
+    /// - 如果地址数已达上限，返回 `Err(Errno::ENOSPC)`
+    fn add_ip_addr(&self, ip_addr: smoltcp::wire::IpCidr) -> Result<(), Errno> {
+        self.common().add_ip_addr(ip_addr)
+    }
+
+    /// # `remove_ip_addr`
+    /// 删除网卡的一个 IP 地址，已绑定到该地址的套接字不会被关闭
+    /// ## 返回值
+    /// - 如果网卡没有该地址，返回 `Err(Errno::EADDRNOTAVAIL)`
+    fn remove_ip_addr(&self, ip_addr: smoltcp::wire::IpCidr) -> Result<(), Errno> {
+        self.common().remove_ip_addr(ip_addr)
+    }
+
     /// @brief 获取smoltcp的网卡接口类型
     #[inline(always)]
     fn smol_iface(&self) -> &Mutex<smoltcp::iface::Interface> {
@@ -68,23 +119,244 @@ pub trait Iface: Sync + Send + Debug + Any {
         &self.common().port_manager
     }
 
+    /// # `start_capture`
+    /// 开始抓取网卡收发的帧，替换正在进行的抓包
+    fn start_capture(&self, capture: Capture) {
+        self.common().start_capture(capture)
+    }
+
+    /// # `stop_capture`
+    /// 停止抓包
+    /// ## 返回值
+    /// - 正在进行的抓包，文件中缓冲的记录已写出，环形缓冲区的内容可以通过 `Capture::dump` 取出
+    fn stop_capture(&self) -> Option<Capture> {
+        self.common().stop_capture()
+    }
+
+    /// # `dump_capture`
+    /// 不停止抓包，取出环形缓冲区抓包的内容
+    fn dump_capture(&self) -> Option<Vec<u8>> {
+        self.common().dump_capture()
+    }
+
+    /// # `inject_faults`
+    /// 在网卡的收发路径上注入丢包、延迟、重复、损坏和乱序，替换正在进行的注入
+    fn inject_faults(&self, faults: FaultInjector) {
+        self.common().inject_faults(faults)
+    }
+
+    /// # `clear_faults`
+    /// 停止故障注入，尚未送达的延迟帧会被丢弃
+    /// ## 返回值
+    /// - 正在进行的故障注入，统计可以通过 `FaultInjector::stats` 取出
+    fn clear_faults(&self) -> Option<FaultInjector> {
+        self.common().clear_faults()
+    }
+
+    /// # `fault_stats`
+    /// 获取故障注入至今的统计
+    fn fault_stats(&self) -> Option<FaultStats> {
+        self.common().fault_stats()
+    }
+
+    /// # `neighbors`
+    /// 列出网卡的邻居表 (ARP 和 NDP)，静态表项在前，还未解析的地址在最后
+    fn neighbors(&self) -> Vec<Neighbor> {
+        self.common().neighbors()
+    }
+
+    /// # `flush_neighbors`
+    /// 清空网卡学到的邻居，静态表项保留
+    fn flush_neighbors(&self) {
+        self.common().flush_neighbors()
+    }
+
+    /// # `add_static_neighbor`
+    /// 添加一个永久的邻居表项，之后发往 `ip` 的帧都使用 `mac`，链路上与之矛盾的 ARP 和 NDP 会被丢弃
+    /// ## 返回值
+    /// - 如果 `ip` 或 `mac` 不是单播地址，返回 `Err(Errno::EINVAL)`
+    /// - 如果 `ip` 已有静态表项，返回 `Err(Errno::EEXIST)`
+    /// - 没有链路层的网卡返回 `Err(Errno::EOPNOTSUPP)`
+    fn add_static_neighbor(
+        &self,
+        ip: smoltcp::wire::IpAddress,
+        mac: smoltcp::wire::EthernetAddress,
+    ) -> Result<(), Errno> {
+        let _ = (ip, mac);
+        Err(Errno::EOPNOTSUPP)
+    }
+
+    /// # `remove_static_neighbor`
+    /// 删除一个静态邻居表项，smoltcp 不能只删除一个缓存项，学到的邻居也会被清空
+    /// ## 返回值
+    /// - 如果 `ip` 没有静态表项，返回 `Err(Errno::ENOENT)`
+    fn remove_static_neighbor(&self, ip: smoltcp::wire::IpAddress) -> Result<(), Errno> {
+        self.common().remove_static_neighbor(ip)
+    }
+
+    /// # `start_dhcp`
+    /// 在网卡上启动 DHCPv4 客户端，租到的地址、默认路由和 DNS 服务器会自动配置
+    /// ## 返回值
+    /// - 没有链路层的网卡返回 `Err(Errno::EOPNOTSUPP)`
+    /// - 客户端已在运行时返回 `Err(Errno::EALREADY)`
+    /// - DHCP 客户端端口已被 UDP 套接字占用时返回 `Err(Errno::EADDRINUSE)`
+    fn start_dhcp(&self, config: DhcpConfig) -> Result<(), Errno> {
+        self.common().start_dhcp(config)
+    }
+
+    /// # `stop_dhcp`
+    /// 停止 DHCPv4 客户端，撤销租到的地址和默认路由
+    /// ## 返回值
+    /// - 客户端停止前持有的租约
+    fn stop_dhcp(&self) -> Option<DhcpLease> {
+        self.common().stop_dhcp()
+    }
+
+    /// # `dhcp_lease`
+    /// 获取 DHCPv4 客户端当前的租约
+    fn dhcp_lease(&self) -> Option<DhcpLease> {
+        self.common().dhcp_lease()
+    }
+
+    /// # `poll_at`
+    /// 下次需要轮询网卡的时间，用于驱动协议栈的定时器和延迟帧
+    fn poll_at(&self) -> Option<smoltcp::time::Instant> {
+        self.common().poll_at()
+    }
+
+    /// # `stats`
+    /// 获取网卡的统计
+    fn stats(&self) -> IfaceStats {
+        self.common().stats()
+    }
+
+    /// # `link_error`
+    /// 获取使网卡停用的设备错误
+    /// ## 返回值
+    /// - 网卡正常工作时返回 `None`，否则返回套接字应当看到的错误，
+    ///   `Errno::EIO`、`Errno::ENETDOWN` 或 `Errno::ENODEV`
+    fn link_error(&self) -> Option<Errno> {
+        self.common().link_error()
+    }
+
+    /// # `is_up`
+    /// 网卡是否可用，网卡被停用、下层链路不通或设备读写出错时不可用
+    fn is_up(&self) -> bool {
+        self.link_error().is_none()
+    }
+
+    /// # `set_up`
+    /// 启用或停用网卡，即管理状态，停用的网卡不再收发
+    fn set_up(&self, up: bool) -> Result<(), Errno> {
+        self.common().set_admin_up(up);
+        Ok(())
+    }
+
+    /// # `mtu`
+    /// 获取网卡的 IP MTU
+    fn mtu(&self) -> usize;
+
+    /// # `set_mtu`
+    /// 运行时修改网卡的 IP MTU，新的 MTU 会同步到 smoltcp 的 `Interface`
+    /// ## 返回值
+    /// - 不支持修改 MTU 的网卡返回 `Err(Errno::EOPNOTSUPP)`
+    fn set_mtu(&self, mtu: usize) -> Result<(), Errno> {
+        let _ = mtu;
+        Err(Errno::EOPNOTSUPP)
+    }
+
     /// Get the raw file descriptor if this interface has one
     /// Returns None if this interface doesn't have a file descriptor
     fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
         None
     }
 
+    /// # `queue_fds`
+    /// 获取网卡每个队列的文件描述符，下标即队列号
+    fn queue_fds(&self) -> Vec<std::os::unix::io::RawFd> {
+        self.raw_fd().into_iter().collect()
+    }
+
+    /// # `poll_queue`
+    /// 只轮询网卡的一个队列，收到的帧来自这个队列，要发送的帧也从这个队列发出
+    /// ## 参数
+    /// - `queue` ：队列号，`queue_fds` 的下标
+    fn poll_queue(&self, queue: usize) {
+        let _ = queue;
+        self.poll()
+    }
+
     // fn addr_assign_type(&self) -> u8;
 
     // fn net_device_type(&self) -> u16;
 
-    // fn net_state(&self) -> NetDeivceState;
+    /// # `net_state`
+    /// 获取网卡的链路状态
+    fn net_state(&self) -> NetDeviceState {
+        self.common().net_state()
+    }
 
-    // fn set_net_state(&self, state: NetDeivceState);
+    /// # `set_net_state`
+    /// 设置网卡的链路状态，操作状态随之更新
+    fn set_net_state(&self, state: NetDeviceState) {
+        self.common().set_net_state(state)
+    }
 
-    // fn operstate(&self) -> Operstate;
+    /// # `operstate`
+    /// 获取网卡的操作状态 (RFC 2863)
+    fn operstate(&self) -> Operstate {
+        self.common().operstate()
+    }
 
-    // fn set_operstate(&self, state: Operstate);
+    /// # `set_operstate`
+    /// 直接设置网卡的操作状态，链路状态下次变化时会重新计算
+    fn set_operstate(&self, state: Operstate) {
+        self.common().set_operstate(state)
+    }
+}
+
+bitflags::bitflags! {
+    /// 网络设备的链路状态，参考 Linux 的 `__LINK_STATE_*`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct NetDeviceState: u16 {
+        /// 网卡已被启用
+        const __LINK_STATE_START = 1 << 0;
+        /// 网卡存在于系统中
+        const __LINK_STATE_PRESENT = 1 << 1;
+        /// 下层链路不通
+        const __LINK_STATE_NOCARRIER = 1 << 2;
+        const __LINK_STATE_LINKWATCH_PENDING = 1 << 3;
+        const __LINK_STATE_DORMANT = 1 << 4;
+    }
+}
+
+/// 网络设备的操作状态，参考 RFC 2863 和 Linux 的 `IF_OPER_*`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum Operstate {
+    IF_OPER_UNKNOWN = 0,
+    IF_OPER_NOTPRESENT = 1,
+    IF_OPER_DOWN = 2,
+    IF_OPER_LOWERLAYERDOWN = 3,
+    IF_OPER_TESTING = 4,
+    IF_OPER_DORMANT = 5,
+    IF_OPER_UP = 6,
+}
+
+/// 检查 `set_mtu` 的参数，IPv4 要求链路 MTU 至少 68 字节
+pub fn check_mtu(mtu: usize) -> Result<(), Errno> {
+    if !(68..=u16::MAX as usize).contains(&mtu) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(())
+}
+
+/// 网卡只接受单播地址，smoltcp 遇到其它地址会 panic
+fn check_ip_addr(ip_addr: &smoltcp::wire::IpCidr) -> Result<(), Errno> {
+    if !ip_addr.address().is_unicast() {
+        return Err(Errno::EINVAL);
+    }
+    Ok(())
 }
 
 /// 网络设备的公共数据
@@ -94,10 +366,10 @@ pub struct NetDeviceCommonData {
     pub addr_assign_type: u8,
     /// 表示网络接口的类型
     pub net_device_type: u16,
-    // /// 表示网络接口的状态
-    // pub state: NetDeivceState,
-    // /// 表示网络接口的操作状态
-    // pub operstate: Operstate,
+    /// 表示网络接口的状态
+    pub state: NetDeviceState,
+    /// 表示网络接口的操作状态
+    pub operstate: Operstate,
 }
 
 impl Default for NetDeviceCommonData {
@@ -105,12 +377,29 @@ impl Default for NetDeviceCommonData {
         Self {
             addr_assign_type: 0,
             net_device_type: 1,
-            // state: NetDeivceState::empty(),
-            // operstate: Operstate::IF_OPER_UNKNOWN,
+            state: NetDeviceState::empty(),
+            operstate: Operstate::IF_OPER_UNKNOWN,
         }
     }
 }
 
+impl NetDeviceCommonData {
+    /// 由链路状态和设备读写结果推出操作状态
+    fn update_operstate(&mut self, io_error: bool) {
+        self.operstate = if !self.state.contains(NetDeviceState::__LINK_STATE_PRESENT) {
+            Operstate::IF_OPER_NOTPRESENT
+        } else if !self.state.contains(NetDeviceState::__LINK_STATE_START) {
+            Operstate::IF_OPER_DOWN
+        } else if self.state.contains(NetDeviceState::__LINK_STATE_NOCARRIER) || io_error {
+            Operstate::IF_OPER_LOWERLAYERDOWN
+        } else if self.state.contains(NetDeviceState::__LINK_STATE_DORMANT) {
+            Operstate::IF_OPER_DORMANT
+        } else {
+            Operstate::IF_OPER_UP
+        };
+    }
+}
+
 // /// 将网络设备注册到sysfs中
 // /// 参考：https://code.dragonos.org.cn/xref/linux-2.6.39/net/core/dev.c?fi=register_netdev#5373
 // fn register_netdevice(dev: Arc<dyn Iface>) -> Result<(), Errno> {
@@ -118,13 +407,59 @@ impl Default for NetDeviceCommonData {
 //     netdev_register_kobject(dev.clone())?;
 
 //     // 标识网络设备在系统中存在
-//     dev.set_net_state(NetDeivceState::__LINK_STATE_PRESENT);
+//     dev.set_net_state(NetDeviceState::__LINK_STATE_PRESENT);
 
 //     return Ok(());
 // }
 
+/// 网卡统计的快照
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IfaceStats {
+    /// 从设备收到的帧数
+    pub rx_packets: u64,
+    /// 从设备收到的字节数
+    pub rx_bytes: u64,
+    /// 交给设备发送的帧数，含发送队列满时丢弃的
+    pub tx_packets: u64,
+    /// 交给设备发送的字节数
+    pub tx_bytes: u64,
+    /// 读设备失败的次数
+    pub rx_errors: u64,
+    /// 写设备失败的次数
+    pub tx_errors: u64,
+    /// 读到但无法使用的帧数
+    pub rx_dropped: u64,
+    /// 因发送队列满丢弃的帧数
+    pub tx_dropped: u64,
+    /// IPv4 和 IPv6 包，对应 /proc/net/snmp 的 Ip InReceives 和 OutRequests
+    pub ip: ProtoStats,
+    /// TCP 段，对应 Tcp InSegs 和 OutSegs
+    pub tcp: ProtoStats,
+    /// UDP 数据报，对应 Udp InDatagrams 和 OutDatagrams
+    pub udp: ProtoStats,
+    /// ICMP 和 ICMPv6 消息，对应 Icmp InMsgs 和 OutMsgs
+    pub icmp: ProtoStats,
+}
+
+/// 一种协议收发的包数
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProtoStats {
+    pub in_packets: u64,
+    pub out_packets: u64,
+}
+
+/// 单次轮询中协议栈还有立即要做的工作时，最多重复轮询的次数
+const MAX_POLL_ROUNDS: usize = 8;
+/// packet socket 发出、等待写入设备的帧数上限
+const MAX_QUEUED_FRAMES: usize = 64;
+
 pub struct IfaceCommon {
-    iface_id: usize,
+    /// 注册时分配的 ifindex，未注册时为 0
+    iface_id: core::sync::atomic::AtomicUsize,
+    /// 网卡名
+    name: RwLock<String>,
+    /// 链路层类型，决定网卡有没有 MAC 地址和邻居
+    medium: smoltcp::phy::Medium,
     smol_iface: Mutex<smoltcp::iface::Interface>,
     /// 存smoltcp网卡的套接字集
     sockets: Mutex<smoltcp::iface::SocketSet<'static>>,
@@ -134,33 +469,81 @@ pub struct IfaceCommon {
     port_manager: PortManager,
     /// 下次轮询的时间
     poll_at_ms: core::sync::atomic::AtomicU64,
-    /// 默认网卡标识
-    /// TODO: 此字段设置目的是解决对bind unspecified地址的分包问题，需要在inet实现多网卡监听或路由子系统实现后移除
-    default_iface: bool,
+    /// 串行化同一网卡的轮询，各队列的轮询线程在这里睡眠等待，不在自旋锁上空转
+    poll_lock: std::sync::Mutex<()>,
+    /// 正在进行的抓包
+    capture: Mutex<Option<Capture>>,
+    /// 正在进行的故障注入
+    faults: Mutex<Option<FaultInjector>>,
+    /// 邻居表，smoltcp 邻居缓存的镜像和静态表项
+    neighbors: Mutex<NeighborTable>,
+    /// packet socket 发出的帧和发送者，在下次轮询时写入设备
+    packet_tx: Mutex<VecDeque<(usize, Vec<u8>)>>,
+    /// 正在运行的 DHCPv4 客户端
+    dhcp: Mutex<Option<DhcpClient>>,
+    /// 停止的 DHCP 客户端留下的 dhcpv4 socket，带着泄漏的报文缓冲区，由下一个客户端重用
+    dhcp_socket: Mutex<Option<smoltcp::socket::dhcpv4::Socket<'static>>>,
+    /// 读设备失败的次数
+    rx_errors: core::sync::atomic::AtomicU64,
+    /// 写设备失败的次数
+    tx_errors: core::sync::atomic::AtomicU64,
+    /// 读到但无法使用的帧数
+    rx_dropped: core::sync::atomic::AtomicU64,
+    /// 因发送队列满丢弃的帧数
+    tx_dropped: core::sync::atomic::AtomicU64,
+    /// 收发的帧数、字节数和各协议的包数
+    traffic: TrafficCounters,
+    /// 使网卡停用的设备错误，`None` 表示网卡可用
+    link_error: Mutex<Option<Errno>>,
+    /// 网卡的链路状态和操作状态
+    netdev: Mutex<NetDeviceCommonData>,
 }
 
 impl fmt::Debug for IfaceCommon {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("IfaceCommon")
             .field("iface_id", &self.iface_id)
+            .field("name", &self.name)
             .field("sockets", &self.sockets)
             // .field("bounds", &self.bounds)
             .field("port_manager", &self.port_manager)
             .field("poll_at_ms", &self.poll_at_ms)
+            .field("link_error", &self.link_error)
+            .field("netdev", &self.netdev)
             .finish()
     }
 }
 
 impl IfaceCommon {
-    pub fn new(iface_id: usize, default_iface: bool, iface: smoltcp::iface::Interface) -> Self {
+    /// `name` 可以含 `%d`，注册时替换为未被占用的编号
+    pub fn new(name: &str, medium: smoltcp::phy::Medium, iface: smoltcp::iface::Interface) -> Self {
         IfaceCommon {
-            iface_id,
+            iface_id: core::sync::atomic::AtomicUsize::new(0),
+            name: RwLock::new(name.to_string()),
+            medium,
             smol_iface: Mutex::new(iface),
             sockets: Mutex::new(smoltcp::iface::SocketSet::new(Vec::new())),
             bounds: RwLock::new(Vec::new()),
             port_manager: PortManager::new(),
             poll_at_ms: core::sync::atomic::AtomicU64::new(0),
-            default_iface,
+            poll_lock: std::sync::Mutex::new(()),
+            capture: Mutex::new(None),
+            faults: Mutex::new(None),
+            neighbors: Mutex::new(NeighborTable::default()),
+            packet_tx: Mutex::new(VecDeque::new()),
+            dhcp: Mutex::new(None),
+            dhcp_socket: Mutex::new(None),
+            rx_errors: core::sync::atomic::AtomicU64::new(0),
+            tx_errors: core::sync::atomic::AtomicU64::new(0),
+            rx_dropped: core::sync::atomic::AtomicU64::new(0),
+            tx_dropped: core::sync::atomic::AtomicU64::new(0),
+            traffic: TrafficCounters::default(),
+            link_error: Mutex::new(None),
+            netdev: Mutex::new(NetDeviceCommonData {
+                state: NetDeviceState::__LINK_STATE_PRESENT | NetDeviceState::__LINK_STATE_START,
+                operstate: Operstate::IF_OPER_UP,
+                ..Default::default()
+            }),
         }
     }
 
@@ -168,36 +551,91 @@ impl IfaceCommon {
     where
         D: smoltcp::phy::Device + ?Sized,
     {
-        let timestamp = std::time::Instant::now().into();
+        use core::sync::atomic::Ordering;
+        // a down or unregistered iface neither sends nor receives, its timers
+        // wait until it is up
+        if !self.is_running() {
+            self.poll_at_ms.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        // only one thread runs the stack of an iface, the others block here
+        let serial = self
+            .poll_lock
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let timestamp = smoltcp::time::Instant::now();
         let mut sockets = self.sockets.lock();
         let mut interface = self.smol_iface.lock();
-
-        let (has_events, poll_at) = {
-            (
-                matches!(
-                    interface.poll(timestamp, device, &mut sockets),
-                    smoltcp::iface::PollResult::SocketStateChanged
-                ),
-                loop {
-                    let poll_at = interface.poll_at(timestamp, &sockets);
-                    let Some(instant) = poll_at else {
-                        break poll_at;
-                    };
-                    if instant > timestamp {
-                        break poll_at;
-                    }
-                },
-            )
+        let ip_addrs = interface.ip_addrs().to_vec();
+        // smoltcp asserts the iface has a link layer before handing out its address
+        let mac = match self.medium {
+            smoltcp::phy::Medium::Ethernet => match interface.hardware_addr() {
+                smoltcp::wire::HardwareAddress::Ethernet(mac) => mac,
+                _ => smoltcp::wire::EthernetAddress::default(),
+            },
+            _ => smoltcp::wire::EthernetAddress::default(),
         };
+        let tap = PacketTap::new(self.iface_id(), self.medium, mac);
+        let deliver = |direction, frame: &[u8]| tap.deliver(direction, frame, None);
+        if let Some(capture) = self.capture.lock().as_mut() {
+            capture.attach(self.medium);
+        }
+        let record = |direction, frame: &[u8]| {
+            if let Some(capture) = self.capture.lock().as_mut() {
+                capture.record(direction, frame);
+            }
+        };
+        let mut device = StatsDevice::new(device, &self.traffic);
+        // the frames of packet sockets are captured, but not handed to packet sockets again
+        let mut device = TappedDevice::new(&mut device, &record);
+        self.transmit_frames(&mut device, timestamp, &tap);
+        let mut device = TappedDevice::new(&mut device, &deliver);
+        let mut faulty = FaultDevice::new(&mut device, &self.faults, timestamp);
+        let mut device =
+            NeighborDevice::new(&mut faulty, &self.neighbors, mac, &ip_addrs, timestamp);
+
+        let mut has_events = false;
+        let mut poll_at = None;
+        for _ in 0..MAX_POLL_ROUNDS {
+            has_events |= matches!(
+                interface.poll(timestamp, &mut device, &mut sockets),
+                smoltcp::iface::PollResult::SocketStateChanged
+            );
+            poll_at = interface.poll_at(timestamp, &sockets);
+            // the frames made up by the neighbor table go in the next round
+            if self.neighbors.lock().has_pending() {
+                poll_at = Some(timestamp);
+            }
+            if poll_at.is_none_or(|instant| instant > timestamp) {
+                break;
+            }
+        }
+        // hands over the frames the faults released during this poll
+        drop(faulty);
+        if let Some(faults) = self.faults.lock().as_ref() {
+            poll_at = match (poll_at, faults.poll_at()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+        // the device was full, the rest of the frames go out in the next poll
+        if !self.packet_tx.lock().is_empty() {
+            poll_at = Some(timestamp);
+        }
 
         // drop sockets here to avoid deadlock
         drop(interface);
         drop(sockets);
+        // sockets woken below may poll this iface again
+        drop(serial);
+        self.poll_dhcp();
 
-        use core::sync::atomic::Ordering;
         if let Some(instant) = poll_at {
             let _old_instant = self.poll_at_ms.load(Ordering::Relaxed);
-            let new_instant = instant.total_millis() as u64;
+            // `Instant::ZERO` asks for an immediate poll, keep it apart from the
+            // 0 meaning no poll is needed
+            let new_instant = instant.max(timestamp).total_millis() as u64;
             self.poll_at_ms.store(new_instant, Ordering::Relaxed);
 
             // TODO: poll at
@@ -208,13 +646,14 @@ impl IfaceCommon {
             self.poll_at_ms.store(0, Ordering::Relaxed);
         }
 
-        self.bounds.read().iter().for_each(|bound_socket| {
-            // incase our inet socket missed the event, we manually notify it each time we poll
-            if has_events {
+        // incase our inet socket missed the event, we manually notify it each time we poll.
+        // a woken socket may bind or close, the list is not locked meanwhile
+        if has_events {
+            self.bound_sockets().iter().for_each(|bound_socket| {
                 bound_socket.on_iface_events();
                 bound_socket.wait_queue().wakeup();
-            }
-        });
+            });
+        }
 
         // TODO: remove closed sockets
         // let closed_sockets = self
@@ -225,28 +664,527 @@ impl IfaceCommon {
         // drop(closed_sockets);
     }
 
-    pub fn update_ip_addrs(&self, ip_addrs: &[smoltcp::wire::IpCidr]) -> Result<(), Errno> {
-        if ip_addrs.len() != 1 {
-            return Err(Errno::EINVAL);
+    /// 在设备可写时写入 packet socket 发出的帧，与 Linux 跳过 qdisc 相同，这些帧不经过故障注入
+    fn transmit_frames<D>(&self, device: &mut D, timestamp: smoltcp::time::Instant, tap: &PacketTap)
+    where
+        D: smoltcp::phy::Device + ?Sized,
+    {
+        use smoltcp::phy::TxToken;
+        loop {
+            let Some((sender, frame)) = self.packet_tx.lock().pop_front() else {
+                break;
+            };
+            let Some(token) = device.transmit(timestamp) else {
+                self.packet_tx.lock().push_front((sender, frame));
+                break;
+            };
+            token.consume(frame.len(), |buffer| buffer.copy_from_slice(&frame));
+            tap.deliver(Direction::Outbound, &frame, Some(sender));
         }
+    }
 
-        self.smol_iface.lock().update_ip_addrs(|addrs| {
-            let dest = addrs.iter_mut().next();
+    /// # `queue_frame`
+    /// 把 packet socket `sender` 发出的帧放入发送队列，由下次轮询写入设备
+    /// ## 返回值
+    /// - 队列已满时返回 `Err(Errno::ENOBUFS)`
+    pub fn queue_frame(&self, sender: usize, frame: Vec<u8>) -> Result<(), Errno> {
+        let mut packet_tx = self.packet_tx.lock();
+        if packet_tx.len() >= MAX_QUEUED_FRAMES {
+            return Err(Errno::ENOBUFS);
+        }
+        packet_tx.push_back((sender, frame));
+        Ok(())
+    }
+
+    /// 下次需要轮询的时间
+    pub fn poll_at(&self) -> Option<smoltcp::time::Instant> {
+        use core::sync::atomic::Ordering;
+        match self.poll_at_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(smoltcp::time::Instant::from_millis(ms as i64)),
+        }
+    }
+
+    /// 记录设备自上次轮询以来的读写结果，读写出错时停用网卡，读写成功时恢复，
+    /// 网卡状态变化时通知绑定的套接字
+    /// ## 返回值
+    /// - 网卡状态是否变化
+    pub fn update_io_status(&self, status: IoStatus) -> bool {
+        use core::sync::atomic::Ordering;
+        self.rx_errors
+            .fetch_add(status.rx_errors, Ordering::Relaxed);
+        self.tx_errors
+            .fetch_add(status.tx_errors, Ordering::Relaxed);
+        self.rx_dropped
+            .fetch_add(status.rx_dropped, Ordering::Relaxed);
+        self.tx_dropped
+            .fetch_add(status.tx_dropped, Ordering::Relaxed);
 
-            if let Some(dest) = dest {
-                *dest = ip_addrs[0];
+        let changed = {
+            let mut link_error = self.link_error.lock();
+            if let Some(err) = status.error {
+                let errno = match err.raw_os_error() {
+                    Some(libc::EIO) => Errno::EIO,
+                    _ => Errno::ENETDOWN,
+                };
+                let changed = link_error.is_none();
+                if changed {
+                    log::warn!("iface {} is down: {}", self.name(), err);
+                }
+                link_error.replace(errno);
+                changed
+            } else if status.ok && link_error.is_some() {
+                log::info!("iface {} is up again", self.name());
+                link_error.take();
+                true
             } else {
-                addrs.push(ip_addrs[0]).expect("Push ipCidr failed: full");
+                false
             }
+        };
+
+        if changed {
+            self.update_link_state(|_| {});
+        }
+        changed
+    }
+
+    pub fn iface_id(&self) -> usize {
+        self.iface_id.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn name(&self) -> String {
+        self.name.read().clone()
+    }
+
+    /// smoltcp 只在以太网卡上提供 `hardware_addr`
+    pub fn medium(&self) -> smoltcp::phy::Medium {
+        self.medium
+    }
+
+    pub(super) fn set_registered(&self, iface_id: usize, name: String) {
+        *self.name.write() = name;
+        self.iface_id
+            .store(iface_id, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// 套接字看到的网卡错误，已注销的网卡返回 `Errno::ENODEV`，
+    /// 停用或下层链路不通的网卡返回 `Errno::ENETDOWN`
+    pub fn link_error(&self) -> Option<Errno> {
+        let state = self.net_state();
+        if !state.contains(NetDeviceState::__LINK_STATE_PRESENT) {
+            return Some(Errno::ENODEV);
+        }
+        if !state.contains(NetDeviceState::__LINK_STATE_START)
+            || state.contains(NetDeviceState::__LINK_STATE_NOCARRIER)
+        {
+            return Some(Errno::ENETDOWN);
+        }
+        *self.link_error.lock()
+    }
+
+    pub fn net_state(&self) -> NetDeviceState {
+        self.netdev.lock().state
+    }
+
+    pub fn set_net_state(&self, state: NetDeviceState) {
+        self.update_link_state(|netdev| netdev.state = state);
+    }
+
+    pub fn operstate(&self) -> Operstate {
+        self.netdev.lock().operstate
+    }
+
+    pub fn set_operstate(&self, state: Operstate) {
+        self.netdev.lock().operstate = state;
+    }
+
+    /// 网卡已注册且已启用，只有这样的网卡会被轮询
+    pub fn is_running(&self) -> bool {
+        self.net_state()
+            .contains(NetDeviceState::__LINK_STATE_PRESENT | NetDeviceState::__LINK_STATE_START)
+    }
+
+    /// 启用或停用网卡，重新启用时清除之前的设备错误
+    pub fn set_admin_up(&self, up: bool) {
+        if up {
+            *self.link_error.lock() = None;
+        }
+        self.update_link_state(|netdev| {
+            netdev.state.set(NetDeviceState::__LINK_STATE_START, up);
+        });
+    }
+
+    /// 设置下层链路是否连通，链路恢复时清除之前的设备错误
+    pub fn set_carrier(&self, carrier: bool) {
+        if self
+            .net_state()
+            .contains(NetDeviceState::__LINK_STATE_NOCARRIER)
+            != carrier
+        {
+            return;
+        }
+        if carrier {
+            *self.link_error.lock() = None;
+        }
+        self.update_link_state(|netdev| {
+            netdev
+                .state
+                .set(NetDeviceState::__LINK_STATE_NOCARRIER, !carrier);
+        });
+    }
+
+    /// 修改链路状态，重新计算操作状态，并通知绑定的套接字
+    fn update_link_state(&self, f: impl FnOnce(&mut NetDeviceCommonData)) {
+        let io_error = self.link_error.lock().is_some();
+        {
+            let mut netdev = self.netdev.lock();
+            f(&mut netdev);
+            netdev.update_operstate(io_error);
+            log::debug!(
+                "iface {}: {:?} {:?}",
+                self.name(),
+                netdev.state,
+                netdev.operstate
+            );
+        }
+
+        // a woken socket may bind or close, the list is not locked meanwhile
+        self.bound_sockets().iter().for_each(|bound_socket| {
+            bound_socket.on_iface_events();
+            bound_socket.wait_queue().wakeup();
+        });
+        if self.iface_id() != 0 {
+            registry::NET_DEVICES.publish(registry::IfaceEvent::StateChanged(self.iface_id()));
+        }
+    }
+
+    /// 设备的能力 (如 MTU) 变化后，重建 smoltcp 的 `Interface`，保留地址和路由，
+    /// 邻居缓存会被清空，已建立的 TCP 连接仍沿用原来的 MSS
+    pub fn update_capabilities<D>(&self, device: &mut D)
+    where
+        D: smoltcp::phy::Device + ?Sized,
+    {
+        let mut interface = self.smol_iface.lock();
+        let hardware_addr = match device.capabilities().medium {
+            smoltcp::phy::Medium::Ethernet => interface.hardware_addr(),
+            smoltcp::phy::Medium::Ip => smoltcp::wire::HardwareAddress::Ip,
+        };
+        let mut config = smoltcp::iface::Config::new(hardware_addr);
+        config.random_seed = std::random::random(..);
+        let mut new_interface =
+            smoltcp::iface::Interface::new(config, device, smoltcp::time::Instant::now());
+
+        new_interface.update_ip_addrs(|addrs| {
+            addrs.extend(interface.ip_addrs().iter().copied());
+        });
+        let mut routes = None;
+        interface
+            .routes_mut()
+            .update(|storage| routes = Some(storage.clone()));
+        if let Some(routes) = routes {
+            new_interface
+                .routes_mut()
+                .update(|storage| *storage = routes);
+        }
+        new_interface.set_any_ip(interface.any_ip());
+        *interface = new_interface;
+        self.neighbors_flushed(&interface);
+    }
+
+    pub fn stats(&self) -> IfaceStats {
+        use core::sync::atomic::Ordering;
+        let (rx, tx) = (&self.traffic.rx, &self.traffic.tx);
+        let proto =
+            |rx: &core::sync::atomic::AtomicU64, tx: &core::sync::atomic::AtomicU64| ProtoStats {
+                in_packets: rx.load(Ordering::Relaxed),
+                out_packets: tx.load(Ordering::Relaxed),
+            };
+        IfaceStats {
+            rx_packets: rx.packets.load(Ordering::Relaxed),
+            rx_bytes: rx.bytes.load(Ordering::Relaxed),
+            tx_packets: tx.packets.load(Ordering::Relaxed),
+            tx_bytes: tx.bytes.load(Ordering::Relaxed),
+            rx_errors: self.rx_errors.load(Ordering::Relaxed),
+            tx_errors: self.tx_errors.load(Ordering::Relaxed),
+            rx_dropped: self.rx_dropped.load(Ordering::Relaxed),
+            tx_dropped: self.tx_dropped.load(Ordering::Relaxed),
+            ip: proto(&rx.ip, &tx.ip),
+            tcp: proto(&rx.tcp, &tx.tcp),
+            udp: proto(&rx.udp, &tx.udp),
+            icmp: proto(&rx.icmp, &tx.icmp),
+        }
+    }
+
+    pub fn start_capture(&self, capture: Capture) {
+        self.capture.lock().replace(capture);
+    }
+
+    pub fn stop_capture(&self) -> Option<Capture> {
+        let mut capture = self.capture.lock().take()?;
+        if let Err(err) = capture.flush() {
+            log::warn!("capture: failed to flush: {}", err);
+        }
+        Some(capture)
+    }
+
+    pub fn dump_capture(&self) -> Option<Vec<u8>> {
+        self.capture
+            .lock()
+            .as_ref()
+            .and_then(|capture| capture.dump())
+    }
+
+    pub fn inject_faults(&self, faults: FaultInjector) {
+        self.faults.lock().replace(faults);
+    }
+
+    pub fn clear_faults(&self) -> Option<FaultInjector> {
+        self.faults.lock().take()
+    }
+
+    pub fn fault_stats(&self) -> Option<FaultStats> {
+        self.faults.lock().as_ref().map(|faults| faults.stats())
+    }
+
+    pub fn neighbors(&self) -> Vec<Neighbor> {
+        self.neighbors
+            .lock()
+            .neighbors(smoltcp::time::Instant::now())
+    }
+
+    /// 设置网卡的直连路由，每条是目的网段和 smoltcp 路由它所经过的本机地址
+    pub(super) fn set_on_link_routes(
+        &self,
+        routes: Vec<(smoltcp::wire::IpCidr, smoltcp::wire::IpAddress)>,
+    ) {
+        self.neighbors.lock().set_on_link(routes);
+    }
+
+    /// smoltcp 的邻居缓存只能随地址更新整体清空
+    pub fn flush_neighbors(&self) {
+        let mut interface = self.smol_iface.lock();
+        interface.update_ip_addrs(|_| {});
+        self.neighbors_flushed(&interface);
+    }
+
+    /// smoltcp 的邻居缓存被清空后，清空镜像，并把静态表项重新告诉 smoltcp
+    fn neighbors_flushed(&self, interface: &smoltcp::iface::Interface) {
+        self.neighbors.lock().flush();
+        self.announce_neighbors(interface);
+    }
+
+    /// 把静态表项告诉 smoltcp，覆盖它缓存的同一地址
+    fn announce_neighbors(&self, interface: &smoltcp::iface::Interface) {
+        let mut neighbors = self.neighbors.lock();
+        // only an Ethernet iface has static entries
+        if !neighbors.has_permanent() {
+            return;
+        }
+        if let smoltcp::wire::HardwareAddress::Ethernet(mac) = interface.hardware_addr() {
+            neighbors.announce(mac, interface.ip_addrs());
+        }
+        // the announcements are received on the next poll
+        self.poll_at_ms.store(
+            smoltcp::time::Instant::now().total_millis() as u64,
+            core::sync::atomic::Ordering::Relaxed,
+        );
+    }
+
+    pub fn add_static_neighbor(
+        &self,
+        ip: smoltcp::wire::IpAddress,
+        mac: smoltcp::wire::EthernetAddress,
+    ) -> Result<(), Errno> {
+        if !ip.is_unicast() || !mac.is_unicast() {
+            return Err(Errno::EINVAL);
+        }
+        let interface = self.smol_iface.lock();
+        if !self.neighbors.lock().add_permanent(ip, mac) {
+            return Err(Errno::EEXIST);
+        }
+        self.announce_neighbors(&interface);
+        Ok(())
+    }
+
+    pub fn remove_static_neighbor(&self, ip: smoltcp::wire::IpAddress) -> Result<(), Errno> {
+        if !self.neighbors.lock().remove_permanent(ip) {
+            return Err(Errno::ENOENT);
+        }
+        // smoltcp still caches the static MAC
+        self.flush_neighbors();
+        Ok(())
+    }
+
+    pub fn start_dhcp(&self, config: DhcpConfig) -> Result<(), Errno> {
+        // the dhcpv4 socket of smoltcp panics without a hardware address
+        if self.medium != smoltcp::phy::Medium::Ethernet {
+            return Err(Errno::EOPNOTSUPP);
+        }
+        let mut dhcp = self.dhcp.lock();
+        if dhcp.is_some() {
+            return Err(Errno::EALREADY);
+        }
+        self.port_manager.bind_port(
+            Types::Dhcpv4,
+            smoltcp::wire::DHCP_CLIENT_PORT,
+            PortFamily::Ipv4,
+        )?;
+        let spare = self.dhcp_socket.lock().take();
+        dhcp.replace(DhcpClient::new(config, &mut self.sockets.lock(), spare));
+        // discovery starts on the next poll
+        self.poll_at_ms.store(
+            smoltcp::time::Instant::now().total_millis() as u64,
+            core::sync::atomic::Ordering::Relaxed,
+        );
+        Ok(())
+    }
+
+    pub fn stop_dhcp(&self) -> Option<DhcpLease> {
+        let mut client = self.dhcp.lock().take()?;
+        if let smoltcp::socket::Socket::Dhcpv4(socket) = self.sockets.lock().remove(client.handle) {
+            self.dhcp_socket.lock().replace(socket);
+        }
+        self.port_manager.unbind_port(
+            Types::Dhcpv4,
+            smoltcp::wire::DHCP_CLIENT_PORT,
+            PortFamily::Ipv4,
+        );
+        client.deconfigure(self)
+    }
+
+    pub fn dhcp_lease(&self) -> Option<DhcpLease> {
+        self.dhcp.lock().as_ref()?.lease().cloned()
+    }
+
+    fn poll_dhcp(&self) {
+        let mut dhcp = self.dhcp.lock();
+        if let Some(client) = dhcp.as_mut() {
+            client.poll(self);
+        }
+    }
+
+    pub fn update_hardware_addr(&self, mac: smoltcp::wire::EthernetAddress) {
+        self.smol_iface
+            .lock()
+            .set_hardware_addr(smoltcp::wire::HardwareAddress::Ethernet(mac));
+    }
+
+    pub fn update_ip_addrs(&self, ip_addrs: &[smoltcp::wire::IpCidr]) -> Result<(), Errno> {
+        for (i, ip_addr) in ip_addrs.iter().enumerate() {
+            check_ip_addr(ip_addr)?;
+            if ip_addrs[..i]
+                .iter()
+                .any(|other| other.address() == ip_addr.address())
+            {
+                return Err(Errno::EINVAL);
+            }
+        }
+        if ip_addrs.len() > smoltcp::config::IFACE_MAX_ADDR_COUNT {
+            return Err(Errno::ENOSPC);
+        }
+
+        let mut interface = self.smol_iface.lock();
+        interface.update_ip_addrs(|addrs| {
+            addrs.clear();
+            addrs.extend(ip_addrs.iter().copied());
         });
+        self.neighbors_flushed(&interface);
+        drop(interface);
+        route::ROUTES.refresh(self);
         Ok(())
     }
 
+    pub fn ip_addrs(&self) -> Vec<smoltcp::wire::IpCidr> {
+        self.smol_iface.lock().ip_addrs().to_vec()
+    }
+
+    pub fn add_ip_addr(&self, ip_addr: smoltcp::wire::IpCidr) -> Result<(), Errno> {
+        check_ip_addr(&ip_addr)?;
+        let mut interface = self.smol_iface.lock();
+        if interface.has_ip_addr(ip_addr.address()) {
+            return Err(Errno::EEXIST);
+        }
+        let mut result = Ok(());
+        interface.update_ip_addrs(|addrs| {
+            result = addrs.push(ip_addr).map_err(|_| Errno::ENOSPC);
+        });
+        self.neighbors_flushed(&interface);
+        drop(interface);
+        route::ROUTES.refresh(self);
+        result
+    }
+
+    pub fn remove_ip_addr(&self, ip_addr: smoltcp::wire::IpCidr) -> Result<(), Errno> {
+        let mut interface = self.smol_iface.lock();
+        let index = interface
+            .ip_addrs()
+            .iter()
+            .position(|cidr| *cidr == ip_addr)
+            .ok_or(Errno::EADDRNOTAVAIL)?;
+        interface.update_ip_addrs(|addrs| {
+            addrs.remove(index);
+        });
+        self.neighbors_flushed(&interface);
+        drop(interface);
+        // the on-link routes may have gone through the address
+        route::ROUTES.refresh(self);
+        Ok(())
+    }
+
+    /// 到 `remote` 的最长前缀匹配，`remote` 不在网卡的任何子网内时返回 `None`
+    pub fn subnet_prefix_len(&self, remote: &smoltcp::wire::IpAddress) -> Option<u8> {
+        self.smol_iface
+            .lock()
+            .ip_addrs()
+            .iter()
+            .filter(|cidr| cidr.contains_addr(remote))
+            .map(|cidr| cidr.prefix_len())
+            .max()
+    }
+
+    /// 发往 `remote` 时使用的源地址，网卡没有同一协议族的地址时返回 `None`。
+    /// IPv4 优先选择与 `remote` 同一子网、前缀最长的地址，否则选择第一个地址；
+    /// IPv6 按 RFC 6724 选择
+    pub fn source_addr(
+        &self,
+        remote: &smoltcp::wire::IpAddress,
+    ) -> Option<smoltcp::wire::IpAddress> {
+        use smoltcp::wire::{IpAddress, IpCidr};
+        let interface = self.smol_iface.lock();
+        match remote {
+            IpAddress::Ipv4(_) => {
+                let v4 = || {
+                    interface
+                        .ip_addrs()
+                        .iter()
+                        .filter(|cidr| matches!(cidr, IpCidr::Ipv4(_)))
+                };
+                v4().filter(|cidr| cidr.contains_addr(remote))
+                    .max_by_key(|cidr| cidr.prefix_len())
+                    .or_else(|| v4().next())
+                    .map(|cidr| cidr.address())
+            }
+            IpAddress::Ipv6(remote) => {
+                // smoltcp falls back to `::1` on an iface without IPv6 addresses
+                interface
+                    .ip_addrs()
+                    .iter()
+                    .any(|cidr| matches!(cidr, IpCidr::Ipv6(_)))
+                    .then(|| IpAddress::Ipv6(interface.get_source_address_ipv6(remote)))
+            }
+        }
+    }
+
     // 需要bounds储存具体的Inet Socket信息，以提供不同种类inet socket的事件分发
     pub fn bind_socket(&self, socket: Arc<dyn InetSocket>) {
         self.bounds.write().push(socket);
     }
 
+    /// 绑定在此网卡上的套接字
+    pub fn bound_sockets(&self) -> Vec<Arc<dyn InetSocket>> {
+        self.bounds.read().clone()
+    }
+
     pub fn unbind_socket(&self, socket: Arc<dyn InetSocket>) {
         let mut bounds = self.bounds.write();
         if let Some(index) = bounds.iter().position(|s| Arc::ptr_eq(s, &socket)) {
@@ -254,9 +1192,4 @@ impl IfaceCommon {
             log::debug!("unbind socket success");
         }
     }
-
-    // TODO: 需要在inet实现多网卡监听或路由子系统实现后移除
-    pub fn is_default_iface(&self) -> bool {
-        self.default_iface
-    }
 }
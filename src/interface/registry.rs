@@ -0,0 +1,378 @@
+//! 网卡注册表，为网卡分配 ifindex 和名字，并向订阅者发布网卡的增删和状态变化
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Weak};
+
+use linux_errnos::Errno;
+use spin::{Mutex, RwLock};
+
+use super::loopback::{LoopbackIface, LOOPBACK_IFACE_ID};
+use super::route::ROUTES;
+use super::{Iface, NetDeviceState};
+use crate::socket::inet::InetSocket;
+
+lazy_static::lazy_static! {
+    /// 网卡表，loopback 网卡在初始化时自动注册
+    pub static ref NET_DEVICES: IfaceRegistry = {
+        let registry = IfaceRegistry::new();
+        let loopback = registry
+            .register(Arc::new(LoopbackIface::new()))
+            .expect("Register loopback failed");
+        assert_eq!(loopback, LOOPBACK_IFACE_ID);
+        registry
+    };
+}
+
+/// 注册表发布的事件，携带网卡的 ifindex
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IfaceEvent {
+    /// 网卡已注册
+    Added(usize),
+    /// 网卡已注销，此后不再被轮询
+    Removed(usize),
+    /// 网卡的链路状态或操作状态变化
+    StateChanged(usize),
+}
+
+#[derive(Debug)]
+struct IfaceTable {
+    ifaces: BTreeMap<usize, Arc<dyn Iface>>,
+    /// 下一个分配的 ifindex，和 Linux 一样从 1 开始且不复用
+    next_ifindex: usize,
+}
+
+#[derive(Debug)]
+pub struct IfaceRegistry {
+    table: RwLock<IfaceTable>,
+    subscribers: Mutex<Vec<Weak<IfaceSubscriber>>>,
+    /// 绑定到未指定地址的套接字，新注册的网卡会通知它们
+    sockets: RwLock<Vec<Arc<dyn InetSocket>>>,
+}
+
+impl IfaceRegistry {
+    fn new() -> Self {
+        IfaceRegistry {
+            table: RwLock::new(IfaceTable {
+                ifaces: BTreeMap::new(),
+                next_ifindex: 1,
+            }),
+            subscribers: Mutex::new(Vec::new()),
+            sockets: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// # `register`
+    /// 注册网卡，分配 ifindex，名字中的 `%d` 被替换为第一个未被占用的编号
+    /// ## 返回值
+    /// - 成功返回网卡的 ifindex
+    /// - 网卡已注册或名字已被占用时返回 `Err(Errno::EEXIST)`
+    pub fn register(&self, iface: Arc<dyn Iface>) -> Result<usize, Errno> {
+        let mut table = self.table.write();
+        if iface.nic_id() != 0 {
+            return Err(Errno::EEXIST);
+        }
+
+        let template = iface.iface_name();
+        let name = if template.contains("%d") {
+            (0..)
+                .map(|n| template.replacen("%d", &n.to_string(), 1))
+                .find(|name| {
+                    !table
+                        .ifaces
+                        .values()
+                        .any(|other| other.iface_name() == *name)
+                })
+                .expect("Run out of iface names")
+        } else if table
+            .ifaces
+            .values()
+            .any(|other| other.iface_name() == template)
+        {
+            return Err(Errno::EEXIST);
+        } else {
+            template
+        };
+
+        let ifindex = table.next_ifindex;
+        table.next_ifindex += 1;
+        iface.common().set_registered(ifindex, name);
+        table.ifaces.insert(ifindex, iface.clone());
+        drop(table);
+
+        // an iface unregistered earlier is back
+        let state = iface.net_state();
+        if !state.contains(NetDeviceState::__LINK_STATE_PRESENT) {
+            iface.set_net_state(state | NetDeviceState::__LINK_STATE_PRESENT);
+        }
+
+        log::info!("iface {} registered as {}", iface.iface_name(), ifindex);
+        // the sockets take their own locks, which must not nest in ours
+        let sockets = self.sockets.read().clone();
+        for socket in sockets {
+            socket.on_iface_added(&iface);
+        }
+        self.publish(IfaceEvent::Added(ifindex));
+        Ok(ifindex)
+    }
+
+    /// # `unregister`
+    /// 注销网卡，删除经它的路由，网卡被标记为不存在，绑定在其上的套接字收到 `Errno::ENODEV`
+    /// ## 返回值
+    /// - 成功返回被注销的网卡
+    /// - 没有该网卡时返回 `Err(Errno::ENODEV)`
+    /// - 和 Linux 一样 loopback 网卡不能注销，返回 `Err(Errno::EOPNOTSUPP)`，
+    ///   所以注册表中总有网卡
+    pub fn unregister(&self, ifindex: usize) -> Result<Arc<dyn Iface>, Errno> {
+        if ifindex == LOOPBACK_IFACE_ID {
+            return Err(Errno::EOPNOTSUPP);
+        }
+        let iface = self
+            .table
+            .write()
+            .ifaces
+            .remove(&ifindex)
+            .ok_or(Errno::ENODEV)?;
+        log::info!("iface {} unregistered", iface.iface_name());
+        ROUTES.remove_iface(iface.as_ref());
+
+        let state = iface.net_state();
+        iface.set_net_state(state - NetDeviceState::__LINK_STATE_PRESENT);
+        iface.common().set_registered(0, iface.iface_name());
+        self.publish(IfaceEvent::Removed(ifindex));
+        Ok(iface)
+    }
+
+    /// # `get`
+    /// 按 ifindex 查找网卡
+    pub fn get(&self, ifindex: usize) -> Option<Arc<dyn Iface>> {
+        self.table.read().ifaces.get(&ifindex).cloned()
+    }
+
+    /// # `get_by_name`
+    /// 按名字查找网卡
+    pub fn get_by_name(&self, name: &str) -> Option<Arc<dyn Iface>> {
+        self.table
+            .read()
+            .ifaces
+            .values()
+            .find(|iface| iface.iface_name() == name)
+            .cloned()
+    }
+
+    /// # `ifaces`
+    /// 按 ifindex 顺序列出已注册的网卡
+    pub fn ifaces(&self) -> Vec<Arc<dyn Iface>> {
+        self.table.read().ifaces.values().cloned().collect()
+    }
+
+    /// # `bind_socket`
+    /// 登记绑定到未指定地址的套接字，此后注册的网卡通过 `on_iface_added` 通知它。
+    /// 套接字须在列出已有网卡之前登记，同一网卡可能既在列表中又被通知，
+    /// `on_iface_added` 对已在其上的网卡什么也不做
+    pub fn bind_socket(&self, socket: Arc<dyn InetSocket>) {
+        self.sockets.write().push(socket);
+    }
+
+    /// # `unbind_socket`
+    /// 取消 `bind_socket` 的登记
+    pub fn unbind_socket(&self, socket: Arc<dyn InetSocket>) {
+        self.sockets
+            .write()
+            .retain(|other| !Arc::ptr_eq(other, &socket));
+    }
+
+    /// # `subscribe`
+    /// 订阅此后发布的事件，订阅在返回值被释放时取消
+    pub fn subscribe(&self) -> io::Result<Arc<IfaceSubscriber>> {
+        let subscriber = Arc::new(IfaceSubscriber::new()?);
+        self.subscribers.lock().push(Arc::downgrade(&subscriber));
+        Ok(subscriber)
+    }
+
+    pub(super) fn publish(&self, event: IfaceEvent) {
+        self.subscribers
+            .lock()
+            .retain(|subscriber| match subscriber.upgrade() {
+                Some(subscriber) => {
+                    subscriber.push(event);
+                    true
+                }
+                None => false,
+            });
+    }
+}
+
+/// 注册表事件的订阅，事件到达时 eventfd 变为可读，可以和网卡一起被 epoll 监听
+#[derive(Debug)]
+pub struct IfaceSubscriber {
+    events: Mutex<VecDeque<IfaceEvent>>,
+    event_fd: RawFd,
+}
+
+impl IfaceSubscriber {
+    fn new() -> io::Result<Self> {
+        let event_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if event_fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            events: Mutex::new(VecDeque::new()),
+            event_fd,
+        })
+    }
+
+    fn push(&self, event: IfaceEvent) {
+        self.events.lock().push_back(event);
+
+        let one: u64 = 1;
+        unsafe {
+            libc::write(
+                self.event_fd,
+                &one as *const u64 as *const libc::c_void,
+                core::mem::size_of::<u64>(),
+            );
+        }
+    }
+
+    /// # `take_events`
+    /// 取出目前为止收到的事件，按发布顺序排列
+    pub fn take_events(&self) -> Vec<IfaceEvent> {
+        // reset the eventfd counter first, an event published meanwhile is
+        // either taken below or signals the eventfd again
+        let mut counter: u64 = 0;
+        unsafe {
+            libc::read(
+                self.event_fd,
+                &mut counter as *mut u64 as *mut libc::c_void,
+                core::mem::size_of::<u64>(),
+            );
+        }
+        self.events.lock().drain(..).collect()
+    }
+}
+
+impl AsRawFd for IfaceSubscriber {
+    fn as_raw_fd(&self) -> RawFd {
+        self.event_fd
+    }
+}
+
+impl Drop for IfaceSubscriber {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.event_fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::veth::{veth_pair, VETH_DEFAULT_MTU};
+    use crate::interface::veth::VethIface;
+
+    /// A registry of its own with the loopback in place, like [`NET_DEVICES`]
+    fn registry() -> IfaceRegistry {
+        let registry = IfaceRegistry::new();
+        registry.register(Arc::new(LoopbackIface::new())).unwrap();
+        registry
+    }
+
+    fn veth() -> (Arc<dyn Iface>, Arc<dyn Iface>) {
+        let (left, right) = veth_pair(VETH_DEFAULT_MTU).unwrap();
+        (
+            Arc::new(VethIface::new(left)),
+            Arc::new(VethIface::new(right)),
+        )
+    }
+
+    #[test]
+    fn register_numbers_ifaces_and_their_names() {
+        let registry = registry();
+        let (left, right) = veth();
+        assert_eq!(registry.register(left.clone()), Ok(2));
+        assert_eq!(registry.register(right.clone()), Ok(3));
+        assert_eq!(left.iface_name(), "veth0");
+        assert_eq!(right.iface_name(), "veth1");
+        assert_eq!(left.nic_id(), 2);
+
+        assert_eq!(registry.register(left.clone()), Err(Errno::EEXIST));
+        assert_eq!(
+            registry.register(Arc::new(LoopbackIface::new())),
+            Err(Errno::EEXIST),
+            "the name is taken"
+        );
+
+        assert!(Arc::ptr_eq(&registry.get(3).unwrap(), &right));
+        assert!(Arc::ptr_eq(&registry.get_by_name("veth0").unwrap(), &left));
+        assert!(registry.get(4).is_none());
+        assert!(registry.get_by_name("veth2").is_none());
+        let names: Vec<String> = registry
+            .ifaces()
+            .iter()
+            .map(|iface| iface.iface_name())
+            .collect();
+        assert_eq!(names, ["lo", "veth0", "veth1"]);
+    }
+
+    #[test]
+    fn unregister_marks_the_iface_gone() {
+        let registry = registry();
+        let (left, _right) = veth();
+        let ifindex = registry.register(left.clone()).unwrap();
+        let removed = registry.unregister(ifindex).unwrap();
+        assert!(Arc::ptr_eq(&removed, &left));
+        assert_eq!(left.nic_id(), 0);
+        assert!(!left
+            .net_state()
+            .contains(NetDeviceState::__LINK_STATE_PRESENT));
+        assert_eq!(left.common().link_error(), Some(Errno::ENODEV));
+        assert!(registry.get(ifindex).is_none());
+        assert_eq!(registry.unregister(ifindex).err(), Some(Errno::ENODEV));
+
+        // registered again under a new index, and present again
+        assert_eq!(registry.register(left.clone()), Ok(ifindex + 1));
+        assert_eq!(left.iface_name(), "veth0");
+        assert_eq!(left.common().link_error(), None);
+    }
+
+    #[test]
+    fn loopback_cannot_be_unregistered() {
+        let registry = registry();
+        assert_eq!(
+            registry.unregister(LOOPBACK_IFACE_ID).err(),
+            Some(Errno::EOPNOTSUPP)
+        );
+        assert_eq!(registry.ifaces().len(), 1);
+    }
+
+    #[test]
+    fn subscribers_see_ifaces_come_and_go() {
+        let registry = registry();
+        let subscriber = registry.subscribe().unwrap();
+        let (left, _right) = veth();
+        let ifindex = registry.register(left).unwrap();
+        registry.unregister(ifindex).unwrap();
+
+        // the eventfd is readable until the events are taken
+        let mut pollfd = libc::pollfd {
+            fd: subscriber.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 0) }, 1);
+        assert_eq!(
+            subscriber.take_events(),
+            [IfaceEvent::Added(ifindex), IfaceEvent::Removed(ifindex)]
+        );
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 0) }, 0);
+        assert!(subscriber.take_events().is_empty());
+
+        // a dropped subscription is forgotten on the next event
+        drop(subscriber);
+        let (left, _right) = veth();
+        registry.register(left).unwrap();
+        assert!(registry.subscribers.lock().is_empty());
+    }
+}
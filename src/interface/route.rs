@@ -0,0 +1,493 @@
+//! 路由表，保存静态路由和每个网卡的默认网关，按最长前缀匹配选出出口网卡和源地址。
+//! 网卡地址所在的网段是隐含的直连路由，度量值为 0。以太网卡上没有网关的静态路由
+//! 由邻居层直接解析目的地址，见 [`crate::driver::neighbor`]
+use std::sync::Arc;
+
+use linux_errnos::Errno;
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr};
+use spin::RwLock;
+
+use super::registry::NET_DEVICES;
+use super::{Iface, IfaceCommon};
+
+lazy_static::lazy_static! {
+    /// 路由表
+    pub static ref ROUTES: RouteTable = RouteTable::new();
+}
+
+/// 一条静态路由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    /// 目的网段
+    pub dst: IpCidr,
+    /// 下一跳，`None` 表示目的网段直接连在网卡上
+    pub gateway: Option<IpAddress>,
+    /// 出口网卡的 ifindex
+    pub ifindex: usize,
+    /// 度量值，前缀长度相同时选择度量值小的路由
+    pub metric: u32,
+}
+
+impl Route {
+    pub fn new(dst: IpCidr, gateway: Option<IpAddress>, ifindex: usize, metric: u32) -> Self {
+        Route {
+            dst,
+            gateway,
+            ifindex,
+            metric,
+        }
+    }
+
+    /// 经 `gateway` 出 `ifindex` 网卡的默认路由
+    pub fn default_via(gateway: IpAddress, ifindex: usize, metric: u32) -> Self {
+        let dst = match gateway {
+            IpAddress::Ipv4(_) => IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0)),
+            IpAddress::Ipv6(_) => IpCidr::Ipv6(Ipv6Cidr::new(Ipv6Address::UNSPECIFIED, 0)),
+        };
+        Route::new(dst, Some(gateway), ifindex, metric)
+    }
+}
+
+/// 路由查找的结果
+#[derive(Debug, Clone)]
+pub struct NextHop {
+    /// 出口网卡
+    pub iface: Arc<dyn Iface>,
+    /// 源地址
+    pub src: IpAddress,
+    /// 网关，`None` 表示目的地址在链路上
+    pub gateway: Option<IpAddress>,
+}
+
+#[derive(Debug)]
+pub struct RouteTable {
+    routes: RwLock<Vec<Route>>,
+}
+
+impl RouteTable {
+    fn new() -> Self {
+        RouteTable {
+            routes: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// # `add`
+    /// 添加静态路由，并同步到出口网卡的 smoltcp `Routes`
+    /// ## 返回值
+    /// - 网关与目的网段协议族不同，或网关不是单播地址时返回 `Err(Errno::EINVAL)`
+    /// - 没有该网卡时返回 `Err(Errno::ENODEV)`
+    /// - 网关不在网卡的任何网段内时返回 `Err(Errno::ENETUNREACH)`
+    /// - 已有目的网段、网卡和度量值都相同的路由时返回 `Err(Errno::EEXIST)`
+    /// - 网卡的 smoltcp `Routes` 已满时返回 `Err(Errno::ENOSPC)`
+    pub fn add(&self, route: Route) -> Result<(), Errno> {
+        let route = Route {
+            dst: network(route.dst),
+            ..route
+        };
+        let iface = NET_DEVICES.get(route.ifindex).ok_or(Errno::ENODEV)?;
+        if let Some(gateway) = route.gateway {
+            if gateway.version() != route.dst.address().version() || !gateway.is_unicast() {
+                return Err(Errno::EINVAL);
+            }
+            if iface.common().subnet_prefix_len(&gateway).is_none() {
+                return Err(Errno::ENETUNREACH);
+            }
+        }
+
+        let mut routes = self.routes.write();
+        if routes.iter().any(|other| {
+            other.dst == route.dst && other.ifindex == route.ifindex && other.metric == route.metric
+        }) {
+            return Err(Errno::EEXIST);
+        }
+        routes.push(route);
+        if let Err(err) = program(&routes, iface.common()) {
+            routes.pop();
+            program(&routes, iface.common())?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// # `remove`
+    /// 删除目的网段为 `dst` 的静态路由，`ifindex` 为 `None` 时不限出口网卡
+    /// ## 返回值
+    /// - 成功返回被删除的路由
+    /// - 没有匹配的路由时返回 `Err(Errno::ESRCH)`
+    pub fn remove(&self, dst: IpCidr, ifindex: Option<usize>) -> Result<Route, Errno> {
+        let dst = network(dst);
+        let mut routes = self.routes.write();
+        let index = routes
+            .iter()
+            .position(|route| route.dst == dst && ifindex.is_none_or(|i| i == route.ifindex))
+            .ok_or(Errno::ESRCH)?;
+        let route = routes.remove(index);
+        if let Some(iface) = NET_DEVICES.get(route.ifindex) {
+            program(&routes, iface.common())?;
+        }
+        Ok(route)
+    }
+
+    /// # `delete`
+    /// 删除与 `route` 完全相同的静态路由，不影响目的网段相同的其它路由
+    /// ## 返回值
+    /// - 没有相同的路由时返回 `Err(Errno::ESRCH)`
+    pub fn delete(&self, route: &Route) -> Result<(), Errno> {
+        let route = Route {
+            dst: network(route.dst),
+            ..*route
+        };
+        let mut routes = self.routes.write();
+        let index = routes
+            .iter()
+            .position(|other| *other == route)
+            .ok_or(Errno::ESRCH)?;
+        routes.remove(index);
+        if let Some(iface) = NET_DEVICES.get(route.ifindex) {
+            program(&routes, iface.common())?;
+        }
+        Ok(())
+    }
+
+    /// # `routes`
+    /// 列出静态路由，不含直连路由
+    pub fn routes(&self) -> Vec<Route> {
+        self.routes.read().clone()
+    }
+
+    /// # `lookup`
+    /// 查找发往 `dst` 的出口网卡、源地址和网关。只考虑已启用的网卡，本机地址走
+    /// 拥有它的网卡，否则按最长前缀匹配，前缀相同时选度量值小的，再相同时优先直连路由
+    /// ## 返回值
+    /// - 没有可用的路由，或出口网卡没有同一协议族的地址时返回 `None`
+    pub fn lookup(&self, dst: &IpAddress) -> Option<NextHop> {
+        let ifaces = NET_DEVICES.ifaces();
+        if let Some(iface) = ifaces.iter().find(|iface| {
+            iface.common().is_running() && iface.smol_iface().lock().has_ip_addr(*dst)
+        }) {
+            return Some(NextHop {
+                iface: iface.clone(),
+                src: *dst,
+                gateway: None,
+            });
+        }
+
+        let running = |ifindex: usize| {
+            ifaces
+                .iter()
+                .find(|iface| iface.nic_id() == ifindex && iface.common().is_running())
+        };
+        let routes = self.routes.read();
+        let statics = routes
+            .iter()
+            .filter(|route| route.dst.contains_addr(dst))
+            .filter_map(|route| {
+                let iface = running(route.ifindex)?;
+                Some((route.dst.prefix_len(), route.metric, iface, route.gateway))
+            });
+        let connected = ifaces
+            .iter()
+            .filter(|iface| iface.common().is_running())
+            .filter_map(|iface| Some((iface.common().subnet_prefix_len(dst)?, 0, iface, None)));
+        // the last of equally good routes wins, the connected ones come last
+        let (_, _, iface, gateway) = statics
+            .chain(connected)
+            .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)))?;
+        let iface = iface.clone();
+        drop(routes);
+
+        // an IPv4 source on the subnet of the gateway, RFC 6724 for IPv6
+        let hint = match gateway {
+            Some(gateway @ IpAddress::Ipv4(_)) => gateway,
+            _ => *dst,
+        };
+        let src = iface.common().source_addr(&hint)?;
+        Some(NextHop {
+            iface,
+            src,
+            gateway,
+        })
+    }
+
+    /// 删除网卡的全部静态路由，网卡注销时调用
+    pub(super) fn remove_iface(&self, iface: &dyn Iface) {
+        let mut routes = self.routes.write();
+        routes.retain(|route| route.ifindex != iface.nic_id());
+        let _ = program(&routes, iface.common());
+    }
+
+    /// 网卡地址变化后重写它的 smoltcp `Routes`，直连路由经过的本机地址可能已经变了
+    pub(super) fn refresh(&self, common: &IfaceCommon) {
+        let routes = self.routes.read();
+        let _ = program(&routes, common);
+    }
+}
+
+/// 把 `routes` 中出 `common` 网卡的路由写入它的 smoltcp `Routes`。smoltcp 在
+/// 前缀相同时不看度量值，每个目的网段只写入度量值最小的一条。smoltcp 只解析
+/// 本网段的邻居，以太网卡的直连路由经网卡的一个同族地址，由邻居层解析目的地址；
+/// 网卡没有同族地址时直连路由不写入
+fn program(routes: &[Route], common: &IfaceCommon) -> Result<(), Errno> {
+    let ethernet = common.medium() == smoltcp::phy::Medium::Ethernet;
+    let mut best: Vec<&Route> = Vec::new();
+    for route in routes
+        .iter()
+        .filter(|route| route.ifindex == common.iface_id() && (route.gateway.is_some() || ethernet))
+    {
+        match best.iter_mut().find(|other| other.dst == route.dst) {
+            Some(other) if other.metric > route.metric => *other = route,
+            Some(_) => {}
+            None => best.push(route),
+        }
+    }
+
+    let mut on_link = Vec::new();
+    let best: Vec<(IpCidr, IpAddress)> = best
+        .into_iter()
+        .filter_map(|route| match route.gateway {
+            Some(gateway) => Some((route.dst, gateway)),
+            None => {
+                let via = common.source_addr(&route.dst.address())?;
+                on_link.push((route.dst, via));
+                Some((route.dst, via))
+            }
+        })
+        .collect();
+    common.set_on_link_routes(on_link);
+
+    let mut result = Ok(());
+    common.smol_iface.lock().routes_mut().update(|storage| {
+        storage.clear();
+        for (cidr, via_router) in best {
+            let route = smoltcp::iface::Route {
+                cidr,
+                via_router,
+                preferred_until: None,
+                expires_at: None,
+            };
+            if storage.push(route).is_err() {
+                result = Err(Errno::ENOSPC);
+                return;
+            }
+        }
+    });
+    result
+}
+
+/// 把网段的主机位清零，`10.0.0.1/8` 和 `10.0.0.0/8` 是同一条路由
+fn network(cidr: IpCidr) -> IpCidr {
+    match cidr {
+        IpCidr::Ipv4(cidr) => IpCidr::Ipv4(cidr.network()),
+        IpCidr::Ipv6(cidr) => {
+            let prefix_len = cidr.prefix_len();
+            let bits = u128::from_be_bytes(cidr.address().octets());
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            IpCidr::Ipv6(Ipv6Cidr::new((bits & mask).into(), prefix_len))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::veth::{veth_pair, VETH_DEFAULT_MTU};
+    use crate::interface::veth::VethIface;
+    use smoltcp::wire::IpAddress;
+
+    fn v4(a: u8, b: u8, c: u8, d: u8) -> IpAddress {
+        IpAddress::v4(a, b, c, d)
+    }
+
+    fn v4_cidr(a: u8, b: u8, c: u8, d: u8, prefix_len: u8) -> IpCidr {
+        IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), prefix_len))
+    }
+
+    /// Registers both ends of a veth pair, with one address each
+    fn ifaces(a: IpCidr, b: IpCidr) -> (Arc<dyn Iface>, Arc<dyn Iface>) {
+        let (left, right) = veth_pair(VETH_DEFAULT_MTU).unwrap();
+        let register = |device, cidr| {
+            let iface: Arc<dyn Iface> = Arc::new(VethIface::new(device));
+            iface.update_ip_addrs(&[cidr]).unwrap();
+            NET_DEVICES.register(iface.clone()).unwrap();
+            iface
+        };
+        (register(left, a), register(right, b))
+    }
+
+    /// Unregistering takes the routes of the ifaces out of [`ROUTES`]
+    fn unregister(ifaces: &[&Arc<dyn Iface>]) {
+        for iface in ifaces {
+            NET_DEVICES.unregister(iface.nic_id()).unwrap();
+        }
+    }
+
+    /// The routes of [`ROUTES`] through `ifaces`, the other tests add their own
+    fn routes_of(ifaces: &[&Arc<dyn Iface>]) -> Vec<Route> {
+        ROUTES
+            .routes()
+            .into_iter()
+            .filter(|route| ifaces.iter().any(|iface| iface.nic_id() == route.ifindex))
+            .collect()
+    }
+
+    #[test]
+    fn lookup_prefers_the_longest_prefix_then_the_metric() {
+        let (a, b) = ifaces(v4_cidr(10, 61, 1, 2, 24), v4_cidr(10, 61, 2, 2, 24));
+        let table = &*ROUTES;
+        let hop = |dst| {
+            let hop = table.lookup(&dst).unwrap();
+            (hop.iface.nic_id(), hop.gateway, hop.src)
+        };
+
+        // a prefix of their own rather than default routes, which the DHCP
+        // tests add as well
+        let far = v4_cidr(198, 51, 0, 0, 16);
+        table
+            .add(Route::new(far, Some(v4(10, 61, 1, 1)), a.nic_id(), 100))
+            .unwrap();
+        table
+            .add(Route::new(far, Some(v4(10, 61, 2, 1)), b.nic_id(), 50))
+            .unwrap();
+        assert_eq!(
+            hop(v4(198, 51, 100, 1)),
+            (b.nic_id(), Some(v4(10, 61, 2, 1)), v4(10, 61, 2, 2))
+        );
+
+        // a longer prefix wins over a smaller metric
+        let wide = Route::new(
+            v4_cidr(10, 70, 0, 0, 16),
+            Some(v4(10, 61, 1, 1)),
+            a.nic_id(),
+            500,
+        );
+        table.add(wide).unwrap();
+        table
+            .add(Route::new(
+                v4_cidr(10, 70, 5, 0, 24),
+                Some(v4(10, 61, 2, 1)),
+                b.nic_id(),
+                900,
+            ))
+            .unwrap();
+        assert_eq!(hop(v4(10, 70, 1, 1)).0, a.nic_id());
+        assert_eq!(hop(v4(10, 70, 5, 9)).0, b.nic_id());
+
+        // the connected route wins over a static one as good as it
+        table
+            .add(Route::new(
+                v4_cidr(10, 61, 1, 0, 24),
+                Some(v4(10, 61, 2, 1)),
+                b.nic_id(),
+                0,
+            ))
+            .unwrap();
+        assert_eq!(hop(v4(10, 61, 1, 77)), (a.nic_id(), None, v4(10, 61, 1, 2)));
+        // and a local address is reached through its own iface
+        assert_eq!(hop(v4(10, 61, 2, 2)), (b.nic_id(), None, v4(10, 61, 2, 2)));
+
+        // without the better default route the other one is used
+        table
+            .delete(&Route::new(far, Some(v4(10, 61, 2, 1)), b.nic_id(), 50))
+            .unwrap();
+        assert_eq!(hop(v4(198, 51, 100, 1)).0, a.nic_id());
+        // neither are the addresses of an iface that is down
+        b.set_up(false).unwrap();
+        assert!(table.lookup(&v4(10, 61, 2, 2)).is_none());
+        b.set_up(true).unwrap();
+        // no IPv6 address, no IPv6 route
+        let v6 = Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        assert!(table.lookup(&IpAddress::Ipv6(v6)).is_none());
+
+        unregister(&[&a, &b]);
+    }
+
+    #[test]
+    fn add_and_remove_check_the_routes() {
+        let (a, b) = ifaces(v4_cidr(10, 62, 1, 2, 24), v4_cidr(10, 62, 2, 2, 24));
+        let table = &*ROUTES;
+        let gateway = Some(v4(10, 62, 1, 1));
+
+        // the host bits are cleared
+        let route = Route::new(v4_cidr(10, 80, 1, 1, 16), gateway, a.nic_id(), 10);
+        table.add(route).unwrap();
+        assert_eq!(routes_of(&[&a])[0].dst, v4_cidr(10, 80, 0, 0, 16));
+        assert_eq!(table.add(route), Err(Errno::EEXIST));
+        table
+            .add(Route {
+                metric: 20,
+                ..route
+            })
+            .unwrap();
+
+        let off_link = Route::new(
+            v4_cidr(10, 81, 0, 0, 16),
+            Some(v4(10, 62, 2, 1)),
+            a.nic_id(),
+            0,
+        );
+        assert_eq!(table.add(off_link), Err(Errno::ENETUNREACH));
+        let v6_gateway = Some(IpAddress::Ipv6(Ipv6Address::LOCALHOST));
+        let mixed = Route::new(v4_cidr(10, 81, 0, 0, 16), v6_gateway, a.nic_id(), 0);
+        assert_eq!(table.add(mixed), Err(Errno::EINVAL));
+        // an on-link route goes through an address of the iface in smoltcp
+        let on_link = Route::new(v4_cidr(10, 81, 0, 0, 16), None, a.nic_id(), 0);
+        table.add(on_link).unwrap();
+        let hop = table.lookup(&v4(10, 81, 7, 7)).unwrap();
+        assert_eq!(
+            (hop.iface.nic_id(), hop.gateway, hop.src),
+            (a.nic_id(), None, v4(10, 62, 1, 2))
+        );
+        let via = |iface: &Arc<dyn Iface>| {
+            let mut via = None;
+            // smoltcp only lets the routes be read through an update
+            iface.smol_iface().lock().routes_mut().update(|storage| {
+                via = storage
+                    .iter()
+                    .find(|route| route.cidr == v4_cidr(10, 81, 0, 0, 16))
+                    .map(|route| route.via_router);
+            });
+            via
+        };
+        assert_eq!(via(&a), Some(v4(10, 62, 1, 2)));
+        // and follows the addresses of the iface
+        a.update_ip_addrs(&[v4_cidr(10, 62, 1, 3, 24)]).unwrap();
+        assert_eq!(via(&a), Some(v4(10, 62, 1, 3)));
+        table.delete(&on_link).unwrap();
+        assert_eq!(via(&a), None);
+        assert_eq!(
+            table.add(Route {
+                ifindex: usize::MAX,
+                ..route
+            }),
+            Err(Errno::ENODEV)
+        );
+
+        // `delete` takes the exact route, `remove` any to the network
+        assert_eq!(
+            table.delete(&Route {
+                metric: 30,
+                ..route
+            }),
+            Err(Errno::ESRCH)
+        );
+        table
+            .delete(&Route {
+                metric: 20,
+                ..route
+            })
+            .unwrap();
+        let removed = table.remove(v4_cidr(10, 80, 9, 9, 16), None).unwrap();
+        assert_eq!(removed.metric, 10);
+        assert_eq!(
+            table.remove(v4_cidr(10, 80, 0, 0, 16), None),
+            Err(Errno::ESRCH)
+        );
+
+        // unregistering an iface drops its routes
+        table.add(route).unwrap();
+        table.remove_iface(a.as_ref());
+        assert!(routes_of(&[&a, &b]).is_empty());
+
+        unregister(&[&a, &b]);
+    }
+}
@@ -1,36 +1,198 @@
-use std::{ops::DerefMut, os::fd::AsRawFd, sync::Arc, time::Instant};
+use std::{
+    ops::DerefMut,
+    os::fd::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
+use linux_errnos::Errno;
 use smoltcp::{
     iface::{Config, Interface},
+    phy::{Device, DeviceCapabilities, Medium},
+    time::Instant,
     wire::HardwareAddress,
 };
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 
-use crate::driver::tap::TapDevice;
+use crate::driver::tap::{self, TapDevice};
 
 use super::{Iface, IfaceCommon};
 
+/// Brings the link of a TUN/TAP device up or down, the state of `common` follows.
+pub(super) fn set_link_up(
+    common: &IfaceCommon,
+    device: &Mutex<TapDevice>,
+    up: bool,
+) -> Result<(), Errno> {
+    device
+        .lock()
+        .set_up(up)
+        .map_err(|err| Errno::from_io_error(err).unwrap_or(Errno::EIO))?;
+    common.set_admin_up(up);
+    update_carrier(common, device);
+    Ok(())
+}
+
+/// Reads the carrier of a TUN/TAP device from its `IFF_UP` and `IFF_RUNNING`
+/// flags. A link brought down outside of us shows up as a failed write first,
+/// its sockets then see `ENETDOWN` until it is back.
+pub(super) fn update_carrier(common: &IfaceCommon, device: &Mutex<TapDevice>) {
+    match device.lock().link_flags() {
+        Ok(Some(flags)) => {
+            let running = libc::IFF_UP | libc::IFF_RUNNING;
+            common.set_carrier(flags & running == running);
+        }
+        Ok(None) => {}
+        Err(err) => {
+            log::debug!("failed to read the link flags: {}", err);
+            common.set_carrier(false);
+        }
+    }
+}
+
+/// Changes the IP MTU of every queue of a TUN/TAP device and of `common`.
+pub(super) fn set_link_mtu(
+    common: &IfaceCommon,
+    queues: &[Arc<Mutex<TapDevice>>],
+    mtu: usize,
+) -> Result<(), Errno> {
+    super::check_mtu(mtu)?;
+    for queue in queues {
+        queue
+            .lock()
+            .set_mtu(mtu)
+            .map_err(|err| Errno::from_io_error(err).unwrap_or(Errno::EIO))?;
+    }
+    common.update_capabilities(queues[0].lock().deref_mut());
+    Ok(())
+}
+
+/// The queues of a TAP device locked for one poll of the interface. Frames are
+/// received from each queue in turn, the replies to a frame leave through its
+/// queue and the other frames through the first one.
+struct QueueSet<'q> {
+    queues: Vec<MutexGuard<'q, TapDevice>>,
+    /// the queue received from first next time
+    next: usize,
+}
+
+impl Device for QueueSet<'_> {
+    type RxToken<'a>
+        = tap::RxToken<'a>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = tap::TxToken<'a>
+    where
+        Self: 'a;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.queues[0].capabilities()
+    }
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let count = self.queues.len();
+        let queue = (0..count)
+            .map(|offset| (self.next + offset) % count)
+            .find(|&queue| self.queues[queue].has_rx())?;
+        self.next = (queue + 1) % count;
+        self.queues[queue].receive(timestamp)
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        self.queues[0].transmit(timestamp)
+    }
+}
+
 #[derive(Debug)]
 pub struct TapIface {
-    pub inner: Arc<Mutex<TapDevice>>,
+    /// 网卡的队列，单队列的网卡只有一个
+    pub queues: Vec<Arc<Mutex<TapDevice>>>,
     pub common: IfaceCommon,
+    /// 有帧等待接收、要在下次轮询中处理的队列
+    pending: Vec<AtomicBool>,
 }
 
 // #[derive(Debug)]
 // pub struct TapIface (Arc<Mutex<TapIfaceInner>>);
 
 impl TapIface {
-    pub fn new(inner: Arc<Mutex<TapDevice>>) -> Self {
-        let mut iface_config = Config::new(HardwareAddress::Ethernet(inner.lock().mac()));
-        iface_config.random_seed = std::random::random();
+    /// Fails with `EINVAL` unless `inner` is a TAP device, use `TunIface` for TUN.
+    pub fn new(inner: Arc<Mutex<TapDevice>>) -> Result<Self, Errno> {
+        Self::with_queues(vec![inner])
+    }
+
+    /// An interface over the queues of a multi-queue TAP device, see
+    /// [`TapDevice::multi_queue`]. Each queue can be polled by a worker of its
+    /// own, which reads its frames ahead. The protocol stack is run by one
+    /// worker at a time, receiving the frames of every queue read meanwhile.
+    /// Fails with `EINVAL` without queues or when a queue is not a TAP device.
+    pub fn with_queues(queues: Vec<Arc<Mutex<TapDevice>>>) -> Result<Self, Errno> {
+        let inner = queues.first().ok_or(Errno::EINVAL)?;
+        // `mac` is only known for TAP devices
+        if queues.iter().any(|queue| queue.lock().mac().is_none()) {
+            return Err(Errno::EINVAL);
+        }
+        let mac = inner.lock().mac().ok_or(Errno::EINVAL)?;
+        let mut iface_config = Config::new(HardwareAddress::Ethernet(mac));
+        iface_config.random_seed = std::random::random(..);
 
         let iface = Interface::new(
             iface_config,
             inner.lock().deref_mut(),
-            Instant::now().into(),
+            smoltcp::time::Instant::now(),
         );
-        let common = IfaceCommon::new(1, true, iface);
-        TapIface { inner, common }
+        let name = inner.lock().name().unwrap_or("tap%d").to_string();
+        let common = IfaceCommon::new(&name, Medium::Ethernet, iface);
+        let pending = queues.iter().map(|_| AtomicBool::new(false)).collect();
+        Ok(TapIface {
+            queues,
+            common,
+            pending,
+        })
+    }
+}
+
+impl TapIface {
+    /// Polls the protocol stack with the frames of every pending queue. The
+    /// stack is run by one thread at a time, the others wait for it in
+    /// [`IfaceCommon::poll`]. Each thread takes the queues it finds marked, so
+    /// the threads lock different queues.
+    fn poll_pending(&self) {
+        // a queue marked during a poll is taken in the next round
+        loop {
+            let queues: Vec<usize> = (0..self.queues.len())
+                .filter(|&queue| self.pending[queue].swap(false, Ordering::SeqCst))
+                .collect();
+            if queues.is_empty() {
+                return;
+            }
+            let mut set = QueueSet {
+                queues: queues
+                    .iter()
+                    .map(|&queue| self.queues[queue].lock())
+                    .collect(),
+                next: 0,
+            };
+            self.common.poll(&mut set);
+            let statuses: Vec<_> = set
+                .queues
+                .iter_mut()
+                .map(|device| {
+                    device.flush();
+                    device.take_io_status()
+                })
+                .collect();
+            // sockets woken by a link change may poll this iface again
+            drop(set);
+            for (&queue, status) in queues.iter().zip(statuses) {
+                if self.common.update_io_status(status) {
+                    update_carrier(&self.common, &self.queues[queue]);
+                }
+            }
+        }
     }
 }
 
@@ -43,24 +205,237 @@ impl Iface for TapIface {
     }
 
     fn mac(&self) -> smoltcp::wire::EthernetAddress {
-        self.inner.lock().mac()
+        self.queues[0].lock().mac().unwrap()
+    }
+
+    fn add_static_neighbor(
+        &self,
+        ip: smoltcp::wire::IpAddress,
+        mac: smoltcp::wire::EthernetAddress,
+    ) -> Result<(), Errno> {
+        self.common.add_static_neighbor(ip, mac)
+    }
+
+    fn set_mac(&self, mac: smoltcp::wire::EthernetAddress) -> Result<(), Errno> {
+        for queue in self.queues.iter() {
+            queue
+                .lock()
+                .set_mac(mac)
+                .map_err(|err| Errno::from_io_error(err).unwrap_or(Errno::EIO))?;
+        }
+        self.common.update_hardware_addr(mac);
+        Ok(())
     }
 
-    // fn iface_name(&self) -> String {
-    //     "tap0".to_string()
-    // }
+    fn set_up(&self, up: bool) -> Result<(), Errno> {
+        set_link_up(&self.common, &self.queues[0], up)
+    }
 
-    // fn nic_id(&self) -> usize {
-    //     self.inner.nic_id()
-    // }
+    fn mtu(&self) -> usize {
+        self.queues[0].lock().capabilities().ip_mtu()
+    }
+
+    fn set_mtu(&self, mtu: usize) -> Result<(), Errno> {
+        set_link_mtu(&self.common, &self.queues, mtu)
+    }
 
     fn poll(&self) {
-        let mut guard = self.inner.lock();
-        let reference = guard.deref_mut();
-        self.common.poll(reference);
+        for pending in self.pending.iter() {
+            pending.store(true, Ordering::SeqCst);
+        }
+        self.poll_pending();
+    }
+
+    fn poll_queue(&self, queue: usize) {
+        // the reads of each queue happen outside of the protocol stack
+        self.queues[queue].lock().prefetch();
+        self.pending[queue].store(true, Ordering::SeqCst);
+        self.poll_pending();
     }
 
     fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
-        Some(self.inner.lock().as_raw_fd())
+        Some(self.queues[0].lock().as_raw_fd())
+    }
+
+    fn queue_fds(&self) -> Vec<std::os::unix::io::RawFd> {
+        self.queues
+            .iter()
+            .map(|queue| queue.lock().as_raw_fd())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    use smoltcp::{
+        phy::{RxToken, TxToken},
+        wire::{
+            ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+            EthernetRepr, IpAddress, IpCidr, Ipv4Address,
+        },
+    };
+
+    use super::*;
+
+    /// A queue over a socketpair, the other end plays the kernel
+    fn queue(medium: Medium) -> (Arc<Mutex<TapDevice>>, OwnedFd) {
+        let mut fds = [0; 2];
+        let res = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(res, 0);
+        let (fd, peer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let device = TapDevice::from_fd(fd, medium, 1500, None).unwrap();
+        (Arc::new(Mutex::new(device)), peer)
+    }
+
+    fn write(peer: &OwnedFd, frame: &[u8]) {
+        let sent = unsafe { libc::write(peer.as_raw_fd(), frame.as_ptr() as _, frame.len()) };
+        assert_eq!(sent as usize, frame.len());
+    }
+
+    fn read(peer: &OwnedFd) -> Option<Vec<u8>> {
+        let mut buffer = [0; 64];
+        let len = unsafe {
+            libc::recv(
+                peer.as_raw_fd(),
+                buffer.as_mut_ptr() as _,
+                buffer.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        (len >= 0).then(|| buffer[..len as usize].to_vec())
+    }
+
+    #[test]
+    fn queue_set_receives_from_each_queue_in_turn() {
+        let (queues, peers): (Vec<_>, Vec<_>) = (0..3).map(|_| queue(Medium::Ethernet)).unzip();
+        write(&peers[0], b"a1");
+        write(&peers[0], b"a2");
+        write(&peers[2], b"c1");
+        let mut set = QueueSet {
+            queues: queues.iter().map(|queue| queue.lock()).collect(),
+            next: 0,
+        };
+
+        let mut received = Vec::new();
+        while let Some((rx, tx)) = set.receive(Instant::ZERO) {
+            let frame = rx.consume(|frame| frame.to_vec());
+            // the reply leaves through the queue of the frame
+            tx.consume(frame.len(), |reply| reply.copy_from_slice(&frame));
+            received.push(frame);
+        }
+        assert_eq!(received, [&b"a1"[..], b"c1", b"a2"]);
+        assert_eq!(read(&peers[0]).unwrap(), b"a1");
+        assert_eq!(read(&peers[0]).unwrap(), b"a2");
+        assert_eq!(read(&peers[1]), None);
+        assert_eq!(read(&peers[2]).unwrap(), b"c1");
+
+        // the other frames leave through the first queue
+        let tx = set.transmit(Instant::ZERO).unwrap();
+        tx.consume(2, |frame| frame.copy_from_slice(b"tx"));
+        assert_eq!(read(&peers[0]).unwrap(), b"tx");
+    }
+
+    #[test]
+    fn tap_iface_needs_tap_queues() {
+        assert_eq!(TapIface::with_queues(Vec::new()).err(), Some(Errno::EINVAL));
+        let (tap, _tap_peer) = queue(Medium::Ethernet);
+        let (tun, _tun_peer) = queue(Medium::Ip);
+        assert_eq!(
+            TapIface::with_queues(vec![tap, tun]).err(),
+            Some(Errno::EINVAL)
+        );
+    }
+
+    /// An ARP request from host `host` of 10.67.0.0/24 to 10.67.0.1
+    fn arp_request(host: u8) -> Vec<u8> {
+        let mac = EthernetAddress([2, 0, 0, 0, 0, host]);
+        let arp = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: mac,
+            source_protocol_addr: Ipv4Address::new(10, 67, 0, host),
+            target_hardware_addr: EthernetAddress::default(),
+            target_protocol_addr: Ipv4Address::new(10, 67, 0, 1),
+        };
+        let mut frame = vec![0; EthernetFrame::<&[u8]>::buffer_len(arp.buffer_len())];
+        let mut ethernet = EthernetFrame::new_unchecked(&mut frame[..]);
+        EthernetRepr {
+            src_addr: mac,
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Arp,
+        }
+        .emit(&mut ethernet);
+        arp.emit(&mut ArpPacket::new_unchecked(ethernet.payload_mut()));
+        frame
+    }
+
+    #[test]
+    fn one_poll_answers_every_queue() {
+        let (queues, peers): (Vec<_>, Vec<_>) = (0..2).map(|_| queue(Medium::Ethernet)).unzip();
+        let iface = TapIface::with_queues(queues).unwrap();
+        let local = Ipv4Address::new(10, 67, 0, 1);
+        iface
+            .common
+            .update_ip_addrs(&[IpCidr::new(IpAddress::Ipv4(local), 24)])
+            .unwrap();
+
+        for (i, peer) in peers.iter().enumerate() {
+            write(peer, &arp_request(i as u8 + 2));
+        }
+        iface.poll();
+
+        // each request is answered through its own queue
+        for (i, peer) in peers.iter().enumerate() {
+            let reply = read(peer).expect("no ARP reply");
+            let ethernet = EthernetFrame::new_checked(&reply[..]).unwrap();
+            assert_eq!(
+                ethernet.dst_addr(),
+                EthernetAddress([2, 0, 0, 0, 0, i as u8 + 2])
+            );
+            let arp = ArpRepr::parse(&ArpPacket::new_checked(ethernet.payload()).unwrap());
+            assert!(matches!(
+                arp,
+                Ok(ArpRepr::EthernetIpv4 {
+                    operation: ArpOperation::Reply,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn queues_polled_at_once_are_all_answered() {
+        let (queues, peers): (Vec<_>, Vec<_>) = (0..4).map(|_| queue(Medium::Ethernet)).unzip();
+        let iface = TapIface::with_queues(queues).unwrap();
+        iface
+            .common
+            .update_ip_addrs(&[IpCidr::new(IpAddress::v4(10, 67, 0, 1), 24)])
+            .unwrap();
+
+        // each worker blocks until the stack is free, its queue is received
+        // from by itself or by the worker polling meanwhile
+        std::thread::scope(|scope| {
+            for (i, peer) in peers.iter().enumerate() {
+                let iface = &iface;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        write(peer, &arp_request(i as u8 + 2));
+                        iface.poll_queue(i);
+                    }
+                });
+            }
+        });
+        for peer in &peers {
+            let replies = std::iter::from_fn(|| read(peer)).count();
+            assert_eq!(replies, 20);
+        }
     }
 }
@@ -0,0 +1,83 @@
+use std::{ops::DerefMut, os::fd::AsRawFd, sync::Arc};
+
+use linux_errnos::Errno;
+use smoltcp::{
+    iface::{Config, Interface},
+    phy::{Device, Medium},
+    wire::HardwareAddress,
+};
+use spin::Mutex;
+
+use crate::driver::tap::TapDevice;
+
+use super::{
+    tap::{set_link_mtu, set_link_up, update_carrier},
+    Iface, IfaceCommon,
+};
+
+/// A layer 3 interface backed by a TUN device, it has no MAC address and
+/// exchanges bare IP packets.
+#[derive(Debug)]
+pub struct TunIface {
+    pub inner: Arc<Mutex<TapDevice>>,
+    pub common: IfaceCommon,
+}
+
+impl TunIface {
+    /// Fails with `EINVAL` unless `inner` is a TUN device, use `TapIface` for TAP.
+    pub fn new(inner: Arc<Mutex<TapDevice>>) -> Result<Self, Errno> {
+        if inner.lock().medium() != Medium::Ip {
+            return Err(Errno::EINVAL);
+        }
+        let mut iface_config = Config::new(HardwareAddress::Ip);
+        iface_config.random_seed = std::random::random(..);
+
+        let iface = Interface::new(
+            iface_config,
+            inner.lock().deref_mut(),
+            smoltcp::time::Instant::now(),
+        );
+        let name = inner.lock().name().unwrap_or("tun%d").to_string();
+        let common = IfaceCommon::new(&name, Medium::Ip, iface);
+        Ok(TunIface { inner, common })
+    }
+}
+
+impl Iface for TunIface {
+    fn common(&self) -> &IfaceCommon {
+        &self.common
+    }
+
+    fn mac(&self) -> smoltcp::wire::EthernetAddress {
+        smoltcp::wire::EthernetAddress([0, 0, 0, 0, 0, 0])
+    }
+
+    fn set_up(&self, up: bool) -> Result<(), Errno> {
+        set_link_up(&self.common, &self.inner, up)
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.lock().capabilities().ip_mtu()
+    }
+
+    fn set_mtu(&self, mtu: usize) -> Result<(), Errno> {
+        set_link_mtu(&self.common, std::slice::from_ref(&self.inner), mtu)
+    }
+
+    fn poll(&self) {
+        let mut guard = self.inner.lock();
+        let reference = guard.deref_mut();
+        self.common.poll(reference);
+        guard.flush();
+        let status = guard.take_io_status();
+        // sockets woken by a link change may poll this iface again
+        drop(guard);
+        if self.common.update_io_status(status) {
+            update_carrier(&self.common, &self.inner);
+        }
+    }
+
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(self.inner.lock().as_raw_fd())
+    }
+}
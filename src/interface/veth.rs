@@ -0,0 +1,81 @@
+use std::{ops::DerefMut, os::fd::AsRawFd};
+
+use linux_errnos::Errno;
+use smoltcp::{
+    iface::{Config, Interface},
+    phy::Medium,
+    wire::HardwareAddress,
+};
+use spin::Mutex;
+
+use crate::driver::veth::VethDevice;
+
+use super::{Iface, IfaceCommon};
+
+/// An interface backed by one end of a [`veth_pair`](crate::driver::veth::veth_pair).
+#[derive(Debug)]
+pub struct VethIface {
+    pub inner: Mutex<VethDevice>,
+    pub common: IfaceCommon,
+}
+
+impl VethIface {
+    pub fn new(mut device: VethDevice) -> Self {
+        let mut iface_config = Config::new(HardwareAddress::Ethernet(device.mac()));
+        iface_config.random_seed = std::random::random(..);
+
+        let iface = Interface::new(iface_config, &mut device, smoltcp::time::Instant::now());
+        let common = IfaceCommon::new("veth%d", Medium::Ethernet, iface);
+        VethIface {
+            inner: Mutex::new(device),
+            common,
+        }
+    }
+}
+
+impl Iface for VethIface {
+    fn common(&self) -> &IfaceCommon {
+        &self.common
+    }
+
+    fn mac(&self) -> smoltcp::wire::EthernetAddress {
+        self.inner.lock().mac()
+    }
+
+    fn add_static_neighbor(
+        &self,
+        ip: smoltcp::wire::IpAddress,
+        mac: smoltcp::wire::EthernetAddress,
+    ) -> Result<(), Errno> {
+        self.common.add_static_neighbor(ip, mac)
+    }
+
+    fn set_mac(&self, mac: smoltcp::wire::EthernetAddress) -> Result<(), Errno> {
+        self.inner.lock().set_mac(mac);
+        self.common.update_hardware_addr(mac);
+        Ok(())
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.lock().ip_mtu()
+    }
+
+    fn set_mtu(&self, mtu: usize) -> Result<(), Errno> {
+        super::check_mtu(mtu)?;
+        let mut guard = self.inner.lock();
+        guard
+            .set_mtu(mtu)
+            .map_err(|err| Errno::from_io_error(err).unwrap_or(Errno::EIO))?;
+        self.common.update_capabilities(guard.deref_mut());
+        Ok(())
+    }
+
+    fn poll(&self) {
+        let mut guard = self.inner.lock();
+        self.common.poll(guard.deref_mut());
+    }
+
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(self.inner.lock().as_raw_fd())
+    }
+}
@@ -1,23 +1,33 @@
-use std::{io::{self, Read}, net::Ipv4Addr, sync::Arc};
+use std::{io::{self, Read}, net::Ipv4Addr, sync::Arc, time::{Duration, Instant}};
 
 use berkeley_socket::{
     driver::{irq::start_network_polling_thread, tap::TapDevice},
-    interface::{tap::TapIface, Iface},
-    posix::SOCK,
+    interface::{
+        dhcp::DhcpConfig,
+        registry::NET_DEVICES,
+        route::{Route, ROUTES},
+        tap::TapIface,
+        Iface,
+    },
+    posix::{PMSG, SOCK},
     socket::{
         endpoint::Endpoint,
-        inet::{common::NET_DEVICES, syscall::Inet},
+        inet::{
+            dns::{getaddrinfo, AddrInfoHints},
+            syscall::Inet,
+            table::{socket_table, SocketInfo},
+        },
         Family,
     },
 };
-use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint, Ipv4Cidr};
+use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint, IpVersion, Ipv4Cidr};
 use spin::Mutex;
 
 fn make_udp_echo() {
     let socket = Inet::socket(SOCK::Datagram, 0).unwrap();
     socket
         .bind(Endpoint::Ip(IpEndpoint::new(
-            IpAddress::v4(192, 168, 213, 2),
+            IpAddress::v4(0, 0, 0, 0),
             1234,
         )))
         .unwrap();
@@ -49,7 +59,7 @@ fn make_tcp_echo() {
     let socket = Inet::socket(SOCK::Stream, 0).unwrap();
     socket
         .bind(Endpoint::Ip(IpEndpoint::new(
-            IpAddress::v4(192, 168, 213, 2),
+            IpAddress::v4(0, 0, 0, 0),
             4321,
         )))
         .unwrap();
@@ -81,29 +91,26 @@ fn make_tcp_echo() {
 }
 
 fn make_request() {
-    log::info!("Input a valid IP address and port to connect to:");
+    log::info!("Input a valid host and port to connect to:");
     let mut input = String::new();
     io::stdin().read_line(&mut input).unwrap();
-    let parts: Vec<&str> = input.trim().split(':').collect();
-    if parts.len() != 2 {
-        log::error!("Invalid input format. Use <IP>:<port>.");
+    let Some((host, port)) = input.trim().rsplit_once(':') else {
+        log::error!("Invalid input format. Use <host>:<port>.");
         return;
-    }
-    let ip: Ipv4Addr = match parts[0].parse() {
-        Ok(ip) => ip,
-        Err(_) => {
-            log::error!("Invalid IP address.");
-            return;
-        }
     };
-    let port: u16 = match parts[1].parse() {
-        Ok(port) => port,
-        Err(_) => {
-            log::error!("Invalid port number.");
+    let hints = AddrInfoHints {
+        family: Some(IpVersion::Ipv4),
+        socket_type: Some(SOCK::Stream),
+        ..Default::default()
+    };
+    let (ip, port) = match getaddrinfo(Some(host), Some(port), &hints) {
+        Ok(infos) => (infos[0].endpoint.addr, infos[0].endpoint.port),
+        Err(e) => {
+            log::error!("Failed to resolve {}: {:?}", input.trim(), e);
             return;
         }
     };
-    let endpoint = Endpoint::Ip(IpEndpoint::new(IpAddress::Ipv4(Ipv4Addr::from(ip)), port));
+    let endpoint = Endpoint::Ip(IpEndpoint::new(ip, port));
 
     let socket = Inet::socket(SOCK::Stream, 0).unwrap();
     match socket.connect(endpoint) {
@@ -143,22 +150,124 @@ fn make_request() {
     log::info!("Connection closed.");
 }
 
+/// Sends 4 echo requests over an ICMP socket, waiting a second for each reply
+fn make_ping() {
+    log::info!("Input a host to ping:");
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).unwrap();
+    let hints = AddrInfoHints {
+        family: Some(IpVersion::Ipv4),
+        socket_type: Some(SOCK::Datagram),
+        ..Default::default()
+    };
+    let ip = match getaddrinfo(Some(input.trim()), None, &hints) {
+        Ok(infos) => infos[0].endpoint.addr,
+        Err(e) => {
+            log::error!("Failed to resolve {}: {:?}", input.trim(), e);
+            return;
+        }
+    };
+
+    let socket = Inet::socket(SOCK::Datagram, libc::IPPROTO_ICMP as u32).unwrap();
+    for seq in 1..=4u16 {
+        // echo request, the stack fills in the identifier and the checksum
+        let mut request = vec![8, 0, 0, 0, 0, 0];
+        request.extend_from_slice(&seq.to_be_bytes());
+        request.extend_from_slice(&[0x42; 56]);
+        let sent_at = Instant::now();
+        let endpoint = Endpoint::Ip(IpEndpoint::new(ip, 0));
+        if let Err(e) = socket.send_to(&request, PMSG::empty(), endpoint) {
+            log::error!("Failed to send to {}: {:?}", ip, e);
+            break;
+        }
+        let mut reply = [0u8; 128];
+        loop {
+            match socket.recv(&mut reply, PMSG::DONTWAIT) {
+                Ok(len) if len >= 8 && reply[6..8] == seq.to_be_bytes() => {
+                    println!(
+                        "{} bytes from {}: icmp_seq={} time={:?}",
+                        len,
+                        ip,
+                        seq,
+                        sent_at.elapsed()
+                    );
+                    break;
+                }
+                Ok(_) => {}
+                Err(linux_errnos::Errno::EAGAIN) if sent_at.elapsed() < Duration::from_secs(1) => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(linux_errnos::Errno::EAGAIN) => {
+                    println!("no reply from {}: icmp_seq={}", ip, seq);
+                    break;
+                }
+                Err(e) => {
+                    log::error!("Failed to receive: {:?}", e);
+                    return;
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_secs(1).saturating_sub(sent_at.elapsed()));
+    }
+    let _ = socket.close();
+}
+
+fn dump_sockets() {
+    println!("{}", SocketInfo::HEADER);
+    for socket in socket_table() {
+        println!("{}", socket);
+    }
+}
+
+fn dump_dhcp_lease(iface: &dyn Iface) {
+    match iface.dhcp_lease() {
+        Some(lease) => println!(
+            "{} via {:?} dns {:?} from {} ({:?}, expires at {})",
+            lease.address,
+            lease.router,
+            lease.dns_servers,
+            lease.server,
+            lease.state(smoltcp::time::Instant::now()),
+            lease.expires_at
+        ),
+        None => println!("no DHCP lease"),
+    }
+}
+
 fn main() {
     env_logger::init();
+    // `--static` keeps the fixed address of the bridge setup in README instead of DHCP
+    let use_static = std::env::args().any(|arg| arg == "--static");
     let device = TapDevice::new("tap0", smoltcp::phy::Medium::Ethernet).unwrap();
-    let iface_inner = TapIface::new(Arc::new(Mutex::new(device)));
+    #[allow(clippy::arc_with_non_send_sync)]
+    let iface_inner = TapIface::new(Arc::new(Mutex::new(device))).unwrap();
 
-    let ip_cidr = IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Addr::new(192, 168, 213, 2), 24));
+    if use_static {
+        let ip_cidr = IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Addr::new(192, 168, 213, 2), 24));
 
-    let ip_cidr = vec![ip_cidr];
+        let ip_cidr = vec![ip_cidr];
 
-    iface_inner.update_ip_addrs(&ip_cidr).unwrap();
+        iface_inner.update_ip_addrs(&ip_cidr).unwrap();
+    }
 
     let iface = Arc::new(iface_inner);
 
-    NET_DEVICES.write().insert(0, iface);
+    let ifindex = NET_DEVICES.register(iface.clone()).unwrap();
+    if use_static {
+        // the bridge of the host is the gateway to other subnets
+        ROUTES
+            .add(Route::default_via(
+                IpAddress::v4(192, 168, 213, 1),
+                ifindex,
+                0,
+            ))
+            .unwrap();
+    } else {
+        // the route to the leased router needs the iface registered first
+        iface.start_dhcp(DhcpConfig::default()).unwrap();
+    }
     scopeguard::defer!({
-        NET_DEVICES.write().clear();
+        let _ = NET_DEVICES.unregister(ifindex);
     });
     let _ = start_network_polling_thread();
 
@@ -170,7 +279,7 @@ fn main() {
     });
 
     loop {
-        let char = io::stdin().bytes().next().unwrap().unwrap();
+        let char = io::stdin().lock().bytes().next().unwrap().unwrap();
         match char {
             b'q' | b'Q' => {
                 log::info!("Exiting...");
@@ -179,8 +288,18 @@ fn main() {
             b'r' => {
                 make_request();
             }
+            b'p' => {
+                make_ping();
+            }
+            b's' => {
+                dump_sockets();
+            }
+            b'd' => {
+                dump_dhcp_lease(iface.as_ref());
+            }
+            b'\n' => {}
             _ => {
-                log::info!("Press 'q' to exit.");
+                log::info!("Press 'r' to connect, 'p' to ping, 's' to list sockets, 'd' to show the DHCP lease, 'q' to exit.");
             }
         }
     }
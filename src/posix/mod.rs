@@ -5,6 +5,7 @@ pub mod family;
 mod msg_flag;
 mod option;
 mod option_level;
+#[allow(clippy::module_inception)]
 pub mod posix;
 mod types;
 
@@ -2,6 +2,12 @@
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Pid(usize);
 
+impl Pid {
+    pub fn data(&self) -> usize {
+        self.0
+    }
+}
+
 pub struct ProcessManager {}
 
 impl ProcessManager {
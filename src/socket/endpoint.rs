@@ -3,12 +3,14 @@
 
 pub use smoltcp::wire::IpEndpoint;
 
+use crate::posix::{family::AddressFamily, posix::SockAddrLl};
+
 // use super::unix::ns::abs::AbsHandle;
 
 #[derive(Debug, Clone)]
 pub enum Endpoint {
-    // /// 链路层端点
-    // LinkLayer(LinkLayerEndpoint),
+    /// 链路层端点
+    LinkLayer(LinkLayerEndpoint),
     /// 网络层端点
     Ip(IpEndpoint),
     Other,
@@ -20,26 +22,77 @@ pub enum Endpoint {
     // Abspath((AbsHandle, String)),
 }
 
-// /// @brief 链路层端点
-// #[derive(Debug, Clone)]
-// pub struct LinkLayerEndpoint {
-//     /// 网卡的接口号
-//     pub interface: usize,
-// }
-
-// impl LinkLayerEndpoint {
-//     /// @brief 创建一个链路层端点
-//     ///
-//     /// @param interface 网卡的接口号
-//     ///
-//     /// @return 返回创建的链路层端点
-//     pub fn new(interface: usize) -> Self {
-//         Self { interface }
-//     }
-// }
+/// @brief 链路层端点，对应 `sockaddr_ll`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LinkLayerEndpoint {
+    /// 网卡的接口号
+    pub interface: usize,
+    /// 以太网协议号，主机字节序
+    pub protocol: u16,
+    /// 链路层类型 `ARPHRD_*`
+    pub hatype: u16,
+    /// 帧的类型 `PACKET_*`
+    pub pkttype: u8,
+    /// 硬件地址的长度
+    pub halen: u8,
+    /// 硬件地址，只有前 `halen` 字节有效
+    pub addr: [u8; 8],
+}
+
+impl LinkLayerEndpoint {
+    /// @brief 创建一个链路层端点
+    ///
+    /// @param interface 网卡的接口号
+    ///
+    /// @return 返回创建的链路层端点
+    pub fn new(interface: usize) -> Self {
+        Self {
+            interface,
+            ..Default::default()
+        }
+    }
+
+    /// @brief 有效的硬件地址
+    pub fn hardware_addr(&self) -> &[u8] {
+        &self.addr[..(self.halen as usize).min(self.addr.len())]
+    }
+}
+
+impl From<SockAddrLl> for LinkLayerEndpoint {
+    fn from(addr: SockAddrLl) -> Self {
+        Self {
+            interface: addr.sll_ifindex as usize,
+            protocol: u16::from_be(addr.sll_protocol),
+            hatype: addr.sll_hatype,
+            pkttype: addr.sll_pkttype,
+            halen: addr.sll_halen,
+            addr: addr.sll_addr,
+        }
+    }
+}
+
+impl From<LinkLayerEndpoint> for SockAddrLl {
+    fn from(endpoint: LinkLayerEndpoint) -> Self {
+        Self {
+            sll_family: AddressFamily::Packet as u16,
+            sll_protocol: endpoint.protocol.to_be(),
+            sll_ifindex: endpoint.interface as u32,
+            sll_hatype: endpoint.hatype,
+            sll_pkttype: endpoint.pkttype,
+            sll_halen: endpoint.halen,
+            sll_addr: endpoint.addr,
+        }
+    }
+}
 
 impl From<IpEndpoint> for Endpoint {
     fn from(endpoint: IpEndpoint) -> Self {
         Self::Ip(endpoint)
     }
 }
+
+impl From<LinkLayerEndpoint> for Endpoint {
+    fn from(endpoint: LinkLayerEndpoint) -> Self {
+        Self::LinkLayer(endpoint)
+    }
+}
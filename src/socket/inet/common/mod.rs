@@ -1,13 +1,15 @@
-use std::collections::BTreeMap;
-
 // use crate::net::{Iface, NET_DEVICES};
+use crate::interface::registry::NET_DEVICES;
+use crate::interface::route::ROUTES;
 use crate::interface::Iface;
 use alloc::sync::Arc;
 
 pub mod port;
 use linux_errnos::Errno as SystemError;
-pub use port::PortManager;
-use spin::RwLock;
+pub use port::{PortFamily, PortManager};
+use smoltcp::wire::{IpAddress, IpEndpoint, IpVersion};
+
+use super::{UNSPECIFIED_LOCAL_ENDPOINT_V4, UNSPECIFIED_LOCAL_ENDPOINT_V6};
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -20,52 +22,161 @@ pub enum Types {
     Dns,
 }
 
-lazy_static::lazy_static! {
-    pub static ref NET_DEVICES: RwLock<BTreeMap<usize, Arc<dyn Iface>>> = RwLock::new(BTreeMap::new());
+/// socket 所属的协议族。没有设置 `IPV6_V6ONLY` 的 IPv6 socket 同时收发 IPv4 数据，
+/// IPv4 地址对用户表示为 IPv4 映射地址 `::ffff:a.b.c.d`，在协议栈中则是 IPv4 地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Domain {
+    pub version: IpVersion,
+    pub v6only: bool,
+}
+
+impl Domain {
+    pub fn new(version: IpVersion) -> Self {
+        Domain {
+            version,
+            v6only: false,
+        }
+    }
+
+    /// 协议族的未指定端点，未绑定的 socket 以它作为本地地址
+    pub fn unspecified(&self) -> IpEndpoint {
+        match self.version {
+            IpVersion::Ipv4 => UNSPECIFIED_LOCAL_ENDPOINT_V4,
+            IpVersion::Ipv6 => UNSPECIFIED_LOCAL_ENDPOINT_V6,
+        }
+    }
+
+    /// # `accepts`
+    /// 协议栈中的地址 `addr` 能否出现在这个 socket 上，用于过滤只绑定端口的
+    /// smoltcp socket 收到的另一协议族的数据
+    pub fn accepts(&self, addr: &IpAddress) -> bool {
+        match (self.version, addr) {
+            (IpVersion::Ipv4, IpAddress::Ipv4(_)) | (IpVersion::Ipv6, IpAddress::Ipv6(_)) => true,
+            (IpVersion::Ipv6, IpAddress::Ipv4(_)) => !self.v6only,
+            (IpVersion::Ipv4, IpAddress::Ipv6(_)) => false,
+        }
+    }
+
+    /// # `to_inner`
+    /// 把用户给出的端点转换为协议栈中的端点，IPv4 映射地址转换为 IPv4 地址
+    /// ## 返回值
+    /// - 端点不属于这个协议族，或设置了 `IPV6_V6ONLY` 时给出 IPv4 映射地址，
+    ///   返回 `Err(SystemError::EAFNOSUPPORT)`
+    pub fn to_inner(&self, endpoint: IpEndpoint) -> Result<IpEndpoint, SystemError> {
+        let addr = match (self.version, endpoint.addr) {
+            (IpVersion::Ipv4, IpAddress::Ipv6(_)) => return Err(SystemError::EAFNOSUPPORT),
+            (_, IpAddress::Ipv6(addr)) => {
+                addr.to_ipv4_mapped().map_or(endpoint.addr, IpAddress::Ipv4)
+            }
+            (_, addr) => addr,
+        };
+        if !self.accepts(&addr) {
+            return Err(SystemError::EAFNOSUPPORT);
+        }
+        Ok(IpEndpoint::new(addr, endpoint.port))
+    }
+
+    /// # `to_user`
+    /// 把协议栈中的端点转换为用户看到的端点，IPv6 socket 上的 IPv4 地址转换为 IPv4 映射地址
+    pub fn to_user(&self, endpoint: IpEndpoint) -> IpEndpoint {
+        match (self.version, endpoint.addr) {
+            (IpVersion::Ipv6, IpAddress::Ipv4(addr)) => {
+                IpEndpoint::new(IpAddress::Ipv6(addr.to_ipv6_mapped()), endpoint.port)
+            }
+            _ => endpoint,
+        }
+    }
+
+    /// # `port_family`
+    /// 绑定到协议栈中的地址 `addr` 后能收到的协议族，决定能与谁共用端口
+    pub fn port_family(&self, addr: &IpAddress) -> PortFamily {
+        match addr {
+            IpAddress::Ipv6(addr) if addr.is_unspecified() && !self.v6only => PortFamily::Both,
+            addr => PortFamily::of(addr),
+        }
+    }
+
+    /// # `set_v6only`
+    /// 设置 `IPV6_V6ONLY`，值是一个 `int`
+    /// ## 返回值
+    /// - 不是 IPv6 socket 时返回 `Err(SystemError::ENOPROTOOPT)`
+    /// - 值短于 `int` 时返回 `Err(SystemError::EINVAL)`
+    pub fn set_v6only(&mut self, val: &[u8]) -> Result<(), SystemError> {
+        if self.version != IpVersion::Ipv6 {
+            return Err(SystemError::ENOPROTOOPT);
+        }
+        let val = val.first_chunk::<4>().ok_or(SystemError::EINVAL)?;
+        self.v6only = i32::from_ne_bytes(*val) != 0;
+        Ok(())
+    }
+
+    /// # `get_v6only`
+    /// 把 `IPV6_V6ONLY` 写入 `value`
+    /// ## 返回值
+    /// - 写入的长度
+    /// - 不是 IPv6 socket 时返回 `Err(SystemError::ENOPROTOOPT)`
+    /// - `value` 短于 `int` 时返回 `Err(SystemError::EINVAL)`
+    pub fn get_v6only(&self, value: &mut [u8]) -> Result<usize, SystemError> {
+        if self.version != IpVersion::Ipv6 {
+            return Err(SystemError::ENOPROTOOPT);
+        }
+        let value = value.first_chunk_mut::<4>().ok_or(SystemError::EINVAL)?;
+        *value = (self.v6only as i32).to_ne_bytes();
+        Ok(value.len())
+    }
 }
 
-/**
- * 目前，以下设计仍然没有考虑多网卡的listen问题，仅只解决了socket在绑定单网卡下的问题。
- */
+/// 绑定后的 socket。绑定具体地址时只在拥有该地址的网卡上有一个 smoltcp socket，
+/// 绑定未指定地址时每个已注册的网卡上各有一个，之后注册的网卡由 [`BoundInner::attach`] 加入
 #[derive(Debug)]
 pub struct BoundInner {
-    handle: smoltcp::iface::SocketHandle,
-    iface: Arc<dyn Iface>,
-    // inner: Vec<(smoltcp::iface::SocketHandle, Arc<dyn Iface>)>
-    // address: smoltcp::wire::IpAddress,
+    /// 每个网卡上的 smoltcp socket，第一个所在的网卡用于分配临时端口。
+    /// 构造时保证不为空，注销网卡也不会移除其中的项，所以总有第一个
+    inner: Vec<(smoltcp::iface::SocketHandle, Arc<dyn Iface>)>,
+    /// 是否绑定到未指定地址
+    any_iface: bool,
+    /// 由 [`BoundInner::bind_port`] 记录，释放端口时使用
+    family: PortFamily,
 }
 
 impl BoundInner {
     /// # `bind`
-    /// 将socket绑定到指定的地址上，置入指定的网络接口中
-    pub fn bind<T>(
-        socket: T,
+    /// 将socket绑定到指定的地址上，置入拥有该地址的网络接口中。
+    /// 地址未指定时在每个已注册的网卡上各置入一个 `new_socket()` 创建的socket
+    /// ## 返回值
+    /// - 没有网卡拥有该地址时返回 `Err(SystemError::ENODEV)`
+    pub fn bind<T, F>(
         // socket_type: Types,
         address: &smoltcp::wire::IpAddress,
+        mut new_socket: F,
     ) -> Result<Self, SystemError>
     where
         T: smoltcp::socket::AnySocket<'static>,
+        F: FnMut() -> T,
     {
         if address.is_unspecified() {
-            // 强绑VirtualIO
-            let iface = NET_DEVICES
-                .read()
-                .iter()
-                .find_map(|(_, v)| {
-                    if v.common().is_default_iface() {
-                        Some(v.clone())
-                    } else {
-                        None
-                    }
+            // the loopback is always registered, there is a first iface
+            let inner = NET_DEVICES
+                .ifaces()
+                .into_iter()
+                .map(|iface| {
+                    let handle = iface.sockets().lock().add(new_socket());
+                    (handle, iface)
                 })
-                .expect("No default interface");
-
-            let handle = iface.sockets().lock().add(socket);
-            Ok(Self { handle, iface })
+                .collect();
+            Ok(Self {
+                inner,
+                any_iface: true,
+                family: PortFamily::Both,
+            })
         } else {
             let iface = get_iface_to_bind(address).ok_or(SystemError::ENODEV)?;
-            let handle = iface.sockets().lock().add(socket);
-            Ok(Self { handle, iface })
+            let handle = iface.sockets().lock().add(new_socket());
+            Ok(Self {
+                inner: vec![(handle, iface)],
+                any_iface: false,
+                family: PortFamily::Both,
+            })
         }
     }
 
@@ -77,80 +188,290 @@ impl BoundInner {
     where
         T: smoltcp::socket::AnySocket<'static>,
     {
-        let (iface, address) = get_ephemeral_iface(&remote);
+        // the routing table picks the egress iface and the source address
+        let next_hop = ROUTES.lookup(&remote).ok_or(SystemError::ENETUNREACH)?;
+        let (iface, address) = (next_hop.iface, next_hop.src);
         log::debug!("bind_ephemeral address: {}", address);
         // let bound_port = iface.port_manager().bind_ephemeral_port(socket_type)?;
         let handle = iface.sockets().lock().add(socket);
         // let endpoint = smoltcp::wire::IpEndpoint::new(local_addr, bound_port);
-        Ok((Self { handle, iface }, address))
+        Ok((
+            Self {
+                inner: vec![(handle, iface)],
+                any_iface: false,
+                family: PortFamily::Both,
+            },
+            address,
+        ))
+    }
+
+    /// # `should_attach`
+    /// 绑定到未指定地址且在 `iface` 上还没有socket时返回 `true`
+    pub fn should_attach(&self, iface: &Arc<dyn Iface>) -> bool {
+        self.any_iface
+            && !self
+                .inner
+                .iter()
+                .any(|(_, other)| Arc::ptr_eq(other, iface))
+    }
+
+    /// # `attach`
+    /// 把 `socket` 置入新注册的网卡 `iface`，调用前先用 [`BoundInner::should_attach`] 检查
+    pub fn attach<T>(&mut self, iface: &Arc<dyn Iface>, socket: T)
+    where
+        T: smoltcp::socket::AnySocket<'static>,
+    {
+        let handle = iface.sockets().lock().add(socket);
+        self.inner.push((handle, iface.clone()));
+    }
+
+    /// # `split_off`
+    /// 把第一个满足 `f` 的socket分离为只在其网卡上的 `BoundInner`，原位置换为 `socket`
+    /// ## 返回值
+    /// - 没有满足 `f` 的socket时返回 `None`，`socket` 被丢弃
+    pub fn split_off<T, F>(&mut self, f: F, socket: T) -> Option<Self>
+    where
+        T: smoltcp::socket::AnySocket<'static>,
+        F: Fn(&T) -> bool,
+    {
+        let index = self
+            .inner
+            .iter()
+            .position(|(handle, iface)| f(iface.sockets().lock().get::<T>(*handle)))?;
+        let iface = self.inner[index].1.clone();
+        let handle = iface.sockets().lock().add(socket);
+        let (split, _) = core::mem::replace(&mut self.inner[index], (handle, iface.clone()));
+        Some(Self {
+            inner: vec![(split, iface)],
+            any_iface: false,
+            family: self.family,
+        })
+    }
+
+    /// # `bind_port`
+    /// 在每个网卡的端口管理器中为 `family` 占用 `port`，`port` 为 0 时不占用
+    /// ## 返回值
+    /// - 端口在某个网卡上已被相交的协议族占用时返回 `Err(SystemError::EADDRINUSE)`，
+    ///   已占用的会被释放
+    pub fn bind_port(
+        &mut self,
+        socket_type: Types,
+        port: u16,
+        family: PortFamily,
+    ) -> Result<(), SystemError> {
+        for (index, (_, iface)) in self.inner.iter().enumerate() {
+            if let Err(err) = iface.port_manager().bind_port(socket_type, port, family) {
+                for (_, iface) in &self.inner[..index] {
+                    iface.port_manager().unbind_port(socket_type, port, family);
+                }
+                return Err(err);
+            }
+        }
+        self.family = family;
+        Ok(())
+    }
+
+    /// # `bind_ephemeral_port`
+    /// 分配一个在每个网卡上都未被占用的动态端口
+    /// ## 返回值
+    /// - 动态端口均已被占用时返回 `Err(SystemError::EADDRINUSE)`
+    pub fn bind_ephemeral_port(
+        &mut self,
+        socket_type: Types,
+        family: PortFamily,
+    ) -> Result<u16, SystemError> {
+        for _ in 0..(65536 - 49152) {
+            let port = self.port_manager().get_ephemeral_port(socket_type)?;
+            match self.bind_port(socket_type, port, family) {
+                Err(SystemError::EADDRINUSE) => continue,
+                result => return result.map(|_| port),
+            }
+        }
+        Err(SystemError::EADDRINUSE)
+    }
+
+    /// # `unbind_port`
+    /// 在每个网卡的端口管理器中释放 `port`
+    pub fn unbind_port(&self, socket_type: Types, port: u16) {
+        for (_, iface) in self.inner.iter() {
+            iface
+                .port_manager()
+                .unbind_port(socket_type, port, self.family);
+        }
+    }
+
+    /// 占用端口的协议族，新注册的网卡上也按它占用
+    pub fn port_family(&self) -> PortFamily {
+        self.family
     }
 
     pub fn port_manager(&self) -> &PortManager {
-        self.iface.port_manager()
+        self.iface().port_manager()
     }
 
+    /// 对第一个网卡上的socket调用 `f`
     pub fn with_mut<T: smoltcp::socket::AnySocket<'static>, R, F: FnMut(&mut T) -> R>(
         &self,
         mut f: F,
     ) -> R {
-        f(self.iface.sockets().lock().get_mut::<T>(self.handle))
+        let (handle, iface) = &self.inner[0];
+        f(iface.sockets().lock().get_mut::<T>(*handle))
     }
 
+    /// 对第一个网卡上的socket调用 `f`
     pub fn with<T: smoltcp::socket::AnySocket<'static>, R, F: Fn(&T) -> R>(&self, f: F) -> R {
-        f(self.iface.sockets().lock().get::<T>(self.handle))
+        let (handle, iface) = &self.inner[0];
+        f(iface.sockets().lock().get::<T>(*handle))
+    }
+
+    /// # `find_map`
+    /// 依次对每个网卡上的socket调用 `f`，返回第一个 `Some`
+    pub fn find_map<T: smoltcp::socket::AnySocket<'static>, R, F: FnMut(&mut T) -> Option<R>>(
+        &self,
+        mut f: F,
+    ) -> Option<R> {
+        self.inner
+            .iter()
+            .find_map(|(handle, iface)| f(iface.sockets().lock().get_mut::<T>(*handle)))
+    }
+
+    /// # `for_each_iface`
+    /// 对每个网卡上的socket调用 `f`，同时给出所在的网卡
+    pub fn for_each_iface<
+        T: smoltcp::socket::AnySocket<'static>,
+        F: FnMut(&Arc<dyn Iface>, &mut T),
+    >(
+        &self,
+        mut f: F,
+    ) {
+        for (handle, iface) in self.inner.iter() {
+            f(iface, iface.sockets().lock().get_mut::<T>(*handle));
+        }
+    }
+
+    /// # `for_each`
+    /// 对每个网卡上的socket调用 `f`
+    pub fn for_each<T: smoltcp::socket::AnySocket<'static>, F: FnMut(&mut T)>(&self, mut f: F) {
+        for (handle, iface) in self.inner.iter() {
+            f(iface.sockets().lock().get_mut::<T>(*handle));
+        }
+    }
+
+    /// # `with_mut_to`
+    /// 对发往 `remote` 的网卡上的socket调用 `f`
+    /// ## 返回值
+    /// - 绑定到未指定地址且没有到 `remote` 的路由时返回 `None`
+    pub fn with_mut_to<T: smoltcp::socket::AnySocket<'static>, R, F: FnOnce(&mut T) -> R>(
+        &self,
+        remote: &smoltcp::wire::IpAddress,
+        f: F,
+    ) -> Option<R> {
+        let (handle, iface) = self.route(remote)?;
+        Some(f(iface.sockets().lock().get_mut::<T>(*handle)))
+    }
+
+    /// # `iface_to`
+    /// 发往 `remote` 的数据经过的网卡，只有一个网卡时总是它
+    /// ## 返回值
+    /// - 绑定到未指定地址且没有到 `remote` 的路由时返回 `None`
+    pub fn iface_to(&self, remote: &smoltcp::wire::IpAddress) -> Option<&Arc<dyn Iface>> {
+        self.route(remote).map(|(_, iface)| iface)
+    }
+
+    fn route(
+        &self,
+        remote: &smoltcp::wire::IpAddress,
+    ) -> Option<&(smoltcp::iface::SocketHandle, Arc<dyn Iface>)> {
+        if !self.any_iface {
+            return self.inner.first();
+        }
+        let next_hop = ROUTES.lookup(remote)?;
+        self.inner
+            .iter()
+            .find(|(_, iface)| Arc::ptr_eq(iface, &next_hop.iface))
     }
 
+    /// 第一个socket所在的网卡
     pub fn iface(&self) -> &Arc<dyn Iface> {
-        &self.iface
+        &self.inner[0].1
+    }
+
+    /// # `ifaces`
+    /// 有socket的全部网卡
+    pub fn ifaces(&self) -> impl Iterator<Item = &Arc<dyn Iface>> {
+        self.inner.iter().map(|(_, iface)| iface)
+    }
+
+    /// # `poll`
+    /// 轮询有socket的全部网卡
+    pub fn poll(&self) {
+        for iface in self.ifaces() {
+            iface.poll();
+        }
+    }
+
+    /// # `link_error`
+    /// 套接字所依赖的链路的错误：有对端 `remote` 时为通往它的网卡的错误，
+    /// 否则为所在网卡的错误。绑定到未指定地址时，全部网卡都不可用才返回错误
+    pub fn link_error(&self, remote: Option<&smoltcp::wire::IpAddress>) -> Option<SystemError> {
+        if let Some(iface) = remote.and_then(|remote| self.iface_to(remote)) {
+            return iface.link_error();
+        }
+        let mut error = None;
+        for iface in self.ifaces() {
+            error.get_or_insert(iface.link_error()?);
+        }
+        error
+    }
+
+    /// # `is_any_iface`
+    /// 是否绑定到未指定地址
+    pub fn is_any_iface(&self) -> bool {
+        self.any_iface
     }
 
     pub fn release(&self) {
-        self.iface.sockets().lock().remove(self.handle);
+        for (handle, iface) in self.inner.iter() {
+            iface.sockets().lock().remove(*handle);
+        }
+    }
+}
+
+/// 端口被两个协议族的 socket 分别占用时，smoltcp 把数据交给第一个只绑定端口的 socket，
+/// 不管协议族。这个 socket 不接受的数据由 [`forward`] 交给同一网卡上的其它 socket
+#[derive(Debug)]
+pub enum Stray {
+    /// 数据报及其来源和目的端点
+    Datagram {
+        payload: alloc::vec::Vec<u8>,
+        remote: IpEndpoint,
+        local: IpEndpoint,
+    },
+    /// 监听 socket 收到的连接
+    Connection(BoundInner),
+}
+
+/// # `forward`
+/// 把 `stray` 依次交给绑定在 `iface` 上的 socket
+/// ## 返回值
+/// - 没有 socket 接受时返回 `Err(stray)`，由调用者处理
+pub fn forward(iface: &Arc<dyn Iface>, mut stray: Stray) -> Result<(), Stray> {
+    for socket in iface.common().bound_sockets() {
+        match socket.adopt(stray) {
+            Ok(()) => return Ok(()),
+            Err(rejected) => stray = rejected,
+        }
     }
+    Err(stray)
 }
 
 #[inline]
 pub fn get_iface_to_bind(ip_addr: &smoltcp::wire::IpAddress) -> Option<Arc<dyn Iface>> {
     // log::debug!("get_iface_to_bind: {:?}", ip_addr);
     // if ip_addr.is_unspecified()
-    NET_DEVICES
-        .read()
-        .iter()
-        .find(|(_, iface)| {
-            let guard = iface.smol_iface().lock();
-            // log::debug!("iface name: {}, ip: {:?}", iface.iface_name(), guard.ip_addrs());
-            guard.has_ip_addr(*ip_addr)
-        })
-        .map(|(_, iface)| iface.clone())
-}
-
-/// Get a suitable iface to deal with sendto/connect request if the socket is not bound to an iface.
-/// If the remote address is the same as that of some iface, we will use the iface.
-/// Otherwise, we will use a default interface.
-fn get_ephemeral_iface(
-    remote_ip_addr: &smoltcp::wire::IpAddress,
-) -> (Arc<dyn Iface>, smoltcp::wire::IpAddress) {
-    get_iface_to_bind(remote_ip_addr)
-        .map(|iface| (iface, *remote_ip_addr))
-        .or({
-            let ifaces = NET_DEVICES.read();
-            ifaces.iter().find_map(|(_, iface)| {
-                iface
-                    .smol_iface()
-                    .lock()
-                    .ip_addrs()
-                    .iter()
-                    .find(|cidr| cidr.contains_addr(remote_ip_addr))
-                    .map(|cidr| (iface.clone(), cidr.address()))
-            })
-        })
-        .or({
-            NET_DEVICES.read().values().next().map(|iface| {
-                (
-                    iface.clone(),
-                    iface.smol_iface().lock().ip_addrs()[0].address(),
-                )
-            })
-        })
-        .expect("No network interface")
+    NET_DEVICES.ifaces().into_iter().find(|iface| {
+        let guard = iface.smol_iface().lock();
+        // log::debug!("iface name: {}, ip: {:?}", iface.iface_name(), guard.ip_addrs());
+        guard.has_ip_addr(*ip_addr)
+    })
 }
@@ -1,6 +1,7 @@
 use hashbrown::HashMap;
 use libc::rand;
 use linux_errnos::Errno as SystemError;
+use smoltcp::wire::IpAddress;
 
 use crate::{
     //     arch::rand::rand,
@@ -10,14 +11,47 @@ use crate::{
 
 use super::Types::{self, *};
 
+/// 占用端口的 socket 能收到的协议族。两个 socket 的协议族不相交时可以共用一个端口，
+/// 例如设置了 `IPV6_V6ONLY` 的 `[::]:p` 和 `0.0.0.0:p`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortFamily {
+    /// IPv4 socket，或绑定到 IPv4 映射地址的 IPv6 socket
+    Ipv4,
+    /// 设置了 `IPV6_V6ONLY` 或绑定到 IPv6 地址的 IPv6 socket
+    Ipv6,
+    /// 绑定到 `[::]` 的双栈 IPv6 socket。ICMP echo 标识符也按此记录，
+    /// smoltcp 的 ICMP socket 不区分协议族
+    Both,
+}
+
+impl PortFamily {
+    /// 绑定到 `addr` 的 IPv4 或不收 IPv4 数据的 IPv6 socket 的协议族
+    pub fn of(addr: &IpAddress) -> Self {
+        match addr {
+            IpAddress::Ipv4(_) => PortFamily::Ipv4,
+            IpAddress::Ipv6(_) => PortFamily::Ipv6,
+        }
+    }
+
+    fn overlaps(self, other: PortFamily) -> bool {
+        self == other || self == PortFamily::Both || other == PortFamily::Both
+    }
+}
+
+type PortTable = SpinLock<HashMap<u16, Vec<(PortFamily, Pid)>>>;
+
 /// # TCP 和 UDP 的端口管理器。
 /// 如果 TCP/UDP 的 socket 绑定了某个端口，它会在对应的表中记录，以检测端口冲突。
+/// ICMP echo socket 的标识符也作为端口记录在单独的表中。
+/// 一个端口上至多有两个协议族不相交的占用者
 #[derive(Debug)]
 pub struct PortManager {
     // TCP 端口记录表
-    tcp_port_table: SpinLock<HashMap<u16, Pid>>,
+    tcp_port_table: PortTable,
     // UDP 端口记录表
-    udp_port_table: SpinLock<HashMap<u16, Pid>>,
+    udp_port_table: PortTable,
+    // ICMP echo 标识符记录表
+    icmp_ident_table: PortTable,
 }
 
 impl Default for PortManager {
@@ -31,6 +65,17 @@ impl PortManager {
         Self {
             tcp_port_table: SpinLock::new(HashMap::new()),
             udp_port_table: SpinLock::new(HashMap::new()),
+            icmp_ident_table: SpinLock::new(HashMap::new()),
+        }
+    }
+
+    fn table(&self, socket_type: Types) -> Option<&PortTable> {
+        match socket_type {
+            // the DHCP client listens on a UDP port
+            Udp | Dhcpv4 => Some(&self.udp_port_table),
+            Tcp => Some(&self.tcp_port_table),
+            Icmp => Some(&self.icmp_ident_table),
+            _ => None,
         }
     }
 
@@ -58,10 +103,9 @@ impl PortManager {
             }
 
             // 使用 ListenTable 检查端口是否被占用
-            let listen_table_guard = match socket_type {
-                Udp => self.udp_port_table.lock(),
-                Tcp => self.tcp_port_table.lock(),
-                _ => panic!("{:?} cann't get a port", socket_type),
+            let listen_table_guard = match self.table(socket_type) {
+                Some(table) => table.lock(),
+                None => panic!("{:?} cann't get a port", socket_type),
             };
             if listen_table_guard.get(&port).is_none() {
                 drop(listen_table_guard);
@@ -73,49 +117,98 @@ impl PortManager {
     }
 
     #[inline]
-    pub fn bind_ephemeral_port(&self, socket_type: Types) -> Result<u16, SystemError> {
+    pub fn bind_ephemeral_port(
+        &self,
+        socket_type: Types,
+        family: PortFamily,
+    ) -> Result<u16, SystemError> {
         let port = self.get_ephemeral_port(socket_type)?;
-        self.bind_port(socket_type, port)?;
+        self.bind_port(socket_type, port, family)?;
         Ok(port)
     }
 
-    /// @brief 检测给定端口是否已被占用，如果未被占用则在 TCP/UDP 对应的表中记录
+    /// @brief 检测给定端口是否已被 `family` 相交的 socket 占用，如果未被占用则在 TCP/UDP 对应的表中记录
     ///
     /// TODO: 增加支持端口复用的逻辑
-    pub fn bind_port(&self, socket_type: Types, port: u16) -> Result<(), SystemError> {
-        if port > 0 {
-            match socket_type {
-                Udp => {
-                    let mut guard = self.udp_port_table.lock();
-                    if guard.get(&port).is_some() {
-                        return Err(SystemError::EADDRINUSE);
-                    }
-                    guard.insert(port, ProcessManager::current_pid());
-                }
-                Tcp => {
-                    let mut guard = self.tcp_port_table.lock();
-                    if guard.get(&port).is_some() {
-                        return Err(SystemError::EADDRINUSE);
-                    }
-                    guard.insert(port, ProcessManager::current_pid());
-                }
-                _ => {}
-            };
+    pub fn bind_port(
+        &self,
+        socket_type: Types,
+        port: u16,
+        family: PortFamily,
+    ) -> Result<(), SystemError> {
+        if port == 0 {
+            return Ok(());
         }
+        let Some(table) = self.table(socket_type) else {
+            return Ok(());
+        };
+        let mut guard = table.lock();
+        let owners = guard.entry(port).or_default();
+        if owners.iter().any(|(other, _)| other.overlaps(family)) {
+            return Err(SystemError::EADDRINUSE);
+        }
+        owners.push((family, ProcessManager::current_pid()));
         Ok(())
     }
 
-    /// @brief 在对应的端口记录表中将端口和 socket 解绑
+    /// @brief 查询占用端口的进程，端口未被占用时返回 `None`，有两个占用者时返回先占用的
+    pub fn owner(&self, socket_type: Types, port: u16) -> Option<Pid> {
+        let table = self.table(socket_type)?.lock();
+        table.get(&port)?.first().map(|(_, pid)| *pid)
+    }
+
+    /// @brief 端口是否被两个协议族的 socket 共用
+    pub fn is_shared(&self, socket_type: Types, port: u16) -> bool {
+        self.table(socket_type)
+            .and_then(|table| table.lock().get(&port).map(|owners| owners.len() > 1))
+            .unwrap_or(false)
+    }
+
+    /// @brief 在对应的端口记录表中将端口和 `family` 的 socket 解绑
     /// should call this function when socket is closed or aborted
-    pub fn unbind_port(&self, socket_type: Types, port: u16) {
-        match socket_type {
-            Udp => {
-                self.udp_port_table.lock().remove(&port);
-            }
-            Tcp => {
-                self.tcp_port_table.lock().remove(&port);
-            }
-            _ => {}
+    pub fn unbind_port(&self, socket_type: Types, port: u16, family: PortFamily) {
+        let Some(table) = self.table(socket_type) else {
+            return;
         };
+        let mut guard = table.lock();
+        if let Some(owners) = guard.get_mut(&port) {
+            owners.retain(|(other, _)| *other != family);
+            if owners.is_empty() {
+                guard.remove(&port);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn families_share_a_port_when_they_do_not_overlap() {
+        let ports = PortManager::new();
+        ports.bind_port(Udp, 80, PortFamily::Ipv4).unwrap();
+        assert_eq!(
+            ports.bind_port(Udp, 80, PortFamily::Both),
+            Err(SystemError::EADDRINUSE)
+        );
+        ports.bind_port(Udp, 80, PortFamily::Ipv6).unwrap();
+        assert_eq!(
+            ports.bind_port(Udp, 80, PortFamily::Ipv6),
+            Err(SystemError::EADDRINUSE)
+        );
+        assert!(ports.is_shared(Udp, 80));
+        // every protocol has a table of its own
+        ports.bind_port(Tcp, 80, PortFamily::Both).unwrap();
+
+        ports.unbind_port(Udp, 80, PortFamily::Ipv4);
+        assert!(!ports.is_shared(Udp, 80));
+        assert_eq!(
+            ports.bind_port(Udp, 80, PortFamily::Both),
+            Err(SystemError::EADDRINUSE)
+        );
+        ports.unbind_port(Udp, 80, PortFamily::Ipv6);
+        assert_eq!(ports.owner(Udp, 80), None);
+        ports.bind_port(Udp, 80, PortFamily::Both).unwrap();
     }
 }
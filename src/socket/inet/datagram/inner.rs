@@ -1,9 +1,14 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use linux_errnos::Errno as SystemError;
 use smoltcp;
 
 use crate::{
+    interface::Iface,
     libs::spinlock::SpinLock,
-    socket::inet::common::{BoundInner, Types as InetTypes},
+    socket::inet::common::{forward, BoundInner, Domain, PortFamily, Stray, Types as InetTypes},
+    socket::inet::table::SocketInfo,
 };
 
 pub type SmolUdpSocket = smoltcp::socket::udp::Socket<'static>;
@@ -12,6 +17,18 @@ pub const DEFAULT_METADATA_BUF_SIZE: usize = 1024;
 pub const DEFAULT_RX_BUF_SIZE: usize = 64 * 1024;
 pub const DEFAULT_TX_BUF_SIZE: usize = 64 * 1024;
 
+fn new_smoltcp_socket() -> SmolUdpSocket {
+    let rx_buffer = smoltcp::socket::udp::PacketBuffer::new(
+        vec![smoltcp::socket::udp::PacketMetadata::EMPTY; DEFAULT_METADATA_BUF_SIZE],
+        vec![0; DEFAULT_RX_BUF_SIZE],
+    );
+    let tx_buffer = smoltcp::socket::udp::PacketBuffer::new(
+        vec![smoltcp::socket::udp::PacketMetadata::EMPTY; DEFAULT_METADATA_BUF_SIZE],
+        vec![0; DEFAULT_TX_BUF_SIZE],
+    );
+    SmolUdpSocket::new(rx_buffer, tx_buffer)
+}
+
 #[derive(Debug)]
 pub struct UnboundUdp {
     socket: SmolUdpSocket,
@@ -25,71 +42,104 @@ impl Default for UnboundUdp {
 
 impl UnboundUdp {
     pub fn new() -> Self {
-        let rx_buffer = smoltcp::socket::udp::PacketBuffer::new(
-            vec![smoltcp::socket::udp::PacketMetadata::EMPTY; DEFAULT_METADATA_BUF_SIZE],
-            vec![0; DEFAULT_RX_BUF_SIZE],
-        );
-        let tx_buffer = smoltcp::socket::udp::PacketBuffer::new(
-            vec![smoltcp::socket::udp::PacketMetadata::EMPTY; DEFAULT_METADATA_BUF_SIZE],
-            vec![0; DEFAULT_TX_BUF_SIZE],
-        );
-        let socket = SmolUdpSocket::new(rx_buffer, tx_buffer);
-
-        Self { socket }
-    }
-
-    pub fn bind(self, local_endpoint: smoltcp::wire::IpEndpoint) -> Result<BoundUdp, SystemError> {
-        let inner = BoundInner::bind(self.socket, &local_endpoint.addr)?;
-        let bind_addr = local_endpoint.addr;
+        Self {
+            socket: new_smoltcp_socket(),
+        }
+    }
+
+    pub fn bind(
+        self,
+        local_endpoint: smoltcp::wire::IpEndpoint,
+        domain: Domain,
+    ) -> Result<BoundUdp, SystemError> {
+        // an unspecified address takes a socket on every iface, the first one is ours
+        let mut socket = Some(self.socket);
+        let mut inner = BoundInner::bind(&local_endpoint.addr, || {
+            socket.take().unwrap_or_else(new_smoltcp_socket)
+        })?;
+        let family = domain.port_family(&local_endpoint.addr);
         let bind_port = if local_endpoint.port == 0 {
-            inner.port_manager().bind_ephemeral_port(InetTypes::Udp)?
+            inner.bind_ephemeral_port(InetTypes::Udp, family)
         } else {
             inner
-                .port_manager()
-                .bind_port(InetTypes::Udp, local_endpoint.port)?;
-            local_endpoint.port
-        };
-
-        if bind_addr.is_unspecified() {
-            if inner
-                .with_mut::<smoltcp::socket::udp::Socket, _, _>(|socket| socket.bind(bind_port))
-                .is_err()
-            {
-                return Err(SystemError::EINVAL);
-            }
-        } else if inner
-            .with_mut::<smoltcp::socket::udp::Socket, _, _>(|socket| {
-                socket.bind(smoltcp::wire::IpEndpoint::new(bind_addr, bind_port))
-            })
-            .is_err()
-        {
-            return Err(SystemError::EINVAL);
+                .bind_port(InetTypes::Udp, local_endpoint.port, family)
+                .map(|_| local_endpoint.port)
         }
-        Ok(BoundUdp {
-            inner,
-            remote: SpinLock::new(None),
-        })
+        .inspect_err(|_| inner.release())?;
+
+        let endpoint = smoltcp::wire::IpEndpoint::new(local_endpoint.addr, bind_port);
+        BoundUdp::new(inner, endpoint, domain)
     }
 
-    pub fn bind_ephemeral(self, remote: smoltcp::wire::IpAddress) -> Result<BoundUdp, SystemError> {
+    pub fn bind_ephemeral(
+        self,
+        remote: smoltcp::wire::IpAddress,
+        domain: Domain,
+    ) -> Result<BoundUdp, SystemError> {
         // let (addr, port) = (remote.addr, remote.port);
-        let (inner, address) = BoundInner::bind_ephemeral(self.socket, remote)?;
-        let bound_port = inner.port_manager().bind_ephemeral_port(InetTypes::Udp)?;
+        let (mut inner, address) = BoundInner::bind_ephemeral(self.socket, remote)?;
+        let bound_port = inner
+            .bind_ephemeral_port(InetTypes::Udp, PortFamily::of(&address))
+            .inspect_err(|_| inner.release())?;
         let endpoint = smoltcp::wire::IpEndpoint::new(address, bound_port);
-        Ok(BoundUdp {
-            inner,
-            remote: SpinLock::new(Some(endpoint)),
-        })
+        BoundUdp::new(inner, endpoint, domain)
+    }
+}
+
+/// Sockets bound to an unspecified address take the datagrams to any address
+fn listen_endpoint(endpoint: smoltcp::wire::IpEndpoint) -> smoltcp::wire::IpListenEndpoint {
+    if endpoint.addr.is_unspecified() {
+        smoltcp::wire::IpListenEndpoint::from(endpoint.port)
+    } else {
+        smoltcp::wire::IpListenEndpoint::from(endpoint)
     }
 }
 
+/// Whether the datagram at the head of the queue is of the socket's family,
+/// see [`BoundUdp::forward_strays`] for the others
+fn head_accepted(socket: &mut SmolUdpSocket, domain: &Domain) -> bool {
+    socket
+        .peek()
+        .is_ok_and(|(_, metadata)| domain.accepts(&metadata.endpoint.addr))
+}
+
 #[derive(Debug)]
 pub struct BoundUdp {
     inner: BoundInner,
     remote: SpinLock<Option<smoltcp::wire::IpEndpoint>>,
+    domain: Domain,
+    /// Datagrams taken off the smoltcp sockets while a socket of the other
+    /// family shares the port, and the ones it hands over
+    received: SpinLock<VecDeque<(Vec<u8>, smoltcp::wire::IpEndpoint)>>,
 }
 
 impl BoundUdp {
+    /// Binds the sockets of `inner` to `endpoint`, whose port is already taken.
+    /// On failure the port and the sockets are given back.
+    fn new(
+        inner: BoundInner,
+        endpoint: smoltcp::wire::IpEndpoint,
+        domain: Domain,
+    ) -> Result<Self, SystemError> {
+        let mut result = Ok(());
+        inner.for_each::<SmolUdpSocket, _>(|socket| {
+            if socket.bind(listen_endpoint(endpoint)).is_err() {
+                result = Err(SystemError::EINVAL);
+            }
+        });
+        if let Err(err) = result {
+            inner.unbind_port(InetTypes::Udp, endpoint.port);
+            inner.release();
+            return Err(err);
+        }
+        Ok(BoundUdp {
+            inner,
+            remote: SpinLock::new(None),
+            domain,
+            received: SpinLock::new(VecDeque::new()),
+        })
+    }
+
     pub fn with_mut_socket<F, T>(&self, f: F) -> T
     where
         F: FnMut(&mut SmolUdpSocket) -> T,
@@ -104,6 +154,13 @@ impl BoundUdp {
         self.inner.with(f)
     }
 
+    /// Like on Linux, writable while at most half of the send buffer is taken
+    pub fn can_send(&self) -> bool {
+        self.with_socket(|socket| {
+            socket.can_send() && socket.send_queue() * 2 <= socket.payload_send_capacity()
+        })
+    }
+
     pub fn endpoint(&self) -> smoltcp::wire::IpListenEndpoint {
         self.inner
             .with::<SmolUdpSocket, _, _>(|socket| socket.endpoint())
@@ -113,35 +170,220 @@ impl BoundUdp {
         self.remote.lock().replace(remote);
     }
 
+    pub fn peer(&self) -> Option<smoltcp::wire::IpEndpoint> {
+        *self.remote.lock()
+    }
+
+    /// The destination of a datagram, `to` or the connected peer
+    pub fn remote(
+        &self,
+        to: Option<smoltcp::wire::IpEndpoint>,
+    ) -> Result<smoltcp::wire::IpEndpoint, SystemError> {
+        to.or(*self.remote.lock()).ok_or(SystemError::ENOTCONN)
+    }
+
+    /// The error of the link to the peer once connected, see [`BoundInner::link_error`]
+    pub fn link_error(&self) -> Option<SystemError> {
+        let remote = self.remote.lock().map(|remote| remote.addr);
+        self.inner.link_error(remote.as_ref())
+    }
+
     #[inline]
     pub fn try_recv(
         &self,
         buf: &mut [u8],
     ) -> Result<(usize, smoltcp::wire::IpEndpoint), SystemError> {
-        self.with_mut_socket(|socket| {
-            if socket.can_recv() {
-                if let Ok((size, metadata)) = socket.recv_slice(buf) {
-                    return Ok((size, metadata.endpoint));
+        self.forward_strays();
+        if let Some((payload, remote)) = self.received.lock().pop_front() {
+            let size = payload.len().min(buf.len());
+            buf[..size].copy_from_slice(&payload[..size]);
+            return Ok((size, remote));
+        }
+        self.inner
+            .find_map::<SmolUdpSocket, _, _>(|socket| {
+                if head_accepted(socket, &self.domain) {
+                    if let Ok((size, metadata)) = socket.recv_slice(buf) {
+                        return Some((size, metadata.endpoint));
+                    }
                 }
+                None
+            })
+            .ok_or(SystemError::EAGAIN)
+    }
+
+    /// Whether a datagram waits on the socket of any iface
+    pub fn can_recv(&self) -> bool {
+        self.forward_strays();
+        if !self.received.lock().is_empty() {
+            return true;
+        }
+        self.inner
+            .find_map::<SmolUdpSocket, _, _>(|socket| {
+                head_accepted(socket, &self.domain).then_some(())
+            })
+            .is_some()
+    }
+
+    /// A socket bound to a port only takes the datagrams of both families.
+    /// The ones the socket's family does not accept are handed to the socket
+    /// of the other family on the same port, or dropped if there is none.
+    /// While the port is shared a stray may wait behind datagrams of the
+    /// socket's family, so the whole queue is taken
+    pub fn forward_strays(&self) {
+        let port = self.endpoint().port;
+        let mut strays = Vec::new();
+        self.inner
+            .for_each_iface::<SmolUdpSocket, _>(|iface, socket| {
+                let shared = iface.port_manager().is_shared(InetTypes::Udp, port);
+                while let Ok((payload, metadata)) = socket.peek() {
+                    if self.domain.accepts(&metadata.endpoint.addr) {
+                        if !shared {
+                            break;
+                        }
+                        self.queue(payload.to_vec(), metadata.endpoint);
+                        let _ = socket.recv();
+                        continue;
+                    }
+                    let local = metadata
+                        .local_address
+                        .expect("A received datagram has no local address");
+                    let stray = Stray::Datagram {
+                        payload: payload.to_vec(),
+                        remote: metadata.endpoint,
+                        local: smoltcp::wire::IpEndpoint::new(local, port),
+                    };
+                    strays.push((iface.clone(), stray));
+                    let _ = socket.recv();
+                }
+            });
+        // the other socket takes the locks of its iface
+        for (iface, stray) in strays {
+            if let Err(Stray::Datagram { remote, local, .. }) = forward(&iface, stray) {
+                log::debug!("udp: dropped a datagram from {:?} to {:?}", remote, local);
             }
-            Err(SystemError::EAGAIN)
-        })
+        }
+    }
+
+    /// Queues a datagram handed over by [`BoundUdp::forward_strays`] of
+    /// another socket if it is addressed to this one
+    pub fn adopt(&self, stray: Stray) -> Result<(), Stray> {
+        let endpoint = self.endpoint();
+        match stray {
+            Stray::Datagram {
+                payload,
+                remote,
+                local,
+            } if self.domain.accepts(&remote.addr)
+                && local.port == endpoint.port
+                && endpoint.addr.is_none_or(|addr| addr == local.addr) =>
+            {
+                self.queue(payload, remote);
+                Ok(())
+            }
+            stray => Err(stray),
+        }
+    }
+
+    fn queue(&self, payload: Vec<u8>, remote: smoltcp::wire::IpEndpoint) {
+        let mut received = self.received.lock();
+        // like a full receive buffer, the datagram is dropped
+        if received.len() < DEFAULT_METADATA_BUF_SIZE {
+            received.push_back((payload, remote));
+        }
     }
 
     pub fn try_send(
         &self,
         buf: &[u8],
-        to: Option<smoltcp::wire::IpEndpoint>,
+        remote: smoltcp::wire::IpEndpoint,
     ) -> Result<usize, SystemError> {
-        let remote = to.or(*self.remote.lock()).ok_or(SystemError::ENOTCONN)?;
+        // a specific local address has no source for the other family
+        if self
+            .endpoint()
+            .addr
+            .is_some_and(|local| local.version() != remote.addr.version())
+        {
+            return Err(SystemError::ENETUNREACH);
+        }
+        self.inner
+            .with_mut_to::<SmolUdpSocket, _, _>(&remote.addr, |socket| {
+                if buf.len() > socket.payload_send_capacity() {
+                    return Err(SystemError::EMSGSIZE);
+                }
+                match socket.send_slice(buf, remote) {
+                    Ok(()) => {
+                        log::debug!("send {} bytes", buf.len());
+                        Ok(buf.len())
+                    }
+                    // the datagram waits for room in the send buffer
+                    Err(smoltcp::socket::udp::SendError::BufferFull) => Err(SystemError::EAGAIN),
+                    Err(smoltcp::socket::udp::SendError::Unaddressable) => {
+                        Err(SystemError::ENOBUFS)
+                    }
+                }
+            })
+            .unwrap_or(Err(SystemError::ENETUNREACH))
+    }
 
-        self.with_mut_socket(|socket| {
-            if socket.can_send() && socket.send_slice(buf, remote).is_ok() {
-                log::debug!("send {} bytes", buf.len());
-                return Ok(buf.len());
-            }
-            Err(SystemError::ENOBUFS)
-        })
+    /// Puts a socket on an iface registered after binding to an unspecified address
+    pub fn attach(&mut self, iface: &Arc<dyn Iface>) -> bool {
+        if !self.inner.should_attach(iface) {
+            return false;
+        }
+        let endpoint = self.endpoint();
+        if let Err(err) =
+            iface
+                .port_manager()
+                .bind_port(InetTypes::Udp, endpoint.port, self.inner.port_family())
+        {
+            log::warn!(
+                "udp port {} is taken on {}: {:?}",
+                endpoint.port,
+                iface.iface_name(),
+                err
+            );
+            return false;
+        }
+        let mut socket = new_smoltcp_socket();
+        socket
+            .bind(endpoint)
+            .expect("A bound udp port is not valid");
+        self.inner.attach(iface, socket);
+        true
+    }
+
+    pub fn socket_info(&self) -> SocketInfo {
+        let endpoint = self.endpoint();
+        let (mut send_queue, mut recv_queue) = (0, 0);
+        self.inner.for_each::<SmolUdpSocket, _>(|socket| {
+            send_queue += socket.send_queue();
+            recv_queue += socket.recv_queue();
+        });
+        recv_queue += self
+            .received
+            .lock()
+            .iter()
+            .map(|(payload, _)| payload.len())
+            .sum::<usize>();
+        SocketInfo {
+            protocol: InetTypes::Udp,
+            local: smoltcp::wire::IpEndpoint::new(
+                endpoint.addr.unwrap_or(self.domain.unspecified().addr),
+                endpoint.port,
+            ),
+            remote: *self.remote.lock(),
+            state: None,
+            send_queue,
+            recv_queue,
+            pid: None,
+            ifaces: self
+                .inner
+                .ifaces()
+                .map(|iface| iface.iface_name())
+                .collect(),
+            timeout: None,
+            keep_alive: None,
+        }
     }
 
     pub fn inner(&self) -> &BoundInner {
@@ -149,11 +391,8 @@ impl BoundUdp {
     }
 
     pub fn close(&self) {
-        self.inner
-            .iface()
-            .port_manager()
-            .unbind_port(InetTypes::Udp, self.endpoint().port);
-        self.with_mut_socket(|socket| {
+        self.inner.unbind_port(InetTypes::Udp, self.endpoint().port);
+        self.inner.for_each::<SmolUdpSocket, _>(|socket| {
             socket.close();
         });
     }
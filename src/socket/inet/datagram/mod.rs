@@ -3,13 +3,18 @@ use linux_errnos::Errno as SystemError;
 use smoltcp;
 
 use crate::event_poll::EPollEventType;
+use crate::interface::registry::NET_DEVICES;
+use crate::interface::Iface;
 use crate::libs::wait_queue::{wq_wait_event_interruptible, WaitQueue};
-use crate::socket::{Socket, PMSG};
+use crate::socket::{Socket, PMSG, PSOL};
 use crate::{libs::rwlock::RwLock, socket::endpoint::Endpoint};
 use alloc::sync::{Arc, Weak};
 use core::sync::atomic::AtomicBool;
 
-use super::InetSocket;
+use super::common::Stray;
+use super::posix::option::Ipv6Options;
+use super::table::SocketInfo;
+use super::{Domain, InetSocket};
 
 pub mod inner;
 
@@ -19,15 +24,18 @@ type EP = EPollEventType;
 #[derive(Debug)]
 pub struct UdpSocket {
     inner: RwLock<Option<UdpInner>>,
+    /// fixed once the socket is bound
+    domain: RwLock<Domain>,
     nonblock: AtomicBool,
     wait_queue: WaitQueue,
     self_ref: Weak<UdpSocket>,
 }
 
 impl UdpSocket {
-    pub fn new(nonblock: bool) -> Arc<Self> {
+    pub fn new(nonblock: bool, version: smoltcp::wire::IpVersion) -> Arc<Self> {
         Arc::new_cyclic(|me| Self {
             inner: RwLock::new(Some(UdpInner::Unbound(UnboundUdp::new()))),
+            domain: RwLock::new(Domain::new(version)),
             nonblock: AtomicBool::new(nonblock),
             wait_queue: WaitQueue::default(),
             self_ref: me.clone(),
@@ -39,26 +47,46 @@ impl UdpSocket {
     }
 
     pub fn do_bind(&self, local_endpoint: smoltcp::wire::IpEndpoint) -> Result<(), SystemError> {
+        let domain = *self.domain.read();
+        let local_endpoint = domain.to_inner(local_endpoint)?;
         let mut inner = self.inner.write();
         if let Some(UdpInner::Unbound(unbound)) = inner.take() {
-            let bound = unbound.bind(local_endpoint)?;
-
-            bound
-                .inner()
-                .iface()
-                .common()
-                .bind_socket(self.self_ref.upgrade().unwrap());
+            // subscribe before bind lists the ifaces, an iface registered in
+            // between waits for our lock and is attached once
+            let any_iface = local_endpoint.addr.is_unspecified();
+            let me = self.self_ref.upgrade().unwrap();
+            if any_iface {
+                NET_DEVICES.bind_socket(me.clone());
+            }
+            let bound = unbound.bind(local_endpoint, domain).inspect_err(|_| {
+                if any_iface {
+                    NET_DEVICES.unbind_socket(me);
+                }
+            })?;
+            self.register(&bound);
             *inner = Some(UdpInner::Bound(bound));
             return Ok(());
         }
         Err(SystemError::EINVAL)
     }
 
+    /// Iface events reach the socket on every iface it is bound to
+    fn register(&self, bound: &inner::BoundUdp) {
+        let me = self.self_ref.upgrade().unwrap();
+        for iface in bound.inner().ifaces() {
+            iface.common().bind_socket(me.clone());
+        }
+    }
+
     pub fn bind_emphemeral(&self, remote: smoltcp::wire::IpAddress) -> Result<(), SystemError> {
         let mut inner_guard = self.inner.write();
         let bound = match inner_guard.take().expect("Udp inner is None") {
             UdpInner::Bound(inner) => inner,
-            UdpInner::Unbound(inner) => inner.bind_ephemeral(remote)?,
+            UdpInner::Unbound(inner) => {
+                let bound = inner.bind_ephemeral(remote, *self.domain.read())?;
+                self.register(&bound);
+                bound
+            }
         };
         inner_guard.replace(UdpInner::Bound(bound));
         Ok(())
@@ -76,6 +104,13 @@ impl UdpSocket {
         let mut inner = self.inner.write();
         if let Some(UdpInner::Bound(bound)) = &mut *inner {
             bound.close();
+            let me = self.self_ref.upgrade().unwrap();
+            for iface in bound.inner().ifaces() {
+                iface.common().unbind_socket(me.clone());
+            }
+            if bound.inner().is_any_iface() {
+                NET_DEVICES.unbind_socket(me);
+            }
             inner.take();
         }
         // unbound socket just drop (only need to free memory)
@@ -88,8 +123,13 @@ impl UdpSocket {
         match self.inner.read().as_ref().expect("Udp Inner is None") {
             UdpInner::Bound(bound) => {
                 let ret = bound.try_recv(buf);
-                bound.inner().iface().poll();
-                ret
+                bound.inner().poll();
+                match ret {
+                    Err(SystemError::EAGAIN) => {
+                        Err(bound.link_error().unwrap_or(SystemError::EAGAIN))
+                    }
+                    ret => ret,
+                }
             }
             _ => Err(SystemError::ENOTCONN),
         }
@@ -100,8 +140,13 @@ impl UdpSocket {
         self.event().contains(EP::EPOLLIN)
     }
 
+    /// 绑定的网卡因设备错误停用时，等待中的收发应当醒来返回错误
+    #[inline]
+    pub fn has_error(&self) -> bool {
+        self.event().contains(EP::EPOLLERR)
+    }
+
     #[inline]
-    #[allow(dead_code)]
     pub fn can_send(&self) -> bool {
         self.event().contains(EP::EPOLLOUT)
     }
@@ -116,7 +161,12 @@ impl UdpSocket {
             let inner = match inner_guard.take().expect("Udp Inner is None") {
                 UdpInner::Bound(bound) => bound,
                 UdpInner::Unbound(unbound) => {
-                    unbound.bind_ephemeral(to.ok_or(SystemError::EADDRNOTAVAIL)?.addr)?
+                    let bound = unbound.bind_ephemeral(
+                        to.ok_or(SystemError::EADDRNOTAVAIL)?.addr,
+                        *self.domain.read(),
+                    )?;
+                    self.register(&bound);
+                    bound
                 }
             };
             // size = inner.try_send(buf, to)?;
@@ -125,15 +175,53 @@ impl UdpSocket {
         // Optimize: 拿两次锁的平均效率是否比一次长时间的读锁效率要高？
         let result = match self.inner.read().as_ref().expect("Udp Inner is None") {
             UdpInner::Bound(bound) => {
-                let ret = bound.try_send(buf, to);
-                bound.inner().iface().poll();
-                ret
+                let remote = bound.remote(to)?;
+                let iface = bound
+                    .inner()
+                    .iface_to(&remote.addr)
+                    .ok_or(SystemError::ENETUNREACH)?;
+                // a down iface is not polled, the datagram would wait for it
+                if !iface.common().is_running() {
+                    return Err(iface.link_error().unwrap_or(SystemError::ENETDOWN));
+                }
+                let ret = bound.try_send(buf, remote);
+                iface.poll();
+                // the datagram is lost if the device failed to take it, a
+                // datagram sent on a down iface is also how it finds out when
+                // the link comes back
+                match iface.link_error() {
+                    Some(err) => Err(err),
+                    None => ret,
+                }
             }
             _ => Err(SystemError::ENOTCONN),
         };
         result
     }
 
+    /// Blocks while the send buffer is full unless the socket or `flags` is nonblocking
+    fn send_datagram(
+        &self,
+        buf: &[u8],
+        to: Option<smoltcp::wire::IpEndpoint>,
+        flags: PMSG,
+    ) -> Result<usize, SystemError> {
+        loop {
+            match self.try_send(buf, to) {
+                Err(SystemError::EAGAIN)
+                    if !self.is_nonblock() && !flags.contains(PMSG::DONTWAIT) =>
+                {
+                    wq_wait_event_interruptible(
+                        &self.wait_queue,
+                        || self.can_send() || self.has_error(),
+                        None,
+                    )?;
+                }
+                result => break result,
+            }
+        }
+    }
+
     pub fn event(&self) -> EPollEventType {
         let mut event = EPollEventType::empty();
         match self.inner.read().as_ref().unwrap() {
@@ -141,16 +229,19 @@ impl UdpSocket {
                 event.insert(EP::EPOLLOUT | EP::EPOLLWRNORM | EP::EPOLLWRBAND);
             }
             UdpInner::Bound(bound) => {
-                let (can_recv, can_send) =
-                    bound.with_socket(|socket| (socket.can_recv(), socket.can_send()));
+                let can_send = bound.can_send();
 
-                if can_recv {
+                if bound.can_recv() {
                     event.insert(EP::EPOLLIN | EP::EPOLLRDNORM);
                 }
 
                 if can_send {
                     event.insert(EP::EPOLLOUT | EP::EPOLLWRNORM | EP::EPOLLWRBAND);
                 }
+
+                if bound.link_error().is_some() {
+                    event.insert(EP::EPOLLERR);
+                }
             }
         }
         event
@@ -189,6 +280,7 @@ impl Socket for UdpSocket {
 
     fn connect(&self, endpoint: Endpoint) -> Result<(), SystemError> {
         if let Endpoint::Ip(remote) = endpoint {
+            let remote = self.domain.read().to_inner(remote)?;
             if !self.is_bound() {
                 self.bind_emphemeral(remote.addr)?;
             }
@@ -204,20 +296,13 @@ impl Socket for UdpSocket {
     }
 
     fn send(&self, buffer: &[u8], flags: PMSG) -> Result<usize, SystemError> {
-        if flags.contains(PMSG::DONTWAIT) {
-            log::warn!("Nonblock send is not implemented yet");
-        }
-
-        self.try_send(buffer, None)
+        self.send_datagram(buffer, None, flags)
     }
 
     fn send_to(&self, buffer: &[u8], flags: PMSG, address: Endpoint) -> Result<usize, SystemError> {
-        if flags.contains(PMSG::DONTWAIT) {
-            log::warn!("Nonblock send is not implemented yet");
-        }
-
         if let Endpoint::Ip(remote) = address {
-            return self.try_send(buffer, Some(remote));
+            let remote = self.domain.read().to_inner(remote)?;
+            return self.send_datagram(buffer, Some(remote), flags);
         }
 
         Err(SystemError::EINVAL)
@@ -230,7 +315,11 @@ impl Socket for UdpSocket {
             loop {
                 match self.try_recv(buffer) {
                     Err(SystemError::EAGAIN) => {
-                        wq_wait_event_interruptible(&self.wait_queue, || self.can_recv(), None)?;
+                        wq_wait_event_interruptible(
+                            &self.wait_queue,
+                            || self.can_recv() || self.has_error(),
+                            None,
+                        )?;
                     }
                     result => break result,
                 }
@@ -256,24 +345,105 @@ impl Socket for UdpSocket {
             loop {
                 match self.try_recv(buffer) {
                     Err(SystemError::EAGAIN) => {
-                        wq_wait_event_interruptible(&self.wait_queue, || self.can_recv(), None)?;
+                        wq_wait_event_interruptible(
+                            &self.wait_queue,
+                            || self.can_recv() || self.has_error(),
+                            None,
+                        )?;
                         log::debug!("UdpSocket::recv_from: wake up");
                     }
                     result => break result,
                 }
             }
         }
-        .map(|(len, remote)| (len, Endpoint::Ip(remote)))
+        .map(|(len, remote)| (len, Endpoint::Ip(self.domain.read().to_user(remote))))
     }
 
     fn close(&self) -> Result<(), SystemError> {
         self.close();
         Ok(())
     }
+
+    fn get_name(&self) -> Result<Endpoint, SystemError> {
+        let domain = *self.domain.read();
+        let local = match self.inner.read().as_ref().expect("Udp Inner is None") {
+            UdpInner::Bound(bound) => {
+                let endpoint = bound.endpoint();
+                smoltcp::wire::IpEndpoint::new(
+                    endpoint.addr.unwrap_or(domain.unspecified().addr),
+                    endpoint.port,
+                )
+            }
+            UdpInner::Unbound(_) => domain.unspecified(),
+        };
+        Ok(Endpoint::Ip(domain.to_user(local)))
+    }
+
+    fn get_peer_name(&self) -> Result<Endpoint, SystemError> {
+        match self.inner.read().as_ref().expect("Udp Inner is None") {
+            UdpInner::Bound(bound) => bound
+                .peer()
+                .map(|remote| Endpoint::Ip(self.domain.read().to_user(remote)))
+                .ok_or(SystemError::ENOTCONN),
+            UdpInner::Unbound(_) => Err(SystemError::ENOTCONN),
+        }
+    }
+
+    fn set_option(&self, level: PSOL, name: usize, val: &[u8]) -> Result<(), SystemError> {
+        if level == PSOL::IPV6 && name == Ipv6Options::IPV6_V6ONLY.bits() as usize {
+            // the family of a bound socket is settled
+            if self.is_bound() {
+                return Err(SystemError::EINVAL);
+            }
+            return self.domain.write().set_v6only(val);
+        }
+        log::debug!("UdpSocket::set_option: {:?} {} not supported", level, name);
+        Ok(())
+    }
+
+    fn get_option(&self, level: PSOL, name: usize, value: &mut [u8]) -> Result<usize, SystemError> {
+        if level == PSOL::IPV6 && name == Ipv6Options::IPV6_V6ONLY.bits() as usize {
+            return self.domain.read().get_v6only(value);
+        }
+        log::debug!("UdpSocket::get_option: {:?} {} not supported", level, name);
+        Ok(0)
+    }
 }
 
 impl InetSocket for UdpSocket {
-    fn on_iface_events(&self) {}
+    fn on_iface_events(&self) {
+        if let Some(UdpInner::Bound(bound)) = self.inner.read().as_ref() {
+            bound.forward_strays();
+        }
+    }
+
+    fn socket_info(&self) -> Option<SocketInfo> {
+        match self.inner.read().as_ref()? {
+            UdpInner::Bound(bound) => Some(bound.socket_info()),
+            UdpInner::Unbound(_) => None,
+        }
+    }
+
+    fn adopt(&self, stray: Stray) -> Result<(), Stray> {
+        let adopted = match self.inner.read().as_ref() {
+            Some(UdpInner::Bound(bound)) => bound.adopt(stray),
+            _ => Err(stray),
+        };
+        if adopted.is_ok() {
+            self.wait_queue.wakeup();
+        }
+        adopted
+    }
+
+    fn on_iface_added(&self, iface: &Arc<dyn Iface>) {
+        let attached = match self.inner.write().as_mut() {
+            Some(UdpInner::Bound(bound)) => bound.attach(iface),
+            _ => false,
+        };
+        if attached {
+            iface.common().bind_socket(self.self_ref.upgrade().unwrap());
+        }
+    }
 }
 
 bitflags::bitflags! {
@@ -0,0 +1,242 @@
+//! `getaddrinfo` 和 `getnameinfo`，名字经 [`RESOLVER`](super::RESOLVER) 解析，
+//! 服务名查一张常用服务的小表，代替 /etc/services
+use smoltcp::wire::{IpAddress, IpEndpoint, IpProtocol, IpVersion};
+
+use crate::posix::SOCK;
+
+use super::{GaiError, RESOLVER};
+
+bitflags::bitflags! {
+    /// # `getaddrinfo` 的 `AI_*` 标志
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct AddrInfoFlags: u32 {
+        /// `AI_PASSIVE` 没有给出名字时返回未指定地址，用于 bind
+        const PASSIVE     = 0x0001;
+        /// `AI_CANONNAME` 在第一项中返回规范名
+        const CANONNAME   = 0x0002;
+        /// `AI_NUMERICHOST` 名字必须是数字地址，不做解析
+        const NUMERICHOST = 0x0004;
+        /// `AI_NUMERICSERV` 服务必须是端口号
+        const NUMERICSERV = 0x0400;
+    }
+}
+
+bitflags::bitflags! {
+    /// # `getnameinfo` 的 `NI_*` 标志
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct NameInfoFlags: u32 {
+        /// `NI_NUMERICHOST` 返回数字地址
+        const NUMERICHOST = 1;
+        /// `NI_NUMERICSERV` 返回端口号
+        const NUMERICSERV = 2;
+        /// `NI_NOFQDN` 只返回名字的第一个标签
+        const NOFQDN      = 4;
+        /// `NI_NAMEREQD` 地址没有名字时返回错误而不是数字地址
+        const NAMEREQD    = 8;
+        /// `NI_DGRAM` 按 UDP 查找服务名
+        const DGRAM       = 16;
+    }
+}
+
+/// `getaddrinfo` 的 hints
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AddrInfoHints {
+    pub flags: AddrInfoFlags,
+    /// 协议族，`None` 对应 `AF_UNSPEC`
+    pub family: Option<IpVersion>,
+    /// `None` 时流和数据报都返回
+    pub socket_type: Option<SOCK>,
+    /// `None` 时不限协议
+    pub protocol: Option<IpProtocol>,
+}
+
+/// `getaddrinfo` 返回的一项
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrInfo {
+    pub endpoint: IpEndpoint,
+    pub socket_type: SOCK,
+    pub protocol: IpProtocol,
+    /// 规范名，只在设置了 `AI_CANONNAME` 时出现在第一项中
+    pub canonical_name: Option<String>,
+}
+
+/// 常用服务的名字、端口以及是否有 TCP 和 UDP 服务
+const SERVICES: &[(&str, u16, bool, bool)] = &[
+    ("ftp", 21, true, false),
+    ("ssh", 22, true, false),
+    ("telnet", 23, true, false),
+    ("smtp", 25, true, false),
+    ("domain", 53, true, true),
+    ("bootps", 67, false, true),
+    ("bootpc", 68, false, true),
+    ("tftp", 69, false, true),
+    ("http", 80, true, false),
+    ("ntp", 123, false, true),
+    ("https", 443, true, false),
+];
+
+/// socket 类型和协议的组合
+const SOCKET_TYPES: [(SOCK, IpProtocol); 2] = [
+    (SOCK::Stream, IpProtocol::Tcp),
+    (SOCK::Datagram, IpProtocol::Udp),
+];
+
+/// # `getaddrinfo`
+/// 把名字和服务解析成可以 bind 或 connect 的端点，每个地址和 socket 类型的组合一项
+/// ## 参数
+/// - `node` 数字地址或名字，`None` 时按 `AI_PASSIVE` 返回未指定地址或环回地址
+/// - `service` 端口号或服务名，`None` 时端口为 0
+/// ## 返回值
+/// - `node` 和 `service` 都是 `None`，或名字不存在时返回 `Err(GaiError::NoName)`
+/// - 没有 `node` 却要求规范名时返回 `Err(GaiError::BadFlags)`
+/// - socket 类型或协议不是 TCP 或 UDP 时返回 `Err(GaiError::SockType)`
+/// - 服务名不存在时返回 `Err(GaiError::Service)`
+/// - 名字解析的其它错误见 [`Resolver::lookup`](super::Resolver::lookup)
+pub fn getaddrinfo(
+    node: Option<&str>,
+    service: Option<&str>,
+    hints: &AddrInfoHints,
+) -> Result<Vec<AddrInfo>, GaiError> {
+    if node.is_none() && service.is_none() {
+        return Err(GaiError::NoName);
+    }
+    if node.is_none() && hints.flags.contains(AddrInfoFlags::CANONNAME) {
+        return Err(GaiError::BadFlags);
+    }
+
+    let socket_types: Vec<(SOCK, IpProtocol)> = SOCKET_TYPES
+        .into_iter()
+        .filter(|(socket_type, protocol)| {
+            hints
+                .socket_type
+                .is_none_or(|wanted| wanted == *socket_type)
+                && hints.protocol.is_none_or(|wanted| wanted == *protocol)
+        })
+        .collect();
+    if socket_types.is_empty() {
+        return Err(GaiError::SockType);
+    }
+    let ports = service_ports(service, hints.flags, &socket_types)?;
+
+    let (addresses, canonical_name) = match node {
+        None => {
+            let passive = hints.flags.contains(AddrInfoFlags::PASSIVE);
+            let addresses = [IpVersion::Ipv4, IpVersion::Ipv6]
+                .into_iter()
+                .filter(|version| hints.family.is_none_or(|family| family == *version))
+                .map(|version| match (version, passive) {
+                    (IpVersion::Ipv4, true) => IpAddress::v4(0, 0, 0, 0),
+                    (IpVersion::Ipv4, false) => IpAddress::v4(127, 0, 0, 1),
+                    (IpVersion::Ipv6, true) => IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 0),
+                    (IpVersion::Ipv6, false) => IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1),
+                })
+                .collect();
+            (addresses, None)
+        }
+        Some(node) => match node.parse::<IpAddress>() {
+            Ok(addr) => {
+                if hints.family.is_some_and(|family| family != addr.version()) {
+                    return Err(GaiError::NoName);
+                }
+                (vec![addr], Some(node.to_owned()))
+            }
+            Err(_) if hints.flags.contains(AddrInfoFlags::NUMERICHOST) => {
+                return Err(GaiError::NoName);
+            }
+            Err(_) => {
+                let entry = RESOLVER.lookup(node, hints.family)?;
+                (entry.addresses, Some(entry.canonical_name))
+            }
+        },
+    };
+
+    let mut canonical_name =
+        canonical_name.filter(|_| hints.flags.contains(AddrInfoFlags::CANONNAME));
+    let mut infos = Vec::new();
+    for addr in addresses {
+        for (&(socket_type, protocol), &port) in socket_types.iter().zip(&ports) {
+            let Some(port) = port else {
+                continue;
+            };
+            infos.push(AddrInfo {
+                endpoint: IpEndpoint::new(addr, port),
+                socket_type,
+                protocol,
+                canonical_name: canonical_name.take(),
+            });
+        }
+    }
+    Ok(infos)
+}
+
+/// 每种 socket 类型上服务的端口，没有该服务的类型为 `None`
+fn service_ports(
+    service: Option<&str>,
+    flags: AddrInfoFlags,
+    socket_types: &[(SOCK, IpProtocol)],
+) -> Result<Vec<Option<u16>>, GaiError> {
+    let Some(service) = service else {
+        return Ok(vec![Some(0); socket_types.len()]);
+    };
+    if let Ok(port) = service.parse::<u16>() {
+        return Ok(vec![Some(port); socket_types.len()]);
+    }
+    if flags.contains(AddrInfoFlags::NUMERICSERV) {
+        return Err(GaiError::NoName);
+    }
+    let &(_, port, tcp, udp) = SERVICES
+        .iter()
+        .find(|(name, ..)| *name == service)
+        .ok_or(GaiError::Service)?;
+    let ports: Vec<Option<u16>> = socket_types
+        .iter()
+        .map(|(_, protocol)| match protocol {
+            IpProtocol::Tcp => tcp.then_some(port),
+            _ => udp.then_some(port),
+        })
+        .collect();
+    if ports.iter().all(Option::is_none) {
+        return Err(GaiError::Service);
+    }
+    Ok(ports)
+}
+
+/// # `getnameinfo`
+/// 把端点转换成名字和服务名
+/// ## 返回值
+/// - `(名字, 服务)`，地址没有名字时名字是数字地址，端口没有服务名时服务是端口号
+/// - 设置了 `NI_NAMEREQD` 而地址没有名字时返回 `Err(GaiError::NoName)`，
+///   名字服务器没有应答时返回 `Err(GaiError::Again)`
+pub fn getnameinfo(
+    endpoint: &IpEndpoint,
+    flags: NameInfoFlags,
+) -> Result<(String, String), GaiError> {
+    let host = if flags.contains(NameInfoFlags::NUMERICHOST) {
+        endpoint.addr.to_string()
+    } else {
+        match RESOLVER.lookup_addr(&endpoint.addr) {
+            Ok(name) if flags.contains(NameInfoFlags::NOFQDN) => {
+                name.split('.').next().unwrap_or_default().to_owned()
+            }
+            Ok(name) => name,
+            Err(GaiError::NoData) if flags.contains(NameInfoFlags::NAMEREQD) => {
+                return Err(GaiError::NoName);
+            }
+            Err(err) if flags.contains(NameInfoFlags::NAMEREQD) => return Err(err),
+            Err(_) => endpoint.addr.to_string(),
+        }
+    };
+
+    let udp = flags.contains(NameInfoFlags::DGRAM);
+    let service = SERVICES
+        .iter()
+        .filter(|_| !flags.contains(NameInfoFlags::NUMERICSERV))
+        .find(|&&(_, port, tcp_service, udp_service)| {
+            port == endpoint.port && if udp { udp_service } else { tcp_service }
+        })
+        .map_or_else(
+            || endpoint.port.to_string(),
+            |(name, ..)| (*name).to_owned(),
+        );
+    Ok((host, service))
+}
@@ -0,0 +1,73 @@
+//! 查询结果的缓存，条目按 TTL 过期，满时淘汰最早过期的条目
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use smoltcp::wire::DnsQueryType;
+
+use super::message::RecordValue;
+use super::GaiError;
+
+/// 一次查询的结果，否定应答缓存为 `GaiError::NoName` 或 `GaiError::NoData`
+pub type Answer = Result<(String, Vec<RecordValue>), GaiError>;
+
+#[derive(Debug)]
+struct Entry {
+    answer: Answer,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct Cache {
+    /// 以小写的名字和查询类型为键
+    entries: HashMap<(String, DnsQueryType), Entry>,
+    capacity: usize,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Cache {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    pub fn get(&mut self, name: &str, type_: DnsQueryType) -> Option<Answer> {
+        let key = (name.to_ascii_lowercase(), type_);
+        let entry = self.entries.get(&key)?;
+        if entry.expires_at <= Instant::now() {
+            self.entries.remove(&key);
+            return None;
+        }
+        Some(entry.answer.clone())
+    }
+
+    /// 缓存 `ttl` 秒，容量为 0 或 TTL 为 0 时不缓存
+    pub fn insert(&mut self, name: &str, type_: DnsQueryType, answer: Answer, ttl: u32) {
+        if self.capacity == 0 || ttl == 0 {
+            return;
+        }
+        let now = Instant::now();
+        self.entries.retain(|_, entry| entry.expires_at > now);
+        if self.entries.len() >= self.capacity {
+            let soonest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = soonest {
+                self.entries.remove(&key);
+            }
+        }
+        self.entries.insert(
+            (name.to_ascii_lowercase(), type_),
+            Entry {
+                answer,
+                expires_at: now + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,402 @@
+//! DNS 报文的构造和解析，报文头、问题和记录的格式来自 smoltcp 的 `wire::dns`
+use smoltcp::wire::{
+    DnsFlags, DnsOpcode, DnsPacket, DnsQueryType, DnsQuestion, DnsRcode, DnsRecord, DnsRecordData,
+    DnsRepr, IpAddress,
+};
+
+/// PTR 记录的类型，smoltcp 没有列出
+pub const TYPE_PTR: DnsQueryType = DnsQueryType::Unknown(12);
+/// 编码后域名的最大长度 (RFC 1035)
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+/// 跟随 CNAME 的最大次数
+const MAX_CNAME_HOPS: usize = 8;
+
+/// 应答中与查询有关的一条记录
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordValue {
+    /// A 或 AAAA 记录
+    Address(IpAddress),
+    /// PTR 记录
+    Name(String),
+}
+
+/// 解析后的应答
+#[derive(Debug)]
+pub struct Response {
+    pub rcode: DnsRcode,
+    /// 应答在 UDP 上被截断，需要改用 TCP 重新查询
+    pub truncated: bool,
+    /// 沿 CNAME 走到的最终名字
+    pub canonical_name: String,
+    pub records: Vec<RecordValue>,
+    /// 可以缓存的秒数：肯定应答取记录 TTL 的最小值，否定应答取 SOA 的 minimum (RFC 2308)
+    pub ttl: Option<u32>,
+}
+
+/// # `encode_name`
+/// 把点分的域名编码成 DNS 报文中的标签序列
+/// ## 返回值
+/// - 名字为空、有空标签或超长时返回 `None`
+pub fn encode_name(name: &str) -> Option<Vec<u8>> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return None;
+        }
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+    }
+    bytes.push(0);
+    (bytes.len() <= MAX_NAME_LEN).then_some(bytes)
+}
+
+/// # `query`
+/// 构造一个请求递归解析的查询报文，`name` 是 [`encode_name`] 编码后的名字
+pub fn query(id: u16, name: &[u8], type_: DnsQueryType) -> Vec<u8> {
+    let repr = DnsRepr {
+        transaction_id: id,
+        opcode: DnsOpcode::Query,
+        flags: DnsFlags::RECURSION_DESIRED,
+        question: DnsQuestion { name, type_ },
+    };
+    let mut buffer = vec![0; repr.buffer_len()];
+    repr.emit(&mut DnsPacket::new_unchecked(&mut buffer[..]));
+    buffer
+}
+
+/// # `parse`
+/// 解析 [`query`] 的应答，`name` 是查询的点分名字
+/// ## 返回值
+/// - 报文格式错误，或不是这个查询的应答时返回 `None`
+pub fn parse(buffer: &[u8], id: u16, name: &str, type_: DnsQueryType) -> Option<Response> {
+    let packet = DnsPacket::new_checked(buffer).ok()?;
+    let flags = packet.flags();
+    if packet.transaction_id() != id || !flags.contains(DnsFlags::RESPONSE) {
+        return None;
+    }
+    let mut response = Response {
+        rcode: packet.rcode(),
+        truncated: flags.contains(DnsFlags::TRUNCATED),
+        canonical_name: name.trim_end_matches('.').to_owned(),
+        records: Vec::new(),
+        ttl: None,
+    };
+    // the records of a truncated reply may be cut anywhere
+    if response.truncated {
+        return Some(response);
+    }
+
+    let mut rest = packet.payload();
+    let mut asked = false;
+    for _ in 0..packet.question_count() {
+        let (next, question) = DnsQuestion::parse(rest).ok()?;
+        asked |= question.type_ == type_
+            && decode_name(&packet, question.name)?.eq_ignore_ascii_case(&response.canonical_name);
+        rest = next;
+    }
+    if !asked {
+        return None;
+    }
+    let mut answers = Vec::new();
+    for _ in 0..packet.answer_record_count() {
+        let (next, record) = DnsRecord::parse(rest).ok()?;
+        answers.push((decode_name(&packet, record.name)?, record));
+        rest = next;
+    }
+
+    for _ in 0..MAX_CNAME_HOPS {
+        let target = answers
+            .iter()
+            .find_map(|(owner, record)| match record.data {
+                DnsRecordData::Cname(target)
+                    if owner.eq_ignore_ascii_case(&response.canonical_name) =>
+                {
+                    Some((record.ttl, target))
+                }
+                _ => None,
+            });
+        let Some((ttl, target)) = target else {
+            break;
+        };
+        response.canonical_name = decode_name(&packet, target)?;
+        response.ttl = Some(response.ttl.map_or(ttl, |min| min.min(ttl)));
+    }
+    for (owner, record) in &answers {
+        if !owner.eq_ignore_ascii_case(&response.canonical_name) {
+            continue;
+        }
+        let value = match record.data {
+            DnsRecordData::A(addr) if type_ == DnsQueryType::A => {
+                RecordValue::Address(IpAddress::Ipv4(addr))
+            }
+            DnsRecordData::Aaaa(addr) if type_ == DnsQueryType::Aaaa => {
+                RecordValue::Address(IpAddress::Ipv6(addr))
+            }
+            DnsRecordData::Other(TYPE_PTR, target) if type_ == TYPE_PTR => {
+                RecordValue::Name(decode_name(&packet, target)?)
+            }
+            _ => continue,
+        };
+        response.records.push(value);
+        response.ttl = Some(response.ttl.map_or(record.ttl, |min| min.min(record.ttl)));
+    }
+
+    if response.records.is_empty() {
+        response.ttl = negative_ttl(&packet, rest);
+    }
+    Some(response)
+}
+
+/// 从否定应答的授权部分的 SOA 记录取出可以缓存的时间
+fn negative_ttl(packet: &DnsPacket<&[u8]>, mut rest: &[u8]) -> Option<u32> {
+    for _ in 0..packet.authority_record_count() {
+        let (next, record) = DnsRecord::parse(rest).ok()?;
+        if let DnsRecordData::Other(DnsQueryType::Soa, data) = record.data {
+            // MINIMUM is the last field of the SOA data
+            let minimum = data.get(data.len().checked_sub(4)?..)?;
+            let minimum = u32::from_be_bytes(minimum.try_into().ok()?);
+            return Some(record.ttl.min(minimum));
+        }
+        rest = next;
+    }
+    None
+}
+
+/// 把报文中的名字（可能带压缩指针）解码成点分形式
+fn decode_name(packet: &DnsPacket<&[u8]>, bytes: &[u8]) -> Option<String> {
+    let mut name = String::new();
+    for label in packet.parse_name(bytes) {
+        let label = core::str::from_utf8(label.ok()?).ok()?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(label);
+    }
+    Some(name)
+}
+
+/// # `reverse_name`
+/// 地址在 in-addr.arpa 或 ip6.arpa 下的名字，用于 PTR 查询
+pub fn reverse_name(addr: &IpAddress) -> String {
+    match addr {
+        IpAddress::Ipv4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddress::Ipv6(addr) => {
+            let mut name = String::new();
+            for byte in addr.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::{Ipv4Address, Ipv6Address};
+
+    const RESPONSE: u16 = 0x8180;
+    /// a compression pointer to the name of the question
+    const QNAME: [u8; 2] = [0xc0, 12];
+
+    fn record(name: &[u8], type_: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = name.to_vec();
+        bytes.extend_from_slice(&type_.to_be_bytes());
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&ttl.to_be_bytes());
+        bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn reply(
+        id: u16,
+        flags: u16,
+        name: &str,
+        type_: DnsQueryType,
+        answers: &[Vec<u8>],
+        authority: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for field in [
+            id,
+            flags,
+            1,
+            answers.len() as u16,
+            authority.len() as u16,
+            0,
+        ] {
+            bytes.extend_from_slice(&field.to_be_bytes());
+        }
+        bytes.extend_from_slice(&encode_name(name).unwrap());
+        bytes.extend_from_slice(&u16::from(type_).to_be_bytes());
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        for record in answers.iter().chain(authority) {
+            bytes.extend_from_slice(record);
+        }
+        bytes
+    }
+
+    #[test]
+    fn encode_name_checks_the_labels() {
+        let expected = b"\x07example\x03com\x00".to_vec();
+        assert_eq!(encode_name("example.com"), Some(expected.clone()));
+        assert_eq!(encode_name("example.com."), Some(expected));
+        assert_eq!(encode_name(""), None);
+        assert_eq!(encode_name("."), None);
+        assert_eq!(encode_name("a..b"), None);
+        assert_eq!(encode_name(&"a".repeat(64)), None);
+        assert!(encode_name(&"a".repeat(63)).is_some());
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert_eq!(encode_name(&long), None);
+    }
+
+    #[test]
+    fn query_asks_for_recursion() {
+        let name = encode_name("example.com").unwrap();
+        let bytes = query(0x1234, &name, DnsQueryType::Aaaa);
+        assert_eq!(&bytes[..12], &[0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&bytes[12..12 + name.len()], &name[..]);
+        assert_eq!(&bytes[12 + name.len()..], &[0, 28, 0, 1]);
+    }
+
+    #[test]
+    fn parse_follows_cnames() {
+        let target = encode_name("edge.example.net").unwrap();
+        let answers = [
+            record(&QNAME, 5, 60, &target),
+            record(&target, 1, 300, &[192, 0, 2, 1]),
+            record(&target, 1, 120, &[192, 0, 2, 2]),
+            // a record of another owner is ignored
+            record(&QNAME, 1, 10, &[192, 0, 2, 3]),
+        ];
+        let bytes = reply(
+            7,
+            RESPONSE,
+            "www.example.com",
+            DnsQueryType::A,
+            &answers,
+            &[],
+        );
+        let response = parse(&bytes, 7, "WWW.example.com.", DnsQueryType::A).unwrap();
+        assert_eq!(response.rcode, DnsRcode::NoError);
+        assert!(!response.truncated);
+        assert_eq!(response.canonical_name, "edge.example.net");
+        assert_eq!(
+            response.records,
+            [
+                RecordValue::Address(IpAddress::Ipv4(Ipv4Address::new(192, 0, 2, 1))),
+                RecordValue::Address(IpAddress::Ipv4(Ipv4Address::new(192, 0, 2, 2))),
+            ]
+        );
+        assert_eq!(response.ttl, Some(60));
+    }
+
+    #[test]
+    fn parse_aaaa_and_ptr() {
+        let addr = Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let answers = [record(&QNAME, 28, 30, &addr.octets())];
+        let bytes = reply(
+            1,
+            RESPONSE,
+            "example.com",
+            DnsQueryType::Aaaa,
+            &answers,
+            &[],
+        );
+        let response = parse(&bytes, 1, "example.com", DnsQueryType::Aaaa).unwrap();
+        assert_eq!(
+            response.records,
+            [RecordValue::Address(IpAddress::Ipv6(addr))]
+        );
+        // an A record in an AAAA reply is not an answer
+        let answers = [record(&QNAME, 1, 30, &[192, 0, 2, 1])];
+        let bytes = reply(
+            1,
+            RESPONSE,
+            "example.com",
+            DnsQueryType::Aaaa,
+            &answers,
+            &[],
+        );
+        let response = parse(&bytes, 1, "example.com", DnsQueryType::Aaaa).unwrap();
+        assert!(response.records.is_empty());
+
+        let name = reverse_name(&IpAddress::Ipv4(Ipv4Address::new(192, 0, 2, 1)));
+        assert_eq!(name, "1.2.0.192.in-addr.arpa");
+        let host = encode_name("host.example.com").unwrap();
+        let answers = [record(&QNAME, 12, 30, &host)];
+        let bytes = reply(2, RESPONSE, &name, TYPE_PTR, &answers, &[]);
+        let response = parse(&bytes, 2, &name, TYPE_PTR).unwrap();
+        assert_eq!(
+            response.records,
+            [RecordValue::Name("host.example.com".into())]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_other_replies() {
+        let answers = [record(&QNAME, 1, 30, &[192, 0, 2, 1])];
+        let bytes = reply(9, RESPONSE, "example.com", DnsQueryType::A, &answers, &[]);
+        assert!(parse(&bytes, 8, "example.com", DnsQueryType::A).is_none());
+        assert!(parse(&bytes, 9, "example.org", DnsQueryType::A).is_none());
+        assert!(parse(&bytes, 9, "example.com", DnsQueryType::Aaaa).is_none());
+        assert!(parse(&bytes[..bytes.len() - 1], 9, "example.com", DnsQueryType::A).is_none());
+        let query = reply(9, 0x0100, "example.com", DnsQueryType::A, &[], &[]);
+        assert!(parse(&query, 9, "example.com", DnsQueryType::A).is_none());
+    }
+
+    #[test]
+    fn parse_truncated_and_negative_replies() {
+        // the records of a truncated reply are not looked at
+        let bytes = reply(
+            3,
+            RESPONSE | 0x0200,
+            "example.com",
+            DnsQueryType::A,
+            &[],
+            &[],
+        );
+        let response = parse(&bytes[..], 3, "example.com", DnsQueryType::A).unwrap();
+        assert!(response.truncated);
+        assert!(response.records.is_empty());
+
+        // the TTL of a negative reply is the SOA TTL capped by its MINIMUM
+        let mut soa = encode_name("ns.example.com").unwrap();
+        soa.extend_from_slice(&encode_name("admin.example.com").unwrap());
+        for field in [1u32, 7200, 900, 86400, 600] {
+            soa.extend_from_slice(&field.to_be_bytes());
+        }
+        let authority = [record(&QNAME, 6, 3600, &soa)];
+        let bytes = reply(
+            4,
+            RESPONSE | 3,
+            "example.com",
+            DnsQueryType::A,
+            &[],
+            &authority,
+        );
+        let response = parse(&bytes, 4, "example.com", DnsQueryType::A).unwrap();
+        assert_eq!(response.rcode, DnsRcode::NXDomain);
+        assert!(response.records.is_empty());
+        assert_eq!(response.ttl, Some(600));
+    }
+
+    #[test]
+    fn reverse_name_of_ipv6() {
+        let addr = Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x1);
+        let name = reverse_name(&IpAddress::Ipv6(addr));
+        assert!(name.starts_with("1.0.0.0.0.0.0.0."));
+        assert!(name.ends_with(".8.b.d.0.1.0.0.2.ip6.arpa"));
+        assert_eq!(name.split('.').count(), 34);
+    }
+}
@@ -0,0 +1,316 @@
+//! 存根解析器：把名字交给递归名字服务器解析，查询 A、AAAA 和 PTR 记录。
+//! 查询经协议栈的 UDP 套接字发出，应答被截断时改用 TCP；每个名字服务器等待 `timeout`，
+//! 所有名字服务器轮流询问 `attempts` 轮。没有配置名字服务器时使用各网卡 DHCP 租约中的 DNS 服务器
+mod addrinfo;
+mod cache;
+mod message;
+mod transport;
+
+pub use addrinfo::{
+    getaddrinfo, getnameinfo, AddrInfo, AddrInfoFlags, AddrInfoHints, NameInfoFlags,
+};
+
+use std::time::Duration;
+
+use smoltcp::wire::{DnsQueryType, DnsRcode, IpAddress, IpEndpoint, IpVersion};
+use spin::{Mutex, RwLock};
+
+use crate::interface::registry::NET_DEVICES;
+use cache::{Answer, Cache};
+use message::RecordValue;
+
+/// 名字服务器的端口
+pub const DNS_PORT: u16 = 53;
+/// 否定应答没有 SOA 记录时缓存的秒数
+const NEGATIVE_TTL: u32 = 60;
+
+lazy_static::lazy_static! {
+    /// 解析器
+    pub static ref RESOLVER: Resolver = Resolver::new(ResolverConfig::default());
+}
+
+/// `getaddrinfo` 和 `getnameinfo` 的错误，对应 `EAI_*`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GaiError {
+    /// `EAI_BADFLAGS`
+    BadFlags,
+    /// `EAI_NONAME` 名字不存在
+    NoName,
+    /// `EAI_AGAIN` 名字服务器暂时无法回答，或都没有应答
+    Again,
+    /// `EAI_FAIL` 名字服务器给出了不可恢复的错误
+    Fail,
+    /// `EAI_NODATA` 名字存在但没有所请求的记录
+    NoData,
+    /// `EAI_FAMILY`
+    Family,
+    /// `EAI_SOCKTYPE`
+    SockType,
+    /// `EAI_SERVICE` 服务名不存在，或不支持所请求的 socket 类型
+    Service,
+}
+
+impl GaiError {
+    /// # `code`
+    /// 对应的 `EAI_*` 错误码
+    pub fn code(&self) -> i32 {
+        match self {
+            GaiError::BadFlags => libc::EAI_BADFLAGS,
+            GaiError::NoName => libc::EAI_NONAME,
+            GaiError::Again => libc::EAI_AGAIN,
+            GaiError::Fail => libc::EAI_FAIL,
+            GaiError::NoData => libc::EAI_NODATA,
+            GaiError::Family => libc::EAI_FAMILY,
+            GaiError::SockType => libc::EAI_SOCKTYPE,
+            GaiError::Service => libc::EAI_SERVICE,
+        }
+    }
+}
+
+/// 解析器的配置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolverConfig {
+    /// 名字服务器，为空时使用各网卡 DHCP 租约中的 DNS 服务器
+    pub nameservers: Vec<IpAddress>,
+    /// 等待每个名字服务器应答的时间
+    pub timeout: Duration,
+    /// 轮流询问所有名字服务器的轮数
+    pub attempts: usize,
+    /// 缓存的查询结果数，0 表示不缓存
+    pub cache_size: usize,
+    /// 不经名字服务器解析的名字，类似 /etc/hosts
+    pub hosts: Vec<(String, IpAddress)>,
+}
+
+impl Default for ResolverConfig {
+    /// 与 resolv.conf 的默认值相同：超时 5 秒，询问 2 轮
+    fn default() -> Self {
+        ResolverConfig {
+            nameservers: Vec::new(),
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            cache_size: 32,
+            hosts: vec![
+                ("localhost".to_owned(), IpAddress::v4(127, 0, 0, 1)),
+                (
+                    "localhost".to_owned(),
+                    IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1),
+                ),
+            ],
+        }
+    }
+}
+
+/// 名字解析的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostEntry {
+    /// 沿 CNAME 走到的最终名字
+    pub canonical_name: String,
+    /// IPv4 地址在前
+    pub addresses: Vec<IpAddress>,
+}
+
+#[derive(Debug)]
+pub struct Resolver {
+    config: RwLock<ResolverConfig>,
+    cache: Mutex<Cache>,
+}
+
+impl Resolver {
+    fn new(config: ResolverConfig) -> Self {
+        Resolver {
+            cache: Mutex::new(Cache::new(config.cache_size)),
+            config: RwLock::new(config),
+        }
+    }
+
+    pub fn config(&self) -> ResolverConfig {
+        self.config.read().clone()
+    }
+
+    /// # `set_config`
+    /// 替换解析器的配置，缓存被清空
+    pub fn set_config(&self, config: ResolverConfig) {
+        *self.cache.lock() = Cache::new(config.cache_size);
+        *self.config.write() = config;
+    }
+
+    pub fn flush_cache(&self) {
+        self.cache.lock().clear();
+    }
+
+    /// # `nameservers`
+    /// 实际使用的名字服务器
+    /// ## 返回值
+    /// - 配置的名字服务器，没有配置时是已注册网卡的 DHCP 租约中的 DNS 服务器
+    pub fn nameservers(&self) -> Vec<IpAddress> {
+        let nameservers = self.config.read().nameservers.clone();
+        if !nameservers.is_empty() {
+            return nameservers;
+        }
+        let mut nameservers = Vec::new();
+        for iface in NET_DEVICES.ifaces() {
+            let Some(lease) = iface.dhcp_lease() else {
+                continue;
+            };
+            for server in lease.dns_servers {
+                let server = IpAddress::Ipv4(server);
+                if !nameservers.contains(&server) {
+                    nameservers.push(server);
+                }
+            }
+        }
+        nameservers
+    }
+
+    /// # `lookup`
+    /// 解析 `name` 的地址，`version` 为 `None` 时同时查询 A 和 AAAA 记录
+    /// ## 返回值
+    /// - 名字不存在时返回 `Err(GaiError::NoName)`
+    /// - 没有所请求协议族的地址时返回 `Err(GaiError::NoData)`
+    /// - 没有名字服务器或名字服务器都没有应答时返回 `Err(GaiError::Again)`
+    pub fn lookup(&self, name: &str, version: Option<IpVersion>) -> Result<HostEntry, GaiError> {
+        let wanted = |addr: &IpAddress| version.is_none_or(|version| addr.version() == version);
+        let addresses: Vec<IpAddress> = self
+            .config
+            .read()
+            .hosts
+            .iter()
+            .filter(|(host, addr)| host.eq_ignore_ascii_case(name) && wanted(addr))
+            .map(|(_, addr)| *addr)
+            .collect();
+        if !addresses.is_empty() {
+            return Ok(HostEntry {
+                canonical_name: name.to_owned(),
+                addresses,
+            });
+        }
+
+        let types = match version {
+            Some(IpVersion::Ipv4) => &[DnsQueryType::A][..],
+            Some(IpVersion::Ipv6) => &[DnsQueryType::Aaaa][..],
+            None => &[DnsQueryType::A, DnsQueryType::Aaaa][..],
+        };
+        let mut entry: Option<HostEntry> = None;
+        let mut error = GaiError::NoData;
+        for &type_ in types {
+            match self.resolve(name, type_) {
+                Ok((canonical_name, records)) => {
+                    let entry = entry.get_or_insert_with(|| HostEntry {
+                        canonical_name,
+                        addresses: Vec::new(),
+                    });
+                    entry
+                        .addresses
+                        .extend(records.into_iter().filter_map(|record| match record {
+                            RecordValue::Address(addr) => Some(addr),
+                            RecordValue::Name(_) => None,
+                        }));
+                }
+                // a missing name is missing for every type
+                Err(GaiError::NoName) => return Err(GaiError::NoName),
+                Err(GaiError::NoData) => {}
+                Err(err) => error = err,
+            }
+        }
+        entry.ok_or(error)
+    }
+
+    /// # `lookup_addr`
+    /// 通过 PTR 记录解析地址对应的名字
+    /// ## 返回值
+    /// - 错误与 [`Resolver::lookup`] 相同
+    pub fn lookup_addr(&self, addr: &IpAddress) -> Result<String, GaiError> {
+        if let Some((host, _)) = self
+            .config
+            .read()
+            .hosts
+            .iter()
+            .find(|(_, other)| other == addr)
+        {
+            return Ok(host.clone());
+        }
+        let (_, records) = self.resolve(&message::reverse_name(addr), message::TYPE_PTR)?;
+        records
+            .into_iter()
+            .find_map(|record| match record {
+                RecordValue::Name(name) => Some(name),
+                RecordValue::Address(_) => None,
+            })
+            .ok_or(GaiError::NoData)
+    }
+
+    /// 查询一种记录，先查缓存
+    fn resolve(&self, name: &str, type_: DnsQueryType) -> Answer {
+        if let Some(answer) = self.cache.lock().get(name, type_) {
+            return answer;
+        }
+        let qname = message::encode_name(name).ok_or(GaiError::NoName)?;
+        let config = self.config();
+        let nameservers = self.nameservers();
+
+        let mut error = GaiError::Again;
+        for _ in 0..config.attempts {
+            for &server in &nameservers {
+                let server = IpEndpoint::new(server, DNS_PORT);
+                let id = std::random::random(..);
+                let query = message::query(id, &qname, type_);
+                let response = match transport::exchange_udp(server, &query, id, config.timeout) {
+                    Ok(reply) => message::parse(&reply, id, name, type_),
+                    Err(err) => {
+                        log::debug!("dns: no reply from {} for {}: {:?}", server, name, err);
+                        continue;
+                    }
+                };
+                let response = match response {
+                    Some(response) if response.truncated => {
+                        match transport::exchange_tcp(server, &query, config.timeout) {
+                            Ok(reply) => message::parse(&reply, id, name, type_),
+                            Err(err) => {
+                                log::debug!(
+                                    "dns: TCP to {} for {} failed: {:?}",
+                                    server,
+                                    name,
+                                    err
+                                );
+                                continue;
+                            }
+                        }
+                    }
+                    response => response,
+                };
+                let Some(response) = response else {
+                    log::debug!("dns: malformed reply from {} for {}", server, name);
+                    error = GaiError::Fail;
+                    continue;
+                };
+
+                let (answer, ttl) = match response.rcode {
+                    DnsRcode::NoError if response.records.is_empty() => {
+                        (Err(GaiError::NoData), response.ttl.unwrap_or(NEGATIVE_TTL))
+                    }
+                    DnsRcode::NoError => (
+                        Ok((response.canonical_name, response.records)),
+                        response.ttl.unwrap_or(0),
+                    ),
+                    DnsRcode::NXDomain => {
+                        (Err(GaiError::NoName), response.ttl.unwrap_or(NEGATIVE_TTL))
+                    }
+                    // the next server may do better
+                    DnsRcode::ServFail => {
+                        error = GaiError::Again;
+                        continue;
+                    }
+                    rcode => {
+                        log::debug!("dns: {} answered {} with {:?}", server, name, rcode);
+                        error = GaiError::Fail;
+                        continue;
+                    }
+                };
+                self.cache.lock().insert(name, type_, answer.clone(), ttl);
+                return answer;
+            }
+        }
+        Err(error)
+    }
+}
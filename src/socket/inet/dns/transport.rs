@@ -0,0 +1,126 @@
+//! 经协议栈的 UDP 和 TCP 套接字与名字服务器交换报文
+use std::time::{Duration, Instant};
+
+use linux_errnos::Errno as SystemError;
+use smoltcp::wire::IpEndpoint;
+
+use crate::socket::endpoint::Endpoint;
+use crate::socket::inet::{TcpSocket, UdpSocket};
+use crate::socket::Socket;
+
+/// 不带 EDNS 时 UDP 上 DNS 报文的最大长度 (RFC 1035)
+const UDP_MAX_LEN: usize = 512;
+/// 等待应答时检查的间隔，与 `wq_wait_event_interruptible` 相同
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// # `exchange_udp`
+/// 用一个新的 UDP 套接字向 `server` 发送查询，等待事务号为 `id` 的应答，
+/// 其它来源或其它事务号的数据报被丢弃
+/// ## 返回值
+/// - `timeout` 内没有应答时返回 `Err(SystemError::ETIMEDOUT)`
+pub fn exchange_udp(
+    server: IpEndpoint,
+    query: &[u8],
+    id: u16,
+    timeout: Duration,
+) -> Result<Vec<u8>, SystemError> {
+    let socket = UdpSocket::new(true, server.addr.version());
+    let result = udp_round_trip(&socket, server, query, id, Instant::now() + timeout);
+    socket.close();
+    result
+}
+
+fn udp_round_trip(
+    socket: &UdpSocket,
+    server: IpEndpoint,
+    query: &[u8],
+    id: u16,
+    deadline: Instant,
+) -> Result<Vec<u8>, SystemError> {
+    socket.connect(Endpoint::Ip(server))?;
+    socket.try_send(query, None)?;
+    let mut buffer = [0u8; UDP_MAX_LEN];
+    loop {
+        match socket.try_recv(&mut buffer) {
+            Ok((len, from)) if from == server && buffer[..len].starts_with(&id.to_be_bytes()) => {
+                return Ok(buffer[..len].to_vec());
+            }
+            Ok((_, from)) => log::debug!("dns: dropped a stray datagram from {}", from),
+            Err(SystemError::EAGAIN) => wait(deadline)?,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// # `exchange_tcp`
+/// 用一个新的 TCP 连接向 `server` 发送查询并读取应答，用于在 UDP 上被截断的应答。
+/// 报文前有两字节的长度 (RFC 1035 4.2.2)
+/// ## 返回值
+/// - `timeout` 内没有完成时返回 `Err(SystemError::ETIMEDOUT)`
+/// - 服务器在应答完整之前关闭连接时返回 `Err(SystemError::ECONNRESET)`
+pub fn exchange_tcp(
+    server: IpEndpoint,
+    query: &[u8],
+    timeout: Duration,
+) -> Result<Vec<u8>, SystemError> {
+    let socket = TcpSocket::new(false, server.addr.version());
+    let result = tcp_round_trip(&socket, server, query, Instant::now() + timeout);
+    let _ = Socket::close(socket.as_ref());
+    result
+}
+
+fn tcp_round_trip(
+    socket: &TcpSocket,
+    server: IpEndpoint,
+    query: &[u8],
+    deadline: Instant,
+) -> Result<Vec<u8>, SystemError> {
+    socket.start_connect(server)?;
+    loop {
+        match socket.check_connect() {
+            Err(SystemError::EAGAIN) => wait(deadline)?,
+            result => break result?,
+        }
+    }
+
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(query);
+    let mut sent = 0;
+    while sent < message.len() {
+        match socket.try_send(&message[sent..]) {
+            Ok(len) => sent += len,
+            Err(SystemError::EAGAIN) => wait(deadline)?,
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut reply = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        if let Some(len) = reply
+            .get(..2)
+            .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+        {
+            if reply.len() >= 2 + len {
+                reply.truncate(2 + len);
+                return Ok(reply.split_off(2));
+            }
+        }
+        match socket.try_recv(&mut buffer) {
+            Ok(0) => return Err(SystemError::ECONNRESET),
+            Ok(len) => reply.extend_from_slice(&buffer[..len]),
+            Err(SystemError::EAGAIN) => wait(deadline)?,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// 睡眠一个检查间隔，到了 `deadline` 时返回 `Err(SystemError::ETIMEDOUT)`
+fn wait(deadline: Instant) -> Result<(), SystemError> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(SystemError::ETIMEDOUT);
+    }
+    std::thread::sleep(POLL_INTERVAL.min(deadline - now));
+    Ok(())
+}
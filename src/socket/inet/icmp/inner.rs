@@ -0,0 +1,305 @@
+use alloc::sync::Arc;
+use linux_errnos::Errno as SystemError;
+use smoltcp;
+
+use crate::{
+    interface::Iface,
+    libs::spinlock::SpinLock,
+    socket::inet::common::{BoundInner, Domain, PortFamily, Types as InetTypes},
+    socket::inet::table::SocketInfo,
+};
+
+pub type SmolIcmpSocket = smoltcp::socket::icmp::Socket<'static>;
+
+pub const DEFAULT_METADATA_BUF_SIZE: usize = 64;
+pub const DEFAULT_RX_BUF_SIZE: usize = 64 * 1024;
+pub const DEFAULT_TX_BUF_SIZE: usize = 64 * 1024;
+
+/// Type, code, checksum, identifier and sequence number in front of the payload
+pub const ECHO_HEADER_LEN: usize = 8;
+
+fn new_smoltcp_socket() -> SmolIcmpSocket {
+    let rx_buffer = smoltcp::socket::icmp::PacketBuffer::new(
+        vec![smoltcp::socket::icmp::PacketMetadata::EMPTY; DEFAULT_METADATA_BUF_SIZE],
+        vec![0; DEFAULT_RX_BUF_SIZE],
+    );
+    let tx_buffer = smoltcp::socket::icmp::PacketBuffer::new(
+        vec![smoltcp::socket::icmp::PacketMetadata::EMPTY; DEFAULT_METADATA_BUF_SIZE],
+        vec![0; DEFAULT_TX_BUF_SIZE],
+    );
+    SmolIcmpSocket::new(rx_buffer, tx_buffer)
+}
+
+/// The ICMP types of an echo request and an echo reply in the family
+fn echo_types(version: smoltcp::wire::IpVersion) -> (u8, u8) {
+    match version {
+        smoltcp::wire::IpVersion::Ipv4 => (8, 0),
+        smoltcp::wire::IpVersion::Ipv6 => (128, 129),
+    }
+}
+
+#[derive(Debug)]
+pub struct UnboundIcmp {
+    socket: SmolIcmpSocket,
+}
+
+impl Default for UnboundIcmp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UnboundIcmp {
+    pub fn new() -> Self {
+        Self {
+            socket: new_smoltcp_socket(),
+        }
+    }
+
+    /// The port of `local_endpoint` is the echo identifier, 0 picks a free one
+    pub fn bind(
+        self,
+        local_endpoint: smoltcp::wire::IpEndpoint,
+        domain: Domain,
+    ) -> Result<BoundIcmp, SystemError> {
+        // an unspecified address takes a socket on every iface, the first one is ours
+        let mut socket = Some(self.socket);
+        let mut inner = BoundInner::bind(&local_endpoint.addr, || {
+            socket.take().unwrap_or_else(new_smoltcp_socket)
+        })?;
+        // smoltcp gives an echo reply to the socket of its identifier whatever the family
+        let ident = if local_endpoint.port == 0 {
+            inner.bind_ephemeral_port(InetTypes::Icmp, PortFamily::Both)
+        } else {
+            inner
+                .bind_port(InetTypes::Icmp, local_endpoint.port, PortFamily::Both)
+                .map(|_| local_endpoint.port)
+        }
+        .inspect_err(|_| inner.release())?;
+        BoundIcmp::new(inner, local_endpoint.addr, ident, domain)
+    }
+
+    pub fn bind_ephemeral(
+        self,
+        remote: smoltcp::wire::IpAddress,
+        domain: Domain,
+    ) -> Result<BoundIcmp, SystemError> {
+        let (mut inner, address) = BoundInner::bind_ephemeral(self.socket, remote)?;
+        let ident = inner
+            .bind_ephemeral_port(InetTypes::Icmp, PortFamily::Both)
+            .inspect_err(|_| inner.release())?;
+        BoundIcmp::new(inner, address, ident, domain)
+    }
+}
+
+#[derive(Debug)]
+pub struct BoundIcmp {
+    inner: BoundInner,
+    /// The port is the echo identifier
+    local: smoltcp::wire::IpEndpoint,
+    remote: SpinLock<Option<smoltcp::wire::IpAddress>>,
+    domain: Domain,
+}
+
+impl BoundIcmp {
+    fn new(
+        inner: BoundInner,
+        address: smoltcp::wire::IpAddress,
+        ident: u16,
+        domain: Domain,
+    ) -> Result<Self, SystemError> {
+        let mut result = Ok(());
+        inner.for_each::<SmolIcmpSocket, _>(|socket| {
+            if socket
+                .bind(smoltcp::socket::icmp::Endpoint::Ident(ident))
+                .is_err()
+            {
+                result = Err(SystemError::EINVAL);
+            }
+        });
+        if let Err(err) = result {
+            inner.unbind_port(InetTypes::Icmp, ident);
+            inner.release();
+            return Err(err);
+        }
+        Ok(Self {
+            inner,
+            local: smoltcp::wire::IpEndpoint::new(address, ident),
+            remote: SpinLock::new(None),
+            domain,
+        })
+    }
+
+    pub fn with_socket<F, T>(&self, f: F) -> T
+    where
+        F: Fn(&SmolIcmpSocket) -> T,
+    {
+        self.inner.with(f)
+    }
+
+    /// Like on Linux, writable while at most half of the send buffer is taken
+    pub fn can_send(&self) -> bool {
+        self.with_socket(|socket| {
+            socket.can_send() && socket.send_queue() * 2 <= socket.payload_send_capacity()
+        })
+    }
+
+    pub fn endpoint(&self) -> smoltcp::wire::IpEndpoint {
+        self.local
+    }
+
+    pub fn connect(&self, remote: smoltcp::wire::IpAddress) {
+        self.remote.lock().replace(remote);
+    }
+
+    pub fn peer(&self) -> Option<smoltcp::wire::IpAddress> {
+        *self.remote.lock()
+    }
+
+    /// The destination of a request, `to` or the connected peer
+    pub fn remote(
+        &self,
+        to: Option<smoltcp::wire::IpAddress>,
+    ) -> Result<smoltcp::wire::IpAddress, SystemError> {
+        to.or(*self.remote.lock()).ok_or(SystemError::EDESTADDRREQ)
+    }
+
+    /// The error of the link to the peer once connected, see [`BoundInner::link_error`]
+    pub fn link_error(&self) -> Option<SystemError> {
+        self.inner.link_error(self.remote.lock().as_ref())
+    }
+
+    /// Takes the next echo reply of the socket's family. The smoltcp socket
+    /// also takes echo requests carrying our identifier, which smoltcp answers
+    /// by itself, they are dropped here like the replies of the other family
+    pub fn try_recv(
+        &self,
+        buf: &mut [u8],
+    ) -> Result<(usize, smoltcp::wire::IpAddress), SystemError> {
+        let (_, reply) = echo_types(self.domain.version);
+        self.inner
+            .find_map::<SmolIcmpSocket, _, _>(|socket| {
+                while let Ok((packet, from)) = socket.recv() {
+                    if packet.first() != Some(&reply) || !self.domain.accepts(&from) {
+                        continue;
+                    }
+                    // like a datagram, what does not fit is discarded
+                    let size = packet.len().min(buf.len());
+                    buf[..size].copy_from_slice(&packet[..size]);
+                    return Some((size, from));
+                }
+                None
+            })
+            .ok_or(SystemError::EAGAIN)
+    }
+
+    /// Whether a packet waits on the socket of any iface, it may still turn out
+    /// to be one [`BoundIcmp::try_recv`] drops
+    pub fn can_recv(&self) -> bool {
+        self.inner
+            .find_map::<SmolIcmpSocket, _, _>(|socket| socket.can_recv().then_some(()))
+            .is_some()
+    }
+
+    /// Sends the echo request in `buf`, an ICMP header and the payload. The
+    /// identifier is replaced with the socket's and the checksum is computed.
+    /// Fails with `EAGAIN` while the send buffer has no room for it
+    pub fn try_send(
+        &self,
+        buf: &[u8],
+        remote: smoltcp::wire::IpAddress,
+    ) -> Result<usize, SystemError> {
+        let (request, _) = echo_types(self.domain.version);
+        if buf.len() < ECHO_HEADER_LEN || buf[0] != request || buf[1] != 0 {
+            return Err(SystemError::EINVAL);
+        }
+        // a specific local address has no source for the other family
+        if !self.local.addr.is_unspecified() && self.local.addr.version() != remote.version() {
+            return Err(SystemError::ENETUNREACH);
+        }
+        self.inner
+            .with_mut_to::<SmolIcmpSocket, _, _>(&remote, |socket| {
+                if buf.len() > socket.payload_send_capacity() {
+                    return Err(SystemError::EMSGSIZE);
+                }
+                let packet = socket.send(buf.len(), remote).map_err(|err| match err {
+                    smoltcp::socket::icmp::SendError::BufferFull => SystemError::EAGAIN,
+                    smoltcp::socket::icmp::SendError::Unaddressable => SystemError::ENOBUFS,
+                })?;
+                packet.copy_from_slice(buf);
+                packet[4..6].copy_from_slice(&self.local.port.to_be_bytes());
+                // smoltcp emits the request again with a valid checksum
+                packet[2..4].fill(0);
+                Ok(buf.len())
+            })
+            .unwrap_or(Err(SystemError::ENETUNREACH))
+    }
+
+    /// Puts a socket on an iface registered after binding to an unspecified address
+    pub fn attach(&mut self, iface: &Arc<dyn Iface>) -> bool {
+        if !self.inner.should_attach(iface) {
+            return false;
+        }
+        if let Err(err) =
+            iface
+                .port_manager()
+                .bind_port(InetTypes::Icmp, self.local.port, PortFamily::Both)
+        {
+            log::warn!(
+                "icmp identifier {} is taken on {}: {:?}",
+                self.local.port,
+                iface.iface_name(),
+                err
+            );
+            return false;
+        }
+        let mut socket = new_smoltcp_socket();
+        socket
+            .bind(smoltcp::socket::icmp::Endpoint::Ident(self.local.port))
+            .expect("A bound icmp identifier is not valid");
+        self.inner.attach(iface, socket);
+        true
+    }
+
+    pub fn socket_info(&self) -> SocketInfo {
+        let (mut send_queue, mut recv_queue) = (0, 0);
+        self.inner.for_each::<SmolIcmpSocket, _>(|socket| {
+            send_queue += socket.send_queue();
+            recv_queue += socket.recv_queue();
+        });
+        SocketInfo {
+            protocol: InetTypes::Icmp,
+            local: self.local,
+            remote: self
+                .peer()
+                .map(|remote| smoltcp::wire::IpEndpoint::new(remote, 0)),
+            state: None,
+            send_queue,
+            recv_queue,
+            pid: None,
+            ifaces: self
+                .inner
+                .ifaces()
+                .map(|iface| iface.iface_name())
+                .collect(),
+            timeout: None,
+            keep_alive: None,
+        }
+    }
+
+    pub fn inner(&self) -> &BoundInner {
+        &self.inner
+    }
+
+    pub fn close(&self) {
+        self.inner.unbind_port(InetTypes::Icmp, self.local.port);
+        self.inner.release();
+    }
+}
+
+// Icmp Inner 负责其内部资源管理
+#[derive(Debug)]
+pub enum IcmpInner {
+    Unbound(UnboundIcmp),
+    Bound(BoundIcmp),
+}
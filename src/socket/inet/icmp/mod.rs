@@ -0,0 +1,379 @@
+//! ICMP echo socket，对应 Linux 无需特权的 ping socket
+//! (`SOCK_DGRAM` 加 `IPPROTO_ICMP` 或 `IPPROTO_ICMPV6`)。
+//! 发送的是 ICMP 头和负载，只能是 echo 请求，标识符换成 socket 绑定的标识符、校验和由协议栈计算；
+//! 收到的是带有这个标识符的 echo 应答。标识符就是 socket 地址中的端口，绑定端口 0 或不绑定时自动分配
+use inner::{IcmpInner, UnboundIcmp};
+use linux_errnos::Errno as SystemError;
+use smoltcp;
+
+use crate::event_poll::EPollEventType;
+use crate::interface::registry::NET_DEVICES;
+use crate::interface::Iface;
+use crate::libs::wait_queue::{wq_wait_event_interruptible, WaitQueue};
+use crate::socket::{Socket, PMSG};
+use crate::{libs::rwlock::RwLock, socket::endpoint::Endpoint};
+use alloc::sync::{Arc, Weak};
+use core::sync::atomic::AtomicBool;
+
+use super::table::SocketInfo;
+use super::{Domain, InetSocket};
+
+pub mod inner;
+
+type EP = EPollEventType;
+
+#[derive(Debug)]
+pub struct IcmpSocket {
+    inner: RwLock<Option<IcmpInner>>,
+    /// ICMPv6 sockets do not take IPv4-mapped addresses
+    domain: Domain,
+    nonblock: AtomicBool,
+    wait_queue: WaitQueue,
+    self_ref: Weak<IcmpSocket>,
+}
+
+impl IcmpSocket {
+    pub fn new(nonblock: bool, version: smoltcp::wire::IpVersion) -> Arc<Self> {
+        Arc::new_cyclic(|me| Self {
+            inner: RwLock::new(Some(IcmpInner::Unbound(UnboundIcmp::new()))),
+            domain: Domain {
+                version,
+                v6only: true,
+            },
+            nonblock: AtomicBool::new(nonblock),
+            wait_queue: WaitQueue::default(),
+            self_ref: me.clone(),
+        })
+    }
+
+    pub fn is_nonblock(&self) -> bool {
+        self.nonblock.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn do_bind(&self, local_endpoint: smoltcp::wire::IpEndpoint) -> Result<(), SystemError> {
+        let local_endpoint = self.domain.to_inner(local_endpoint)?;
+        let mut inner = self.inner.write();
+        match inner.take().expect("Icmp Inner is None") {
+            IcmpInner::Unbound(unbound) => {
+                // subscribe before bind lists the ifaces, an iface registered in
+                // between waits for our lock and is attached once
+                let any_iface = local_endpoint.addr.is_unspecified();
+                let me = self.self_ref.upgrade().unwrap();
+                if any_iface {
+                    NET_DEVICES.bind_socket(me.clone());
+                }
+                let bound = unbound.bind(local_endpoint, self.domain).inspect_err(|_| {
+                    if any_iface {
+                        NET_DEVICES.unbind_socket(me);
+                    }
+                })?;
+                self.register(&bound);
+                inner.replace(IcmpInner::Bound(bound));
+                Ok(())
+            }
+            bound => {
+                inner.replace(bound);
+                Err(SystemError::EINVAL)
+            }
+        }
+    }
+
+    /// Iface events reach the socket on every iface it is bound to
+    fn register(&self, bound: &inner::BoundIcmp) {
+        let me = self.self_ref.upgrade().unwrap();
+        for iface in bound.inner().ifaces() {
+            iface.common().bind_socket(me.clone());
+        }
+    }
+
+    /// Binds an identifier and the source address towards `remote` on first use
+    fn bind_ephemeral(&self, remote: smoltcp::wire::IpAddress) -> Result<(), SystemError> {
+        let mut inner_guard = self.inner.write();
+        let bound = match inner_guard.take().expect("Icmp Inner is None") {
+            IcmpInner::Bound(inner) => inner,
+            IcmpInner::Unbound(inner) => match inner.bind_ephemeral(remote, self.domain) {
+                Ok(bound) => {
+                    self.register(&bound);
+                    bound
+                }
+                Err(err) => {
+                    inner_guard.replace(IcmpInner::Unbound(UnboundIcmp::new()));
+                    return Err(err);
+                }
+            },
+        };
+        inner_guard.replace(IcmpInner::Bound(bound));
+        Ok(())
+    }
+
+    pub fn is_bound(&self) -> bool {
+        matches!(*self.inner.read(), Some(IcmpInner::Bound(_)))
+    }
+
+    pub fn close(&self) {
+        let mut inner = self.inner.write();
+        if let Some(IcmpInner::Bound(bound)) = &*inner {
+            bound.close();
+            let me = self.self_ref.upgrade().unwrap();
+            for iface in bound.inner().ifaces() {
+                iface.common().unbind_socket(me.clone());
+            }
+            if bound.inner().is_any_iface() {
+                NET_DEVICES.unbind_socket(me);
+            }
+            inner.take();
+        }
+    }
+
+    pub fn try_recv(
+        &self,
+        buf: &mut [u8],
+    ) -> Result<(usize, smoltcp::wire::IpAddress), SystemError> {
+        match self.inner.read().as_ref().expect("Icmp Inner is None") {
+            IcmpInner::Bound(bound) => {
+                let ret = bound.try_recv(buf);
+                bound.inner().poll();
+                match ret {
+                    Err(SystemError::EAGAIN) => {
+                        Err(bound.link_error().unwrap_or(SystemError::EAGAIN))
+                    }
+                    ret => ret,
+                }
+            }
+            _ => Err(SystemError::ENOTCONN),
+        }
+    }
+
+    #[inline]
+    pub fn can_recv(&self) -> bool {
+        self.event().contains(EP::EPOLLIN)
+    }
+
+    /// 绑定的网卡因设备错误停用时，等待中的收发应当醒来返回错误
+    #[inline]
+    pub fn has_error(&self) -> bool {
+        self.event().contains(EP::EPOLLERR)
+    }
+
+    #[inline]
+    pub fn can_send(&self) -> bool {
+        self.event().contains(EP::EPOLLOUT)
+    }
+
+    pub fn try_send(
+        &self,
+        buf: &[u8],
+        to: Option<smoltcp::wire::IpAddress>,
+    ) -> Result<usize, SystemError> {
+        if !self.is_bound() {
+            self.bind_ephemeral(to.ok_or(SystemError::EDESTADDRREQ)?)?;
+        }
+        match self.inner.read().as_ref().expect("Icmp Inner is None") {
+            IcmpInner::Bound(bound) => {
+                let remote = bound.remote(to)?;
+                let iface = bound
+                    .inner()
+                    .iface_to(&remote)
+                    .ok_or(SystemError::ENETUNREACH)?;
+                // a down iface is not polled, the request would wait for it
+                if !iface.common().is_running() {
+                    return Err(iface.link_error().unwrap_or(SystemError::ENETDOWN));
+                }
+                let ret = bound.try_send(buf, remote);
+                iface.poll();
+                match iface.link_error() {
+                    Some(err) => Err(err),
+                    None => ret,
+                }
+            }
+            _ => Err(SystemError::ENOTCONN),
+        }
+    }
+
+    pub fn event(&self) -> EPollEventType {
+        let mut event = EPollEventType::empty();
+        match self.inner.read().as_ref().unwrap() {
+            IcmpInner::Unbound(_) => {
+                event.insert(EP::EPOLLOUT | EP::EPOLLWRNORM | EP::EPOLLWRBAND);
+            }
+            IcmpInner::Bound(bound) => {
+                if bound.can_recv() {
+                    event.insert(EP::EPOLLIN | EP::EPOLLRDNORM);
+                }
+
+                if bound.can_send() {
+                    event.insert(EP::EPOLLOUT | EP::EPOLLWRNORM | EP::EPOLLWRBAND);
+                }
+
+                if bound.link_error().is_some() {
+                    event.insert(EP::EPOLLERR);
+                }
+            }
+        }
+        event
+    }
+
+    /// Blocks while the send buffer is full unless the socket or `flags` is nonblocking
+    fn send_request(
+        &self,
+        buffer: &[u8],
+        to: Option<smoltcp::wire::IpAddress>,
+        flags: PMSG,
+    ) -> Result<usize, SystemError> {
+        loop {
+            match self.try_send(buffer, to) {
+                Err(SystemError::EAGAIN)
+                    if !self.is_nonblock() && !flags.contains(PMSG::DONTWAIT) =>
+                {
+                    wq_wait_event_interruptible(
+                        &self.wait_queue,
+                        || self.can_send() || self.has_error(),
+                        None,
+                    )?;
+                }
+                result => break result,
+            }
+        }
+    }
+
+    /// Blocks until an echo reply arrives unless the socket or `flags` is nonblocking
+    fn recv_reply(
+        &self,
+        buffer: &mut [u8],
+        flags: PMSG,
+    ) -> Result<(usize, smoltcp::wire::IpAddress), SystemError> {
+        if self.is_nonblock() || flags.contains(PMSG::DONTWAIT) {
+            return self.try_recv(buffer);
+        }
+        loop {
+            match self.try_recv(buffer) {
+                Err(SystemError::EAGAIN) => {
+                    wq_wait_event_interruptible(
+                        &self.wait_queue,
+                        || self.can_recv() || self.has_error(),
+                        None,
+                    )?;
+                }
+                result => break result,
+            }
+        }
+    }
+}
+
+impl Socket for IcmpSocket {
+    fn wait_queue(&self) -> &WaitQueue {
+        &self.wait_queue
+    }
+
+    fn poll(&self) -> usize {
+        self.event().bits() as usize
+    }
+
+    fn bind(&self, local_endpoint: Endpoint) -> Result<(), SystemError> {
+        if let Endpoint::Ip(local_endpoint) = local_endpoint {
+            return self.do_bind(local_endpoint);
+        }
+        Err(SystemError::EAFNOSUPPORT)
+    }
+
+    fn send_buffer_size(&self) -> usize {
+        match self.inner.read().as_ref().unwrap() {
+            IcmpInner::Bound(bound) => bound.with_socket(|socket| socket.payload_send_capacity()),
+            _ => inner::DEFAULT_TX_BUF_SIZE,
+        }
+    }
+
+    fn recv_buffer_size(&self) -> usize {
+        match self.inner.read().as_ref().unwrap() {
+            IcmpInner::Bound(bound) => bound.with_socket(|socket| socket.payload_recv_capacity()),
+            _ => inner::DEFAULT_RX_BUF_SIZE,
+        }
+    }
+
+    /// The port of `endpoint` is ignored, like the kernel does for ping sockets
+    fn connect(&self, endpoint: Endpoint) -> Result<(), SystemError> {
+        let Endpoint::Ip(remote) = endpoint else {
+            return Err(SystemError::EAFNOSUPPORT);
+        };
+        let remote = self.domain.to_inner(remote)?.addr;
+        if !self.is_bound() {
+            self.bind_ephemeral(remote)?;
+        }
+        if let Some(IcmpInner::Bound(bound)) = self.inner.read().as_ref() {
+            bound.connect(remote);
+        }
+        Ok(())
+    }
+
+    fn send(&self, buffer: &[u8], flags: PMSG) -> Result<usize, SystemError> {
+        self.send_request(buffer, None, flags)
+    }
+
+    fn send_to(&self, buffer: &[u8], flags: PMSG, address: Endpoint) -> Result<usize, SystemError> {
+        let Endpoint::Ip(remote) = address else {
+            return Err(SystemError::EINVAL);
+        };
+        let remote = self.domain.to_inner(remote)?;
+        self.send_request(buffer, Some(remote.addr), flags)
+    }
+
+    fn recv(&self, buffer: &mut [u8], flags: PMSG) -> Result<usize, SystemError> {
+        self.recv_reply(buffer, flags).map(|(len, _)| len)
+    }
+
+    fn recv_from(
+        &self,
+        buffer: &mut [u8],
+        flags: PMSG,
+        address: Option<Endpoint>,
+    ) -> Result<(usize, Endpoint), SystemError> {
+        if let Some(endpoint) = address {
+            self.connect(endpoint)?;
+        }
+        self.recv_reply(buffer, flags)
+            .map(|(len, remote)| (len, Endpoint::Ip(smoltcp::wire::IpEndpoint::new(remote, 0))))
+    }
+
+    fn close(&self) -> Result<(), SystemError> {
+        self.close();
+        Ok(())
+    }
+
+    fn get_name(&self) -> Result<Endpoint, SystemError> {
+        match self.inner.read().as_ref().expect("Icmp Inner is None") {
+            IcmpInner::Bound(bound) => Ok(Endpoint::Ip(bound.endpoint())),
+            IcmpInner::Unbound(_) => Ok(Endpoint::Ip(self.domain.unspecified())),
+        }
+    }
+
+    fn get_peer_name(&self) -> Result<Endpoint, SystemError> {
+        match self.inner.read().as_ref().expect("Icmp Inner is None") {
+            IcmpInner::Bound(bound) => bound
+                .peer()
+                .map(|remote| Endpoint::Ip(smoltcp::wire::IpEndpoint::new(remote, 0)))
+                .ok_or(SystemError::ENOTCONN),
+            IcmpInner::Unbound(_) => Err(SystemError::ENOTCONN),
+        }
+    }
+}
+
+impl InetSocket for IcmpSocket {
+    fn on_iface_events(&self) {}
+
+    fn socket_info(&self) -> Option<SocketInfo> {
+        match self.inner.read().as_ref()? {
+            IcmpInner::Bound(bound) => Some(bound.socket_info()),
+            IcmpInner::Unbound(_) => None,
+        }
+    }
+
+    fn on_iface_added(&self, iface: &Arc<dyn Iface>) {
+        let attached = match self.inner.write().as_mut() {
+            Some(IcmpInner::Bound(bound)) => bound.attach(iface),
+            _ => false,
+        };
+        if attached {
+            iface.common().bind_socket(self.self_ref.upgrade().unwrap());
+        }
+    }
+}
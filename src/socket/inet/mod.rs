@@ -1,27 +1,33 @@
 use smoltcp;
 
-// pub mod raw;
-// pub mod icmp;
 pub mod common;
 pub mod datagram;
+pub mod dns;
+pub mod icmp;
 pub mod posix;
+pub mod raw;
 pub mod stream;
 pub mod syscall;
+pub mod table;
 
 pub use common::BoundInner;
+pub use common::Domain;
 pub use common::Types;
-// pub use raw::RawSocket;
 pub use datagram::UdpSocket;
+pub use icmp::IcmpSocket;
+pub use raw::RawSocket;
 pub use stream::TcpSocket;
 
 use smoltcp::wire::IpAddress;
 use smoltcp::wire::IpEndpoint;
 use smoltcp::wire::Ipv4Address;
-// use smoltcp::wire::Ipv6Address;
+use smoltcp::wire::Ipv6Address;
 
 // pub use syscall::Inet;
 
 use super::Socket;
+use crate::interface::Iface;
+use alloc::sync::Arc;
 
 /// A local endpoint, which indicates that the local endpoint is unspecified.
 ///
@@ -30,11 +36,25 @@ use super::Socket;
 /// unspecified endpoint helps with that.
 const UNSPECIFIED_LOCAL_ENDPOINT_V4: IpEndpoint =
     IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::UNSPECIFIED), 0);
-// const UNSPECIFIED_LOCAL_ENDPOINT_V6: IpEndpoint =
-//     IpEndpoint::new(IpAddress::Ipv6(Ipv6Address::UNSPECIFIED), 0);
+const UNSPECIFIED_LOCAL_ENDPOINT_V6: IpEndpoint =
+    IpEndpoint::new(IpAddress::Ipv6(Ipv6Address::UNSPECIFIED), 0);
 
 pub trait InetSocket: Socket {
     /// `on_iface_events`
     /// 通知socket发生的事件
     fn on_iface_events(&self);
+
+    /// `on_iface_added`
+    /// 通知绑定到未指定地址的socket有新的网卡注册
+    fn on_iface_added(&self, iface: &Arc<dyn Iface>);
+
+    /// `socket_info`
+    /// 获取socket的快照，未绑定或已关闭时返回 `None`
+    fn socket_info(&self) -> Option<table::SocketInfo>;
+
+    /// `adopt`
+    /// 接收同一网卡上另一协议族的 socket 转交的数据，不接受时原样返回
+    fn adopt(&self, stray: common::Stray) -> Result<(), common::Stray> {
+        Err(stray)
+    }
 }
@@ -65,3 +65,24 @@ bitflags::bitflags! {
         // ... other flags ...
     }
 }
+
+bitflags::bitflags! {
+    pub struct Ipv6Options: u32 {
+        const IPV6_ADDRFORM = 1;              // Turn an IPv6 socket into another family
+        const IPV6_CHECKSUM = 7;              // Checksum offset for raw sockets
+        const IPV6_NEXTHOP = 9;               // Next hop address
+        const IPV6_UNICAST_HOPS = 16;         // Hop limit of unicast packets
+        const IPV6_MULTICAST_IF = 17;         // Multicast interface
+        const IPV6_MULTICAST_HOPS = 18;       // Hop limit of multicast packets
+        const IPV6_MULTICAST_LOOP = 19;       // Multicast loopback
+        const IPV6_ADD_MEMBERSHIP = 20;       // Join a multicast group
+        const IPV6_DROP_MEMBERSHIP = 21;      // Leave a multicast group
+        const IPV6_ROUTER_ALERT = 22;         // Router alert
+        const IPV6_MTU_DISCOVER = 23;         // MTU discovery
+        const IPV6_MTU = 24;                  // MTU
+        const IPV6_RECVERR = 25;              // Receive errors
+        const IPV6_V6ONLY = 26;               // Only IPv6, no IPv4-mapped addresses
+        const IPV6_JOIN_ANYCAST = 27;         // Join an anycast group
+        const IPV6_LEAVE_ANYCAST = 28;        // Leave an anycast group
+    }
+}
@@ -0,0 +1,488 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use linux_errnos::Errno as SystemError;
+use smoltcp;
+use smoltcp::wire::{Icmpv6Packet, IpAddress, IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet};
+
+use crate::{
+    interface::{registry::NET_DEVICES, route::ROUTES, Iface},
+    libs::spinlock::SpinLock,
+    process::{Pid, ProcessManager},
+    socket::inet::common::{get_iface_to_bind, BoundInner, Domain, Types as InetTypes},
+    socket::inet::table::SocketInfo,
+};
+
+pub type SmolRawSocket = smoltcp::socket::raw::Socket<'static>;
+
+pub const DEFAULT_METADATA_BUF_SIZE: usize = 64;
+pub const DEFAULT_RX_BUF_SIZE: usize = 64 * 1024;
+pub const DEFAULT_TX_BUF_SIZE: usize = 64 * 1024;
+
+/// `IPPROTO_RAW`, a socket that only sends; its packets carry their own header
+/// and any protocol, like on Linux `IP_HDRINCL` starts out set
+pub const IPPROTO_RAW: IpProtocol = IpProtocol::Unknown(255);
+
+/// The TTL or hop limit of the headers we build, the Linux default
+const DEFAULT_HOP_LIMIT: u8 = 64;
+/// The largest IP packet, the total length is 16 bits
+const MAX_PACKET_LEN: usize = 65535;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+
+fn new_smoltcp_socket(version: IpVersion, protocol: IpProtocol) -> SmolRawSocket {
+    let rx_buffer = smoltcp::socket::raw::PacketBuffer::new(
+        vec![smoltcp::socket::raw::PacketMetadata::EMPTY; DEFAULT_METADATA_BUF_SIZE],
+        vec![0; DEFAULT_RX_BUF_SIZE],
+    );
+    let tx_buffer = smoltcp::socket::raw::PacketBuffer::new(
+        vec![smoltcp::socket::raw::PacketMetadata::EMPTY; DEFAULT_METADATA_BUF_SIZE],
+        vec![0; DEFAULT_TX_BUF_SIZE],
+    );
+    SmolRawSocket::new(version, protocol, rx_buffer, tx_buffer)
+}
+
+/// The socket an `IPPROTO_RAW` socket sends packets of `protocol` through,
+/// without a receive buffer the packets of `protocol` are not kept
+fn new_send_only_socket(version: IpVersion, protocol: IpProtocol) -> SmolRawSocket {
+    let rx_buffer = smoltcp::socket::raw::PacketBuffer::new(Vec::new(), Vec::new());
+    let tx_buffer = smoltcp::socket::raw::PacketBuffer::new(
+        vec![smoltcp::socket::raw::PacketMetadata::EMPTY; DEFAULT_METADATA_BUF_SIZE],
+        vec![0; DEFAULT_TX_BUF_SIZE],
+    );
+    SmolRawSocket::new(version, protocol, rx_buffer, tx_buffer)
+}
+
+/// The source and destination of a received packet and the length of its header
+fn addresses(version: IpVersion, packet: &[u8]) -> Option<(IpAddress, IpAddress, usize)> {
+    match version {
+        IpVersion::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(packet).ok()?;
+            Some((
+                packet.src_addr().into(),
+                packet.dst_addr().into(),
+                packet.header_len() as usize,
+            ))
+        }
+        IpVersion::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(packet).ok()?;
+            Some((
+                packet.src_addr().into(),
+                packet.dst_addr().into(),
+                packet.header_len(),
+            ))
+        }
+    }
+}
+
+/// The protocol of a complete packet given with `IP_HDRINCL`, `None` if its
+/// header is not one of `version`
+fn header_protocol(version: IpVersion, packet: &[u8]) -> Option<IpProtocol> {
+    match version {
+        IpVersion::Ipv4 => {
+            let header = Ipv4Packet::new_unchecked(packet);
+            let header_len = header.header_len() as usize;
+            (packet.len() >= IPV4_HEADER_LEN
+                && header.version() == 4
+                && (IPV4_HEADER_LEN..=packet.len()).contains(&header_len))
+            .then(|| header.next_header())
+        }
+        IpVersion::Ipv6 => {
+            let header = Ipv6Packet::new_unchecked(packet);
+            (packet.len() >= IPV6_HEADER_LEN && header.version() == 6).then(|| header.next_header())
+        }
+    }
+}
+
+/// A raw socket has smoltcp sockets on every iface from the start, like on
+/// Linux it takes the packets of its protocol without being bound
+#[derive(Debug)]
+pub struct BoundRaw {
+    /// The smoltcp sockets on every iface, by protocol. Only the socket's own
+    /// protocol receives, `IPPROTO_RAW` gets a send-only entry for each protocol
+    /// it sends until the packets are out. Empty until an iface is registered
+    inners: SpinLock<BTreeMap<u8, BoundInner>>,
+    /// Every iface the socket follows, all of them are polled
+    ifaces: Vec<Arc<dyn Iface>>,
+    version: IpVersion,
+    protocol: IpProtocol,
+    /// Set by bind, the source of sent packets and the only destination received
+    local: SpinLock<Option<IpAddress>>,
+    /// Set by connect, the only source received
+    remote: SpinLock<Option<IpAddress>>,
+    /// The process that opened the socket, a raw socket takes no port
+    owner: Pid,
+}
+
+impl BoundRaw {
+    /// Does not need an iface, the sockets come with the first one registered
+    pub fn new(version: IpVersion, protocol: IpProtocol) -> Self {
+        let raw = Self {
+            inners: SpinLock::new(BTreeMap::new()),
+            ifaces: NET_DEVICES.ifaces(),
+            version,
+            protocol,
+            local: SpinLock::new(None),
+            remote: SpinLock::new(None),
+            owner: ProcessManager::current_pid(),
+        };
+        if protocol != IPPROTO_RAW {
+            // fails only without ifaces
+            let _ = raw.with_inner(protocol, |_| ());
+        }
+        raw
+    }
+
+    /// Calls `f` with the smoltcp sockets of `protocol`, they are put on every
+    /// iface the first time; without an iface that fails with `ENODEV`
+    fn with_inner<R>(
+        &self,
+        protocol: IpProtocol,
+        f: impl FnOnce(&BoundInner) -> R,
+    ) -> Result<R, SystemError> {
+        let mut inners = self.inners.lock();
+        let inner = match inners.entry(u8::from(protocol)) {
+            alloc::collections::btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            alloc::collections::btree_map::Entry::Vacant(entry) => {
+                let (version, receives) = (self.version, self.protocol != IPPROTO_RAW);
+                let inner = BoundInner::bind(&Domain::new(version).unspecified().addr, || {
+                    if receives {
+                        new_smoltcp_socket(version, protocol)
+                    } else {
+                        new_send_only_socket(version, protocol)
+                    }
+                })?;
+                entry.insert(inner)
+            }
+        };
+        Ok(f(inner))
+    }
+
+    /// Calls `f` with the smoltcp sockets that receive, `None` before an iface
+    /// is registered and for `IPPROTO_RAW`
+    fn with_own<R>(&self, f: impl FnOnce(&BoundInner) -> R) -> Option<R> {
+        if self.protocol == IPPROTO_RAW {
+            return None;
+        }
+        self.inners.lock().get(&u8::from(self.protocol)).map(f)
+    }
+
+    /// Calls `f` with the receiving socket on the first iface
+    pub fn with_socket<F, T>(&self, f: F) -> Option<T>
+    where
+        F: Fn(&SmolRawSocket) -> T,
+    {
+        self.with_own(|inner| inner.with(f))
+    }
+
+    /// Only sets the local address, an unspecified one clears it
+    pub fn bind(&self, address: IpAddress) -> Result<(), SystemError> {
+        if address.is_unspecified() {
+            self.local.lock().take();
+            return Ok(());
+        }
+        if get_iface_to_bind(&address).is_none() {
+            return Err(SystemError::EADDRNOTAVAIL);
+        }
+        self.local.lock().replace(address);
+        Ok(())
+    }
+
+    pub fn connect(&self, remote: IpAddress) {
+        self.remote.lock().replace(remote);
+    }
+
+    pub fn local(&self) -> Option<IpAddress> {
+        *self.local.lock()
+    }
+
+    pub fn peer(&self) -> Option<IpAddress> {
+        *self.remote.lock()
+    }
+
+    /// The destination of a packet, `to` or the connected peer
+    pub fn remote(&self, to: Option<IpAddress>) -> Result<IpAddress, SystemError> {
+        to.or(*self.remote.lock()).ok_or(SystemError::EDESTADDRREQ)
+    }
+
+    /// Takes the next packet sent to the bound address from the connected peer.
+    /// An IPv4 packet comes with its header, of an IPv6 one only the payload
+    /// is returned, as Linux does
+    pub fn try_recv(&self, buf: &mut [u8]) -> Result<(usize, IpAddress), SystemError> {
+        let local = self.local();
+        let remote = self.peer();
+        self.with_own(|inner| {
+            inner.find_map::<SmolRawSocket, _, _>(|socket| {
+                while let Ok(packet) = socket.recv() {
+                    let Some((src, dst, header_len)) = addresses(self.version, packet) else {
+                        continue;
+                    };
+                    if local.is_some_and(|local| local != dst)
+                        || remote.is_some_and(|remote| remote != src)
+                    {
+                        continue;
+                    }
+                    let packet = match self.version {
+                        IpVersion::Ipv4 => packet,
+                        IpVersion::Ipv6 => &packet[header_len..],
+                    };
+                    // like a datagram, what does not fit is discarded
+                    let size = packet.len().min(buf.len());
+                    buf[..size].copy_from_slice(&packet[..size]);
+                    return Some((size, src));
+                }
+                None
+            })
+        })
+        .flatten()
+        .ok_or(SystemError::EAGAIN)
+    }
+
+    /// Like on Linux, writable while at most half of the send buffer of every
+    /// iface is taken, also by the packets `IPPROTO_RAW` sent of any protocol
+    pub fn can_send(&self) -> bool {
+        let mut can_send = true;
+        for inner in self.inners.lock().values() {
+            inner.for_each::<SmolRawSocket, _>(|socket| {
+                can_send &=
+                    socket.can_send() && socket.send_queue() * 2 <= socket.payload_send_capacity();
+            });
+        }
+        can_send
+    }
+
+    /// Whether a packet waits on the socket of any iface, it may still turn out
+    /// to be one [`BoundRaw::try_recv`] drops
+    pub fn can_recv(&self) -> bool {
+        self.with_own(|inner| {
+            inner
+                .find_map::<SmolRawSocket, _, _>(|socket| socket.can_recv().then_some(()))
+                .is_some()
+        })
+        .unwrap_or(false)
+    }
+
+    /// Sends `buf` to `remote`. Without `hdrincl` it is the payload and the
+    /// header is built here; with it `buf` is a complete packet whose length
+    /// is fixed up and whose source is filled in when unspecified.
+    /// Fails with `EAGAIN` while the send buffer has no room for it
+    pub fn try_send(
+        &self,
+        buf: &[u8],
+        remote: IpAddress,
+        hdrincl: bool,
+    ) -> Result<usize, SystemError> {
+        let local = self.local();
+        // a specific local address has no source for the other family
+        if local.is_some_and(|local| local.version() != remote.version()) {
+            return Err(SystemError::ENETUNREACH);
+        }
+        let src = match local {
+            Some(local) => local,
+            None => ROUTES.lookup(&remote).ok_or(SystemError::ENETUNREACH)?.src,
+        };
+        if hdrincl {
+            self.send_packet(buf, src, remote)
+        } else {
+            self.send_payload(buf, src, remote)
+        }
+    }
+
+    fn send_payload(
+        &self,
+        buf: &[u8],
+        src: IpAddress,
+        remote: IpAddress,
+    ) -> Result<usize, SystemError> {
+        let repr =
+            smoltcp::wire::IpRepr::new(src, remote, self.protocol, buf.len(), DEFAULT_HOP_LIMIT);
+        let header_len = repr.header_len();
+        if header_len + buf.len() > MAX_PACKET_LEN {
+            return Err(SystemError::EMSGSIZE);
+        }
+        self.with_inner(self.protocol, |inner| {
+            inner
+                .with_mut_to::<SmolRawSocket, _, _>(&remote, |socket| {
+                    let packet = socket
+                        .send(header_len + buf.len())
+                        .map_err(|_| SystemError::EAGAIN)?;
+                    repr.emit(
+                        &mut packet[..header_len],
+                        &smoltcp::phy::ChecksumCapabilities::default(),
+                    );
+                    let payload = &mut packet[header_len..];
+                    payload.copy_from_slice(buf);
+                    // the kernel always computes the ICMPv6 checksum
+                    if let (IpProtocol::Icmpv6, IpAddress::Ipv6(src), IpAddress::Ipv6(dst)) =
+                        (self.protocol, src, remote)
+                    {
+                        if payload.len() >= 4 {
+                            Icmpv6Packet::new_unchecked(payload).fill_checksum(&src, &dst);
+                        }
+                    }
+                    Ok(buf.len())
+                })
+                .unwrap_or(Err(SystemError::ENETUNREACH))
+        })?
+    }
+
+    /// smoltcp checks the header and fills in the IPv4 checksum when it
+    /// dispatches the packet. It drops packets of another protocol than the
+    /// socket's, so `IPPROTO_RAW` sends through a socket of the header's protocol
+    fn send_packet(
+        &self,
+        buf: &[u8],
+        src: IpAddress,
+        remote: IpAddress,
+    ) -> Result<usize, SystemError> {
+        if buf.len() > MAX_PACKET_LEN {
+            return Err(SystemError::EMSGSIZE);
+        }
+        let protocol = header_protocol(self.version, buf).ok_or(SystemError::EINVAL)?;
+        if self.protocol != IPPROTO_RAW && protocol != self.protocol {
+            return Err(SystemError::EINVAL);
+        }
+        self.with_inner(protocol, |inner| {
+            inner
+                .with_mut_to::<SmolRawSocket, _, _>(&remote, |socket| {
+                    let packet = socket.send(buf.len()).map_err(|_| SystemError::EAGAIN)?;
+                    packet.copy_from_slice(buf);
+                    match src {
+                        IpAddress::Ipv4(src) => {
+                            let mut packet = Ipv4Packet::new_unchecked(packet);
+                            packet.set_total_len(buf.len() as u16);
+                            if packet.src_addr().is_unspecified() {
+                                packet.set_src_addr(src);
+                            }
+                        }
+                        IpAddress::Ipv6(src) => {
+                            let mut packet = Ipv6Packet::new_unchecked(packet);
+                            packet.set_payload_len((buf.len() - IPV6_HEADER_LEN) as u16);
+                            if packet.src_addr().is_unspecified() {
+                                packet.set_src_addr(src);
+                            }
+                        }
+                    }
+                    Ok(buf.len())
+                })
+                .unwrap_or(Err(SystemError::ENETUNREACH))
+        })?
+    }
+
+    /// Takes the send-only sockets of `IPPROTO_RAW` off the ifaces once their
+    /// packets are gone. smoltcp counts a packet taken by any raw socket as
+    /// handled and answers no ICMP error for it, so a send-only socket left on
+    /// an iface would stop the port unreachable errors of the protocols it sent
+    pub fn release_sent(&self) {
+        if self.protocol != IPPROTO_RAW {
+            return;
+        }
+        self.inners.lock().retain(|_, inner| {
+            let mut send_queue = 0;
+            inner.for_each::<SmolRawSocket, _>(|socket| send_queue += socket.send_queue());
+            if send_queue == 0 {
+                inner.release();
+            }
+            send_queue != 0
+        });
+    }
+
+    /// Puts sockets on an iface registered after the socket was created,
+    /// returns whether the socket did not follow `iface` yet
+    pub fn attach(&mut self, iface: &Arc<dyn Iface>) -> bool {
+        let (version, protocol) = (self.version, self.protocol);
+        let inners = self.inners.get_mut();
+        for (&other, inner) in inners.iter_mut() {
+            if !inner.should_attach(iface) {
+                continue;
+            }
+            let other = IpProtocol::from(other);
+            let socket = if protocol == IPPROTO_RAW {
+                new_send_only_socket(version, other)
+            } else {
+                new_smoltcp_socket(version, other)
+            };
+            inner.attach(iface, socket);
+        }
+        // the first iface, the socket's own sockets are put on it now
+        if protocol != IPPROTO_RAW && !inners.contains_key(&u8::from(protocol)) {
+            if let Ok(inner) = BoundInner::bind(&Domain::new(version).unspecified().addr, || {
+                new_smoltcp_socket(version, protocol)
+            }) {
+                inners.insert(u8::from(protocol), inner);
+            }
+        }
+        if self.ifaces.iter().any(|other| Arc::ptr_eq(other, iface)) {
+            return false;
+        }
+        self.ifaces.push(iface.clone());
+        true
+    }
+
+    /// Polls every iface the socket follows
+    pub fn poll(&self) {
+        for iface in self.ifaces() {
+            iface.poll();
+        }
+    }
+
+    /// The error of the iface towards the connected peer, without one the
+    /// socket fails only when none of its ifaces is usable
+    pub fn link_error(&self) -> Option<SystemError> {
+        if let Some(iface) = self.peer().and_then(|remote| self.iface_to(&remote)) {
+            return iface.link_error();
+        }
+        let mut error = None;
+        for iface in self.ifaces() {
+            error.get_or_insert(iface.link_error()?);
+        }
+        error
+    }
+
+    /// Every iface the socket follows
+    pub fn ifaces(&self) -> impl Iterator<Item = &Arc<dyn Iface>> {
+        self.ifaces.iter()
+    }
+
+    /// The iface packets to `remote` go out of
+    pub fn iface_to(&self, remote: &IpAddress) -> Option<Arc<dyn Iface>> {
+        ROUTES.lookup(remote).map(|next_hop| next_hop.iface)
+    }
+
+    /// The local endpoint, its port is the protocol like on Linux
+    pub fn endpoint(&self) -> smoltcp::wire::IpEndpoint {
+        let addr = self
+            .local()
+            .unwrap_or(Domain::new(self.version).unspecified().addr);
+        smoltcp::wire::IpEndpoint::new(addr, u8::from(self.protocol) as u16)
+    }
+
+    pub fn socket_info(&self) -> SocketInfo {
+        let (mut send_queue, mut recv_queue) = (0, 0);
+        for inner in self.inners.lock().values() {
+            inner.for_each::<SmolRawSocket, _>(|socket| {
+                send_queue += socket.send_queue();
+                recv_queue += socket.recv_queue();
+            });
+        }
+        SocketInfo {
+            protocol: InetTypes::Raw,
+            local: self.endpoint(),
+            remote: self
+                .peer()
+                .map(|remote| smoltcp::wire::IpEndpoint::new(remote, 0)),
+            state: None,
+            send_queue,
+            recv_queue,
+            pid: Some(self.owner),
+            ifaces: self.ifaces.iter().map(|iface| iface.iface_name()).collect(),
+            timeout: None,
+            keep_alive: None,
+        }
+    }
+
+    pub fn close(&self) {
+        for inner in self.inners.lock().values() {
+            inner.release();
+        }
+    }
+}
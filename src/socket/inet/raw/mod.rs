@@ -0,0 +1,366 @@
+//! 原始 IP socket (`SOCK_RAW`)，收发一种 IP 协议的报文。
+//! socket 创建后就在每个网卡上收取该协议的报文，IPv4 报文带 IP 头，IPv6 报文只有负载；
+//! 发送时由协议栈构造 IP 头，设置了 `IP_HDRINCL` 的 IPv4 socket 则发送用户给出的完整报文。
+//! bind 只设置源地址并只收取发往该地址的报文，connect 只收取来自对端的报文。
+//! `IPPROTO_RAW` socket 只发送，`IP_HDRINCL` 默认打开，报文可以是任意协议
+use inner::{BoundRaw, IPPROTO_RAW};
+use linux_errnos::Errno as SystemError;
+use smoltcp;
+
+use crate::event_poll::EPollEventType;
+use crate::interface::registry::NET_DEVICES;
+use crate::interface::Iface;
+use crate::libs::wait_queue::{wq_wait_event_interruptible, WaitQueue};
+use crate::socket::{Socket, PMSG, PSOL};
+use crate::{libs::rwlock::RwLock, socket::endpoint::Endpoint};
+use alloc::sync::{Arc, Weak};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::posix::option::IpOptions;
+use super::table::SocketInfo;
+use super::{Domain, InetSocket};
+
+pub mod inner;
+
+type EP = EPollEventType;
+
+#[derive(Debug)]
+pub struct RawSocket {
+    /// `None` after close
+    inner: RwLock<Option<BoundRaw>>,
+    /// IPv6 raw sockets do not take IPv4-mapped addresses
+    domain: Domain,
+    hdrincl: AtomicBool,
+    nonblock: AtomicBool,
+    wait_queue: WaitQueue,
+    self_ref: Weak<RawSocket>,
+}
+
+impl RawSocket {
+    /// # `new`
+    /// 创建收发 `protocol` 报文的 socket，并在每个已注册的网卡上开始收取，
+    /// 还没有网卡时从第一个注册的网卡开始收取
+    pub fn new(
+        nonblock: bool,
+        version: smoltcp::wire::IpVersion,
+        protocol: smoltcp::wire::IpProtocol,
+    ) -> Arc<Self> {
+        let socket = Arc::new_cyclic(|me| Self {
+            inner: RwLock::new(None),
+            domain: Domain {
+                version,
+                v6only: true,
+            },
+            hdrincl: AtomicBool::new(protocol == IPPROTO_RAW),
+            nonblock: AtomicBool::new(nonblock),
+            wait_queue: WaitQueue::default(),
+            self_ref: me.clone(),
+        });
+        socket.register(version, protocol);
+        socket
+    }
+
+    pub fn is_nonblock(&self) -> bool {
+        self.nonblock.load(Ordering::Relaxed)
+    }
+
+    /// Iface events reach the socket on every iface, and on ifaces registered later
+    fn register(&self, version: smoltcp::wire::IpVersion, protocol: smoltcp::wire::IpProtocol) {
+        let me = self.self_ref.upgrade().unwrap();
+        // subscribe before the ifaces are listed, an iface registered in
+        // between waits for our lock and is attached once
+        let mut inner = self.inner.write();
+        NET_DEVICES.bind_socket(me.clone());
+        let bound = BoundRaw::new(version, protocol);
+        for iface in bound.ifaces() {
+            iface.common().bind_socket(me.clone());
+        }
+        *inner = Some(bound);
+    }
+
+    pub fn close(&self) {
+        let mut inner = self.inner.write();
+        if let Some(bound) = inner.take() {
+            bound.close();
+            let me = self.self_ref.upgrade().unwrap();
+            for iface in bound.ifaces() {
+                iface.common().unbind_socket(me.clone());
+            }
+            NET_DEVICES.unbind_socket(me);
+        }
+    }
+
+    pub fn try_recv(
+        &self,
+        buf: &mut [u8],
+    ) -> Result<(usize, smoltcp::wire::IpAddress), SystemError> {
+        let inner = self.inner.read();
+        let bound = inner.as_ref().ok_or(SystemError::EBADF)?;
+        let ret = bound.try_recv(buf);
+        bound.poll();
+        match ret {
+            Err(SystemError::EAGAIN) => Err(bound.link_error().unwrap_or(SystemError::EAGAIN)),
+            ret => ret,
+        }
+    }
+
+    #[inline]
+    pub fn can_recv(&self) -> bool {
+        self.event().contains(EP::EPOLLIN)
+    }
+
+    /// 所在的网卡都因设备错误停用时，等待中的收发应当醒来返回错误
+    #[inline]
+    pub fn has_error(&self) -> bool {
+        self.event().contains(EP::EPOLLERR)
+    }
+
+    #[inline]
+    pub fn can_send(&self) -> bool {
+        self.event().contains(EP::EPOLLOUT)
+    }
+
+    pub fn try_send(
+        &self,
+        buf: &[u8],
+        to: Option<smoltcp::wire::IpAddress>,
+    ) -> Result<usize, SystemError> {
+        let inner = self.inner.read();
+        let bound = inner.as_ref().ok_or(SystemError::EBADF)?;
+        let remote = bound.remote(to)?;
+        let iface = bound.iface_to(&remote).ok_or(SystemError::ENETUNREACH)?;
+        // a down iface is not polled, the packet would wait for it
+        if !iface.common().is_running() {
+            return Err(iface.link_error().unwrap_or(SystemError::ENETDOWN));
+        }
+        let ret = bound.try_send(buf, remote, self.hdrincl.load(Ordering::Relaxed));
+        iface.poll();
+        bound.release_sent();
+        match iface.link_error() {
+            Some(err) => Err(err),
+            None => ret,
+        }
+    }
+
+    pub fn event(&self) -> EPollEventType {
+        let mut event = EPollEventType::empty();
+        let inner = self.inner.read();
+        let Some(bound) = inner.as_ref() else {
+            return event;
+        };
+        if bound.can_recv() {
+            event.insert(EP::EPOLLIN | EP::EPOLLRDNORM);
+        }
+
+        if bound.can_send() {
+            event.insert(EP::EPOLLOUT | EP::EPOLLWRNORM | EP::EPOLLWRBAND);
+        }
+
+        if bound.link_error().is_some() {
+            event.insert(EP::EPOLLERR);
+        }
+        event
+    }
+
+    /// Blocks while the send buffer is full unless the socket or `flags` is nonblocking
+    fn send_packet(
+        &self,
+        buffer: &[u8],
+        to: Option<smoltcp::wire::IpAddress>,
+        flags: PMSG,
+    ) -> Result<usize, SystemError> {
+        loop {
+            match self.try_send(buffer, to) {
+                Err(SystemError::EAGAIN)
+                    if !self.is_nonblock() && !flags.contains(PMSG::DONTWAIT) =>
+                {
+                    wq_wait_event_interruptible(
+                        &self.wait_queue,
+                        || self.can_send() || self.has_error(),
+                        None,
+                    )?;
+                }
+                result => break result,
+            }
+        }
+    }
+
+    /// Blocks until a packet arrives unless the socket or `flags` is nonblocking
+    fn recv_packet(
+        &self,
+        buffer: &mut [u8],
+        flags: PMSG,
+    ) -> Result<(usize, smoltcp::wire::IpAddress), SystemError> {
+        if self.is_nonblock() || flags.contains(PMSG::DONTWAIT) {
+            return self.try_recv(buffer);
+        }
+        loop {
+            match self.try_recv(buffer) {
+                Err(SystemError::EAGAIN) => {
+                    wq_wait_event_interruptible(
+                        &self.wait_queue,
+                        || self.can_recv() || self.has_error(),
+                        None,
+                    )?;
+                }
+                result => break result,
+            }
+        }
+    }
+}
+
+impl Socket for RawSocket {
+    fn wait_queue(&self) -> &WaitQueue {
+        &self.wait_queue
+    }
+
+    fn poll(&self) -> usize {
+        self.event().bits() as usize
+    }
+
+    /// The port of `local_endpoint` is ignored
+    fn bind(&self, local_endpoint: Endpoint) -> Result<(), SystemError> {
+        let Endpoint::Ip(local_endpoint) = local_endpoint else {
+            return Err(SystemError::EAFNOSUPPORT);
+        };
+        let local = self.domain.to_inner(local_endpoint)?.addr;
+        match self.inner.read().as_ref() {
+            Some(bound) => bound.bind(local),
+            None => Err(SystemError::EBADF),
+        }
+    }
+
+    fn send_buffer_size(&self) -> usize {
+        match self.inner.read().as_ref() {
+            Some(bound) => bound
+                .with_socket(|socket| socket.payload_send_capacity())
+                .unwrap_or(inner::DEFAULT_TX_BUF_SIZE),
+            None => inner::DEFAULT_TX_BUF_SIZE,
+        }
+    }
+
+    fn recv_buffer_size(&self) -> usize {
+        match self.inner.read().as_ref() {
+            Some(bound) => bound
+                .with_socket(|socket| socket.payload_recv_capacity())
+                .unwrap_or(0),
+            None => inner::DEFAULT_RX_BUF_SIZE,
+        }
+    }
+
+    /// The port of `endpoint` is ignored
+    fn connect(&self, endpoint: Endpoint) -> Result<(), SystemError> {
+        let Endpoint::Ip(remote) = endpoint else {
+            return Err(SystemError::EAFNOSUPPORT);
+        };
+        let remote = self.domain.to_inner(remote)?.addr;
+        match self.inner.read().as_ref() {
+            Some(bound) => {
+                bound.connect(remote);
+                Ok(())
+            }
+            None => Err(SystemError::EBADF),
+        }
+    }
+
+    fn send(&self, buffer: &[u8], flags: PMSG) -> Result<usize, SystemError> {
+        self.send_packet(buffer, None, flags)
+    }
+
+    fn send_to(&self, buffer: &[u8], flags: PMSG, address: Endpoint) -> Result<usize, SystemError> {
+        let Endpoint::Ip(remote) = address else {
+            return Err(SystemError::EINVAL);
+        };
+        let remote = self.domain.to_inner(remote)?;
+        self.send_packet(buffer, Some(remote.addr), flags)
+    }
+
+    fn recv(&self, buffer: &mut [u8], flags: PMSG) -> Result<usize, SystemError> {
+        self.recv_packet(buffer, flags).map(|(len, _)| len)
+    }
+
+    fn recv_from(
+        &self,
+        buffer: &mut [u8],
+        flags: PMSG,
+        address: Option<Endpoint>,
+    ) -> Result<(usize, Endpoint), SystemError> {
+        if let Some(endpoint) = address {
+            self.connect(endpoint)?;
+        }
+        self.recv_packet(buffer, flags)
+            .map(|(len, remote)| (len, Endpoint::Ip(smoltcp::wire::IpEndpoint::new(remote, 0))))
+    }
+
+    fn close(&self) -> Result<(), SystemError> {
+        self.close();
+        Ok(())
+    }
+
+    fn get_name(&self) -> Result<Endpoint, SystemError> {
+        match self.inner.read().as_ref() {
+            Some(bound) => Ok(Endpoint::Ip(bound.endpoint())),
+            None => Err(SystemError::EBADF),
+        }
+    }
+
+    fn get_peer_name(&self) -> Result<Endpoint, SystemError> {
+        self.inner
+            .read()
+            .as_ref()
+            .and_then(|bound| bound.peer())
+            .map(|remote| Endpoint::Ip(smoltcp::wire::IpEndpoint::new(remote, 0)))
+            .ok_or(SystemError::ENOTCONN)
+    }
+
+    /// `IP_HDRINCL` is an `int`, it exists only on IPv4 sockets
+    fn set_option(&self, level: PSOL, name: usize, val: &[u8]) -> Result<(), SystemError> {
+        if level == PSOL::IP && name == IpOptions::IP_HDRINCL.bits() as usize {
+            if self.domain.version != smoltcp::wire::IpVersion::Ipv4 {
+                return Err(SystemError::ENOPROTOOPT);
+            }
+            let val = val.first_chunk::<4>().ok_or(SystemError::EINVAL)?;
+            self.hdrincl
+                .store(i32::from_ne_bytes(*val) != 0, Ordering::Relaxed);
+            return Ok(());
+        }
+        log::debug!("RawSocket::set_option: {:?} {} not supported", level, name);
+        Ok(())
+    }
+
+    fn get_option(&self, level: PSOL, name: usize, value: &mut [u8]) -> Result<usize, SystemError> {
+        if level == PSOL::IP && name == IpOptions::IP_HDRINCL.bits() as usize {
+            if self.domain.version != smoltcp::wire::IpVersion::Ipv4 {
+                return Err(SystemError::ENOPROTOOPT);
+            }
+            let value = value.first_chunk_mut::<4>().ok_or(SystemError::EINVAL)?;
+            *value = (self.hdrincl.load(Ordering::Relaxed) as i32).to_ne_bytes();
+            return Ok(value.len());
+        }
+        log::debug!("RawSocket::get_option: {:?} {} not supported", level, name);
+        Ok(0)
+    }
+}
+
+impl InetSocket for RawSocket {
+    /// The packets of `IPPROTO_RAW` that waited for a neighbor may be out now
+    fn on_iface_events(&self) {
+        if let Some(bound) = self.inner.read().as_ref() {
+            bound.release_sent();
+        }
+    }
+
+    fn socket_info(&self) -> Option<SocketInfo> {
+        self.inner.read().as_ref().map(|bound| bound.socket_info())
+    }
+
+    fn on_iface_added(&self, iface: &Arc<dyn Iface>) {
+        let attached = match self.inner.write().as_mut() {
+            Some(bound) => bound.attach(iface),
+            None => false,
+        };
+        if attached {
+            iface.common().bind_socket(self.self_ref.upgrade().unwrap());
+        }
+    }
+}
@@ -1,10 +1,15 @@
 use core::sync::atomic::AtomicUsize;
 
 use crate::event_poll::EPollEventType;
+use crate::interface::Iface;
 use crate::libs::rwlock::RwLock;
+use crate::libs::spinlock::SpinLock;
 // use crate::net::socket::EPollEventType;
+use crate::socket::inet::common::{Domain, PortFamily, Stray};
+use crate::socket::inet::table::SocketInfo;
 use crate::socket::{self, inet::Types};
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use linux_errnos::Errno as SystemError;
 use smoltcp;
@@ -48,15 +53,27 @@ impl Init {
     pub(super) fn bind(
         self,
         local_endpoint: smoltcp::wire::IpEndpoint,
+        domain: Domain,
     ) -> Result<Self, SystemError> {
         match self {
             Init::Unbound((socket, _)) => {
-                let bound = socket::inet::BoundInner::bind(*socket, &local_endpoint.addr)?;
-                bound
-                    .port_manager()
-                    .bind_port(Types::Tcp, local_endpoint.port)?;
+                // an unspecified address takes a socket on every iface, the first one is ours
+                let mut socket = Some(*socket);
+                let mut bound = socket::inet::BoundInner::bind(&local_endpoint.addr, || {
+                    socket.take().unwrap_or_else(new_smoltcp_socket)
+                })?;
+                let family = domain.port_family(&local_endpoint.addr);
+                let bind_port = if local_endpoint.port == 0 {
+                    bound.bind_ephemeral_port(Types::Tcp, family)
+                } else {
+                    bound
+                        .bind_port(Types::Tcp, local_endpoint.port, family)
+                        .map(|_| local_endpoint.port)
+                }
+                .inspect_err(|_| bound.release())?;
                 // bound.iface().common().bind_socket()
-                Ok(Init::Bound((bound, local_endpoint)))
+                let endpoint = smoltcp::wire::IpEndpoint::new(local_endpoint.addr, bind_port);
+                Ok(Init::Bound((bound, endpoint)))
             }
             Init::Bound(_) => {
                 log::debug!("Already Bound");
@@ -71,12 +88,12 @@ impl Init {
     ) -> Result<(socket::inet::BoundInner, smoltcp::wire::IpEndpoint), (Self, SystemError)> {
         match self {
             Init::Unbound((socket, ver)) => {
-                let (bound, address) =
+                let (mut bound, address) =
                     socket::inet::BoundInner::bind_ephemeral(*socket, remote_endpoint.addr)
                         .map_err(|err| (Self::new(ver), err))?;
                 let bound_port = bound
-                    .port_manager()
-                    .bind_ephemeral_port(Types::Tcp)
+                    .bind_ephemeral_port(Types::Tcp, PortFamily::of(&address))
+                    .inspect_err(|_| bound.release())
                     .map_err(|err| (Self::new(ver), err))?;
                 let endpoint = smoltcp::wire::IpEndpoint::new(address, bound_port);
                 Ok((bound, endpoint))
@@ -96,6 +113,10 @@ impl Init {
         if local.addr.is_unspecified() {
             return Err((Init::Bound((inner, local)), SystemError::EINVAL));
         }
+        // a specific local address has no source for the other family
+        if local.addr.version() != remote_endpoint.addr.version() {
+            return Err((Init::Bound((inner, local)), SystemError::ENETUNREACH));
+        }
         let result = inner.with_mut::<smoltcp::socket::tcp::Socket, _, _>(|socket| {
             socket
                 .connect(
@@ -112,7 +133,12 @@ impl Init {
     }
 
     /// # `listen`
-    pub(super) fn listen(self, backlog: usize) -> Result<Listening, (Self, SystemError)> {
+    /// 开始监听，`domain` 决定只绑定端口时接受哪些协议族的连接
+    pub(super) fn listen(
+        self,
+        backlog: usize,
+        domain: Domain,
+    ) -> Result<Listening, (Self, SystemError)> {
         let (inner, local) = match self {
             Init::Unbound(_) => {
                 return Err((self, SystemError::EINVAL));
@@ -129,15 +155,9 @@ impl Init {
         if let Err(err) = || -> Result<(), SystemError> {
             for _ in 0..(backlog - 1) {
                 // -1 because the first one is already bound
-                let new_listen = socket::inet::BoundInner::bind(
-                    new_listen_smoltcp_socket(listen_addr),
-                    listen_addr
-                        .addr
-                        .as_ref()
-                        .unwrap_or(&smoltcp::wire::IpAddress::from(
-                            smoltcp::wire::Ipv4Address::UNSPECIFIED,
-                        )),
-                )?;
+                let new_listen = socket::inet::BoundInner::bind(&local.addr, || {
+                    new_listen_smoltcp_socket(listen_addr)
+                })?;
                 inners.push(new_listen);
             }
             Ok(())
@@ -145,19 +165,24 @@ impl Init {
             return Err((Init::Bound((inner, local)), err));
         }
 
-        if let Err(err) = inner.with_mut::<smoltcp::socket::tcp::Socket, _, _>(|socket| {
-            socket
-                .listen(listen_addr)
-                .map_err(|_| SystemError::ECONNREFUSED)
-        }) {
-            return Err((Init::Bound((inner, local)), err));
+        if inner
+            .find_map::<smoltcp::socket::tcp::Socket, _, _>(|socket| {
+                socket.listen(listen_addr).err()
+            })
+            .is_some()
+        {
+            return Err((Init::Bound((inner, local)), SystemError::ECONNREFUSED));
         }
 
-        inners.push(inner);
+        // the bound socket goes first, it holds the port
+        inners.insert(0, inner);
         Ok(Listening {
             inners,
             connect: AtomicUsize::new(0),
             listen_addr,
+            domain,
+            adopted: SpinLock::new(Vec::new()),
+            rejected: Vec::new(),
         })
     }
 
@@ -165,9 +190,40 @@ impl Init {
         match self {
             Init::Unbound(_) => {}
             Init::Bound((inner, endpoint)) => {
-                inner.port_manager().unbind_port(Types::Tcp, endpoint.port);
-                inner.with_mut::<smoltcp::socket::tcp::Socket, _, _>(|socket| socket.close());
+                inner.unbind_port(Types::Tcp, endpoint.port);
+                inner.for_each::<smoltcp::socket::tcp::Socket, _>(|socket| socket.close());
+            }
+        }
+    }
+
+    /// Puts a socket on an iface registered after binding to an unspecified address
+    pub(super) fn attach(&mut self, iface: &Arc<dyn Iface>) -> bool {
+        match self {
+            Init::Bound((inner, local)) if inner.should_attach(iface) => {
+                if !bind_port_on(iface, local.port, inner.port_family()) {
+                    return false;
+                }
+                inner.attach(iface, new_smoltcp_socket());
+                true
             }
+            _ => false,
+        }
+    }
+}
+
+/// Takes `port` on an iface registered after the socket was bound, the socket
+/// skips the iface if another one has it there
+fn bind_port_on(iface: &Arc<dyn Iface>, port: u16, family: PortFamily) -> bool {
+    match iface.port_manager().bind_port(Types::Tcp, port, family) {
+        Ok(()) => true,
+        Err(err) => {
+            log::warn!(
+                "tcp port {} is taken on {}: {:?}",
+                port,
+                iface.iface_name(),
+                err
+            );
+            false
         }
     }
 }
@@ -280,57 +336,182 @@ impl Connecting {
     }
 }
 
+/// A listening socket can be accepted once the three-way handshake completes,
+/// `is_active` alone also holds in `SynReceived`.
+fn is_acceptable(socket: &smoltcp::socket::tcp::Socket) -> bool {
+    use smoltcp::socket::tcp::State;
+    !matches!(
+        socket.state(),
+        State::Closed | State::Listen | State::SynSent | State::SynReceived
+    )
+}
+
+/// Whether a listening socket took a connection of the other family
+fn is_stray(socket: &smoltcp::socket::tcp::Socket, domain: &Domain) -> bool {
+    socket
+        .remote_endpoint()
+        .is_some_and(|remote| !domain.accepts(&remote.addr))
+}
+
 #[derive(Debug)]
 pub struct Listening {
     inners: Vec<socket::inet::BoundInner>,
     connect: AtomicUsize,
     listen_addr: smoltcp::wire::IpListenEndpoint,
+    domain: Domain,
+    /// Connections handed over by the listener of the other family on the same port
+    adopted: SpinLock<Vec<socket::inet::BoundInner>>,
+    /// Strays nobody took, kept until their RST is sent
+    rejected: Vec<socket::inet::BoundInner>,
 }
 
 impl Listening {
     pub fn accept(&mut self) -> Result<(Established, smoltcp::wire::IpEndpoint), SystemError> {
-        let connected: &mut socket::inet::BoundInner = self
-            .inners
-            .get_mut(self.connect.load(core::sync::atomic::Ordering::Relaxed))
-            .unwrap();
-
-        if connected.with::<smoltcp::socket::tcp::Socket, _, _>(|socket| !socket.is_active()) {
-            return Err(SystemError::EAGAIN);
-        }
+        let adopted = {
+            let mut adopted = self.adopted.lock();
+            adopted
+                .iter()
+                .position(|inner| inner.with(is_acceptable))
+                .map(|index| adopted.remove(index))
+        };
+        let established = match adopted {
+            Some(adopted) => adopted,
+            None => {
+                let (listen_addr, domain) = (self.listen_addr, self.domain);
+                let connected: &mut socket::inet::BoundInner = self
+                    .inners
+                    .get_mut(self.connect.load(core::sync::atomic::Ordering::Relaxed))
+                    .unwrap();
+
+                // the connected socket leaves the listeners, a new one listens on its iface
+                connected
+                    .split_off(
+                        |socket: &smoltcp::socket::tcp::Socket| {
+                            is_acceptable(socket) && !is_stray(socket, &domain)
+                        },
+                        new_listen_smoltcp_socket(listen_addr),
+                    )
+                    .ok_or(SystemError::EAGAIN)?
+            }
+        };
 
-        let remote_endpoint = connected.with::<smoltcp::socket::tcp::Socket, _, _>(|socket| {
+        let remote_endpoint = established.with::<smoltcp::socket::tcp::Socket, _, _>(|socket| {
             socket
                 .remote_endpoint()
                 .expect("A Connected Tcp With No Remote Endpoint")
         });
 
-        // log::debug!("local at {:?}", local_endpoint);
+        Ok((Established { inner: established }, remote_endpoint))
+    }
 
-        let mut new_listen = socket::inet::BoundInner::bind(
-            new_listen_smoltcp_socket(self.listen_addr),
-            self.listen_addr
-                .addr
-                .as_ref()
-                .unwrap_or(&smoltcp::wire::IpAddress::from(
-                    smoltcp::wire::Ipv4Address::UNSPECIFIED,
-                )),
-        )?;
+    /// Puts the listeners on an iface registered after binding to an unspecified address
+    pub(super) fn attach(&mut self, iface: &Arc<dyn Iface>) -> bool {
+        let family = self.inners[0].port_family();
+        if !self.inners[0].should_attach(iface)
+            || !bind_port_on(iface, self.listen_addr.port, family)
+        {
+            return false;
+        }
+        for inner in self.inners.iter_mut() {
+            inner.attach(iface, new_listen_smoltcp_socket(self.listen_addr));
+        }
+        true
+    }
+
+    /// Listeners bound to a port only take the connections of both families.
+    /// The ones the socket's family does not accept leave the listeners, a
+    /// new one listens in their place, to be handed to the listener of the
+    /// other family on the same port by the caller
+    pub(super) fn take_strays(&mut self) -> Vec<socket::inet::BoundInner> {
+        // an aborted socket sends its RST on the next poll, then it is released
+        self.rejected.retain(|inner| {
+            let closed = inner.with(|socket: &smoltcp::socket::tcp::Socket| {
+                socket.state() == smoltcp::socket::tcp::State::Closed
+            });
+            if closed {
+                inner.release();
+            }
+            !closed
+        });
+        let (domain, listen_addr) = (self.domain, self.listen_addr);
+        let mut strays = Vec::new();
+        for inner in self.inners.iter_mut() {
+            while inner
+                .find_map::<smoltcp::socket::tcp::Socket, _, _>(|socket| {
+                    is_stray(socket, &domain).then_some(())
+                })
+                .is_some()
+            {
+                strays.extend(inner.split_off(
+                    |socket: &smoltcp::socket::tcp::Socket| is_stray(socket, &domain),
+                    new_listen_smoltcp_socket(listen_addr),
+                ));
+            }
+        }
+        strays
+    }
 
-        // swap the connected socket with the new_listen socket
-        // TODO is smoltcp socket swappable?
-        core::mem::swap(&mut new_listen, connected);
+    /// Resets a stray no listener took
+    pub(super) fn reject(&mut self, stray: socket::inet::BoundInner) {
+        stray.with_mut::<smoltcp::socket::tcp::Socket, _, _>(|socket| {
+            log::debug!(
+                "tcp: rejected {:?} on {}",
+                socket.remote_endpoint(),
+                self.listen_addr
+            );
+            socket.abort();
+        });
+        self.rejected.push(stray);
+    }
+
+    /// Takes a stray of another listener if it is addressed to this one and
+    /// the backlog has room for it
+    pub(super) fn adopt(&self, stray: Stray) -> Result<(), Stray> {
+        match stray {
+            Stray::Connection(inner)
+                if self.adopted.lock().len() < self.inners.len() && self.takes(&inner) =>
+            {
+                self.adopted.lock().push(inner);
+                Ok(())
+            }
+            stray => Err(stray),
+        }
+    }
 
-        Ok((Established { inner: new_listen }, remote_endpoint))
+    fn takes(&self, inner: &socket::inet::BoundInner) -> bool {
+        let (domain, listen_addr) = (self.domain, self.listen_addr);
+        inner.with(|socket: &smoltcp::socket::tcp::Socket| {
+            match (socket.local_endpoint(), socket.remote_endpoint()) {
+                (Some(local), Some(remote)) => {
+                    domain.accepts(&remote.addr)
+                        && local.port == listen_addr.port
+                        && listen_addr.addr.is_none_or(|addr| addr == local.addr)
+                }
+                _ => false,
+            }
+        })
     }
 
     pub fn update_io_events(&self, pollee: &AtomicUsize) {
+        let domain = self.domain;
         let position = self.inners.iter().position(|inner| {
-            inner.with::<smoltcp::socket::tcp::Socket, _, _>(|socket| socket.is_active())
+            inner
+                .find_map::<smoltcp::socket::tcp::Socket, _, _>(|socket| {
+                    (is_acceptable(socket) && !is_stray(socket, &domain)).then_some(())
+                })
+                .is_some()
         });
-
         if let Some(position) = position {
             self.connect
                 .store(position, core::sync::atomic::Ordering::Relaxed);
+        }
+        let adopted = self
+            .adopted
+            .lock()
+            .iter()
+            .any(|inner| inner.with(is_acceptable));
+
+        if position.is_some() || adopted {
             pollee.fetch_or(
                 EPollEventType::EPOLLIN.bits() as usize,
                 core::sync::atomic::Ordering::Relaxed,
@@ -347,9 +528,7 @@ impl Listening {
         smoltcp::wire::IpEndpoint::new(
             self.listen_addr
                 .addr
-                .unwrap_or(smoltcp::wire::IpAddress::from(
-                    smoltcp::wire::Ipv4Address::UNSPECIFIED,
-                )),
+                .unwrap_or(self.domain.unspecified().addr),
             self.listen_addr.port,
         )
     }
@@ -358,12 +537,12 @@ impl Listening {
         // log::debug!("Close Listening Socket");
         let port = self.get_name().port;
         for inner in self.inners.iter() {
+            inner.for_each::<smoltcp::socket::tcp::Socket, _>(|socket| socket.close());
+        }
+        for inner in self.adopted.lock().iter() {
             inner.with_mut::<smoltcp::socket::tcp::Socket, _, _>(|socket| socket.close());
         }
-        self.inners[0]
-            .iface()
-            .port_manager()
-            .unbind_port(Types::Tcp, port);
+        self.inners[0].unbind_port(Types::Tcp, port);
     }
 
     pub fn release(&self) {
@@ -371,6 +550,9 @@ impl Listening {
         for inner in self.inners.iter() {
             inner.release();
         }
+        for inner in self.adopted.lock().iter().chain(self.rejected.iter()) {
+            inner.release();
+        }
     }
 }
 
@@ -412,6 +594,10 @@ impl Established {
         self.inner
             .with_mut::<smoltcp::socket::tcp::Socket, _, _>(|socket| {
                 match socket.recv_slice(buf) {
+                    // a zero-length read succeeds without looking at the queue
+                    Ok(0) if buf.is_empty() => Ok(0),
+                    // nothing received yet, EOF is reported through `Finished`
+                    Ok(0) => Err(SystemError::EAGAIN),
                     Ok(size) => Ok(size),
                     Err(tcp::RecvError::InvalidState) => {
                         socket.may_recv();
@@ -452,6 +638,8 @@ impl Established {
         self.inner
             .with_mut::<smoltcp::socket::tcp::Socket, _, _>(|socket| {
                 match socket.send_slice(buf) {
+                    Ok(0) if buf.is_empty() => Ok(0),
+                    // the send queue is full
                     Ok(0) => Err(SystemError::EAGAIN),
                     Ok(size) => Ok(size),
                     Err(tcp::SendError::InvalidState) => {
@@ -546,7 +734,68 @@ impl Inner {
         }
     }
 
-    pub fn iface(&self) -> Option<&alloc::sync::Arc<dyn crate::interface::Iface>> {
+    /// Every iface the socket has a smoltcp socket on
+    pub fn ifaces(&self) -> Vec<Arc<dyn Iface>> {
+        match self {
+            Inner::Init(Init::Unbound(_)) => Vec::new(),
+            Inner::Init(Init::Bound((inner, _))) => inner.ifaces().cloned().collect(),
+            Inner::Connecting(conn) => vec![conn.inner.iface().clone()],
+            Inner::Listening(listen) => listen.inners[0].ifaces().cloned().collect(),
+            Inner::Established(est) => vec![est.inner.iface().clone()],
+        }
+    }
+
+    /// Whether the socket is bound to an unspecified address
+    pub fn is_any_iface(&self) -> bool {
+        match self {
+            Inner::Init(Init::Bound((inner, _))) => inner.is_any_iface(),
+            Inner::Listening(listen) => listen.inners[0].is_any_iface(),
+            _ => false,
+        }
+    }
+
+    pub(super) fn attach(&mut self, iface: &Arc<dyn Iface>) -> bool {
+        match self {
+            Inner::Init(init) => init.attach(iface),
+            Inner::Listening(listen) => listen.attach(iface),
+            _ => false,
+        }
+    }
+
+    pub fn socket_info(&self) -> Option<SocketInfo> {
+        let mut info = match self {
+            Inner::Init(Init::Unbound(_)) => return None,
+            Inner::Init(Init::Bound((inner, local))) => SocketInfo {
+                local: *local,
+                ..inner.with(SocketInfo::from_tcp)
+            },
+            Inner::Connecting(conn) => conn.inner.with(SocketInfo::from_tcp),
+            Inner::Established(est) => est.inner.with(SocketInfo::from_tcp),
+            Inner::Listening(listen) => {
+                // like ss, the accept queue and the backlog take the place of the queues
+                let mut pending = 0;
+                for inner in listen.inners.iter() {
+                    inner.for_each::<smoltcp::socket::tcp::Socket, _>(|socket| {
+                        pending += is_acceptable(socket) as usize;
+                    });
+                }
+                pending += listen.adopted.lock().len();
+                SocketInfo {
+                    local: listen.get_name(),
+                    remote: None,
+                    state: Some(smoltcp::socket::tcp::State::Listen),
+                    send_queue: listen.inners.len(),
+                    recv_queue: pending,
+                    ..listen.inners[0].with(SocketInfo::from_tcp)
+                }
+            }
+        };
+        let ifaces = self.ifaces();
+        info.ifaces = ifaces.iter().map(|iface| iface.iface_name()).collect();
+        Some(info)
+    }
+
+    pub fn iface(&self) -> Option<&Arc<dyn Iface>> {
         match self {
             Inner::Init(_) => None,
             Inner::Connecting(conn) => Some(conn.inner.iface()),
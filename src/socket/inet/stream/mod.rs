@@ -2,6 +2,8 @@ use alloc::sync::{Arc, Weak};
 use core::sync::atomic::{AtomicBool, AtomicUsize};
 use linux_errnos::Errno as SystemError;
 
+use crate::interface::registry::NET_DEVICES;
+use crate::interface::Iface;
 use crate::libs::wait_queue::{wq_wait_event_interruptible, WaitQueue};
 // use crate::event_poll::EPollEventType;
 use crate::socket::common::shutdown::{ShutdownBit, ShutdownTemp};
@@ -16,12 +18,17 @@ mod inner;
 mod option;
 pub use option::Options as TcpOption;
 
-use super::{InetSocket, UNSPECIFIED_LOCAL_ENDPOINT_V4};
+use super::common::{forward, Stray};
+use super::posix::option::Ipv6Options;
+use super::table::SocketInfo;
+use super::{Domain, InetSocket};
 
 type EP = crate::event_poll::EPollEventType;
 #[derive(Debug)]
 pub struct TcpSocket {
     inner: RwLock<Option<inner::Inner>>,
+    /// fixed once the socket is bound
+    domain: RwLock<Domain>,
     #[allow(dead_code)]
     shutdown: Shutdown, // TODO set shutdown status
     nonblock: AtomicBool,
@@ -34,6 +41,7 @@ impl TcpSocket {
     pub fn new(_nonblock: bool, ver: smoltcp::wire::IpVersion) -> Arc<Self> {
         Arc::new_cyclic(|me| Self {
             inner: RwLock::new(Some(inner::Inner::Init(inner::Init::new(ver)))),
+            domain: RwLock::new(Domain::new(ver)),
             shutdown: Shutdown::new(),
             nonblock: AtomicBool::new(false),
             wait_queue: WaitQueue::default(),
@@ -42,9 +50,10 @@ impl TcpSocket {
         })
     }
 
-    pub fn new_established(inner: inner::Established, nonblock: bool) -> Arc<Self> {
+    pub fn new_established(inner: inner::Established, nonblock: bool, domain: Domain) -> Arc<Self> {
         Arc::new_cyclic(|me| Self {
             inner: RwLock::new(Some(inner::Inner::Established(inner))),
+            domain: RwLock::new(domain),
             shutdown: Shutdown::new(),
             nonblock: AtomicBool::new(nonblock),
             wait_queue: WaitQueue::default(),
@@ -58,15 +67,33 @@ impl TcpSocket {
     }
 
     pub fn do_bind(&self, local_endpoint: smoltcp::wire::IpEndpoint) -> Result<(), SystemError> {
+        let domain = *self.domain.read();
+        let local_endpoint = domain.to_inner(local_endpoint)?;
         let mut writer = self.inner.write();
         match writer.take().expect("Tcp inner::Inner is None") {
             inner::Inner::Init(inner) => {
-                let bound = inner.bind(local_endpoint)?;
+                // subscribe before bind lists the ifaces, an iface registered in
+                // between waits for our lock and is attached once
+                let any_iface = local_endpoint.addr.is_unspecified();
+                let me = self.self_ref.upgrade().unwrap();
+                if any_iface {
+                    NET_DEVICES.bind_socket(me.clone());
+                }
+                let bound = match inner.bind(local_endpoint, domain) {
+                    Ok(bound) => bound,
+                    Err(err) => {
+                        if any_iface {
+                            NET_DEVICES.unbind_socket(me);
+                        }
+                        // the socket stays usable after a failed bind
+                        writer.replace(inner::Inner::Init(inner::Init::new(domain.version)));
+                        return Err(err);
+                    }
+                };
                 if let inner::Init::Bound((ref bound, _)) = bound {
-                    bound
-                        .iface()
-                        .common()
-                        .bind_socket(self.self_ref.upgrade().unwrap());
+                    for iface in bound.ifaces() {
+                        iface.common().bind_socket(me.clone());
+                    }
                 }
                 writer.replace(inner::Inner::Init(bound));
                 Ok(())
@@ -84,7 +111,7 @@ impl TcpSocket {
         let inner = writer.take().expect("Tcp inner::Inner is None");
         let (listening, err) = match inner {
             inner::Inner::Init(init) => {
-                let listen_result = init.listen(backlog);
+                let listen_result = init.listen(backlog, *self.domain.read());
                 match listen_result {
                     Ok(listening) => (inner::Inner::Listening(listening), None),
                     Err((init, err)) => (inner::Inner::Init(init), Some(err)),
@@ -102,7 +129,8 @@ impl TcpSocket {
     }
 
     pub fn try_accept(&self) -> Result<(Arc<TcpSocket>, smoltcp::wire::IpEndpoint), SystemError> {
-        match self
+        let domain = *self.domain.read();
+        let (socket, remote) = match self
             .inner
             .write()
             .as_mut()
@@ -110,12 +138,23 @@ impl TcpSocket {
         {
             inner::Inner::Listening(listening) => listening.accept().map(|(stream, remote)| {
                 (
-                    TcpSocket::new_established(stream, self.is_nonblock()),
-                    remote,
+                    TcpSocket::new_established(stream, self.is_nonblock(), domain),
+                    domain.to_user(remote),
                 )
             }),
             _ => Err(SystemError::EINVAL),
-        }
+        }?;
+        // accepted sockets need iface events to update their pollee, registered
+        // after releasing the listener, which the iface reads while polling
+        socket
+            .inner
+            .read()
+            .as_ref()
+            .and_then(|inner| inner.iface())
+            .expect("An Established Tcp With No Iface")
+            .common()
+            .bind_socket(socket.clone());
+        Ok((socket, remote))
     }
 
     // SHOULD refactor
@@ -123,8 +162,11 @@ impl TcpSocket {
         &self,
         remote_endpoint: smoltcp::wire::IpEndpoint,
     ) -> Result<(), SystemError> {
+        let remote_endpoint = self.domain.read().to_inner(remote_endpoint)?;
         let mut writer = self.inner.write();
         let inner = writer.take().expect("Tcp inner::Inner is None");
+        // a bound socket is already registered on its iface by `do_bind`
+        let need_register = matches!(inner, inner::Inner::Init(inner::Init::Unbound(_)));
         let (init, result) = match inner {
             inner::Inner::Init(init) => {
                 let conn_result = init.connect(remote_endpoint);
@@ -153,24 +195,35 @@ impl TcpSocket {
             }
         };
 
-        match result {
-            Ok(()) | Err(SystemError::EINPROGRESS) => {
-                init.iface().unwrap().poll();
-            }
-            _ => {}
-        }
+        let iface = match result {
+            Ok(()) | Err(SystemError::EINPROGRESS) => init.iface().cloned(),
+            _ => None,
+        };
 
         writer.replace(init);
+        // poll after releasing the lock, iface events come back to this socket
+        drop(writer);
+
+        if let Some(iface) = iface {
+            if need_register {
+                iface.common().bind_socket(self.self_ref.upgrade().unwrap());
+            }
+            iface.poll();
+        }
         result
     }
 
     // for irq use
     pub fn finish_connect(&self) -> Result<(), SystemError> {
         let mut writer = self.inner.write();
-        let inner::Inner::Connecting(conn) = writer.take().expect("Tcp inner::Inner is None")
-        else {
-            log::error!("TcpSocket::finish_connect: not Connecting");
-            return Err(SystemError::EINVAL);
+        let conn = match writer.take().expect("Tcp inner::Inner is None") {
+            inner::Inner::Connecting(conn) => conn,
+            // `check_connect` may have finished the connection already
+            other => {
+                writer.replace(other);
+                log::debug!("TcpSocket::finish_connect: not Connecting");
+                return Err(SystemError::EINVAL);
+            }
         };
 
         let (inner, result) = conn.into_result();
@@ -199,23 +252,48 @@ impl TcpSocket {
         result
     }
 
-    pub fn try_recv(&self, buf: &mut [u8]) -> Result<usize, SystemError> {
+    /// The iface to poll around a send or receive, polled without holding
+    /// `inner`, since the poll hands iface events back to this socket
+    fn polling_iface(&self) -> Option<Arc<dyn Iface>> {
         self.inner
             .read()
             .as_ref()
-            .map(|inner| {
-                inner.iface().unwrap().poll();
-                let result = match inner {
-                    inner::Inner::Established(inner) => inner.recv_slice(buf),
-                    _ => Err(SystemError::EINVAL),
-                };
-                inner.iface().unwrap().poll();
-                result
-            })
-            .unwrap()
+            .expect("Tcp inner::Inner is None")
+            .iface()
+            .cloned()
+    }
+
+    /// The error of a down iface, reported once the buffered data is consumed
+    fn link_error(&self) -> Option<SystemError> {
+        self.polling_iface().and_then(|iface| iface.link_error())
+    }
+
+    pub fn try_recv(&self, buf: &mut [u8]) -> Result<usize, SystemError> {
+        let iface = self.polling_iface().ok_or(SystemError::EINVAL)?;
+        iface.poll();
+        let result = match self
+            .inner
+            .read()
+            .as_ref()
+            .expect("Tcp inner::Inner is None")
+        {
+            inner::Inner::Established(inner) => inner.recv_slice(buf),
+            _ => Err(SystemError::EINVAL),
+        };
+        iface.poll();
+        match result {
+            Err(SystemError::EAGAIN) => Err(iface.link_error().unwrap_or(SystemError::EAGAIN)),
+            result => result,
+        }
     }
 
     pub fn try_send(&self, buf: &[u8]) -> Result<usize, SystemError> {
+        // a down iface is not polled, the data would wait for it
+        if let Some(iface) = self.polling_iface() {
+            if !iface.common().is_running() {
+                return Err(iface.link_error().unwrap_or(SystemError::ENETDOWN));
+            }
+        }
         // TODO: add nonblock check of connecting socket
         let sent = match self
             .inner
@@ -226,11 +304,21 @@ impl TcpSocket {
             inner::Inner::Established(inner) => inner.send_slice(buf),
             _ => Err(SystemError::EINVAL),
         };
-        self.inner.read().as_ref().unwrap().iface().unwrap().poll();
-        sent
+        let Some(iface) = self.polling_iface() else {
+            return sent;
+        };
+        // the device error is only cleared by a frame going through, so the
+        // data is queued first; what was queued is retransmitted once the
+        // link is back, only a send that queued nothing reports the error
+        iface.poll();
+        match (sent, iface.link_error()) {
+            (Err(SystemError::EAGAIN), Some(err)) => Err(err),
+            (sent, _) => sent,
+        }
     }
 
     fn update_events(&self) -> bool {
+        self.update_link_events();
         match self
             .inner
             .read()
@@ -250,6 +338,40 @@ impl TcpSocket {
         }
     }
 
+    /// Hands the connections of the other family on a listener to the
+    /// listener sharing its port, the ones nobody takes are reset
+    fn forward_strays(&self) {
+        let strays = match self.inner.write().as_mut() {
+            Some(inner::Inner::Listening(listening)) => listening.take_strays(),
+            _ => return,
+        };
+        // the other listener takes its own lock
+        for stray in strays {
+            let iface = stray.iface().clone();
+            if let Err(Stray::Connection(stray)) = forward(&iface, Stray::Connection(stray)) {
+                match self.inner.write().as_mut() {
+                    Some(inner::Inner::Listening(listening)) => listening.reject(stray),
+                    _ => stray.release(),
+                }
+            }
+        }
+    }
+
+    /// EPOLLERR follows the state of the iface
+    fn update_link_events(&self) {
+        if self.link_error().is_some() {
+            self.pollee.fetch_or(
+                EP::EPOLLERR.bits() as usize,
+                core::sync::atomic::Ordering::Relaxed,
+            );
+        } else {
+            self.pollee.fetch_and(
+                !EP::EPOLLERR.bits() as usize,
+                core::sync::atomic::Ordering::Relaxed,
+            );
+        }
+    }
+
     fn is_epoll_in(&self) -> bool {
         EP::from_bits_truncate(self.poll() as u32).contains(EP::EPOLLIN)
     }
@@ -257,6 +379,10 @@ impl TcpSocket {
     fn is_epoll_out(&self) -> bool {
         EP::from_bits_truncate(self.poll() as u32).contains(EP::EPOLLOUT)
     }
+
+    fn is_epoll_err(&self) -> bool {
+        EP::from_bits_truncate(self.poll() as u32).contains(EP::EPOLLERR)
+    }
 }
 
 impl Socket for TcpSocket {
@@ -265,21 +391,20 @@ impl Socket for TcpSocket {
     }
 
     fn get_name(&self) -> Result<Endpoint, SystemError> {
-        match self
+        let domain = *self.domain.read();
+        let local = match self
             .inner
             .read()
             .as_ref()
             .expect("Tcp inner::Inner is None")
         {
-            inner::Inner::Init(inner::Init::Unbound((_, ver))) => Ok(Endpoint::Ip(match ver {
-                smoltcp::wire::IpVersion::Ipv4 => UNSPECIFIED_LOCAL_ENDPOINT_V4,
-                smoltcp::wire::IpVersion::Ipv6 => todo!("UNSPECIFIED_LOCAL_ENDPOINT_V6"),
-            })),
-            inner::Inner::Init(inner::Init::Bound((_, local))) => Ok(Endpoint::Ip(*local)),
-            inner::Inner::Connecting(connecting) => Ok(Endpoint::Ip(connecting.get_name())),
-            inner::Inner::Established(established) => Ok(Endpoint::Ip(established.get_name())),
-            inner::Inner::Listening(listening) => Ok(Endpoint::Ip(listening.get_name())),
-        }
+            inner::Inner::Init(inner::Init::Unbound(_)) => domain.unspecified(),
+            inner::Inner::Init(inner::Init::Bound((_, local))) => *local,
+            inner::Inner::Connecting(connecting) => connecting.get_name(),
+            inner::Inner::Established(established) => established.get_name(),
+            inner::Inner::Listening(listening) => listening.get_name(),
+        };
+        Ok(Endpoint::Ip(domain.to_user(local)))
     }
 
     fn get_peer_name(&self) -> Result<Endpoint, SystemError> {
@@ -290,10 +415,11 @@ impl Socket for TcpSocket {
             .expect("Tcp inner::Inner is None")
         {
             inner::Inner::Init(_) => Err(SystemError::ENOTCONN),
-            inner::Inner::Connecting(connecting) => Ok(Endpoint::Ip(connecting.get_peer_name())),
-            inner::Inner::Established(established) => Ok(Endpoint::Ip(established.get_peer_name())),
+            inner::Inner::Connecting(connecting) => Ok(connecting.get_peer_name()),
+            inner::Inner::Established(established) => Ok(established.get_peer_name()),
             inner::Inner::Listening(_) => Err(SystemError::ENOTCONN),
         }
+        .map(|remote| Endpoint::Ip(self.domain.read().to_user(remote)))
     }
 
     fn bind(&self, endpoint: Endpoint) -> Result<(), SystemError> {
@@ -313,7 +439,11 @@ impl Socket for TcpSocket {
 
         loop {
             match self.check_connect() {
-                Err(SystemError::EAGAIN) => {}
+                Err(SystemError::EAGAIN) => {
+                    if let Some(err) = self.link_error() {
+                        break Err(err);
+                    }
+                }
                 result => break result,
             }
         }
@@ -348,7 +478,11 @@ impl Socket for TcpSocket {
             match self.try_recv(buffer) {
                 Err(SystemError::EAGAIN) if self.is_nonblock() => break Err(SystemError::EAGAIN),
                 Err(SystemError::EAGAIN) => {
-                    wq_wait_event_interruptible(&self.wait_queue, || self.is_epoll_in(), None)?;
+                    wq_wait_event_interruptible(
+                        &self.wait_queue,
+                        || self.is_epoll_in() || self.is_epoll_err(),
+                        None,
+                    )?;
                 }
                 result => break result,
             }
@@ -360,7 +494,11 @@ impl Socket for TcpSocket {
             match self.try_send(buffer) {
                 Err(SystemError::EAGAIN) if self.is_nonblock() => break Err(SystemError::EAGAIN),
                 Err(SystemError::EAGAIN) => {
-                    wq_wait_event_interruptible(&self.wait_queue, || self.is_epoll_out(), None)?;
+                    wq_wait_event_interruptible(
+                        &self.wait_queue,
+                        || self.is_epoll_out() || self.is_epoll_err(),
+                        None,
+                    )?;
                 }
                 result => break result,
             }
@@ -407,10 +545,12 @@ impl Socket for TcpSocket {
             log::warn!("TcpSocket::close: already closed, unexpected");
             return Ok(());
         };
-        if let Some(iface) = inner.iface() {
-            iface
-                .common()
-                .unbind_socket(self.self_ref.upgrade().unwrap());
+        let me = self.self_ref.upgrade().unwrap();
+        for iface in inner.ifaces() {
+            iface.common().unbind_socket(me.clone());
+        }
+        if inner.is_any_iface() {
+            NET_DEVICES.unbind_socket(me);
         }
 
         match inner {
@@ -437,6 +577,16 @@ impl Socket for TcpSocket {
     }
 
     fn set_option(&self, level: PSOL, name: usize, val: &[u8]) -> Result<(), SystemError> {
+        if level == PSOL::IPV6 && name == Ipv6Options::IPV6_V6ONLY.bits() as usize {
+            // the family of a bound socket is settled
+            if !matches!(
+                self.inner.read().as_ref(),
+                Some(inner::Inner::Init(inner::Init::Unbound(_)))
+            ) {
+                return Err(SystemError::EINVAL);
+            }
+            return self.domain.write().set_v6only(val);
+        }
         if level != PSOL::TCP {
             // return Err(SystemError::EINVAL);
             log::debug!("TcpSocket::set_option: not TCP");
@@ -515,13 +665,49 @@ impl Socket for TcpSocket {
         }
         Ok(())
     }
+
+    fn get_option(&self, level: PSOL, name: usize, value: &mut [u8]) -> Result<usize, SystemError> {
+        if level == PSOL::IPV6 && name == Ipv6Options::IPV6_V6ONLY.bits() as usize {
+            return self.domain.read().get_v6only(value);
+        }
+        log::debug!("TcpSocket::get_option: {:?} {} not supported", level, name);
+        Ok(0)
+    }
 }
 
 impl InetSocket for TcpSocket {
     fn on_iface_events(&self) {
+        self.forward_strays();
         if self.update_events() {
             let _result = self.finish_connect();
             // set error
         }
     }
+
+    fn socket_info(&self) -> Option<SocketInfo> {
+        self.inner.read().as_ref()?.socket_info()
+    }
+
+    fn adopt(&self, stray: Stray) -> Result<(), Stray> {
+        let adopted = match self.inner.read().as_ref() {
+            Some(inner::Inner::Listening(listening)) => listening.adopt(stray),
+            _ => Err(stray),
+        };
+        if adopted.is_ok() {
+            self.update_events();
+            self.wait_queue.wakeup();
+        }
+        adopted
+    }
+
+    fn on_iface_added(&self, iface: &Arc<dyn Iface>) {
+        let attached = self
+            .inner
+            .write()
+            .as_mut()
+            .is_some_and(|inner| inner.attach(iface));
+        if attached {
+            iface.common().bind_socket(self.self_ref.upgrade().unwrap());
+        }
+    }
 }
@@ -5,7 +5,7 @@ use smoltcp::{self, wire::IpProtocol};
 use crate::{
     posix::SOCK,
     socket::{
-        inet::{TcpSocket, UdpSocket},
+        inet::{IcmpSocket, RawSocket, TcpSocket, UdpSocket},
         Family,
         Socket, // SocketInode,
     },
@@ -19,7 +19,14 @@ fn create_inet_socket(
     // log::debug!("type: {:?}, protocol: {:?}", socket_type, protocol);
     match socket_type {
         SOCK::Datagram => match protocol {
-            IpProtocol::HopByHop | IpProtocol::Udp => Ok(UdpSocket::new(false)),
+            IpProtocol::HopByHop | IpProtocol::Udp => Ok(UdpSocket::new(false, version)),
+            // ping sockets, ICMPv4 for AF_INET and ICMPv6 for AF_INET6
+            IpProtocol::Icmp if version == smoltcp::wire::IpVersion::Ipv4 => {
+                Ok(IcmpSocket::new(false, version))
+            }
+            IpProtocol::Icmpv6 if version == smoltcp::wire::IpVersion::Ipv6 => {
+                Ok(IcmpSocket::new(false, version))
+            }
             _ => Err(SystemError::EPROTONOSUPPORT),
         },
         SOCK::Stream => match protocol {
@@ -31,9 +38,11 @@ fn create_inet_socket(
                 Err(SystemError::EPROTONOSUPPORT)
             }
         },
-        SOCK::Raw => {
-            todo!("raw")
-        }
+        SOCK::Raw => match protocol {
+            // like Linux, a raw socket needs a protocol
+            IpProtocol::HopByHop => Err(SystemError::EPROTONOSUPPORT),
+            _ => Ok(RawSocket::new(false, version, protocol)),
+        },
         _ => Err(SystemError::EPROTONOSUPPORT),
     }
 }
@@ -49,13 +58,13 @@ impl Family for Inet {
     }
 }
 
-// pub struct Inet6;
-// impl Family for Inet6 {
-//     fn socket(stype: PSOCK, protocol: u32) -> Result<Arc<dyn Socket>, SystemError> {
-//         create_inet_socket(
-//             smoltcp::wire::IpVersion::Ipv6,
-//             stype,
-//             smoltcp::wire::IpProtocol::from(protocol as u8),
-//         )
-//     }
-// }
+pub struct Inet6;
+impl Family for Inet6 {
+    fn socket(stype: SOCK, protocol: u32) -> Result<Arc<dyn Socket>, SystemError> {
+        create_inet_socket(
+            smoltcp::wire::IpVersion::Ipv6,
+            stype,
+            smoltcp::wire::IpProtocol::from(protocol as u8),
+        )
+    }
+}
@@ -0,0 +1,210 @@
+//! 套接字表，列出绑定在各网卡上的套接字，类似 `ss` 和 /proc/net/tcp
+use core::fmt;
+
+use alloc::sync::Arc;
+use smoltcp::socket::tcp;
+use smoltcp::time::Duration;
+use smoltcp::wire::IpEndpoint;
+
+use crate::interface::registry::NET_DEVICES;
+use crate::process::Pid;
+
+use super::{InetSocket, Types};
+
+/// 一个套接字的快照
+#[derive(Debug, Clone)]
+pub struct SocketInfo {
+    /// `Types::Tcp`、`Types::Udp`、`Types::Icmp` 或 `Types::Raw`
+    pub protocol: Types,
+    /// ICMP 的端口是 echo 标识符，原始 socket 的端口是协议号
+    pub local: IpEndpoint,
+    /// 未连接时为 `None`
+    pub remote: Option<IpEndpoint>,
+    /// TCP 状态，其它协议为 `None`
+    pub state: Option<tcp::State>,
+    /// 待发送的字节数，监听套接字为 backlog
+    pub send_queue: usize,
+    /// 待读取的字节数，监听套接字为等待 accept 的连接数
+    pub recv_queue: usize,
+    /// 占用本地端口的进程，原始 socket 为打开它的进程。由 [`socket_table`] 查找
+    pub pid: Option<Pid>,
+    /// 套接字所在的网卡名
+    pub ifaces: Vec<String>,
+    /// TCP 的超时时间，超过后连接被关闭
+    pub timeout: Option<Duration>,
+    /// TCP 的 keep-alive 间隔
+    pub keep_alive: Option<Duration>,
+}
+
+impl SocketInfo {
+    /// 与 `Display` 的列对齐的表头
+    pub const HEADER: &'static str = "Proto State        Recv-Q Send-Q Local Address:Port       Peer Address:Port        Pid        Iface";
+
+    pub(super) fn from_tcp(socket: &tcp::Socket) -> Self {
+        SocketInfo {
+            protocol: Types::Tcp,
+            local: socket
+                .local_endpoint()
+                .unwrap_or(super::UNSPECIFIED_LOCAL_ENDPOINT_V4),
+            remote: socket.remote_endpoint(),
+            state: Some(socket.state()),
+            send_queue: socket.send_queue(),
+            recv_queue: socket.recv_queue(),
+            pid: None,
+            ifaces: Vec::new(),
+            timeout: socket.timeout(),
+            keep_alive: socket.keep_alive(),
+        }
+    }
+}
+
+impl fmt::Display for SocketInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let protocol = match self.protocol {
+            Types::Tcp => "tcp",
+            Types::Udp => "udp",
+            Types::Icmp => "icmp",
+            Types::Raw => "raw",
+            _ => "?",
+        };
+        let state = match (self.state, self.remote) {
+            (Some(state), _) => state.to_string(),
+            (None, Some(_)) => "ESTABLISHED".to_string(),
+            (None, None) => "UNCONN".to_string(),
+        };
+        let remote = self
+            .remote
+            .map_or_else(|| "*:*".to_string(), |remote| remote.to_string());
+        let pid = self
+            .pid
+            .map_or_else(|| "-".to_string(), |pid| pid.data().to_string());
+        write!(
+            f,
+            "{:<5} {:<12} {:>6} {:>6} {:<24} {:<24} {:<10} {}",
+            protocol,
+            state,
+            self.recv_queue,
+            self.send_queue,
+            self.local.to_string(),
+            remote,
+            pid,
+            self.ifaces.join(",")
+        )?;
+        let mut timers = Vec::new();
+        if let Some(timeout) = self.timeout {
+            timers.push(format!("timeout:{}ms", timeout.total_millis()));
+        }
+        if let Some(keep_alive) = self.keep_alive {
+            timers.push(format!("keepalive:{}ms", keep_alive.total_millis()));
+        }
+        if !timers.is_empty() {
+            write!(f, " timer:({})", timers.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// # `socket_table`
+/// 列出绑定在已注册网卡上的全部套接字，每个套接字只出现一次
+pub fn socket_table() -> Vec<SocketInfo> {
+    let mut sockets: Vec<Arc<dyn InetSocket>> = Vec::new();
+    for iface in NET_DEVICES.ifaces() {
+        for socket in iface.common().bound_sockets() {
+            // a socket bound to an unspecified address is on every iface
+            if !sockets.iter().any(|other| Arc::ptr_eq(other, &socket)) {
+                sockets.push(socket);
+            }
+        }
+    }
+    sockets
+        .iter()
+        .filter_map(|socket| socket.socket_info())
+        .map(|mut info| {
+            info.pid = info.pid.or_else(|| owner(&info));
+            info
+        })
+        .collect()
+}
+
+/// 在套接字所在的第一个网卡上查找占用其本地端口的进程
+fn owner(info: &SocketInfo) -> Option<Pid> {
+    let iface = NET_DEVICES.get_by_name(info.ifaces.first()?)?;
+    iface.port_manager().owner(info.protocol, info.local.port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where the column `name` of the header starts and ends
+    fn column(name: &str) -> (usize, usize) {
+        let start = SocketInfo::HEADER.find(name).unwrap();
+        (start, start + name.len())
+    }
+
+    #[test]
+    fn display_lines_up_with_the_header() {
+        let info = SocketInfo {
+            protocol: Types::Tcp,
+            local: IpEndpoint::new(smoltcp::wire::IpAddress::v4(10, 0, 0, 1), 8080),
+            remote: Some(IpEndpoint::new(
+                smoltcp::wire::IpAddress::v4(10, 0, 0, 2),
+                40000,
+            )),
+            state: Some(tcp::State::Established),
+            send_queue: 12,
+            recv_queue: 3456,
+            pid: Some(crate::process::ProcessManager::current_pid()),
+            ifaces: vec!["veth0".to_string(), "lo".to_string()],
+            timeout: None,
+            keep_alive: Some(Duration::from_millis(75000)),
+        };
+        let line = info.to_string();
+        let [proto, state, recv_q, send_q, local, peer, pid, iface] = [
+            "Proto",
+            "State",
+            "Recv-Q",
+            "Send-Q",
+            "Local Address:Port",
+            "Peer Address:Port",
+            "Pid",
+            "Iface",
+        ]
+        .map(column);
+        let starts_at = |(start, _): (usize, usize), value: &str| {
+            assert_eq!(&line[start..start + value.len()], value, "{}", line);
+            assert!(start == 0 || &line[start - 1..start] == " ", "{}", line);
+        };
+        // the queues are right aligned under their names
+        let ends_at = |(_, end): (usize, usize), value: &str| {
+            assert_eq!(&line[end - value.len()..end], value, "{}", line);
+            assert_eq!(&line[end..end + 1], " ", "{}", line);
+        };
+        starts_at(proto, "tcp");
+        starts_at(state, "ESTABLISHED");
+        ends_at(recv_q, "3456");
+        ends_at(send_q, "12");
+        starts_at(local, "10.0.0.1:8080");
+        starts_at(peer, "10.0.0.2:40000");
+        starts_at(pid, &info.pid.unwrap().data().to_string());
+        starts_at(iface, "veth0,lo timer:(keepalive:75000ms)");
+
+        // an unconnected socket without a state or a process
+        let info = SocketInfo {
+            protocol: Types::Udp,
+            remote: None,
+            state: None,
+            pid: None,
+            keep_alive: None,
+            ..info
+        };
+        let line = info.to_string();
+        assert_eq!(
+            line.len(),
+            SocketInfo::HEADER.len() - "Iface".len() + "veth0,lo".len()
+        );
+        assert_eq!(&line[state.0..state.0 + 6], "UNCONN");
+        assert_eq!(&line[peer.0..peer.0 + 3], "*:*");
+        assert_eq!(&line[pid.0..pid.0 + 2], "- ");
+    }
+}
@@ -1,6 +1,7 @@
 pub mod common;
 pub mod endpoint;
 pub mod inet;
+pub mod packet;
 
 use crate::{libs::wait_queue::WaitQueue, posix::SOCK};
 use core::any::Any;
@@ -0,0 +1,467 @@
+//! 链路层 packet socket (`AF_PACKET`)，在一个网卡或全部网卡上收发整个帧。
+//! `SOCK_RAW` 收发带链路层头的帧，`SOCK_DGRAM` 收发链路层头之后的负载，发送时由协议栈加上以太网头。
+//! 只收取协议号与 socket 相同的帧，`ETH_P_ALL` 收取全部帧，包括网卡发出的帧；协议号为 0 时不收取。
+//! 帧在 `IfaceCommon::poll` 经过设备时交给 socket，与抓包看到的相同；发出的帧在网卡下次轮询时写入设备
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+use linux_errnos::Errno as SystemError;
+use smoltcp::phy::Medium;
+use smoltcp::wire::{EthernetAddress, EthernetFrame};
+use spin::{Mutex, RwLock};
+
+use crate::driver::tapped::Direction;
+use crate::event_poll::EPollEventType;
+use crate::interface::registry::NET_DEVICES;
+use crate::interface::Iface;
+use crate::libs::wait_queue::{wq_wait_event_interruptible, WaitQueue};
+use crate::posix::{PMSG, SOCK};
+use crate::socket::endpoint::{Endpoint, LinkLayerEndpoint};
+use crate::socket::Socket;
+
+pub mod syscall;
+
+type EP = EPollEventType;
+
+/// 收取全部协议的帧
+pub const ETH_P_ALL: u16 = libc::ETH_P_ALL as u16;
+/// 以太网头的长度
+const ETH_HEADER_LEN: usize = libc::ETH_HLEN as usize;
+/// 接收队列中帧的总字节数上限，超过时新到的帧被丢弃
+pub const DEFAULT_RX_BUF_SIZE: usize = 256 * 1024;
+
+/// 全部打开的 packet socket，关闭或释放时移除。只持有弱引用，不关闭的 socket 也能被释放
+static PACKET_SOCKETS: RwLock<Vec<Weak<PacketSocket>>> = RwLock::new(Vec::new());
+/// 分配 socket 的编号，用于认出发送者自己发出的帧
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// # `PacketTap`
+/// 把一个网卡上收发的帧交给 packet socket，由 `IfaceCommon::poll` 在每次轮询时创建
+#[derive(Debug, Clone, Copy)]
+pub struct PacketTap {
+    ifindex: usize,
+    medium: Medium,
+    /// 以太网卡的 MAC 地址，用于区分发给本机的帧
+    mac: EthernetAddress,
+}
+
+impl PacketTap {
+    pub fn new(ifindex: usize, medium: Medium, mac: EthernetAddress) -> Self {
+        Self {
+            ifindex,
+            medium,
+            mac,
+        }
+    }
+
+    /// # `deliver`
+    /// 把帧交给想要它的 packet socket，`sender` 是发出这一帧的 packet socket，它收不到自己发出的帧
+    pub fn deliver(&self, direction: Direction, frame: &[u8], sender: Option<usize>) {
+        // 先取出 socket 再放开锁，最后一个引用在这里释放时要从表中移除自己
+        let sockets: Vec<Arc<PacketSocket>> = PACKET_SOCKETS
+            .read()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        if sockets.is_empty() {
+            return;
+        }
+        let Some((header_len, from)) = self.parse(direction, frame) else {
+            return;
+        };
+        for socket in sockets.iter() {
+            if Some(socket.id) == sender || !socket.wants(self.ifindex, from.protocol, direction) {
+                continue;
+            }
+            let data = match socket.socket_type {
+                SOCK::Datagram => &frame[header_len..],
+                _ => frame,
+            };
+            socket.enqueue(from, data);
+        }
+    }
+
+    /// 帧的链路层头长度，以及发送方的链路层端点
+    fn parse(&self, direction: Direction, frame: &[u8]) -> Option<(usize, LinkLayerEndpoint)> {
+        let mut from = LinkLayerEndpoint::new(self.ifindex);
+        match self.medium {
+            Medium::Ethernet => {
+                let ethernet = EthernetFrame::new_checked(frame).ok()?;
+                let dst = ethernet.dst_addr();
+                from.protocol = u16::from(ethernet.ethertype());
+                from.hatype = libc::ARPHRD_ETHER;
+                from.pkttype = match direction {
+                    Direction::Outbound => libc::PACKET_OUTGOING,
+                    Direction::Inbound if dst == self.mac => libc::PACKET_HOST,
+                    Direction::Inbound if dst.is_broadcast() => libc::PACKET_BROADCAST,
+                    Direction::Inbound if dst.is_multicast() => libc::PACKET_MULTICAST,
+                    Direction::Inbound => libc::PACKET_OTHERHOST,
+                };
+                from.halen = 6;
+                from.addr[..6].copy_from_slice(ethernet.src_addr().as_bytes());
+                Some((ETH_HEADER_LEN, from))
+            }
+            Medium::Ip => {
+                // 没有链路层头，由 IP 版本得到协议号
+                from.protocol = match frame.first()? >> 4 {
+                    4 => libc::ETH_P_IP as u16,
+                    6 => libc::ETH_P_IPV6 as u16,
+                    _ => 0,
+                };
+                from.hatype = libc::ARPHRD_NONE;
+                from.pkttype = match direction {
+                    Direction::Outbound => libc::PACKET_OUTGOING,
+                    Direction::Inbound => libc::PACKET_HOST,
+                };
+                Some((0, from))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RecvQueue {
+    frames: VecDeque<(LinkLayerEndpoint, Vec<u8>)>,
+    /// 队列中帧的总字节数
+    size: usize,
+}
+
+#[derive(Debug)]
+pub struct PacketSocket {
+    id: usize,
+    /// `SOCK::Raw` 或 `SOCK::Datagram`
+    socket_type: SOCK,
+    /// 收取的协议号，主机字节序
+    protocol: AtomicU16,
+    /// 绑定的网卡，0 表示全部网卡
+    ifindex: AtomicUsize,
+    recv_queue: Mutex<RecvQueue>,
+    closed: AtomicBool,
+    nonblock: AtomicBool,
+    wait_queue: WaitQueue,
+}
+
+impl PacketSocket {
+    /// # `new`
+    /// 创建收取协议号为 `protocol` (主机字节序) 的帧的 socket，创建后就在全部网卡上收取
+    pub fn new(socket_type: SOCK, protocol: u16, nonblock: bool) -> Arc<Self> {
+        let socket = Arc::new(Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            socket_type,
+            protocol: AtomicU16::new(protocol),
+            ifindex: AtomicUsize::new(0),
+            recv_queue: Mutex::new(RecvQueue::default()),
+            closed: AtomicBool::new(false),
+            nonblock: AtomicBool::new(nonblock),
+            wait_queue: WaitQueue::default(),
+        });
+        PACKET_SOCKETS.write().push(Arc::downgrade(&socket));
+        socket
+    }
+
+    pub fn is_nonblock(&self) -> bool {
+        self.nonblock.load(Ordering::Relaxed)
+    }
+
+    pub fn protocol(&self) -> u16 {
+        self.protocol.load(Ordering::Relaxed)
+    }
+
+    pub fn ifindex(&self) -> usize {
+        self.ifindex.load(Ordering::Relaxed)
+    }
+
+    /// # `wants`
+    /// socket 是否收取 `ifindex` 上协议号为 `protocol` 的帧，与 Linux 相同，发出的帧只交给 `ETH_P_ALL` 的 socket
+    fn wants(&self, ifindex: usize, protocol: u16, direction: Direction) -> bool {
+        let bound = self.ifindex();
+        if bound != 0 && bound != ifindex {
+            return false;
+        }
+        match self.protocol() {
+            0 => false,
+            ETH_P_ALL => true,
+            wanted => direction == Direction::Inbound && wanted == protocol,
+        }
+    }
+
+    fn enqueue(&self, from: LinkLayerEndpoint, data: &[u8]) {
+        {
+            let mut queue = self.recv_queue.lock();
+            if queue.size + data.len() > DEFAULT_RX_BUF_SIZE {
+                log::trace!("packet socket {}: queue full, frame dropped", self.id);
+                return;
+            }
+            queue.size += data.len();
+            queue.frames.push_back((from, data.to_vec()));
+        }
+        self.wait_queue.wakeup();
+    }
+
+    fn bound_iface(&self) -> Option<Arc<dyn Iface>> {
+        match self.ifindex() {
+            0 => None,
+            ifindex => NET_DEVICES.get(ifindex),
+        }
+    }
+
+    pub fn close(&self) {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        PACKET_SOCKETS
+            .write()
+            .retain(|socket| !core::ptr::eq(socket.as_ptr(), self));
+        self.wait_queue.wakeup();
+    }
+
+    /// # `try_recv`
+    /// 取出下一帧，放不下的部分被丢弃
+    /// ## 返回值
+    /// - 没有帧时返回 `Err(SystemError::EAGAIN)`
+    pub fn try_recv(&self, buf: &mut [u8]) -> Result<(usize, LinkLayerEndpoint), SystemError> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(SystemError::EBADF);
+        }
+        if let Some(iface) = self.bound_iface() {
+            iface.poll();
+        }
+        let mut queue = self.recv_queue.lock();
+        let (from, frame) = queue.frames.pop_front().ok_or(SystemError::EAGAIN)?;
+        queue.size -= frame.len();
+        let size = frame.len().min(buf.len());
+        buf[..size].copy_from_slice(&frame[..size]);
+        Ok((size, from))
+    }
+
+    #[inline]
+    pub fn can_recv(&self) -> bool {
+        !self.recv_queue.lock().frames.is_empty()
+    }
+
+    /// # `try_send`
+    /// 在 `to` 的网卡或绑定的网卡上发送，`SOCK_DGRAM` 发往 `to` 的硬件地址
+    /// ## 返回值
+    /// - 没有网卡或网卡不存在时返回 `Err(SystemError::ENXIO)`
+    /// - 网卡停用时返回 `Err(SystemError::ENETDOWN)`
+    /// - 帧超过网卡 MTU 时返回 `Err(SystemError::EMSGSIZE)`
+    /// - `SOCK_DGRAM` 在以太网卡上没有目的硬件地址时返回 `Err(SystemError::EDESTADDRREQ)`
+    /// - `SOCK_RAW` 的以太网帧不完整时返回 `Err(SystemError::EINVAL)`
+    pub fn try_send(
+        &self,
+        buf: &[u8],
+        to: Option<LinkLayerEndpoint>,
+    ) -> Result<usize, SystemError> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(SystemError::EBADF);
+        }
+        let ifindex = match to {
+            Some(to) if to.interface != 0 => to.interface,
+            _ => self.ifindex(),
+        };
+        let iface = match ifindex {
+            0 => None,
+            ifindex => NET_DEVICES.get(ifindex),
+        }
+        .ok_or(SystemError::ENXIO)?;
+        if !iface.common().is_running() {
+            return Err(iface.link_error().unwrap_or(SystemError::ENETDOWN));
+        }
+
+        let mtu = iface.mtu();
+        let frame = match (iface.common().medium(), self.socket_type) {
+            (Medium::Ethernet, SOCK::Datagram) => {
+                let to = to
+                    .filter(|to| to.halen as usize >= 6)
+                    .ok_or(SystemError::EDESTADDRREQ)?;
+                if buf.len() > mtu {
+                    return Err(SystemError::EMSGSIZE);
+                }
+                let protocol = match to.protocol {
+                    0 => self.protocol(),
+                    protocol => protocol,
+                };
+                let mut frame = vec![0; ETH_HEADER_LEN + buf.len()];
+                let mut ethernet = EthernetFrame::new_unchecked(&mut frame[..]);
+                ethernet.set_dst_addr(EthernetAddress::from_bytes(&to.addr[..6]));
+                ethernet.set_src_addr(iface.mac());
+                ethernet.set_ethertype(protocol.into());
+                ethernet.payload_mut().copy_from_slice(buf);
+                frame
+            }
+            (Medium::Ethernet, _) => {
+                if buf.len() < ETH_HEADER_LEN {
+                    return Err(SystemError::EINVAL);
+                }
+                if buf.len() > ETH_HEADER_LEN + mtu {
+                    return Err(SystemError::EMSGSIZE);
+                }
+                buf.to_vec()
+            }
+            (Medium::Ip, _) => {
+                if buf.len() > mtu {
+                    return Err(SystemError::EMSGSIZE);
+                }
+                buf.to_vec()
+            }
+        };
+        iface.common().queue_frame(self.id, frame)?;
+        iface.poll();
+        match iface.link_error() {
+            Some(err) => Err(err),
+            None => Ok(buf.len()),
+        }
+    }
+
+    pub fn event(&self) -> EPollEventType {
+        let mut event = EP::EPOLLOUT | EP::EPOLLWRNORM | EP::EPOLLWRBAND;
+        if self.can_recv() {
+            event.insert(EP::EPOLLIN | EP::EPOLLRDNORM);
+        }
+        if self.bound_iface().is_some_and(|iface| !iface.is_up()) {
+            event.insert(EP::EPOLLERR);
+        }
+        event
+    }
+
+    /// # `recv_frame`
+    /// 接收一帧，socket 或 `flags` 不是非阻塞时等待帧到达
+    fn recv_frame(
+        &self,
+        buffer: &mut [u8],
+        flags: PMSG,
+    ) -> Result<(usize, LinkLayerEndpoint), SystemError> {
+        if self.is_nonblock() || flags.contains(PMSG::DONTWAIT) {
+            return self.try_recv(buffer);
+        }
+        loop {
+            match self.try_recv(buffer) {
+                Err(SystemError::EAGAIN) => {
+                    wq_wait_event_interruptible(
+                        &self.wait_queue,
+                        || self.can_recv() || self.closed.load(Ordering::Relaxed),
+                        None,
+                    )?;
+                }
+                result => break result,
+            }
+        }
+    }
+}
+
+impl Drop for PacketSocket {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Socket for PacketSocket {
+    fn wait_queue(&self) -> &WaitQueue {
+        &self.wait_queue
+    }
+
+    fn poll(&self) -> usize {
+        self.event().bits() as usize
+    }
+
+    /// 绑定到 `interface` 的网卡，0 表示全部网卡；协议号不为 0 时替换 socket 的协议号
+    fn bind(&self, endpoint: Endpoint) -> Result<(), SystemError> {
+        let Endpoint::LinkLayer(endpoint) = endpoint else {
+            return Err(SystemError::EAFNOSUPPORT);
+        };
+        if endpoint.interface != 0 && NET_DEVICES.get(endpoint.interface).is_none() {
+            return Err(SystemError::ENODEV);
+        }
+        self.ifindex.store(endpoint.interface, Ordering::Relaxed);
+        if endpoint.protocol != 0 {
+            self.protocol.store(endpoint.protocol, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn send_buffer_size(&self) -> usize {
+        self.bound_iface()
+            .map_or(0, |iface| iface.mtu() + ETH_HEADER_LEN)
+    }
+
+    fn recv_buffer_size(&self) -> usize {
+        DEFAULT_RX_BUF_SIZE
+    }
+
+    fn connect(&self, _endpoint: Endpoint) -> Result<(), SystemError> {
+        Err(SystemError::EOPNOTSUPP)
+    }
+
+    fn send(&self, buffer: &[u8], _flags: PMSG) -> Result<usize, SystemError> {
+        self.try_send(buffer, None)
+    }
+
+    fn send_to(
+        &self,
+        buffer: &[u8],
+        _flags: PMSG,
+        address: Endpoint,
+    ) -> Result<usize, SystemError> {
+        let Endpoint::LinkLayer(to) = address else {
+            return Err(SystemError::EINVAL);
+        };
+        self.try_send(buffer, Some(to))
+    }
+
+    fn recv(&self, buffer: &mut [u8], flags: PMSG) -> Result<usize, SystemError> {
+        self.recv_frame(buffer, flags).map(|(len, _)| len)
+    }
+
+    fn recv_from(
+        &self,
+        buffer: &mut [u8],
+        flags: PMSG,
+        _address: Option<Endpoint>,
+    ) -> Result<(usize, Endpoint), SystemError> {
+        self.recv_frame(buffer, flags)
+            .map(|(len, from)| (len, Endpoint::LinkLayer(from)))
+    }
+
+    fn close(&self) -> Result<(), SystemError> {
+        self.close();
+        Ok(())
+    }
+
+    /// 绑定的网卡、协议号和网卡的硬件地址
+    fn get_name(&self) -> Result<Endpoint, SystemError> {
+        let mut endpoint = LinkLayerEndpoint::new(self.ifindex());
+        endpoint.protocol = self.protocol();
+        if let Some(iface) = self.bound_iface() {
+            match iface.common().medium() {
+                Medium::Ethernet => {
+                    endpoint.hatype = libc::ARPHRD_ETHER;
+                    endpoint.halen = 6;
+                    endpoint.addr[..6].copy_from_slice(iface.mac().as_bytes());
+                }
+                Medium::Ip => endpoint.hatype = libc::ARPHRD_NONE,
+            }
+        }
+        Ok(Endpoint::LinkLayer(endpoint))
+    }
+
+    fn get_peer_name(&self) -> Result<Endpoint, SystemError> {
+        Err(SystemError::EOPNOTSUPP)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sockets_leave_the_table_when_dropped_unclosed() {
+        let socket = PacketSocket::new(SOCK::Raw, ETH_P_ALL, false);
+        let weak = Arc::downgrade(&socket);
+        let listed = || PACKET_SOCKETS.read().iter().any(|other| other.ptr_eq(&weak));
+        assert!(listed());
+        drop(socket);
+        assert!(weak.upgrade().is_none());
+        assert!(!listed());
+    }
+}
@@ -0,0 +1,23 @@
+use std::sync::Arc;
+
+use linux_errnos::Errno as SystemError;
+
+use crate::{
+    posix::SOCK,
+    socket::{packet::PacketSocket, Family, Socket},
+};
+
+fn create_packet_socket(socket_type: SOCK, protocol: u16) -> Result<Arc<dyn Socket>, SystemError> {
+    match socket_type {
+        SOCK::Raw | SOCK::Datagram => Ok(PacketSocket::new(socket_type, protocol, false)),
+        _ => Err(SystemError::ESOCKTNOSUPPORT),
+    }
+}
+
+pub struct Packet;
+impl Family for Packet {
+    /// `protocol` 与 Linux 相同是网络字节序的以太网协议号，如 `htons(ETH_P_ALL)`
+    fn socket(stype: SOCK, protocol: u32) -> Result<Arc<dyn Socket>, SystemError> {
+        create_packet_socket(stype, u16::from_be(protocol as u16))
+    }
+}
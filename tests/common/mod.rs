@@ -0,0 +1,58 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use berkeley_socket::socket::endpoint::Endpoint;
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+pub fn endpoint(addr: IpAddress, port: u16) -> Endpoint {
+    Endpoint::Ip(IpEndpoint::new(addr, port))
+}
+
+pub fn ip(endpoint: Endpoint) -> IpEndpoint {
+    match endpoint {
+        Endpoint::Ip(endpoint) => endpoint,
+        _ => panic!("not an IP endpoint"),
+    }
+}
+
+/// Runs `test` on a thread of its own, so that a lost frame fails the test
+/// instead of hanging it
+pub fn with_timeout(test: impl FnOnce() + Send + 'static) {
+    let (done, finished) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        test();
+        let _ = done.send(());
+    });
+    match finished.recv_timeout(Duration::from_secs(30)) {
+        Ok(()) => handle.join().unwrap(),
+        // the test panicked, report its panic
+        Err(mpsc::RecvTimeoutError::Disconnected) => handle.join().unwrap(),
+        Err(mpsc::RecvTimeoutError::Timeout) => panic!("timed out"),
+    }
+}
+
+/// An IPv4 packet for `IP_HDRINCL` with the total length and the source left
+/// at zero for the stack to fill in
+pub fn ipv4_packet(protocol: u8, dst: IpAddress, payload: &[u8]) -> Vec<u8> {
+    let IpAddress::Ipv4(dst) = dst else {
+        panic!("not an IPv4 address");
+    };
+    let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, protocol, 0, 0, 0, 0, 0, 0];
+    packet.extend_from_slice(&dst.octets());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// A UDP datagram without a checksum, which IPv4 allows
+pub fn udp_datagram(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    datagram.extend_from_slice(&src_port.to_be_bytes());
+    datagram.extend_from_slice(&dst_port.to_be_bytes());
+    datagram.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    datagram
+}
@@ -0,0 +1,704 @@
+//! Sockets talking to each other through the loopback interface.
+mod common;
+
+use std::sync::{Mutex, Once, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use berkeley_socket::{
+    driver::irq::start_network_polling_thread,
+    posix::{PMSG, PSOL, SOCK},
+    process::ProcessManager,
+    socket::{
+        inet::{
+            dns::{
+                getaddrinfo, getnameinfo, AddrInfoHints, GaiError, NameInfoFlags, ResolverConfig,
+                DNS_PORT, RESOLVER,
+            },
+            syscall::{Inet, Inet6},
+            table::socket_table,
+            Types,
+        },
+        Family, Socket,
+    },
+};
+use common::{endpoint, ip, ipv4_packet, udp_datagram, with_timeout};
+use linux_errnos::Errno;
+use smoltcp::wire::{
+    Icmpv4Packet, Icmpv6Packet, IpAddress, IpEndpoint, IpVersion, Ipv4Address, Ipv4Packet,
+    Ipv6Address,
+};
+
+const LOCALHOST: IpAddress = IpAddress::v4(127, 0, 0, 1);
+const LOCALHOST6: IpAddress = IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1);
+const ANY: IpAddress = IpAddress::v4(0, 0, 0, 0);
+const ANY6: IpAddress = IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 0);
+/// `127.0.0.1` as an IPv6 socket sees it
+const MAPPED_LOCALHOST: IpAddress = IpAddress::v6(0, 0, 0, 0, 0, 0xffff, 0x7f00, 0x0001);
+
+/// The loopback is registered on first use, only the polling thread is missing
+fn setup() {
+    static SETUP: Once = Once::new();
+    SETUP.call_once(|| {
+        start_network_polling_thread().unwrap();
+    });
+}
+
+#[test]
+fn udp_round_trip() {
+    setup();
+    with_timeout(|| {
+        let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(LOCALHOST, 5300)).unwrap();
+        let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+
+        let mut buffer = [0; 64];
+        client
+            .send_to(b"ping", PMSG::empty(), endpoint(LOCALHOST, 5300))
+            .unwrap();
+        let (len, from) = server.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+        assert_eq!(ip(from.clone()).addr, LOCALHOST);
+
+        server.send_to(b"pong", PMSG::empty(), from).unwrap();
+        let (len, from) = client.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"pong");
+        assert_eq!(ip(from), IpEndpoint::new(LOCALHOST, 5300));
+        client.close().unwrap();
+        server.close().unwrap();
+    });
+}
+
+#[test]
+fn tcp_round_trip() {
+    setup();
+    with_timeout(|| {
+        let listener = Inet::socket(SOCK::Stream, 0).unwrap();
+        listener.bind(endpoint(LOCALHOST, 5400)).unwrap();
+        listener.listen(4).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, from) = listener.accept().unwrap();
+            assert_eq!(ip(from).addr, LOCALHOST);
+            let mut buffer = [0; 64];
+            let len = stream.recv(&mut buffer, PMSG::empty()).unwrap();
+            assert_eq!(&buffer[..len], b"hello");
+            stream.send(b"world", PMSG::empty()).unwrap();
+        });
+
+        let client = Inet::socket(SOCK::Stream, 0).unwrap();
+        client.connect(endpoint(LOCALHOST, 5400)).unwrap();
+        // zero-length reads and writes succeed without touching the stream
+        assert_eq!(client.recv(&mut [], PMSG::empty()), Ok(0));
+        assert_eq!(client.send(&[], PMSG::empty()), Ok(0));
+
+        assert_eq!(client.send(b"hello", PMSG::empty()), Ok(5));
+        let mut buffer = [0; 64];
+        let len = client.recv(&mut buffer, PMSG::empty()).unwrap();
+        assert_eq!(&buffer[..len], b"world");
+        server.join().unwrap();
+    });
+}
+
+#[test]
+fn socket_table_names_the_owner_of_every_socket() {
+    setup();
+    with_timeout(|| {
+        let udp = Inet::socket(SOCK::Datagram, 0).unwrap();
+        udp.bind(endpoint(LOCALHOST, 5500)).unwrap();
+        let tcp = Inet::socket(SOCK::Stream, 0).unwrap();
+        tcp.bind(endpoint(LOCALHOST, 5501)).unwrap();
+        tcp.listen(2).unwrap();
+        let ping = Inet::socket(SOCK::Datagram, libc::IPPROTO_ICMP as u32).unwrap();
+        ping.bind(endpoint(LOCALHOST, 5502)).unwrap();
+        // a raw socket takes no port, its local port is the protocol
+        let raw = Inet::socket(SOCK::Raw, libc::IPPROTO_UDPLITE as u32).unwrap();
+
+        let table = socket_table();
+        for (protocol, port) in [
+            (Types::Udp, 5500),
+            (Types::Tcp, 5501),
+            (Types::Icmp, 5502),
+            (Types::Raw, libc::IPPROTO_UDPLITE as u16),
+        ] {
+            let info = table
+                .iter()
+                .find(|info| info.protocol == protocol && info.local.port == port)
+                .unwrap_or_else(|| panic!("no {:?} socket on port {}", protocol, port));
+            assert_eq!(info.pid, Some(ProcessManager::current_pid()), "{}", info);
+        }
+        for socket in [udp, tcp, ping, raw] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+fn set_v6only(socket: &dyn Socket) {
+    socket
+        .set_option(PSOL::IPV6, libc::IPV6_V6ONLY as usize, &1i32.to_ne_bytes())
+        .unwrap();
+}
+
+#[test]
+fn ipv6_round_trip() {
+    setup();
+    with_timeout(|| {
+        let server = Inet6::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(LOCALHOST6, 5600)).unwrap();
+        let client = Inet6::socket(SOCK::Datagram, 0).unwrap();
+
+        let mut buffer = [0; 64];
+        client
+            .send_to(b"ping", PMSG::empty(), endpoint(LOCALHOST6, 5600))
+            .unwrap();
+        let (len, from) = server.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+        assert_eq!(ip(from.clone()).addr, LOCALHOST6);
+        server.send_to(b"pong", PMSG::empty(), from).unwrap();
+        let (len, from) = client.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"pong");
+        assert_eq!(ip(from), IpEndpoint::new(LOCALHOST6, 5600));
+
+        let listener = Inet6::socket(SOCK::Stream, 0).unwrap();
+        listener.bind(endpoint(LOCALHOST6, 5601)).unwrap();
+        listener.listen(2).unwrap();
+        let stream = Inet6::socket(SOCK::Stream, 0).unwrap();
+        stream.connect(endpoint(LOCALHOST6, 5601)).unwrap();
+        let (accepted, from) = listener.accept().unwrap();
+        assert_eq!(ip(from), ip(stream.get_name().unwrap()));
+        assert_eq!(stream.send(b"hello", PMSG::empty()), Ok(5));
+        let len = accepted.recv(&mut buffer, PMSG::empty()).unwrap();
+        assert_eq!(&buffer[..len], b"hello");
+        for socket in [server, client, listener, stream, accepted] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+#[test]
+fn dual_stack_sockets_see_ipv4_peers_as_mapped_addresses() {
+    setup();
+    with_timeout(|| {
+        let server = Inet6::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(ANY6, 5602)).unwrap();
+        let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+
+        let mut buffer = [0; 64];
+        client
+            .send_to(b"ping", PMSG::empty(), endpoint(LOCALHOST, 5602))
+            .unwrap();
+        let (len, from) = server.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+        assert_eq!(ip(from.clone()).addr, MAPPED_LOCALHOST);
+        // a mapped address takes the datagram back over IPv4
+        server.send_to(b"pong", PMSG::empty(), from).unwrap();
+        let (len, from) = client.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"pong");
+        assert_eq!(ip(from), IpEndpoint::new(LOCALHOST, 5602));
+
+        let listener = Inet6::socket(SOCK::Stream, 0).unwrap();
+        listener.bind(endpoint(ANY6, 5603)).unwrap();
+        listener.listen(2).unwrap();
+        let stream = Inet::socket(SOCK::Stream, 0).unwrap();
+        stream.connect(endpoint(LOCALHOST, 5603)).unwrap();
+        let (accepted, from) = listener.accept().unwrap();
+        assert_eq!(ip(from).addr, MAPPED_LOCALHOST);
+        assert_eq!(ip(accepted.get_peer_name().unwrap()).addr, MAPPED_LOCALHOST);
+        for socket in [server, client, listener, stream, accepted] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+#[test]
+fn v6only_sockets_share_their_port_with_ipv4_sockets() {
+    setup();
+    with_timeout(|| {
+        // the IPv4 socket comes first, it is handed the IPv6 datagram
+        let four = Inet::socket(SOCK::Datagram, 0).unwrap();
+        four.bind(endpoint(ANY, 5604)).unwrap();
+        let six = Inet6::socket(SOCK::Datagram, 0).unwrap();
+        set_v6only(six.as_ref());
+        six.bind(endpoint(ANY6, 5604)).unwrap();
+        // a dual-stack socket overlaps both
+        let dual = Inet6::socket(SOCK::Datagram, 0).unwrap();
+        assert_eq!(dual.bind(endpoint(ANY6, 5604)), Err(Errno::EADDRINUSE));
+
+        let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+        client
+            .send_to(b"four", PMSG::empty(), endpoint(LOCALHOST, 5604))
+            .unwrap();
+        let client6 = Inet6::socket(SOCK::Datagram, 0).unwrap();
+        client6
+            .send_to(b"six", PMSG::empty(), endpoint(LOCALHOST6, 5604))
+            .unwrap();
+        let mut buffer = [0; 64];
+        let (len, from) = six.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"six");
+        assert_eq!(ip(from).addr, LOCALHOST6);
+        let (len, from) = four.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"four");
+        assert_eq!(ip(from).addr, LOCALHOST);
+        for socket in [&four, &six] {
+            assert_eq!(
+                socket.recv_from(&mut buffer, PMSG::DONTWAIT, None).err(),
+                Some(Errno::EAGAIN)
+            );
+        }
+
+        // the v6only listener comes first, it is handed the IPv4 connection
+        let listener6 = Inet6::socket(SOCK::Stream, 0).unwrap();
+        set_v6only(listener6.as_ref());
+        listener6.bind(endpoint(ANY6, 5605)).unwrap();
+        listener6.listen(2).unwrap();
+        let listener = Inet::socket(SOCK::Stream, 0).unwrap();
+        listener.bind(endpoint(ANY, 5605)).unwrap();
+        listener.listen(2).unwrap();
+        let dual = Inet6::socket(SOCK::Stream, 0).unwrap();
+        assert_eq!(dual.bind(endpoint(ANY6, 5605)), Err(Errno::EADDRINUSE));
+
+        let stream = Inet::socket(SOCK::Stream, 0).unwrap();
+        stream.connect(endpoint(LOCALHOST, 5605)).unwrap();
+        let stream6 = Inet6::socket(SOCK::Stream, 0).unwrap();
+        stream6.connect(endpoint(LOCALHOST6, 5605)).unwrap();
+        let (accepted, from) = listener.accept().unwrap();
+        assert_eq!(ip(from).addr, LOCALHOST);
+        let (accepted6, from) = listener6.accept().unwrap();
+        assert_eq!(ip(from).addr, LOCALHOST6);
+        assert_eq!(stream.send(b"four", PMSG::empty()), Ok(4));
+        let len = accepted.recv(&mut buffer, PMSG::empty()).unwrap();
+        assert_eq!(&buffer[..len], b"four");
+        for socket in [
+            four, six, dual, client, client6, listener6, listener, stream, stream6, accepted,
+            accepted6,
+        ] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+#[test]
+fn v6only_sockets_do_not_take_ipv4() {
+    setup();
+    with_timeout(|| {
+        let six = Inet6::socket(SOCK::Datagram, 0).unwrap();
+        set_v6only(six.as_ref());
+        six.bind(endpoint(ANY6, 5606)).unwrap();
+        // mapped addresses are IPv4
+        assert_eq!(
+            six.send_to(b"four", PMSG::empty(), endpoint(MAPPED_LOCALHOST, 5606)),
+            Err(Errno::EAFNOSUPPORT)
+        );
+        // the IPv4 datagram is dropped, the IPv6 one behind it is received
+        let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+        client
+            .send_to(b"four", PMSG::empty(), endpoint(LOCALHOST, 5606))
+            .unwrap();
+        let client6 = Inet6::socket(SOCK::Datagram, 0).unwrap();
+        client6
+            .send_to(b"six", PMSG::empty(), endpoint(LOCALHOST6, 5606))
+            .unwrap();
+        let mut buffer = [0; 64];
+        let (len, _) = six.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"six");
+
+        // the IPv4 connection is reset, the listener still takes IPv6 ones
+        let listener = Inet6::socket(SOCK::Stream, 0).unwrap();
+        set_v6only(listener.as_ref());
+        listener.bind(endpoint(ANY6, 5607)).unwrap();
+        listener.listen(2).unwrap();
+        let stream = Inet::socket(SOCK::Stream, 0).unwrap();
+        // on the loopback the handshake may complete before the listener
+        // looks at the peer, the RST comes right after
+        match stream.connect(endpoint(LOCALHOST, 5607)) {
+            Ok(()) => assert_eq!(
+                stream.recv(&mut buffer, PMSG::empty()),
+                Err(Errno::ECONNRESET)
+            ),
+            Err(err) => assert_eq!(err, Errno::ECONNREFUSED),
+        }
+        let stream6 = Inet6::socket(SOCK::Stream, 0).unwrap();
+        stream6.connect(endpoint(LOCALHOST6, 5607)).unwrap();
+        let (accepted, from) = listener.accept().unwrap();
+        assert_eq!(ip(from).addr, LOCALHOST6);
+        for socket in [six, client, client6, listener, stream, stream6, accepted] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+/// An echo request with identifier `0xdead`, which the stack replaces
+fn echo_request(kind: u8, sequence: u16, payload: &[u8]) -> Vec<u8> {
+    let mut request = vec![kind, 0, 0, 0, 0xde, 0xad];
+    request.extend_from_slice(&sequence.to_be_bytes());
+    request.extend_from_slice(payload);
+    request
+}
+
+#[test]
+fn ping_sockets_get_the_replies_to_their_identifier() {
+    setup();
+    with_timeout(|| {
+        let ping = Inet::socket(SOCK::Datagram, libc::IPPROTO_ICMP as u32).unwrap();
+        ping.bind(endpoint(ANY, 5700)).unwrap();
+        let other = Inet::socket(SOCK::Datagram, libc::IPPROTO_ICMP as u32).unwrap();
+        other.bind(endpoint(ANY, 5701)).unwrap();
+        // only echo requests are sent
+        assert_eq!(
+            ping.send_to(
+                &echo_request(0, 1, b"x"),
+                PMSG::empty(),
+                endpoint(LOCALHOST, 0)
+            ),
+            Err(Errno::EINVAL)
+        );
+
+        let request = echo_request(8, 1, b"hello");
+        assert_eq!(
+            ping.send_to(&request, PMSG::DONTWAIT, endpoint(LOCALHOST, 0)),
+            Ok(request.len())
+        );
+        let mut reply = [0; 64];
+        let (len, from) = ping.recv_from(&mut reply, PMSG::empty(), None).unwrap();
+        assert_eq!(ip(from), IpEndpoint::new(LOCALHOST, 0));
+        assert_eq!(len, request.len());
+        assert_eq!(reply[0], 0);
+        assert_eq!(reply[4..6], 5700u16.to_be_bytes());
+        assert_eq!(reply[6..len], request[6..]);
+        // the request looped back to the socket is not a reply
+        assert_eq!(ping.recv(&mut reply, PMSG::DONTWAIT), Err(Errno::EAGAIN));
+        assert_eq!(other.recv(&mut reply, PMSG::DONTWAIT), Err(Errno::EAGAIN));
+
+        let ping6 = Inet6::socket(SOCK::Datagram, libc::IPPROTO_ICMPV6 as u32).unwrap();
+        ping6.bind(endpoint(ANY6, 5702)).unwrap();
+        let request = echo_request(128, 2, b"six");
+        ping6
+            .send_to(&request, PMSG::empty(), endpoint(LOCALHOST6, 0))
+            .unwrap();
+        let (len, from) = ping6.recv_from(&mut reply, PMSG::empty(), None).unwrap();
+        assert_eq!(ip(from), IpEndpoint::new(LOCALHOST6, 0));
+        assert_eq!(reply[0], 129);
+        assert_eq!(reply[4..6], 5702u16.to_be_bytes());
+        assert_eq!(reply[6..len], request[6..]);
+        for socket in [ping, other, ping6] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+#[test]
+fn ping_sockets_drop_the_replies_of_the_other_family() {
+    setup();
+    with_timeout(|| {
+        let ping = Inet::socket(SOCK::Datagram, libc::IPPROTO_ICMP as u32).unwrap();
+        ping.bind(endpoint(ANY, 5703)).unwrap();
+        // smoltcp hands an ICMPv6 reply with the identifier to the socket as well
+        let raw6 = Inet6::socket(SOCK::Raw, libc::IPPROTO_ICMPV6 as u32).unwrap();
+        let mut stray = echo_request(129, 1, b"six");
+        stray[4..6].copy_from_slice(&5703u16.to_be_bytes());
+        raw6.send_to(&stray, PMSG::empty(), endpoint(LOCALHOST6, 0))
+            .unwrap();
+
+        let request = echo_request(8, 2, b"four");
+        ping.send_to(&request, PMSG::empty(), endpoint(LOCALHOST, 0))
+            .unwrap();
+        let mut reply = [0; 64];
+        let (len, from) = ping.recv_from(&mut reply, PMSG::empty(), None).unwrap();
+        assert_eq!(ip(from).addr, LOCALHOST);
+        assert_eq!(reply[6..len], request[6..]);
+        assert_eq!(ping.recv(&mut reply, PMSG::DONTWAIT), Err(Errno::EAGAIN));
+        for socket in [ping, raw6] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+#[test]
+fn raw_sockets_send_whole_packets_with_ip_hdrincl() {
+    setup();
+    with_timeout(|| {
+        let raw = Inet::socket(SOCK::Raw, libc::IPPROTO_ICMP as u32).unwrap();
+        let mut option = [0; 4];
+        raw.get_option(PSOL::IP, libc::IP_HDRINCL as usize, &mut option)
+            .unwrap();
+        assert_eq!(i32::from_ne_bytes(option), 0);
+        raw.set_option(PSOL::IP, libc::IP_HDRINCL as usize, &1i32.to_ne_bytes())
+            .unwrap();
+        raw.get_option(PSOL::IP, libc::IP_HDRINCL as usize, &mut option)
+            .unwrap();
+        assert_eq!(i32::from_ne_bytes(option), 1);
+
+        // a header of another protocol or a truncated one is refused
+        let udp = ipv4_packet(libc::IPPROTO_UDP as u8, LOCALHOST, &udp_datagram(1, 2, b""));
+        assert_eq!(
+            raw.send_to(&udp, PMSG::empty(), endpoint(LOCALHOST, 0)),
+            Err(Errno::EINVAL)
+        );
+        assert_eq!(
+            raw.send_to(&udp[..12], PMSG::empty(), endpoint(LOCALHOST, 0)),
+            Err(Errno::EINVAL)
+        );
+
+        let mut request = echo_request(8, 0x5800, b"hdrincl");
+        Icmpv4Packet::new_unchecked(&mut request[..]).fill_checksum();
+        let packet = ipv4_packet(libc::IPPROTO_ICMP as u8, LOCALHOST, &request);
+        assert_eq!(
+            raw.send_to(&packet, PMSG::empty(), endpoint(LOCALHOST, 0)),
+            Ok(packet.len())
+        );
+        // the request comes back with the length and the source filled in,
+        // then the reply of the loopback
+        let mut buffer = [0; 128];
+        let mut seen = Vec::new();
+        while seen.len() < 2 {
+            let len = raw.recv(&mut buffer, PMSG::empty()).unwrap();
+            let received = Ipv4Packet::new_checked(&buffer[..len]).unwrap();
+            if received.payload()[6..] != request[6..] {
+                continue;
+            }
+            assert_eq!(received.total_len() as usize, packet.len());
+            assert_eq!(IpAddress::from(received.src_addr()), LOCALHOST);
+            seen.push(received.payload()[0]);
+        }
+        assert_eq!(seen, [8, 0]);
+
+        // IPv6 has no IP_HDRINCL
+        let raw6 = Inet6::socket(SOCK::Raw, libc::IPPROTO_ICMPV6 as u32).unwrap();
+        assert_eq!(
+            raw6.get_option(PSOL::IP, libc::IP_HDRINCL as usize, &mut option),
+            Err(Errno::ENOPROTOOPT)
+        );
+        for socket in [raw, raw6] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+#[test]
+fn ipproto_raw_sockets_only_send() {
+    setup();
+    with_timeout(|| {
+        let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(LOCALHOST, 5801)).unwrap();
+        let raw = Inet::socket(SOCK::Raw, libc::IPPROTO_RAW as u32).unwrap();
+        let mut option = [0; 4];
+        raw.get_option(PSOL::IP, libc::IP_HDRINCL as usize, &mut option)
+            .unwrap();
+        assert_eq!(i32::from_ne_bytes(option), 1);
+
+        // any protocol goes out, here a UDP datagram
+        let packet = ipv4_packet(
+            libc::IPPROTO_UDP as u8,
+            LOCALHOST,
+            &udp_datagram(5802, 5801, b"raw"),
+        );
+        assert_eq!(
+            raw.send_to(&packet, PMSG::empty(), endpoint(LOCALHOST, 0)),
+            Ok(packet.len())
+        );
+        let mut buffer = [0; 64];
+        let (len, from) = server.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"raw");
+        assert_eq!(ip(from), IpEndpoint::new(LOCALHOST, 5802));
+        // nothing is ever received, not even the packets of the protocol sent
+        assert_eq!(raw.recv(&mut buffer, PMSG::DONTWAIT), Err(Errno::EAGAIN));
+        for socket in [server, raw] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+#[test]
+fn raw_icmpv6_sockets_get_the_checksum_filled_in() {
+    setup();
+    with_timeout(|| {
+        let raw6 = Inet6::socket(SOCK::Raw, libc::IPPROTO_ICMPV6 as u32).unwrap();
+        let request = echo_request(128, 0x5803, b"checksum");
+        assert_eq!(request[2..4], [0, 0]);
+        raw6.send_to(&request, PMSG::empty(), endpoint(LOCALHOST6, 0))
+            .unwrap();
+        // the loopback ignores checksums, the request comes back as it was sent
+        let mut buffer = [0; 128];
+        loop {
+            // an IPv6 raw socket gets the payload only
+            let (len, from) = raw6.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+            if buffer[0] != 128 || buffer[6..len] != request[6..] {
+                continue;
+            }
+            assert_eq!(ip(from).addr, LOCALHOST6);
+            assert!(Icmpv6Packet::new_checked(&buffer[..len])
+                .unwrap()
+                .verify_checksum(&Ipv6Address::LOCALHOST, &Ipv6Address::LOCALHOST));
+            break;
+        }
+        raw6.close().unwrap();
+    });
+}
+
+/// The resolver is global, the tests pointing it at a nameserver take turns
+static RESOLVER_TURN: Mutex<()> = Mutex::new(());
+
+/// Points the resolver at a nameserver on the loopback
+fn use_local_nameserver(timeout: Duration, attempts: usize) {
+    RESOLVER.set_config(ResolverConfig {
+        nameservers: vec![LOCALHOST],
+        timeout,
+        attempts,
+        ..Default::default()
+    });
+}
+
+/// The reply to an A query, with `addr` as the only answer unless the reply is
+/// truncated
+fn dns_reply(query: &[u8], addr: Ipv4Address, ttl: u32, truncated: bool) -> Vec<u8> {
+    let mut reply = query[..2].to_vec();
+    // QR, RD and RA, TC for a truncated reply
+    reply.extend_from_slice(&[0x81 | if truncated { 0x02 } else { 0 }, 0x80]);
+    let answers: u16 = if truncated { 0 } else { 1 };
+    reply.extend_from_slice(&[0, 1]);
+    reply.extend_from_slice(&answers.to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0]);
+    reply.extend_from_slice(&query[12..]);
+    if !truncated {
+        // the name points back at the question
+        reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        reply.extend_from_slice(&ttl.to_be_bytes());
+        reply.extend_from_slice(&[0, 4]);
+        reply.extend_from_slice(&addr.octets());
+    }
+    reply
+}
+
+/// A UDP nameserver on the loopback answering `queries` A queries with `addr`,
+/// or with truncated replies; returns how many queries it saw
+fn udp_nameserver(
+    queries: usize,
+    addr: Ipv4Address,
+    ttl: u32,
+    truncated: bool,
+) -> thread::JoinHandle<usize> {
+    let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+    server.bind(endpoint(LOCALHOST, DNS_PORT)).unwrap();
+    thread::spawn(move || {
+        let mut query = [0; 512];
+        for _ in 0..queries {
+            let (len, from) = server.recv_from(&mut query, PMSG::empty(), None).unwrap();
+            let reply = dns_reply(&query[..len], addr, ttl, truncated);
+            server.send_to(&reply, PMSG::empty(), from).unwrap();
+        }
+        server.close().unwrap();
+        queries
+    })
+}
+
+#[test]
+fn resolver_asks_the_nameserver_and_caches_the_answer() {
+    setup();
+    let _turn = RESOLVER_TURN.lock().unwrap_or_else(PoisonError::into_inner);
+    with_timeout(|| {
+        use_local_nameserver(Duration::from_millis(300), 1);
+        let addr = Ipv4Address::new(10, 1, 2, 3);
+        let nameserver = udp_nameserver(1, addr, 1, false);
+        let hints = AddrInfoHints {
+            family: Some(IpVersion::Ipv4),
+            socket_type: Some(SOCK::Stream),
+            ..Default::default()
+        };
+        let infos = getaddrinfo(Some("plain.test"), Some("http"), &hints).unwrap();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].endpoint, IpEndpoint::new(addr.into(), 80));
+        assert_eq!(nameserver.join().unwrap(), 1);
+
+        // nobody answers now, the second query is served from the cache
+        let entry = RESOLVER
+            .lookup("PLAIN.test", Some(IpVersion::Ipv4))
+            .unwrap();
+        assert_eq!(entry.addresses, [IpAddress::from(addr)]);
+        // until the TTL runs out
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(
+            RESOLVER.lookup("plain.test", Some(IpVersion::Ipv4)),
+            Err(GaiError::Again)
+        );
+
+        // the hosts table needs no nameserver
+        let entry = RESOLVER.lookup("localhost", None).unwrap();
+        assert_eq!(entry.addresses, [LOCALHOST, LOCALHOST6]);
+        let name = getnameinfo(&IpEndpoint::new(LOCALHOST, 22), NameInfoFlags::empty());
+        assert_eq!(name, Ok(("localhost".to_owned(), "ssh".to_owned())));
+
+        // without nameservers of its own it asks those of the DHCP leases, none here
+        RESOLVER.set_config(ResolverConfig::default());
+        assert!(RESOLVER.nameservers().is_empty());
+        assert_eq!(RESOLVER.lookup("plain.test", None), Err(GaiError::Again));
+    });
+}
+
+#[test]
+fn resolver_retries_a_truncated_reply_over_tcp() {
+    setup();
+    let _turn = RESOLVER_TURN.lock().unwrap_or_else(PoisonError::into_inner);
+    with_timeout(|| {
+        use_local_nameserver(Duration::from_secs(2), 1);
+        let addr = Ipv4Address::new(10, 4, 5, 6);
+        let nameserver = udp_nameserver(1, addr, 60, true);
+        let listener = Inet::socket(SOCK::Stream, 0).unwrap();
+        listener.bind(endpoint(LOCALHOST, DNS_PORT)).unwrap();
+        listener.listen(1).unwrap();
+        let tcp = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            // the query comes after its length
+            let mut message = Vec::new();
+            let mut buffer = [0; 512];
+            while message.len() < 2
+                || message.len() < 2 + u16::from_be_bytes([message[0], message[1]]) as usize
+            {
+                let len = stream.recv(&mut buffer, PMSG::empty()).unwrap();
+                assert_ne!(len, 0, "connection closed early");
+                message.extend_from_slice(&buffer[..len]);
+            }
+            let reply = dns_reply(&message[2..], addr, 60, false);
+            let mut framed = (reply.len() as u16).to_be_bytes().to_vec();
+            framed.extend_from_slice(&reply);
+            let mut sent = 0;
+            while sent < framed.len() {
+                sent += stream.send(&framed[sent..], PMSG::empty()).unwrap();
+            }
+            // closed once the resolver has the reply
+            [stream, listener]
+        });
+
+        let entry = RESOLVER.lookup("big.test", Some(IpVersion::Ipv4)).unwrap();
+        assert_eq!(entry.addresses, [IpAddress::from(addr)]);
+        assert_eq!(nameserver.join().unwrap(), 1);
+        for socket in tcp.join().unwrap() {
+            socket.close().unwrap();
+        }
+        RESOLVER.set_config(ResolverConfig::default());
+    });
+}
+
+#[test]
+fn resolver_gives_up_on_a_silent_nameserver_after_its_attempts() {
+    setup();
+    let _turn = RESOLVER_TURN.lock().unwrap_or_else(PoisonError::into_inner);
+    with_timeout(|| {
+        use_local_nameserver(Duration::from_millis(200), 3);
+        let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(LOCALHOST, DNS_PORT)).unwrap();
+        let started = Instant::now();
+        assert_eq!(
+            RESOLVER.lookup("silent.test", Some(IpVersion::Ipv4)),
+            Err(GaiError::Again)
+        );
+        assert!(started.elapsed() >= Duration::from_millis(600));
+        // one query an attempt, none answered
+        let mut query = [0; 512];
+        let mut queries = 0;
+        while server.recv(&mut query, PMSG::DONTWAIT).is_ok() {
+            queries += 1;
+        }
+        assert_eq!(queries, 3);
+        server.close().unwrap();
+        RESOLVER.set_config(ResolverConfig::default());
+    });
+}
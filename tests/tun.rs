@@ -0,0 +1,173 @@
+//! A TUN interface over one end of a socketpair, the test plays the kernel
+//! on the other end.
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+
+use berkeley_socket::{
+    driver::tap::TapDevice,
+    interface::{
+        dhcp::DhcpConfig,
+        registry::NET_DEVICES,
+        route::{Route, ROUTES},
+        tap::TapIface,
+        tun::TunIface,
+        Iface,
+    },
+};
+use linux_errnos::Errno;
+use smoltcp::{
+    phy::{ChecksumCapabilities, Medium},
+    wire::{
+        Icmpv4Packet, Icmpv4Repr, IpAddress, IpCidr, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr,
+    },
+};
+use spin::Mutex;
+
+const LOCAL: Ipv4Address = Ipv4Address::new(10, 65, 0, 2);
+const PEER: Ipv4Address = Ipv4Address::new(10, 65, 0, 1);
+
+/// Two connected sockets keeping the packet boundaries, like a TUN fd
+fn socketpair() -> (OwnedFd, OwnedFd) {
+    let mut fds = [0; 2];
+    let res = unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    assert_eq!(res, 0);
+    unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+}
+
+fn device(medium: Medium) -> (Arc<Mutex<TapDevice>>, OwnedFd) {
+    let (fd, peer) = socketpair();
+    let device = TapDevice::from_fd(fd, medium, 1500, None).unwrap();
+    (Arc::new(Mutex::new(device)), peer)
+}
+
+fn echo_request(ident: u16) -> Vec<u8> {
+    let icmp = Icmpv4Repr::EchoRequest {
+        ident,
+        seq_no: 1,
+        data: b"tun",
+    };
+    let ip = Ipv4Repr {
+        src_addr: PEER,
+        dst_addr: LOCAL,
+        next_header: IpProtocol::Icmp,
+        payload_len: icmp.buffer_len(),
+        hop_limit: 64,
+    };
+    let mut packet = vec![0; ip.buffer_len() + icmp.buffer_len()];
+    let checksum = ChecksumCapabilities::default();
+    let mut ipv4 = Ipv4Packet::new_unchecked(&mut packet[..]);
+    ip.emit(&mut ipv4, &checksum);
+    icmp.emit(
+        &mut Icmpv4Packet::new_unchecked(ipv4.payload_mut()),
+        &checksum,
+    );
+    packet
+}
+
+#[test]
+fn a_tun_device_needs_a_tun_iface() {
+    let (tun, _peer) = device(Medium::Ip);
+    assert_eq!(TapIface::new(tun).err(), Some(Errno::EINVAL));
+    let (tap, _peer) = device(Medium::Ethernet);
+    assert_eq!(TunIface::new(tap).err(), Some(Errno::EINVAL));
+}
+
+#[test]
+fn tun_iface_answers_a_ping() {
+    let (tun, peer) = device(Medium::Ip);
+    let iface = TunIface::new(tun).unwrap();
+    iface
+        .update_ip_addrs(&[IpCidr::new(IpAddress::Ipv4(LOCAL), 24)])
+        .unwrap();
+    // no link layer, no MAC address
+    assert_eq!(iface.mac(), Default::default());
+    assert_eq!(
+        iface.set_mac(smoltcp::wire::EthernetAddress([2, 0, 0, 0, 0, 1])),
+        Err(Errno::EOPNOTSUPP)
+    );
+    assert_eq!(
+        iface.start_dhcp(DhcpConfig::default()),
+        Err(Errno::EOPNOTSUPP)
+    );
+
+    let request = echo_request(0x1234);
+    let sent = unsafe { libc::write(peer.as_raw_fd(), request.as_ptr() as _, request.len()) };
+    assert_eq!(sent as usize, request.len());
+    iface.poll();
+
+    let mut buffer = [0; 1500];
+    let len = unsafe {
+        libc::recv(
+            peer.as_raw_fd(),
+            buffer.as_mut_ptr() as _,
+            buffer.len(),
+            libc::MSG_DONTWAIT,
+        )
+    };
+    assert!(len > 0, "no reply: {}", std::io::Error::last_os_error());
+    // a bare IP packet, no Ethernet header in front
+    let ip = Ipv4Packet::new_checked(&buffer[..len as usize]).unwrap();
+    assert_eq!(ip.src_addr(), LOCAL);
+    assert_eq!(ip.dst_addr(), PEER);
+    let icmp = Icmpv4Packet::new_checked(ip.payload()).unwrap();
+    let reply = Icmpv4Repr::parse(&icmp, &ChecksumCapabilities::default()).unwrap();
+    assert_eq!(
+        reply,
+        Icmpv4Repr::EchoReply {
+            ident: 0x1234,
+            seq_no: 1,
+            data: b"tun",
+        }
+    );
+}
+
+#[test]
+fn a_hung_up_peer_takes_the_link_down() {
+    let (tun, peer) = device(Medium::Ip);
+    let iface = TunIface::new(tun).unwrap();
+    iface
+        .update_ip_addrs(&[IpCidr::new(IpAddress::Ipv4(LOCAL), 24)])
+        .unwrap();
+    assert_eq!(iface.common().link_error(), None);
+
+    // the request stays queued, the reply finds nobody to write to
+    let request = echo_request(0x4321);
+    let sent = unsafe { libc::write(peer.as_raw_fd(), request.as_ptr() as _, request.len()) };
+    assert_eq!(sent as usize, request.len());
+    drop(peer);
+    iface.poll();
+
+    let stats = iface.common().stats();
+    assert_eq!(stats.rx_packets, 1);
+    assert!(stats.rx_errors > 0);
+    assert_eq!(stats.tx_errors, 1);
+    assert_eq!(iface.common().link_error(), Some(Errno::ENETDOWN));
+}
+
+#[test]
+fn tun_route_needs_no_gateway() {
+    let (tun, _peer) = device(Medium::Ip);
+    let iface: Arc<dyn Iface> = Arc::new(TunIface::new(tun).unwrap());
+    iface
+        .update_ip_addrs(&[IpCidr::new(IpAddress::v4(10, 66, 0, 2), 24)])
+        .unwrap();
+    let ifindex = NET_DEVICES.register(iface.clone()).unwrap();
+    // a point-to-point link reaches any network directly
+    let route = Route::new(
+        IpCidr::new(IpAddress::v4(198, 51, 100, 0), 24),
+        None,
+        ifindex,
+        0,
+    );
+    ROUTES.add(route).unwrap();
+    let next_hop = ROUTES.lookup(&IpAddress::v4(198, 51, 100, 7)).unwrap();
+    assert!(Arc::ptr_eq(&next_hop.iface, &iface));
+    ROUTES.remove(route.dst, None).unwrap();
+}
@@ -0,0 +1,831 @@
+//! Two stacks in one process talking over an in-memory veth pair.
+mod common;
+
+use std::sync::{Arc, Barrier, Once};
+use std::thread;
+use std::time::Duration;
+
+use berkeley_socket::{
+    driver::{
+        capture::{Capture, CaptureFormat},
+        fault::{FaultConfig, FaultInjector},
+        irq::start_network_polling_thread,
+        veth::veth_pair,
+        veth::VETH_DEFAULT_MTU,
+    },
+    event_poll::EPollEventType,
+    interface::{
+        registry::NET_DEVICES,
+        route::{Route, ROUTES},
+        veth::VethIface,
+        Iface,
+    },
+    posix::{PMSG, SOCK},
+    socket::{
+        endpoint::{Endpoint, LinkLayerEndpoint},
+        inet::syscall::{Inet, Inet6},
+        packet::{syscall::Packet, ETH_P_ALL},
+        Family, Socket,
+    },
+};
+use common::{endpoint, ip, ipv4_packet, udp_datagram, with_timeout};
+use linux_errnos::Errno;
+use smoltcp::wire::{EthernetAddress, EthernetFrame, IpAddress, IpCidr, IpEndpoint, Ipv4Packet};
+
+/// Addresses of the two ends of the pair
+const LEFT: IpAddress = IpAddress::v4(10, 64, 0, 1);
+const RIGHT: IpAddress = IpAddress::v4(10, 64, 0, 2);
+
+/// Registers a veth pair with an address on each end
+fn pair(left: IpAddress, right: IpAddress) -> (Arc<dyn Iface>, Arc<dyn Iface>) {
+    let (left_device, right_device) = veth_pair(VETH_DEFAULT_MTU).unwrap();
+    let [left, right] = [(left_device, left), (right_device, right)].map(|(device, addr)| {
+        let iface: Arc<dyn Iface> = Arc::new(VethIface::new(device));
+        iface.update_ip_addrs(&[IpCidr::new(addr, 24)]).unwrap();
+        NET_DEVICES.register(iface.clone()).unwrap();
+        iface
+    });
+    (left, right)
+}
+
+fn setup() {
+    static SETUP: Once = Once::new();
+    SETUP.call_once(|| {
+        pair(LEFT, RIGHT);
+        start_network_polling_thread().unwrap();
+    });
+}
+
+#[test]
+fn udp_round_trip() {
+    setup();
+    with_timeout(|| {
+        let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(RIGHT, 7000)).unwrap();
+        let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+        client.bind(endpoint(LEFT, 7001)).unwrap();
+
+        let mut buffer = [0; 64];
+        for n in 0..8u8 {
+            let message = [n; 32];
+            client
+                .send_to(&message, PMSG::empty(), endpoint(RIGHT, 7000))
+                .unwrap();
+            let (len, from) = server.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+            assert_eq!(&buffer[..len], &message);
+            assert_eq!(ip(from.clone()), IpEndpoint::new(LEFT, 7001));
+
+            server.send_to(&buffer[..len], PMSG::empty(), from).unwrap();
+            let (len, from) = client.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+            assert_eq!(&buffer[..len], &message);
+            assert_eq!(ip(from), IpEndpoint::new(RIGHT, 7000));
+        }
+        client.close().unwrap();
+        server.close().unwrap();
+    });
+}
+
+#[test]
+fn tcp_round_trip() {
+    setup();
+    with_timeout(|| {
+        let listener = Inet::socket(SOCK::Stream, 0).unwrap();
+        listener.bind(endpoint(RIGHT, 8000)).unwrap();
+        listener.listen(4).unwrap();
+        // larger than the socket buffers, so both sides wait on each other
+        let data: Vec<u8> = (0..256 * 1024u32).map(|n| (n % 251) as u8).collect();
+        let total = data.len();
+        // shutdown() does not send a FIN yet, so the server stops at the
+        // length it expects instead of at EOF
+        let server = thread::spawn(move || {
+            let (stream, from) = listener.accept().unwrap();
+            assert_eq!(ip(from).addr, LEFT);
+            let mut buffer = vec![0; 4096];
+            let mut echoed = 0;
+            while echoed < total {
+                let len = stream.recv(&mut buffer, PMSG::empty()).unwrap();
+                assert_ne!(len, 0, "connection closed early");
+                let mut sent = 0;
+                while sent < len {
+                    sent += stream.send(&buffer[sent..len], PMSG::empty()).unwrap();
+                }
+                echoed += len;
+            }
+        });
+
+        let client = Inet::socket(SOCK::Stream, 0).unwrap();
+        client.bind(endpoint(LEFT, 8001)).unwrap();
+        client.connect(endpoint(RIGHT, 8000)).unwrap();
+        assert_eq!(
+            ip(client.get_peer_name().unwrap()),
+            IpEndpoint::new(RIGHT, 8000)
+        );
+
+        let reader = {
+            let client = client.clone();
+            thread::spawn(move || {
+                let mut echoed = Vec::with_capacity(total);
+                let mut buffer = vec![0; 4096];
+                while echoed.len() < total {
+                    let read = client.recv(&mut buffer, PMSG::empty()).unwrap();
+                    assert_ne!(read, 0, "connection closed early");
+                    echoed.extend_from_slice(&buffer[..read]);
+                }
+                echoed
+            })
+        };
+        let mut sent = 0;
+        while sent < data.len() {
+            sent += client.send(&data[sent..], PMSG::empty()).unwrap();
+        }
+        assert!(reader.join().unwrap() == data);
+        server.join().unwrap();
+    });
+}
+
+#[test]
+fn link_down_fails_bound_sockets() {
+    setup();
+    // a pair of its own, the other tests keep their link
+    let (left_addr, right_addr) = (IpAddress::v4(10, 64, 1, 1), IpAddress::v4(10, 64, 1, 2));
+    let (_left, right) = pair(left_addr, right_addr);
+    with_timeout(move || {
+        let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(right_addr, 7100)).unwrap();
+        let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+        client.bind(endpoint(left_addr, 7101)).unwrap();
+        assert_eq!(server.poll() & EPollEventType::EPOLLERR.bits() as usize, 0);
+
+        // a blocked receive is woken by the link going down
+        let blocked = {
+            let server = server.clone();
+            thread::spawn(move || server.recv(&mut [0; 64], PMSG::empty()))
+        };
+        thread::sleep(Duration::from_millis(100));
+        right.set_up(false).unwrap();
+        assert_eq!(blocked.join().unwrap(), Err(Errno::ENETDOWN));
+        assert_ne!(server.poll() & EPollEventType::EPOLLERR.bits() as usize, 0);
+        assert_eq!(
+            server.recv(&mut [0; 64], PMSG::DONTWAIT),
+            Err(Errno::ENETDOWN)
+        );
+
+        right.set_up(true).unwrap();
+        assert_eq!(server.poll() & EPollEventType::EPOLLERR.bits() as usize, 0);
+        client
+            .send_to(b"back", PMSG::empty(), endpoint(right_addr, 7100))
+            .unwrap();
+        let mut buffer = [0; 64];
+        let len = server.recv(&mut buffer, PMSG::empty()).unwrap();
+        assert_eq!(&buffer[..len], b"back");
+    });
+}
+
+#[test]
+fn ifaces_hold_several_addresses_of_both_families() {
+    setup();
+    let (left, _right) = pair(IpAddress::v4(10, 70, 0, 1), IpAddress::v4(10, 70, 0, 2));
+    let v4 = IpCidr::new(IpAddress::v4(10, 71, 0, 1), 16);
+    let v6 = IpCidr::new(IpAddress::v6(0xfd70, 0, 0, 0, 0, 0, 0, 1), 64);
+    left.add_ip_addr(v6).unwrap();
+    left.add_ip_addr(v4).unwrap();
+    assert_eq!(
+        left.ip_addrs(),
+        [IpCidr::new(IpAddress::v4(10, 70, 0, 1), 24), v6, v4]
+    );
+    assert_eq!(left.add_ip_addr(v4), Err(Errno::EEXIST));
+    // neither a multicast nor the unspecified address is an address of an iface
+    assert_eq!(
+        left.add_ip_addr(IpCidr::new(IpAddress::v4(224, 0, 0, 1), 4)),
+        Err(Errno::EINVAL)
+    );
+    assert_eq!(
+        left.add_ip_addr(IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0)),
+        Err(Errno::EINVAL)
+    );
+
+    left.remove_ip_addr(v6).unwrap();
+    assert_eq!(left.remove_ip_addr(v6), Err(Errno::EADDRNOTAVAIL));
+    assert_eq!(
+        left.ip_addrs(),
+        [IpCidr::new(IpAddress::v4(10, 70, 0, 1), 24), v4]
+    );
+    assert_eq!(
+        left.update_ip_addrs(&[v4, v4]),
+        Err(Errno::EINVAL),
+        "duplicates are refused"
+    );
+}
+
+#[test]
+fn source_address_follows_the_subnet_of_the_peer() {
+    setup();
+    // the other end is kept off the subnets, its routes would compete
+    let (left, _right) = pair(IpAddress::v4(10, 72, 0, 1), IpAddress::v4(10, 74, 0, 2));
+    let v6 = |net: u16, host: u16| IpAddress::v6(0xfd72, net, 0, 0, 0, 0, 0, host);
+    left.add_ip_addr(IpCidr::new(IpAddress::v4(10, 73, 0, 1), 24))
+        .unwrap();
+    left.add_ip_addr(IpCidr::new(v6(1, 1), 64)).unwrap();
+    left.add_ip_addr(IpCidr::new(v6(2, 1), 64)).unwrap();
+
+    let common = left.common();
+    assert_eq!(
+        common.source_addr(&IpAddress::v4(10, 73, 0, 9)),
+        Some(IpAddress::v4(10, 73, 0, 1))
+    );
+    assert_eq!(
+        common.source_addr(&IpAddress::v4(10, 72, 0, 9)),
+        Some(IpAddress::v4(10, 72, 0, 1))
+    );
+    // off every subnet the first address of the family is used
+    assert_eq!(
+        common.source_addr(&IpAddress::v4(192, 0, 2, 1)),
+        Some(IpAddress::v4(10, 72, 0, 1))
+    );
+    assert_eq!(common.source_addr(&v6(2, 9)), Some(v6(2, 1)));
+    assert_eq!(common.source_addr(&v6(1, 9)), Some(v6(1, 1)));
+
+    // an unbound socket takes the address of the subnet of its peer
+    for (remote, local) in [
+        (IpAddress::v4(10, 73, 0, 9), IpAddress::v4(10, 73, 0, 1)),
+        (IpAddress::v4(10, 72, 0, 9), IpAddress::v4(10, 72, 0, 1)),
+        (v6(2, 9), v6(2, 1)),
+        (v6(1, 9), v6(1, 1)),
+    ] {
+        let client = match remote {
+            IpAddress::Ipv4(_) => Inet::socket(SOCK::Datagram, 0),
+            IpAddress::Ipv6(_) => Inet6::socket(SOCK::Datagram, 0),
+        }
+        .unwrap();
+        client.connect(endpoint(remote, 7200)).unwrap();
+        assert_eq!(ip(client.get_name().unwrap()).addr, local);
+        client.close().unwrap();
+    }
+}
+
+#[test]
+fn wildcard_sockets_serve_every_iface() {
+    setup();
+    let localhost = IpAddress::v4(127, 0, 0, 1);
+    let any = IpAddress::v4(0, 0, 0, 0);
+    with_timeout(move || {
+        let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(any, 7300)).unwrap();
+        let listener = Inet::socket(SOCK::Stream, 0).unwrap();
+        listener.bind(endpoint(any, 8300)).unwrap();
+        listener.listen(4).unwrap();
+
+        // one peer over the veth pair, one over the loopback
+        for (client_addr, server_addr) in [(LEFT, RIGHT), (localhost, localhost)] {
+            let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+            client.bind(endpoint(client_addr, 0)).unwrap();
+            client
+                .send_to(b"udp", PMSG::empty(), endpoint(server_addr, 7300))
+                .unwrap();
+            let mut buffer = [0; 64];
+            let (len, from) = server.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+            assert_eq!(&buffer[..len], b"udp");
+            assert_eq!(ip(from.clone()).addr, client_addr);
+            // both ends of the pair are local, a reply to LEFT is routed
+            // to the iface owning it and never crosses the pair
+            if client_addr == localhost {
+                server.send_to(b"back", PMSG::empty(), from).unwrap();
+                let (len, from) = client.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+                assert_eq!(&buffer[..len], b"back");
+                assert_eq!(ip(from), IpEndpoint::new(server_addr, 7300));
+            }
+            client.close().unwrap();
+
+            let client = Inet::socket(SOCK::Stream, 0).unwrap();
+            client.bind(endpoint(client_addr, 0)).unwrap();
+            // port 0 picks an ephemeral port at bind time
+            assert_ne!(ip(client.get_name().unwrap()).port, 0);
+            client.connect(endpoint(server_addr, 8300)).unwrap();
+            let (stream, from) = listener.accept().unwrap();
+            assert_eq!(ip(from).addr, client_addr);
+            assert_eq!(
+                ip(stream.get_name().unwrap()),
+                IpEndpoint::new(server_addr, 8300)
+            );
+            client.send(b"tcp", PMSG::empty()).unwrap();
+            let len = stream.recv(&mut buffer, PMSG::empty()).unwrap();
+            assert_eq!(&buffer[..len], b"tcp");
+        }
+        server.close().unwrap();
+        listener.close().unwrap();
+    });
+}
+
+#[test]
+fn wildcard_binds_race_iface_registration() {
+    setup();
+    let any = IpAddress::v4(0, 0, 0, 0);
+    with_timeout(move || {
+        let start = Arc::new(Barrier::new(2));
+        let registrar = {
+            let start = start.clone();
+            thread::spawn(move || {
+                start.wait();
+                (0..8)
+                    .flat_map(|n| {
+                        let (left, right) =
+                            pair(IpAddress::v4(10, 82, n, 1), IpAddress::v4(10, 82, n, 2));
+                        [
+                            (left, IpAddress::v4(10, 82, n, 1)),
+                            (right, IpAddress::v4(10, 82, n, 2)),
+                        ]
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+        start.wait();
+        let sockets: Vec<_> = (7800..7816)
+            .map(|port| {
+                let socket = Inet::socket(SOCK::Datagram, 0).unwrap();
+                socket.bind(endpoint(any, port)).unwrap();
+                (socket, port)
+            })
+            .collect();
+        let ifaces = registrar.join().unwrap();
+
+        // a wildcard socket holds its port on every iface, whenever the
+        // iface came up during its bind
+        for (iface, addr) in &ifaces {
+            for (_, port) in &sockets {
+                let other = Inet::socket(SOCK::Datagram, 0).unwrap();
+                assert_eq!(
+                    other.bind(endpoint(*addr, *port)),
+                    Err(Errno::EADDRINUSE),
+                    "port {} is free on {}",
+                    port,
+                    iface.iface_name()
+                );
+            }
+        }
+        for (socket, _) in sockets {
+            socket.close().unwrap();
+        }
+    });
+}
+
+#[test]
+fn on_link_routes_reach_another_subnet() {
+    setup();
+    let (left_addr, right_addr) = (IpAddress::v4(10, 83, 1, 1), IpAddress::v4(10, 83, 2, 1));
+    let (left_device, right_device) = veth_pair(VETH_DEFAULT_MTU).unwrap();
+    let [left, right] = [
+        (left_device, left_addr, right_addr),
+        (right_device, right_addr, left_addr),
+    ]
+    .map(|(device, addr, peer)| {
+        let iface: Arc<dyn Iface> = Arc::new(VethIface::new(device));
+        iface.update_ip_addrs(&[IpCidr::new(addr, 24)]).unwrap();
+        let ifindex = NET_DEVICES.register(iface.clone()).unwrap();
+        // the subnet of the peer is on the cable, without a gateway
+        let subnet = IpCidr::new(peer, 24);
+        ROUTES.add(Route::new(subnet, None, ifindex, 0)).unwrap();
+        iface
+    });
+    with_timeout(move || {
+        let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(right_addr, 7900)).unwrap();
+        let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+        client.bind(endpoint(left_addr, 7901)).unwrap();
+        // the first datagram waits for the ARP exchange
+        client
+            .send_to(b"on-link", PMSG::empty(), endpoint(right_addr, 7900))
+            .unwrap();
+        let mut buffer = [0; 64];
+        let (len, from) = server.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"on-link");
+        assert_eq!(ip(from.clone()), IpEndpoint::new(left_addr, 7901));
+        server.send_to(b"back", PMSG::empty(), from).unwrap();
+        let (len, _) = client.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        assert_eq!(&buffer[..len], b"back");
+
+        let listener = Inet::socket(SOCK::Stream, 0).unwrap();
+        listener.bind(endpoint(right_addr, 8900)).unwrap();
+        listener.listen(1).unwrap();
+        let stream = Inet::socket(SOCK::Stream, 0).unwrap();
+        stream.bind(endpoint(left_addr, 8901)).unwrap();
+        stream.connect(endpoint(right_addr, 8900)).unwrap();
+        let (accepted, from) = listener.accept().unwrap();
+        assert_eq!(ip(from), IpEndpoint::new(left_addr, 8901));
+        stream.send(b"tcp", PMSG::empty()).unwrap();
+        let len = accepted.recv(&mut buffer, PMSG::empty()).unwrap();
+        assert_eq!(&buffer[..len], b"tcp");
+
+        // each end knows the other by its own MAC
+        let mac = |iface: &Arc<dyn Iface>, addr| {
+            iface
+                .neighbors()
+                .into_iter()
+                .find(|neighbor| neighbor.ip == addr)
+                .and_then(|neighbor| neighbor.mac)
+        };
+        assert_eq!(mac(&left, right_addr), Some(right.mac()));
+        assert_eq!(mac(&right, left_addr), Some(left.mac()));
+        for socket in [client, server, stream, accepted, listener] {
+            socket.close().unwrap();
+        }
+        for iface in [left, right] {
+            NET_DEVICES.unregister(iface.nic_id()).unwrap();
+        }
+    });
+}
+
+#[test]
+fn traffic_is_counted_per_iface_and_protocol() {
+    setup();
+    // a pair of its own, the other tests send nothing over it
+    let (left_addr, right_addr) = (IpAddress::v4(10, 76, 0, 1), IpAddress::v4(10, 76, 0, 2));
+    let (left, right) = pair(left_addr, right_addr);
+    with_timeout(move || {
+        let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(right_addr, 7400)).unwrap();
+        let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+        client.bind(endpoint(left_addr, 7401)).unwrap();
+        let mut buffer = [0; 256];
+        // the first datagram resolves the neighbors, ARP is no IP
+        client
+            .send_to(b"warm", PMSG::empty(), endpoint(right_addr, 7400))
+            .unwrap();
+        server.recv(&mut buffer, PMSG::empty()).unwrap();
+        let (left_before, right_before) = (left.stats(), right.stats());
+        assert!(left_before.tx_packets > left_before.ip.out_packets);
+        assert_eq!(left_before.udp.out_packets, 1);
+        assert_eq!(right_before.udp.in_packets, 1);
+
+        // Ethernet, IPv4 and UDP headers around the payload
+        let payload = [7; 100];
+        let frame_len = 14 + 20 + 8 + payload.len() as u64;
+        client
+            .send_to(&payload, PMSG::empty(), endpoint(right_addr, 7400))
+            .unwrap();
+        assert_eq!(server.recv(&mut buffer, PMSG::empty()), Ok(payload.len()));
+        let (left_after, right_after) = (left.stats(), right.stats());
+        assert_eq!(left_after.tx_packets - left_before.tx_packets, 1);
+        assert_eq!(left_after.tx_bytes - left_before.tx_bytes, frame_len);
+        assert_eq!(left_after.ip.out_packets - left_before.ip.out_packets, 1);
+        assert_eq!(left_after.udp.out_packets, 2);
+        assert_eq!(right_after.rx_packets - right_before.rx_packets, 1);
+        assert_eq!(right_after.rx_bytes - right_before.rx_bytes, frame_len);
+        assert_eq!(right_after.ip.in_packets - right_before.ip.in_packets, 1);
+        assert_eq!(right_after.udp.in_packets, 2);
+
+        // a closed port answers with an ICMP port unreachable
+        client
+            .send_to(b"closed", PMSG::empty(), endpoint(right_addr, 7402))
+            .unwrap();
+        while left.stats().icmp.in_packets == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(right.stats().udp.in_packets, 3);
+        assert_eq!(right.stats().icmp.out_packets, 1);
+        assert_eq!(left.stats().icmp.in_packets, 1);
+
+        // a handshake takes at least two segments each way
+        let listener = Inet::socket(SOCK::Stream, 0).unwrap();
+        listener.bind(endpoint(right_addr, 8400)).unwrap();
+        listener.listen(2).unwrap();
+        let stream = Inet::socket(SOCK::Stream, 0).unwrap();
+        stream.bind(endpoint(left_addr, 8401)).unwrap();
+        stream.connect(endpoint(right_addr, 8400)).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        stream.send(b"tcp", PMSG::empty()).unwrap();
+        accepted.recv(&mut buffer, PMSG::empty()).unwrap();
+        let (left_after, right_after) = (left.stats(), right.stats());
+        assert!(left_after.tcp.out_packets >= 2);
+        assert!(right_after.tcp.in_packets >= 2);
+        assert!(right_after.tcp.out_packets >= 1);
+        assert_eq!(left_after.udp.out_packets, 3);
+        assert_eq!(left_after.rx_errors + left_after.tx_errors, 0);
+        assert_eq!(right_after.rx_dropped + right_after.tx_dropped, 0);
+    });
+}
+
+#[test]
+fn nonblocking_sends_fail_once_the_buffer_is_full() {
+    setup();
+    let (left_addr, right_addr) = (IpAddress::v4(10, 77, 0, 1), IpAddress::v4(10, 77, 0, 2));
+    pair(left_addr, right_addr);
+    // nobody answers the neighbor solicitation, the packets stay queued
+    let absent = IpAddress::v4(10, 77, 0, 99);
+    with_timeout(move || {
+        let udp = Inet::socket(SOCK::Datagram, 0).unwrap();
+        udp.bind(endpoint(left_addr, 7300)).unwrap();
+        let ping = Inet::socket(SOCK::Datagram, libc::IPPROTO_ICMP as u32).unwrap();
+        ping.bind(endpoint(left_addr, 7301)).unwrap();
+        let raw = Inet::socket(SOCK::Raw, libc::IPPROTO_ICMP as u32).unwrap();
+        raw.bind(endpoint(left_addr, 0)).unwrap();
+        let mut request = vec![8, 0, 0, 0, 0, 0, 0, 0];
+        request.resize(1000, 0);
+
+        for socket in [&udp, &ping, &raw] {
+            let full = (0..1000).find_map(|_| {
+                match socket.send_to(&request, PMSG::DONTWAIT, endpoint(absent, 7302)) {
+                    Ok(len) => {
+                        assert_eq!(len, request.len());
+                        None
+                    }
+                    Err(err) => Some(err),
+                }
+            });
+            assert_eq!(full, Some(Errno::EAGAIN));
+            assert_eq!(socket.poll() & EPollEventType::EPOLLOUT.bits() as usize, 0);
+        }
+        for socket in [udp, ping, raw] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+#[test]
+fn ipproto_raw_sockets_leave_port_unreachable_errors_alone() {
+    setup();
+    let (left_addr, right_addr) = (IpAddress::v4(10, 78, 0, 1), IpAddress::v4(10, 78, 0, 2));
+    pair(left_addr, right_addr);
+    with_timeout(move || {
+        let icmp = Inet::socket(SOCK::Raw, libc::IPPROTO_ICMP as u32).unwrap();
+        icmp.bind(endpoint(left_addr, 0)).unwrap();
+        // the socket a raw packet goes out through is put on every iface
+        let localhost = IpAddress::v4(127, 0, 0, 1);
+        let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(localhost, 7500)).unwrap();
+        let raw = Inet::socket(SOCK::Raw, libc::IPPROTO_RAW as u32).unwrap();
+        let packet = ipv4_packet(
+            libc::IPPROTO_UDP as u8,
+            localhost,
+            &udp_datagram(7501, 7500, b"raw"),
+        );
+        raw.send_to(&packet, PMSG::empty(), endpoint(localhost, 0))
+            .unwrap();
+        let mut buffer = [0; 128];
+        assert_eq!(server.recv(&mut buffer, PMSG::empty()), Ok(3));
+
+        // once the packet is out the socket no longer takes UDP on the pair
+        let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+        client.bind(endpoint(left_addr, 7501)).unwrap();
+        client
+            .send_to(b"closed", PMSG::empty(), endpoint(right_addr, 7502))
+            .unwrap();
+        loop {
+            let len = icmp.recv(&mut buffer, PMSG::empty()).unwrap();
+            let packet = Ipv4Packet::new_checked(&buffer[..len]).unwrap();
+            let error = packet.payload();
+            // the port unreachable error quotes the header of the datagram
+            if error[..2] == [3, 3] && error[30..32] == 7502u16.to_be_bytes() {
+                break;
+            }
+        }
+        for socket in [icmp, server, raw, client] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+/// The address of a packet socket on `iface`, a frame of `protocol` to `mac`
+fn link(iface: &Arc<dyn Iface>, protocol: u16, mac: Option<EthernetAddress>) -> Endpoint {
+    let mut endpoint = LinkLayerEndpoint::new(iface.nic_id());
+    endpoint.protocol = protocol;
+    if let Some(mac) = mac {
+        endpoint.halen = 6;
+        endpoint.addr[..6].copy_from_slice(mac.as_bytes());
+    }
+    Endpoint::LinkLayer(endpoint)
+}
+
+fn packet_socket(socket_type: SOCK, protocol: u16) -> Arc<dyn Socket> {
+    Packet::socket(socket_type, protocol.to_be() as u32).unwrap()
+}
+
+/// A local experimental ethertype, the stacks send no frames of it
+const ETH_P_TEST: u16 = 0x88b5;
+
+#[test]
+fn packet_sockets_see_the_frames_of_their_iface() {
+    setup();
+    let (left, right) = pair(IpAddress::v4(10, 79, 0, 1), IpAddress::v4(10, 79, 0, 2));
+    with_timeout(move || {
+        let sender = packet_socket(SOCK::Raw, ETH_P_ALL);
+        assert_eq!(
+            sender.bind(Endpoint::LinkLayer(LinkLayerEndpoint::new(4096))),
+            Err(Errno::ENODEV)
+        );
+        sender.bind(link(&left, 0, None)).unwrap();
+        // sees the frames of the left end both ways, those of the sender included
+        let observer = packet_socket(SOCK::Raw, ETH_P_ALL);
+        observer.bind(link(&left, 0, None)).unwrap();
+        let all = packet_socket(SOCK::Raw, ETH_P_ALL);
+        all.bind(link(&right, 0, None)).unwrap();
+        let datagram = packet_socket(SOCK::Datagram, ETH_P_TEST);
+        datagram.bind(link(&right, 0, None)).unwrap();
+        let ip = packet_socket(SOCK::Datagram, libc::ETH_P_IP as u16);
+        ip.bind(link(&right, 0, None)).unwrap();
+
+        let mut frame = Vec::new();
+        frame.extend_from_slice(right.mac().as_bytes());
+        frame.extend_from_slice(left.mac().as_bytes());
+        frame.extend_from_slice(&ETH_P_TEST.to_be_bytes());
+        frame.extend_from_slice(b"frame");
+        assert_eq!(sender.send(&frame, PMSG::empty()), Ok(frame.len()));
+
+        let mut buffer = [0; 128];
+        let expect = |socket: &Arc<dyn Socket>, pkttype: u8, expected: &[u8]| {
+            let mut buffer = [0; 128];
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+                let Endpoint::LinkLayer(from) = from else {
+                    panic!("not a link layer address");
+                };
+                if from.protocol != ETH_P_TEST {
+                    continue;
+                }
+                assert_eq!(from.pkttype, pkttype);
+                assert_eq!(from.hardware_addr(), left.mac().as_bytes());
+                assert_eq!(&buffer[..len], expected);
+                break;
+            }
+        };
+        expect(&observer, libc::PACKET_OUTGOING, &frame);
+        expect(&all, libc::PACKET_HOST, &frame);
+        // the link layer header is stripped
+        expect(&datagram, libc::PACKET_HOST, b"frame");
+        assert_eq!(ip.recv(&mut buffer, PMSG::DONTWAIT), Err(Errno::EAGAIN));
+        // the sender does not get its own frame back
+        while let Ok(len) = sender.recv(&mut buffer, PMSG::DONTWAIT) {
+            assert_ne!(&buffer[..len], &frame[..]);
+        }
+
+        // the stack puts the link layer header on a datagram
+        datagram
+            .send_to(
+                b"reply",
+                PMSG::empty(),
+                link(&right, ETH_P_TEST, Some(left.mac())),
+            )
+            .unwrap();
+        loop {
+            let len = observer.recv(&mut buffer, PMSG::empty()).unwrap();
+            let ethernet = EthernetFrame::new_checked(&buffer[..len]).unwrap();
+            if u16::from(ethernet.ethertype()) != ETH_P_TEST {
+                continue;
+            }
+            assert_eq!(ethernet.dst_addr(), left.mac());
+            assert_eq!(ethernet.src_addr(), right.mac());
+            assert_eq!(ethernet.payload(), b"reply");
+            break;
+        }
+        for socket in [sender, observer, all, datagram, ip] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+/// The frames of a pcap file, after checking its global header and that the
+/// records fill it exactly
+fn pcap_frames(data: &[u8]) -> Vec<&[u8]> {
+    let u32_at = |at: usize| u32::from_ne_bytes(data[at..at + 4].try_into().unwrap());
+    assert_eq!(u32_at(0), 0xa1b2c3d4);
+    assert_eq!(
+        &data[4..8],
+        [2u16.to_ne_bytes(), 4u16.to_ne_bytes()].concat()
+    );
+    // LINKTYPE_ETHERNET
+    assert_eq!(u32_at(20), 1);
+    let mut frames = Vec::new();
+    let mut at = 24;
+    while at < data.len() {
+        let (captured, len) = (u32_at(at + 8) as usize, u32_at(at + 12) as usize);
+        assert_eq!(captured, len);
+        frames.push(&data[at + 16..at + 16 + captured]);
+        at += 16 + captured;
+    }
+    assert_eq!(at, data.len());
+    frames
+}
+
+#[test]
+fn captures_record_both_directions_until_stopped() {
+    setup();
+    let (left_addr, right_addr) = (IpAddress::v4(10, 80, 0, 1), IpAddress::v4(10, 80, 0, 2));
+    let (left, right) = pair(left_addr, right_addr);
+    with_timeout(move || {
+        left.start_capture(Capture::ring(1 << 20, CaptureFormat::Pcap));
+        let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(right_addr, 7600)).unwrap();
+        let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+        client.bind(endpoint(left_addr, 7601)).unwrap();
+        let mut buffer = [0; 64];
+        client
+            .send_to(b"request", PMSG::empty(), endpoint(right_addr, 7600))
+            .unwrap();
+        let (len, from) = server.recv_from(&mut buffer, PMSG::empty(), None).unwrap();
+        server.send_to(&buffer[..len], PMSG::empty(), from).unwrap();
+        assert_eq!(client.recv(&mut buffer, PMSG::empty()), Ok(len));
+
+        let capture = left.stop_capture().unwrap();
+        assert_eq!(left.dump_capture(), None);
+        client
+            .send_to(b"unseen", PMSG::empty(), endpoint(right_addr, 7600))
+            .unwrap();
+        server.recv(&mut buffer, PMSG::empty()).unwrap();
+
+        let data = capture.dump().unwrap();
+        let frames = pcap_frames(&data);
+        let udp = |frame: &&[u8], payload: &[u8]| {
+            frame.len() >= 14 + 20 + 8 && frame[12..14] == [8, 0] && frame[14 + 28..] == *payload
+        };
+        // the request going out and the echo coming back
+        let echoed: Vec<_> = frames
+            .iter()
+            .filter(|frame| udp(frame, b"request"))
+            .collect();
+        assert_eq!(echoed.len(), 2);
+        assert_eq!(&echoed[0][6..12], left.mac().as_bytes());
+        assert_eq!(&echoed[1][..6], left.mac().as_bytes());
+        assert_eq!(&echoed[1][6..12], right.mac().as_bytes());
+        assert!(!frames.iter().any(|frame| udp(frame, b"unseen")));
+        for socket in [server, client] {
+            socket.close().unwrap();
+        }
+    });
+}
+
+#[test]
+fn transfers_survive_a_lossy_link() {
+    setup();
+    let (left_addr, right_addr) = (IpAddress::v4(10, 81, 0, 1), IpAddress::v4(10, 81, 0, 2));
+    let (left, _right) = pair(left_addr, right_addr);
+    with_timeout(move || {
+        let server = Inet::socket(SOCK::Datagram, 0).unwrap();
+        server.bind(endpoint(right_addr, 7700)).unwrap();
+        let client = Inet::socket(SOCK::Datagram, 0).unwrap();
+        client.bind(endpoint(left_addr, 7701)).unwrap();
+        let mut buffer = [0; 64];
+        // resolves the neighbors before the faults, ARP is not retried quickly
+        client
+            .send_to(b"warm", PMSG::empty(), endpoint(right_addr, 7700))
+            .unwrap();
+        server.recv(&mut buffer, PMSG::empty()).unwrap();
+
+        left.inject_faults(FaultInjector::new(FaultConfig {
+            seed: 25,
+            loss: 0.05,
+            duplicate: 0.05,
+            reorder: 0.25,
+            delay: Duration::from_millis(2).into(),
+            ..Default::default()
+        }));
+
+        // numbered datagrams, some go missing, come twice or overtake others
+        for n in 0..200u32 {
+            client
+                .send_to(&n.to_be_bytes(), PMSG::empty(), endpoint(right_addr, 7700))
+                .unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+        let mut received = Vec::new();
+        while let Ok(len) = server.recv(&mut buffer, PMSG::DONTWAIT) {
+            assert_eq!(len, 4);
+            received.push(u32::from_be_bytes(buffer[..4].try_into().unwrap()));
+        }
+        let mut sorted = received.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert!(sorted.len() < 200, "nothing was lost");
+        assert!(received != sorted, "nothing came twice or out of order");
+
+        // TCP retransmits what is lost and puts the rest back in order
+        let listener = Inet::socket(SOCK::Stream, 0).unwrap();
+        listener.bind(endpoint(right_addr, 8700)).unwrap();
+        listener.listen(1).unwrap();
+        let data: Vec<u8> = (0..128 * 1024u32).map(|n| (n % 251) as u8).collect();
+        let total = data.len();
+        let reader = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut read = Vec::with_capacity(total);
+            let mut buffer = vec![0; 4096];
+            while read.len() < total {
+                let len = stream.recv(&mut buffer, PMSG::empty()).unwrap();
+                assert_ne!(len, 0, "connection closed early");
+                read.extend_from_slice(&buffer[..len]);
+            }
+            read
+        });
+        let stream = Inet::socket(SOCK::Stream, 0).unwrap();
+        stream.bind(endpoint(left_addr, 8701)).unwrap();
+        stream.connect(endpoint(right_addr, 8700)).unwrap();
+        let mut sent = 0;
+        while sent < total {
+            sent += stream.send(&data[sent..], PMSG::empty()).unwrap();
+        }
+        assert!(reader.join().unwrap() == data);
+
+        let stats = left.clear_faults().unwrap().stats();
+        assert!(stats.dropped > 0 && stats.duplicated > 0 && stats.reordered > 0);
+        for socket in [server, client, stream] {
+            socket.close().unwrap();
+        }
+    });
+}